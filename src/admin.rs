@@ -0,0 +1,88 @@
+//! Admin (POST /admin/reload) and developer-only (POST /debug/echo) endpoints
+
+use std::env;
+use std::sync::Arc;
+
+use actix_web::{web, HttpRequest, HttpResponse};
+use arc_swap::ArcSwap;
+use log::{info, warn};
+use serde_json::json;
+
+use crate::client_ip::client_ip;
+use crate::config::{keyed_diff, TellConfig};
+use crate::error::ApiError;
+use crate::providers::resolve_provider;
+use crate::request::DEFAULT_TEMPERATURE;
+use crate::templates::{load_templates, TEMPLATES};
+use crate::tools::{load_tools, TOOLS};
+use crate::validate::parse_chat_request;
+
+// POST /debug/echo: the body as /sdk-chat deserializes it, plus the values the server falls back
+// to for fields the client left out
+pub async fn debug_echo(req: HttpRequest, body: web::Bytes) -> Result<HttpResponse, ApiError> {
+    let request = parse_chat_request(&body)?;
+
+    let resolved = json!({
+        "model": request.model,
+        "provider": resolve_provider(&request.model).ok().map(|route| route.provider.name()),
+        "temperature": request.temperature.unwrap_or(DEFAULT_TEMPERATURE),
+        "maxSteps": request.max_steps.unwrap_or(1),
+    });
+    Ok(HttpResponse::Ok().json(json!({
+        "request": request,
+        "resolved": resolved,
+        "clientIp": client_ip(req.peer_addr(), req.headers()),
+    })))
+}
+
+// Admin endpoints take the ADMIN_TOKEN as `Authorization: Bearer <token>`
+fn check_admin_token(req: &HttpRequest) -> Result<(), ApiError> {
+    let expected = env::var("ADMIN_TOKEN").unwrap_or_default();
+    let given = req
+        .headers()
+        .get("Authorization")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .unwrap_or_default();
+    // Compared in constant time, so response times don't leak how much of a guess was right
+    let matches = !expected.is_empty()
+        && given.len() == expected.len()
+        && given.bytes().zip(expected.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0;
+    if !matches {
+        warn!("Rejected {} {}: missing or wrong admin token", req.method(), req.path());
+        return Err(ApiError::unauthorized("A valid admin token is required"));
+    }
+    Ok(())
+}
+
+// POST /admin/reload: re-read TELL_CONFIG, TOOLS_FILE and TEMPLATES_DIR and swap them in. All are
+// validated before anything is swapped, so an invalid file rejects the whole reload. Requests already
+// running keep the config they started with. Returns what changed.
+pub async fn admin_reload(req: HttpRequest, config: web::Data<ArcSwap<TellConfig>>) -> Result<HttpResponse, ApiError> {
+    check_admin_token(&req)?;
+    let new_config = TellConfig::from_env()
+        .map_err(|e| ApiError::bad_request(format!("Invalid TELL_CONFIG, nothing was reloaded: {}", e)))?;
+    let new_tools =
+        load_tools().map_err(|e| ApiError::bad_request(format!("Invalid TOOLS_FILE, nothing was reloaded: {}", e)))?;
+    let new_templates = load_templates()
+        .map_err(|e| ApiError::bad_request(format!("Invalid TEMPLATES_DIR, nothing was reloaded: {}", e)))?;
+
+    let old_tools = TOOLS.load();
+    let old_templates = TEMPLATES.load();
+    let changes = json!({
+        "config": config.load().diff(&new_config),
+        "tools": keyed_diff(
+            old_tools.iter().map(|tool| (tool.name.as_str(), tool)),
+            new_tools.iter().map(|tool| (tool.name.as_str(), tool)),
+        ),
+        "templates": keyed_diff(
+            old_templates.iter().map(|(name, template)| (name.as_str(), template)),
+            new_templates.iter().map(|(name, template)| (name.as_str(), template)),
+        ),
+    });
+    config.store(Arc::new(new_config));
+    TOOLS.store(Arc::new(new_tools));
+    TEMPLATES.store(Arc::new(new_templates));
+    info!("Reloaded configuration: {}", changes);
+    Ok(HttpResponse::Ok().json(json!({ "reloaded": true, "changes": changes })))
+}
@@ -0,0 +1,65 @@
+//! POST /batch: several independent chat requests in one call
+
+use std::env;
+
+use actix_web::{web, HttpRequest, HttpResponse};
+use arc_swap::ArcSwap;
+use log::info;
+use serde_json::{json, Value};
+
+use crate::chat::{assemble_stream, chat, request_id};
+use crate::config::TellConfig;
+use crate::error::ApiError;
+use crate::validate::chat_request_from_value;
+
+// POST /batch: independent chat requests run concurrently (each holding an upstream slot),
+// answered together as a JSON array in input order. Every item gets the buffered result of its
+// stream, {text, toolCalls, usage, finishReason, error}; a request that fails gets the error
+// shape of an error response without affecting the others.
+pub async fn batch(
+    req: HttpRequest,
+    body: web::Bytes,
+    config: web::Data<ArcSwap<TellConfig>>,
+) -> Result<HttpResponse, ApiError> {
+    let items: Vec<Value> = serde_json::from_slice(&body)
+        .map_err(|e| ApiError::bad_request(format!("Invalid JSON: expected an array of chat requests: {}", e)))?;
+    let max_items = env::var("MAX_BATCH_SIZE").ok().and_then(|v| v.parse().ok()).unwrap_or(100);
+    if items.len() > max_items {
+        return Err(ApiError::bad_request(format!(
+            "Batch has {} requests; at most {} are allowed (MAX_BATCH_SIZE)",
+            items.len(),
+            max_items
+        )));
+    }
+    info!("Running a batch of {} requests", items.len());
+    let config = config.load_full();
+
+    // Items are cancelled one at a time, as <batch request id>-<index>
+    let batch_id = request_id(&req);
+    let items = items.into_iter().enumerate().map(|(index, item)| {
+        batch_item(&req, item, format!("{}-{}", batch_id, index), &config)
+    });
+    let results = futures::future::join_all(items).await;
+    Ok(HttpResponse::Ok().insert_header(("X-Request-Id", batch_id)).json(results))
+}
+
+async fn batch_item(req: &HttpRequest, item: Value, request_id: String, config: &TellConfig) -> Value {
+    let result = async {
+        let request = chat_request_from_value(item)?;
+        let response = chat(req, request, request_id.clone(), config, true).await?;
+        assemble_stream(response.into_body()).await
+    }
+    .await;
+
+    let mut result = result.unwrap_or_else(|e| {
+        json!({
+            "text": "",
+            "toolCalls": [],
+            "usage": null,
+            "finishReason": "error",
+            "error": { "code": e.status.as_u16(), "message": e.message, "type": e.error_type },
+        })
+    });
+    result["requestId"] = json!(request_id);
+    result
+}
@@ -0,0 +1,140 @@
+//! Ending streams early: POST /cancel/{requestId} and the client's own deadline
+
+use std::collections::HashMap;
+use std::env;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task::Poll;
+use std::time::{Duration, Instant};
+
+use actix_web::{
+    body::{BodyStream, BoxBody, MessageBody},
+    web, HttpRequest, HttpResponse,
+};
+use bytes::Bytes;
+use log::{info, warn};
+use serde_json::json;
+use tokio::sync::oneshot;
+
+use crate::convert::{FrameWriter, Usage};
+use crate::error::ApiError;
+use crate::request::ChatRequest;
+
+// In-flight /sdk-chat requests by request id. An entry is dead once its stream has ended
+// (the receiving side is dropped) or it has outlived the request's upstream timeout; dead
+// entries are swept whenever a new request registers.
+lazy_static::lazy_static! {
+    static ref CANCELLATIONS: Mutex<HashMap<String, (oneshot::Sender<()>, Instant)>> = Mutex::new(HashMap::new());
+}
+
+pub type Cancelled = Pin<Box<dyn Future<Output = ()>>>;
+
+// Register a request as cancellable. The returned future completes if the request is
+// cancelled, and never otherwise.
+pub fn register_cancellation(request_id: &str, timeout: Duration) -> Cancelled {
+    let (sender, receiver) = oneshot::channel();
+    let mut cancellations = CANCELLATIONS.lock().unwrap();
+    let now = Instant::now();
+    cancellations.retain(|_, (sender, expires)| !sender.is_closed() && *expires > now);
+    if cancellations.insert(request_id.to_string(), (sender, now + timeout)).is_some() {
+        warn!("Request id {} reused while still in flight; only the newest can be cancelled", request_id);
+    }
+    Box::pin(async move {
+        if receiver.await.is_err() {
+            std::future::pending::<()>().await;
+        }
+    })
+}
+
+// POST /cancel/{requestId}: stop an in-flight /sdk-chat stream. The upstream request is
+// dropped and the client's stream ends with a finishReason "stop" finish frame.
+pub async fn cancel_request(request_id: web::Path<String>) -> Result<HttpResponse, ApiError> {
+    let request_id = request_id.into_inner();
+    let entry = CANCELLATIONS.lock().unwrap().remove(&request_id);
+    // Sending fails if the stream has already ended
+    let cancelled = entry.is_some_and(|(sender, _)| sender.send(()).is_ok());
+    if !cancelled {
+        return Err(ApiError::not_found(format!("No active request with id {}", request_id)));
+    }
+    info!("Cancelling request {}", request_id);
+    Ok(HttpResponse::Ok().json(json!({ "requestId": request_id, "cancelled": true })))
+}
+
+// Completes with the frames that end a stream early, once it has to stop
+pub type StreamStop = Pin<Box<dyn Future<Output = String>>>;
+
+// A cancelled request stops with a "stop" finish; one that outlives the client's deadline stops
+// with a timeout error
+pub fn stream_stop(request_id: &str, cancelled: Cancelled, deadline: Option<Duration>, frames: FrameWriter) -> StreamStop {
+    let request_id = request_id.to_string();
+    Box::pin(async move {
+        let Some(deadline) = deadline else {
+            cancelled.await;
+            info!("Request {} cancelled", request_id);
+            return frames.finish("stop", Usage::default());
+        };
+        tokio::select! {
+            _ = cancelled => {
+                info!("Request {} cancelled", request_id);
+                frames.finish("stop", Usage::default())
+            }
+            _ = actix_web::rt::time::sleep(deadline) => {
+                warn!("Request {} hit its {}ms deadline", request_id, deadline.as_millis());
+                let message = format!(
+                    "timeout_error: The request's {}ms deadline passed before the response was complete",
+                    deadline.as_millis()
+                );
+                format!("{}{}", frames.error(&message), frames.finish("error", Usage::default()))
+            }
+        }
+    })
+}
+
+// The client's deadline from the timeoutMs field or the X-Timeout-Ms header, clamped to
+// MAX_CLIENT_TIMEOUT_MS (default 600000)
+pub fn client_deadline(req: &HttpRequest, request: &ChatRequest) -> Result<Option<Duration>, ApiError> {
+    let header = req
+        .headers()
+        .get("X-Timeout-Ms")
+        .map(|v| {
+            v.to_str()
+                .ok()
+                .and_then(|v| v.trim().parse::<u64>().ok())
+                .ok_or_else(|| ApiError::bad_request("X-Timeout-Ms must be a whole number of milliseconds"))
+        })
+        .transpose()?;
+    let Some(ms) = request.timeout_ms.or(header) else {
+        return Ok(None);
+    };
+    if ms == 0 {
+        return Err(ApiError::bad_request("The request timeout must be at least 1ms"));
+    }
+    let max_ms = env::var("MAX_CLIENT_TIMEOUT_MS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(600_000);
+    if ms > max_ms {
+        info!("Clamped client timeout {}ms to {}ms", ms, max_ms);
+    }
+    Ok(Some(Duration::from_millis(ms.min(max_ms))))
+}
+
+// End the response body with the frames from `stop` as soon as it completes. Dropping the
+// rest of the body drops the upstream request with it.
+pub fn end_stream_early(response: HttpResponse, mut stop: StreamStop) -> HttpResponse {
+    response.map_body(|_, mut body| {
+        let mut done = false;
+        let stream = futures::stream::poll_fn(move |cx| {
+            if done {
+                return Poll::Ready(None);
+            }
+            if let Poll::Ready(ending) = stop.as_mut().poll(cx) {
+                done = true;
+                return Poll::Ready(Some(Ok(Bytes::from(ending))));
+            }
+            Pin::new(&mut body).poll_next(cx)
+        });
+        BoxBody::new(BodyStream::new(stream))
+    })
+}
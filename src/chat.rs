@@ -0,0 +1,445 @@
+//! POST /sdk-chat, and the pipeline every chat request goes through: the guards, provider
+//! routing, the upstream call with its fallbacks, and the response stream
+
+use std::collections::HashMap;
+use std::env;
+use std::pin::Pin;
+use std::task::Poll;
+use std::time::Duration;
+
+use actix_web::{
+    body::{BodyStream, BoxBody, MessageBody},
+    http::header::{HeaderName, HeaderValue},
+    web, HttpRequest, HttpResponse,
+};
+use arc_swap::ArcSwap;
+use bytes::{Bytes, BytesMut};
+use log::{info, warn};
+use serde_json::{json, Value};
+use tokio_stream::StreamExt;
+
+use crate::cancel::{client_deadline, end_stream_early, register_cancellation, stream_stop};
+use crate::client_ip::client_ip;
+use crate::config::TellConfig;
+use crate::conversations::{record_conversation, ConversationRecorder};
+use crate::convert::{FrameWriter, LineBuffer, SdkVersion, Usage};
+use crate::error::ApiError;
+use crate::guards::{
+    check_model_allowed, finish_at_step_limit, max_tool_repeats, session_budget_exceeded_response,
+    session_token_budget, session_tokens_used, step_limit_response, tool_loop_guard_response, tool_steps_taken,
+    track_usage, trailing_tool_call_repeats,
+};
+use crate::messages::{apply_server_system_prompt, dedupe_consecutive_messages, prepare_messages};
+use crate::metrics::STEP_LIMIT_STOPS;
+use crate::mock::{mock_response, mock_scenario};
+use crate::output::OutputFormat;
+use crate::providers::openai::openai_scope_headers;
+use crate::providers::{
+    build_upstream_request, provider_override, resolve_provider, stream_from_provider, upstream_timeout, Provider,
+    Route,
+};
+use crate::request::ChatRequest;
+use crate::resume::{make_resumable, resumable_streams_enabled};
+use crate::slots::UpstreamSlot;
+use crate::stream::{assemble_response, frames_response, hold_until_streamed, is_event_stream, prepend_to_stream};
+use crate::templates::apply_template;
+use crate::tools::{ToolChoice, ToolChoiceMode};
+use crate::trim::trim_history;
+use crate::validate::parse_chat_request;
+
+pub async fn sdk_chat(
+    req: HttpRequest,
+    body: web::Bytes,
+    config: web::Data<ArcSwap<TellConfig>>,
+) -> Result<HttpResponse, ApiError> {
+    info!("Raw request body: {}", String::from_utf8_lossy(&body));
+
+    check_json_content_type(&req)?;
+    let output_format = OutputFormat::from_request(&req)?;
+    let mut request = parse_chat_request(&body)?;
+    if request.ai_sdk_version.is_none() {
+        request.ai_sdk_version = SdkVersion::from_header(&req)?;
+    }
+    let sdk_version = request.ai_sdk_version.unwrap_or_default();
+    let stream = request.stream.unwrap_or_else(|| accepts_stream(&req));
+    // A body that asks for a stream always gets one
+    let pinned_to_stream = request.stream == Some(true);
+    let response = chat(&req, request, request_id(&req), &config.load_full(), false).await?;
+    if !stream {
+        return buffered_response(response).await;
+    }
+    // Only AI SDK clients can be answered with the JSON object; other formats always stream
+    let response = match short_response_window() {
+        Some(window) if !pinned_to_stream && output_format == OutputFormat::default() => {
+            short_response(response, window).await
+        }
+        _ => response,
+    };
+    Ok(output_format.apply(response, sdk_version))
+}
+
+// Request bodies are JSON; a body sent as anything else is refused rather than guessed at
+fn check_json_content_type(req: &HttpRequest) -> Result<(), ApiError> {
+    let Some(content_type) = req.headers().get("Content-Type") else {
+        return Ok(());
+    };
+    let media_type = content_type.to_str().unwrap_or_default().split(';').next().unwrap_or_default().trim();
+    let media_type = media_type.to_ascii_lowercase();
+    if media_type == "application/json" || media_type.ends_with("+json") {
+        Ok(())
+    } else {
+        Err(ApiError::unsupported_media_type(format!(
+            "Content-Type must be application/json, not '{}'",
+            media_type
+        )))
+    }
+}
+
+// Whether the client wants the SSE stream: the first of application/json and
+// text/event-stream in its Accept header decides, and anything else streams
+fn accepts_stream(req: &HttpRequest) -> bool {
+    let Some(accept) = req.headers().get("Accept").and_then(|v| v.to_str().ok()) else {
+        return true;
+    };
+    accept
+        .split(',')
+        .map(|media_range| media_range.split(';').next().unwrap_or_default().trim().to_ascii_lowercase())
+        .find_map(|media_type| match media_type.as_str() {
+            "application/json" => Some(false),
+            "text/event-stream" => Some(true),
+            _ => None,
+        })
+        .unwrap_or(true)
+}
+
+// A streamed response collected into one JSON object, as /batch returns them. Responses that
+// aren't streams (dry runs) pass through unchanged.
+async fn buffered_response(response: HttpResponse) -> Result<HttpResponse, ApiError> {
+    if !is_event_stream(&response) {
+        return Ok(response);
+    }
+    let (response, body) = response.into_parts();
+    let assembled = assemble_stream(body).await?;
+    Ok(json_in_place_of_stream(&response, assembled))
+}
+
+// The assembled turn as JSON, with the stream's status and headers other than its content type
+fn json_in_place_of_stream(stream: &HttpResponse<()>, assembled: Value) -> HttpResponse {
+    let mut buffered = HttpResponse::build(stream.status());
+    for (name, value) in stream.headers() {
+        if name != "content-type" && name != "cache-control" {
+            buffered.append_header((name.clone(), value.clone()));
+        }
+    }
+    buffered.json(assembled)
+}
+
+// SHORT_RESPONSE_WINDOW_MS: how long to wait for a response to complete before streaming it.
+// Unset or 0 (the default) streams every response from the first frame.
+fn short_response_window() -> Option<Duration> {
+    env::var("SHORT_RESPONSE_WINDOW_MS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|ms| *ms > 0)
+        .map(Duration::from_millis)
+}
+
+// Hold a stream back for up to `window`. A response that completes within it without tool calls
+// is sent as one JSON object, as for Accept: application/json. Otherwise what was held back is
+// written as the start of the stream, which carries on from there.
+async fn short_response(response: HttpResponse, window: Duration) -> HttpResponse {
+    if !is_event_stream(&response) {
+        return response;
+    }
+    let (response, mut body) = response.into_parts();
+    let deadline = tokio::time::Instant::now() + window;
+    let mut held = BytesMut::new();
+    let mut lines = LineBuffer::default();
+    let mut error = loop {
+        let next = futures::future::poll_fn(|cx| Pin::new(&mut body).poll_next(cx));
+        match tokio::time::timeout_at(deadline, next).await {
+            Ok(Some(Ok(chunk))) => {
+                held.extend_from_slice(&chunk);
+                let frames = lines.push(&chunk);
+                if frames.lines().any(|line| line.starts_with("9:") || line.starts_with("b:")) {
+                    break None;
+                }
+            }
+            Ok(Some(Err(e))) => break Some(e),
+            Ok(None) => {
+                let assembled = assemble_response(&String::from_utf8_lossy(&held));
+                return json_in_place_of_stream(&response, assembled);
+            }
+            // Too long for a short answer
+            Err(_) => break None,
+        }
+    };
+    let held = futures::stream::iter((!held.is_empty()).then(|| Ok(held.freeze())));
+    let rest = futures::stream::poll_fn(move |cx| match error.take() {
+        Some(e) => Poll::Ready(Some(Err(e))),
+        None => Pin::new(&mut body).poll_next(cx),
+    });
+    response.set_body(BoxBody::new(BodyStream::new(held.chain(rest))))
+}
+
+pub async fn assemble_stream(body: impl MessageBody) -> Result<Value, ApiError> {
+    let body = actix_web::body::to_bytes(body)
+        .await
+        .map_err(|e| ApiError::internal(format!("Failed to read the response: {}", e.into())))?;
+    Ok(assemble_response(&String::from_utf8_lossy(&body)))
+}
+
+// One chat request, through to the converted response stream. `request_id` names it for
+// cancellation and the X-Request-Id header; requests run together under one HttpRequest (batch
+// items, compared models) each need their own. With `wait_for_slot` the request queues for an
+// upstream slot instead of failing when all are taken.
+pub async fn chat(
+    req: &HttpRequest,
+    mut request: ChatRequest,
+    request_id: String,
+    config: &TellConfig,
+    wait_for_slot: bool,
+) -> Result<HttpResponse, ApiError> {
+    if request.session_id.is_none() {
+        request.session_id = req
+            .headers()
+            .get("X-Session-Id")
+            .and_then(|v| v.to_str().ok())
+            .filter(|v| !v.is_empty())
+            .map(str::to_string);
+    }
+    let frames = FrameWriter::new();
+    apply_template(&mut request)?;
+
+    info!("Parsed request from {}: model={}, messages={}, temperature={:?}, max_steps={:?}",
+          client_ip(req.peer_addr(), req.headers()), request.model, request.messages.len(),
+          request.temperature, request.max_steps);
+
+    if request.dedupe_messages {
+        let dropped = dedupe_consecutive_messages(&mut request.messages);
+        if dropped > 0 {
+            info!("Dropped {} duplicate consecutive message(s)", dropped);
+        }
+    }
+
+    // Break out of agentic loops where the model keeps repeating the same tool call
+    let max_repeats = max_tool_repeats();
+    if max_repeats > 0 {
+        if let Some((tool_name, repeats)) = trailing_tool_call_repeats(&request.messages) {
+            if repeats >= max_repeats {
+                info!("Tool loop guard triggered: {} called {} times in a row with identical arguments",
+                      tool_name, repeats);
+                return Ok(tool_loop_guard_response(frames, &tool_name, repeats));
+            }
+        }
+    }
+
+    // A loop that has used its maxSteps gets one last answer without tools, or a terminal
+    // message when the request insists on a tool call
+    let step_limit = request.max_steps.filter(|max_steps| tool_steps_taken(&request.messages) >= *max_steps);
+    if let Some(max_steps) = step_limit {
+        if request.tool_choice.as_ref().is_some_and(ToolChoice::forces_tool_call) {
+            info!("Step limit of {} reached and toolChoice forces a tool call; stopping the loop", max_steps);
+            STEP_LIMIT_STOPS.with_label_values(&["terminal_message"]).inc();
+            return Ok(step_limit_response(frames, max_steps));
+        }
+        info!("Step limit of {} reached; asking for a final answer without tools", max_steps);
+        STEP_LIMIT_STOPS.with_label_values(&["final_completion"]).inc();
+        request.tool_choice = Some(ToolChoice::Mode(ToolChoiceMode::None));
+    }
+
+    // A session that has spent its token budget gets no further calls
+    let session_id = request.session_id.clone();
+    let remaining_tokens = match (&session_id, session_token_budget(&request)) {
+        (Some(session_id), Some(budget)) => {
+            let remaining = budget.saturating_sub(session_tokens_used(session_id));
+            if remaining == 0 {
+                info!("Session {} has used its budget of {} tokens", session_id, budget);
+                return Ok(session_budget_exceeded_response(frames, budget));
+            }
+            Some(remaining)
+        }
+        _ => None,
+    };
+
+    // Only models on ALLOWED_MODELS, fallbacks included
+    for model in std::iter::once(&request.model).chain(&request.fallback_models) {
+        check_model_allowed(model)?;
+    }
+
+    if let Some(scenario) = mock_scenario(&request.model)? {
+        return Ok(mock_response(frames, scenario));
+    }
+
+    // Fallbacks start over from the request as the client sent it
+    let fallback_models = std::mem::take(&mut request.fallback_models);
+    let client_request = (!fallback_models.is_empty()).then(|| request.clone());
+
+    // Determine provider based on model name, unless the client picked one
+    let route = match provider_override(req)? {
+        Some(route) => route,
+        None => resolve_provider(&request.model)?,
+    };
+    let provider = route.provider;
+    prepare_messages(provider, &mut request)?;
+
+    // Recorded as the client sent it, before the server system prompt is added
+    let recorder = ConversationRecorder::new(&request);
+
+    apply_server_system_prompt(req, &mut request.messages);
+    // A dry run trims without the summary call, so it never reaches the provider
+    let mut trimmed = trim_history(req, provider, &mut request, config).await;
+
+    let routing_annotation = explain_routing_enabled().then(|| routing_annotation(frames, &request.model, &route));
+    let timeout = upstream_timeout(request.max_steps);
+    // The client's deadline only matters when it's shorter than the server's own timeout
+    let deadline = client_deadline(req, &request)?.filter(|deadline| *deadline < timeout);
+    if is_dry_run(req) {
+        let upstream = build_upstream_request(provider, request, openai_scope_headers(req), config)?;
+        let mut response = HttpResponse::Ok();
+        if let Some(trimmed) = trimmed {
+            response.insert_header((HeaderName::from_static("x-trimmed-messages"), trimmed));
+        }
+        return Ok(response.json(upstream.dry_run_json(provider)));
+    }
+
+    let upstream_slot = if wait_for_slot {
+        UpstreamSlot::wait().await
+    } else {
+        UpstreamSlot::acquire()?
+    };
+    let cancelled = register_cancellation(&request_id, deadline.unwrap_or(timeout));
+    let mut stop = stream_stop(&request_id, cancelled, deadline, frames);
+    let mut served_model = request.model.clone();
+    let mut served_provider = provider;
+    let response = async {
+        let mut response = stream_from_provider(provider, request, req, config, frames).await;
+        let Some(client_request) = &client_request else {
+            return response;
+        };
+        for model in &fallback_models {
+            match &response {
+                Err(error) if error.is_upstream_failure() => {
+                    warn!("{} failed ({}), falling back to {}", served_model, error, model);
+                }
+                _ => break,
+            }
+            let (provider, mut request) = match fallback_request(req, client_request, model) {
+                Ok(fallback) => fallback,
+                Err(e) => {
+                    warn!("Skipping fallback model {}: {}", model, e);
+                    continue;
+                }
+            };
+            trimmed = trim_history(req, provider, &mut request, config).await;
+            served_model = model.clone();
+            served_provider = provider;
+            response = stream_from_provider(provider, request, req, config, frames).await;
+        }
+        response
+    };
+    let mut stopped = false;
+    let response = tokio::select! {
+        response = response => response,
+        ending = stop.as_mut() => {
+            stopped = true;
+            Ok(frames_response(format!("{}{}", frames.start_step(), ending)))
+        }
+    };
+    // An overloaded provider is reported in the stream, where the client's chat UI shows it
+    let response = match response {
+        Err(error) if error.error_type == "overloaded_error" => overloaded_stream_response(frames, &error),
+        response => response?,
+    };
+    let mut response = if stopped { response } else { end_stream_early(response, stop) };
+    if step_limit.is_some() {
+        response = finish_at_step_limit(response, frames);
+    }
+    if let Ok(value) = HeaderValue::from_str(&request_id) {
+        response.headers_mut().insert(HeaderName::from_static("x-request-id"), value);
+    }
+    if let Ok(value) = HeaderValue::from_str(&frames.message_id()) {
+        response.headers_mut().insert(HeaderName::from_static("x-message-id"), value);
+    }
+    if let Ok(value) = HeaderValue::from_str(&served_model) {
+        response.headers_mut().insert(HeaderName::from_static("x-served-model"), value);
+    }
+    if let Some(trimmed) = trimmed {
+        response.headers_mut().insert(HeaderName::from_static("x-trimmed-messages"), trimmed.into());
+    }
+    if let Some(remaining) = remaining_tokens {
+        response.headers_mut().insert(HeaderName::from_static("x-session-tokens-remaining"), remaining.into());
+    }
+
+    let response = match routing_annotation {
+        Some(annotation) => prepend_to_stream(response, annotation),
+        None => response,
+    };
+    let response = match recorder {
+        Some(recorder) => record_conversation(response, recorder),
+        None => response,
+    };
+    let response = track_usage(response, served_provider, served_model, session_id);
+    let response = hold_until_streamed(response, upstream_slot);
+    if resumable_streams_enabled() {
+        return Ok(make_resumable(&frames.message_id(), response));
+    }
+    Ok(response)
+}
+
+// The client's request with a fallback model swapped in, routed and checked as if the client
+// had asked for that model
+fn fallback_request(
+    req: &HttpRequest,
+    client_request: &ChatRequest,
+    model: &str,
+) -> Result<(Provider, ChatRequest), ApiError> {
+    let mut request = client_request.clone();
+    request.model = model.to_string();
+    let provider = resolve_provider(model)?.provider;
+    prepare_messages(provider, &mut request)?;
+    apply_server_system_prompt(req, &mut request.messages);
+    Ok((provider, request))
+}
+
+// The client's X-Request-Id, or a generated one. Returned as X-Request-Id on the response and
+// used to cancel the stream with POST /cancel/{requestId}.
+pub fn request_id(req: &HttpRequest) -> String {
+    req.headers()
+        .get("X-Request-Id")
+        .and_then(|v| v.to_str().ok())
+        .filter(|v| !v.is_empty())
+        .map(str::to_string)
+        .unwrap_or_else(|| format!("req-{}", uuid::Uuid::new_v4().simple()))
+}
+
+// ?dryRun=true or X-Dry-Run: true builds the upstream request and returns it instead of sending it
+pub fn is_dry_run(req: &HttpRequest) -> bool {
+    let query = web::Query::<HashMap<String, String>>::from_query(req.query_string()).ok();
+    let value = query
+        .and_then(|query| query.get("dryRun").cloned())
+        .or_else(|| req.headers().get("X-Dry-Run").and_then(|v| v.to_str().ok()).map(str::to_string));
+    value.is_some_and(|v| v == "true" || v == "1")
+}
+
+// EXPLAIN_ROUTING=1 streams an annotation saying which routing rule picked the provider (dev only)
+fn explain_routing_enabled() -> bool {
+    env::var("EXPLAIN_ROUTING")
+        .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
+        .unwrap_or(false)
+}
+
+fn routing_annotation(frames: FrameWriter, model: &str, route: &Route) -> Bytes {
+    let annotation = json!([{
+        "type": "routing",
+        "model": model,
+        "provider": route.provider.name(),
+        "rule": route.rule,
+    }]);
+    Bytes::from(frames.annotation(&annotation))
+}
+
+fn overloaded_stream_response(frames: FrameWriter, error: &ApiError) -> HttpResponse {
+    let error = json!({ "type": error.error_type, "message": error.message });
+    frames_response(format!("{}{}", frames.start_step(), frames.stream_error(&error, Usage::default())))
+}
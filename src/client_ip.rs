@@ -0,0 +1,82 @@
+//! The client address behind trusted proxies, for logs and per-client limits
+
+use std::env;
+use std::net::{IpAddr, SocketAddr};
+
+use actix_web::http::header::HeaderMap;
+use log::warn;
+
+// Proxies allowed to report the client address, from TRUSTED_PROXIES: comma-separated IPs or
+// CIDR blocks (e.g. "10.0.0.0/8,127.0.0.1"). Empty by default, so forwarding headers are ignored.
+lazy_static::lazy_static! {
+    static ref TRUSTED_PROXIES: Vec<(IpAddr, u8)> = env::var("TRUSTED_PROXIES")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .filter_map(|entry| {
+            let parsed = parse_cidr(entry);
+            if parsed.is_none() {
+                warn!("Ignoring invalid TRUSTED_PROXIES entry '{}'", entry);
+            }
+            parsed
+        })
+        .collect();
+}
+
+// "10.0.0.0/8" or a bare address, which is a block of one
+fn parse_cidr(entry: &str) -> Option<(IpAddr, u8)> {
+    let (address, prefix) = match entry.split_once('/') {
+        Some((address, prefix)) => (address.parse::<IpAddr>().ok()?, Some(prefix.parse::<u8>().ok()?)),
+        None => (entry.parse::<IpAddr>().ok()?, None),
+    };
+    let bits = if address.is_ipv4() { 32 } else { 128 };
+    let prefix = prefix.unwrap_or(bits);
+    (prefix <= bits).then_some((address, prefix))
+}
+
+fn is_trusted_proxy(ip: IpAddr) -> bool {
+    let to_bits = |ip: IpAddr| match ip {
+        IpAddr::V4(ip) => (u128::from(u32::from(ip)), 32),
+        IpAddr::V6(ip) => (u128::from(ip), 128),
+    };
+    let (ip_bits, width) = to_bits(ip);
+    TRUSTED_PROXIES.iter().any(|&(network, prefix)| {
+        let (network_bits, network_width) = to_bits(network);
+        if network_width != width {
+            return false;
+        }
+        let shift = width - u32::from(prefix);
+        shift == width || (ip_bits >> shift) == (network_bits >> shift)
+    })
+}
+
+// The client's address for logs and per-client limits. Forwarding headers are only believed
+// when the socket peer is a trusted proxy: X-Forwarded-For is read right to left, skipping
+// trusted proxies, and the first other address is the client. X-Real-IP is used when there's
+// no X-Forwarded-For. Otherwise it's the socket peer.
+pub fn client_ip(peer: Option<SocketAddr>, headers: &HeaderMap) -> String {
+    let Some(peer) = peer.map(|peer| peer.ip()) else {
+        return "unknown".to_string();
+    };
+    if !is_trusted_proxy(peer) {
+        return peer.to_string();
+    }
+
+    let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok());
+    if let Some(forwarded_for) = header("X-Forwarded-For") {
+        let hops: Vec<IpAddr> = forwarded_for.split(',').filter_map(|hop| hop.trim().parse().ok()).collect();
+        // Every hop trusted: the leftmost is as close to the client as it gets
+        return hops
+            .iter()
+            .rev()
+            .find(|hop| !is_trusted_proxy(**hop))
+            .or(hops.first())
+            .unwrap_or(&peer)
+            .to_string();
+    }
+    header("X-Real-IP")
+        .and_then(|ip| ip.trim().parse::<IpAddr>().ok())
+        .unwrap_or(peer)
+        .to_string()
+}
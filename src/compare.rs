@@ -0,0 +1,122 @@
+//! POST /compare: one chat request streamed from several models at once, for side-by-side evaluation
+
+use std::env;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use actix_web::{body::MessageBody, web, HttpRequest, HttpResponse};
+use arc_swap::ArcSwap;
+use bytes::Bytes;
+use futures::stream::{self, LocalBoxStream};
+use futures::StreamExt;
+use log::{info, warn};
+use serde_json::Value;
+
+use crate::config::TellConfig;
+use crate::convert::{FrameWriter, LineBuffer, Usage};
+use crate::validate::chat_request_from_value;
+use crate::chat::{chat, request_id};
+use crate::error::ApiError;
+use crate::request::ChatRequest;
+
+// The body is a /sdk-chat request plus "models". The response is an SSE stream in which every
+// event is named after the model that produced it and carries one of its AI SDK frames as its
+// data, unchanged. Each model streams independently: a model that fails only ends its own frames.
+pub async fn compare(
+    req: HttpRequest,
+    body: web::Bytes,
+    config: web::Data<ArcSwap<TellConfig>>,
+) -> Result<HttpResponse, ApiError> {
+    let mut body: Value =
+        serde_json::from_slice(&body).map_err(|e| ApiError::bad_request(format!("Invalid JSON: {}", e)))?;
+    let models: Vec<String> = body
+        .as_object_mut()
+        .and_then(|body| body.remove("models"))
+        .map(serde_json::from_value)
+        .transpose()
+        .map_err(|e| ApiError::bad_request(format!("Invalid models: {}", e)))?
+        .unwrap_or_default();
+    let max_models = env::var("MAX_COMPARE_MODELS").ok().and_then(|v| v.parse().ok()).unwrap_or(4);
+    if models.len() < 2 || models.len() > max_models {
+        return Err(ApiError::bad_request(format!(
+            "models must list between 2 and {} models to compare (MAX_COMPARE_MODELS)",
+            max_models
+        )));
+    }
+    let duplicate = models.iter().enumerate().find_map(|(i, model)| models[..i].contains(model).then_some(model));
+    if let Some(duplicate) = duplicate {
+        return Err(ApiError::bad_request(format!("Model '{}' is listed more than once", duplicate)));
+    }
+    let request = chat_request_from_value(body)?;
+    info!("Comparing {} models: {}", models.len(), models.join(", "));
+
+    // Each model gets its own session, so their tool calls and pipelines don't mix
+    let session_id = request.session_id.clone().or_else(|| {
+        req.headers().get("X-Session-Id").and_then(|v| v.to_str().ok()).map(str::to_string)
+    });
+    let config = config.load_full();
+    // Each model is cancelled on its own, as <compare request id>-<index in models>
+    let compare_id = request_id(&req);
+    let streams = models.into_iter().enumerate().map(|(index, model)| {
+        let mut request = request.clone();
+        request.session_id = session_id.as_ref().map(|session_id| format!("{}/{}", session_id, model));
+        request.model = model;
+        model_stream(req.clone(), request, format!("{}-{}", compare_id, index), config.clone())
+    });
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .insert_header(("X-Request-Id", compare_id.clone()))
+        .streaming(stream::select_all(streams)))
+}
+
+// One model's frames, as SSE events named after the model. A request that fails before
+// streaming becomes an error and finish frame of its own.
+fn model_stream(
+    req: HttpRequest,
+    request: ChatRequest,
+    request_id: String,
+    config: Arc<TellConfig>,
+) -> LocalBoxStream<'static, Result<Bytes, actix_web::Error>> {
+    let model = request.model.clone();
+    let frames = FrameWriter::new();
+    stream::once(async move { chat(&req, request, request_id, &config, true).await })
+        .flat_map(move |response| {
+            let model = model.clone();
+            match response {
+                Ok(response) => {
+                    let mut body = response.into_body();
+                    let mut lines = LineBuffer::default();
+                    stream::poll_fn(move |cx| Pin::new(&mut body).poll_next(cx))
+                        .map(move |chunk| match chunk {
+                            Ok(chunk) => Ok(tag_frames(&model, &lines.push(&chunk))),
+                            Err(e) => {
+                                warn!("Compare stream for {} failed: {}", model, e);
+                                let message = format!("Stream error: {}", e);
+                                Ok(tag_frames(&model, &frames.error(&message)))
+                            }
+                        })
+                        .boxed_local()
+                }
+                Err(error) => {
+                    info!("Compare request for {} failed: {}", model, error);
+                    let failed = format!(
+                        "{}{}",
+                        frames.error(&format!("{}: {}", error.error_type, error.message)),
+                        frames.finish("error", Usage::default())
+                    );
+                    stream::once(async move { Ok(tag_frames(&model, &failed)) }).boxed_local()
+                }
+            }
+        })
+        .boxed_local()
+}
+
+fn tag_frames(model: &str, frames: &str) -> Bytes {
+    let mut events = String::new();
+    for frame in frames.lines().filter(|frame| !frame.is_empty()) {
+        events.push_str(&format!("event: {}\ndata: {}\n\n", model, frame));
+    }
+    Bytes::from(events)
+}
@@ -0,0 +1,241 @@
+//! Deployment configuration read from the environment and the TELL_CONFIG file
+
+use std::env;
+use std::time::Duration;
+use std::collections::{BTreeMap, HashMap};
+
+use actix_web::http::header::HeaderName;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use log::info;
+
+use crate::providers::{known_providers, Provider, UpstreamRequest};
+
+// Request headers clients may send cross-origin: the standard ones plus every header a feature
+// reads (request ids, sessions, timeouts, dry runs, provider forcing, SDK version, system prompt
+// bypass, output format, OpenAI billing scope)
+pub const CORS_REQUEST_HEADERS: &[&str] = &[
+    "content-type",
+    "authorization",
+    "origin",
+    "x-request-id",
+    "x-session-id",
+    "x-timeout-ms",
+    "x-dry-run",
+    "x-provider",
+    "x-ai-sdk-version",
+    "x-bypass-system-prompt",
+    "x-output-format",
+    "openai-organization",
+    "openai-project",
+];
+
+// Response headers the browser lets client code read
+pub const CORS_EXPOSED_HEADERS: &[&str] = &[
+    "content-type",
+    "x-request-id",
+    "x-message-id",
+    "x-served-model",
+    "x-session-tokens-remaining",
+    "x-trimmed-messages",
+    "retry-after",
+    "x-upstream-ratelimit-remaining-requests",
+    "x-upstream-ratelimit-remaining-tokens",
+    "x-upstream-ratelimit-remaining-input-tokens",
+    "x-upstream-ratelimit-remaining-output-tokens",
+    "x-upstream-retry-after",
+];
+
+pub const DEFAULT_CORS_METHODS: &str = "GET,POST,OPTIONS";
+
+// CORS_ALLOWED_METHODS (comma-separated) replaces the default methods; CORS_ALLOWED_HEADERS adds
+// to the built-in request headers, which the server's own features need
+#[derive(Debug, Clone)]
+pub struct CorsSettings {
+    pub methods: Vec<actix_web::http::Method>,
+    pub headers: Vec<HeaderName>,
+}
+
+impl CorsSettings {
+    pub fn from_env() -> Result<Self, String> {
+        let methods = env::var("CORS_ALLOWED_METHODS").unwrap_or_else(|_| DEFAULT_CORS_METHODS.to_string());
+        let methods = methods
+            .split(',')
+            .map(str::trim)
+            .filter(|method| !method.is_empty())
+            .map(|method| {
+                actix_web::http::Method::from_bytes(method.to_uppercase().as_bytes())
+                    .map_err(|_| format!("'{}' is not an HTTP method", method))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        if methods.is_empty() {
+            return Err("CORS_ALLOWED_METHODS is empty".to_string());
+        }
+
+        let extra_headers = env::var("CORS_ALLOWED_HEADERS").unwrap_or_default();
+        let mut headers = Vec::new();
+        for header in CORS_REQUEST_HEADERS.iter().copied().chain(extra_headers.split(',').map(str::trim)) {
+            if header.is_empty() {
+                continue;
+            }
+            let name = HeaderName::from_bytes(header.as_bytes())
+                .map_err(|_| format!("'{}' is not a header name", header))?;
+            if !headers.contains(&name) {
+                headers.push(name);
+            }
+        }
+        Ok(CorsSettings { methods, headers })
+    }
+}
+
+// HttpServer connection settings, tuned for many long-lived SSE streams: KEEP_ALIVE_SECS (75),
+// CLIENT_REQUEST_TIMEOUT_MS (30000, for the client to send its request head) and WORKERS (one per
+// core when unset). HTTP2_CLEARTEXT=true also accepts HTTP/2 without TLS (h2c), for internal
+// deployments behind a proxy that speaks it.
+#[derive(Debug, Clone)]
+pub struct ServerSettings {
+    pub keep_alive: Duration,
+    pub client_request_timeout: Duration,
+    pub workers: Option<usize>,
+    pub http2_cleartext: bool,
+}
+
+impl ServerSettings {
+    pub fn from_env() -> Result<Self, String> {
+        let number = |name: &str, default: u64| match env::var(name) {
+            Ok(value) => {
+                value.trim().parse::<u64>().map_err(|_| format!("{} must be a whole number, got '{}'", name, value))
+            }
+            Err(_) => Ok(default),
+        };
+        let workers = match number("WORKERS", 0)? {
+            0 => None,
+            workers => Some(workers as usize),
+        };
+        Ok(ServerSettings {
+            keep_alive: Duration::from_secs(number("KEEP_ALIVE_SECS", 75)?),
+            client_request_timeout: Duration::from_millis(number("CLIENT_REQUEST_TIMEOUT_MS", 30_000)?),
+            workers,
+            http2_cleartext: env::var("HTTP2_CLEARTEXT").is_ok_and(|v| v.eq_ignore_ascii_case("true") || v == "1"),
+        })
+    }
+}
+
+// Deployment-specific request defaults, read at startup from the file named by TELL_CONFIG
+// (TOML if it ends in .toml, JSON otherwise). Overrides are keyed by provider name and by
+// model name as the client sends it; a model's overrides apply on top of its provider's.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TellConfig {
+    #[serde(default)]
+    providers: HashMap<String, RequestOverrides>,
+    #[serde(default)]
+    models: HashMap<String, RequestOverrides>,
+}
+
+#[derive(Debug, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RequestOverrides {
+    // Top-level fields set in the upstream body, replacing the server's defaults
+    #[serde(default)]
+    body: serde_json::Map<String, Value>,
+    #[serde(default)]
+    headers: HashMap<String, String>,
+}
+
+// Body fields the server always builds itself
+pub const RESERVED_BODY_FIELDS: &[&str] = &["model", "messages", "stream"];
+
+impl TellConfig {
+    pub fn from_env() -> Result<Self, String> {
+        let Ok(path) = env::var("TELL_CONFIG") else {
+            return Ok(TellConfig::default());
+        };
+        let contents = std::fs::read_to_string(&path).map_err(|e| format!("can't read {}: {}", path, e))?;
+        let config: TellConfig = if path.ends_with(".toml") {
+            toml::from_str(&contents).map_err(|e| format!("{}: {}", path, e))?
+        } else {
+            serde_json::from_str(&contents).map_err(|e| format!("{}: {}", path, e))?
+        };
+        config.validate()?;
+        info!(
+            "Loaded TELL_CONFIG from {}: overrides for {} provider(s) and {} model(s)",
+            path,
+            config.providers.len(),
+            config.models.len()
+        );
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let known: Vec<&str> = known_providers().map(|p| p.name()).collect();
+        if let Some(unknown) = self.providers.keys().find(|name| !known.contains(&name.as_str())) {
+            return Err(format!("unknown provider '{}'. Known providers: {}", unknown, known.join(", ")));
+        }
+
+        let scoped = self
+            .providers
+            .iter()
+            .map(|(name, overrides)| (format!("providers.{}", name), overrides))
+            .chain(self.models.iter().map(|(name, overrides)| (format!("models.{}", name), overrides)));
+        for (scope, overrides) in scoped {
+            if let Some(field) = overrides.body.keys().find(|field| RESERVED_BODY_FIELDS.contains(&field.as_str())) {
+                return Err(format!("{}: the {} field can't be overridden", scope, field));
+            }
+            for (name, value) in &overrides.headers {
+                reqwest::header::HeaderName::from_bytes(name.as_bytes())
+                    .map_err(|_| format!("{}: invalid header name '{}'", scope, name))?;
+                reqwest::header::HeaderValue::from_str(value)
+                    .map_err(|_| format!("{}: invalid value for header '{}'", scope, name))?;
+            }
+        }
+        Ok(())
+    }
+
+    // What a reload from `self` to `new` changes, per provider and model
+    pub fn diff(&self, new: &TellConfig) -> Value {
+        let providers = keyed_diff(
+            self.providers.iter().map(|(name, overrides)| (name.as_str(), overrides)),
+            new.providers.iter().map(|(name, overrides)| (name.as_str(), overrides)),
+        );
+        let models = keyed_diff(
+            self.models.iter().map(|(name, overrides)| (name.as_str(), overrides)),
+            new.models.iter().map(|(name, overrides)| (name.as_str(), overrides)),
+        );
+        json!({ "providers": providers, "models": models })
+    }
+
+    // Merge the overrides into a built request, skipping fields the client set itself
+    pub fn apply(&self, provider: Provider, model: &str, client_fields: &[&str], upstream: &mut UpstreamRequest) {
+        let overrides = [self.providers.get(provider.name()), self.models.get(model)];
+        for overrides in overrides.into_iter().flatten() {
+            if let Some(body) = upstream.body.as_object_mut() {
+                for (field, value) in &overrides.body {
+                    if !client_fields.contains(&field.as_str()) {
+                        body.insert(field.clone(), value.clone());
+                    }
+                }
+            }
+            for (name, value) in &overrides.headers {
+                upstream.set_header(name, value);
+            }
+        }
+    }
+}
+
+// The names added, removed and changed between two sets of named definitions
+pub fn keyed_diff<'a, T: PartialEq + 'a>(
+    old: impl IntoIterator<Item = (&'a str, &'a T)>,
+    new: impl IntoIterator<Item = (&'a str, &'a T)>,
+) -> Value {
+    let old: BTreeMap<&str, &T> = old.into_iter().collect();
+    let new: BTreeMap<&str, &T> = new.into_iter().collect();
+    let added: Vec<&str> = new.keys().filter(|name| !old.contains_key(*name)).copied().collect();
+    let removed: Vec<&str> = old.keys().filter(|name| !new.contains_key(*name)).copied().collect();
+    let changed: Vec<&str> = new
+        .iter()
+        .filter(|(name, value)| old.get(*name).is_some_and(|old| **old != ***value))
+        .map(|(name, _)| *name)
+        .collect();
+    json!({ "added": added, "removed": removed, "changed": changed })
+}
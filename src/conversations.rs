@@ -0,0 +1,181 @@
+//! Conversation history: the turns of each session, recorded from the streamed responses
+
+use std::env;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task::Poll;
+use std::time::SystemTime;
+
+use actix_web::{
+    body::{BodyStream, BoxBody, MessageBody},
+    web, HttpResponse,
+};
+use bytes::BytesMut;
+use log::{info, warn};
+use serde_json::{json, Value};
+
+use crate::error::ApiError;
+use crate::request::ChatRequest;
+use crate::stream::assemble_response;
+
+// Optional conversation history (PERSIST=true): each /sdk-chat request with a session id and
+// the assistant response assembled from its stream, in the SQLite database at PERSIST_PATH
+// (tell.db by default). If the database can't be opened the server runs without persistence.
+lazy_static::lazy_static! {
+    static ref CONVERSATIONS: Option<ConversationStore> = {
+        let enabled = env::var("PERSIST")
+            .map(|v| v.eq_ignore_ascii_case("true") || v == "1")
+            .unwrap_or(false);
+        if !enabled {
+            return None;
+        }
+        let path = env::var("PERSIST_PATH").unwrap_or_else(|_| "tell.db".to_string());
+        match ConversationStore::open(&path) {
+            Ok(store) => {
+                info!("Persisting conversations to {}", path);
+                Some(store)
+            }
+            Err(e) => {
+                warn!("Failed to open conversation store at {}, running without persistence: {}", path, e);
+                None
+            }
+        }
+    };
+}
+
+struct ConversationStore {
+    conn: Mutex<rusqlite::Connection>,
+}
+
+impl ConversationStore {
+    fn open(path: &str) -> rusqlite::Result<Self> {
+        let conn = rusqlite::Connection::open(path)?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS conversation_turns (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                session_id TEXT NOT NULL,
+                created_at INTEGER NOT NULL,
+                model TEXT NOT NULL,
+                messages TEXT NOT NULL,
+                response TEXT NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS conversation_turns_session ON conversation_turns (session_id, id);",
+        )?;
+        Ok(ConversationStore { conn: Mutex::new(conn) })
+    }
+
+    fn record(&self, session_id: &str, model: &str, messages: &Value, response: &Value) -> rusqlite::Result<()> {
+        let created_at = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        self.conn.lock().unwrap().execute(
+            "INSERT INTO conversation_turns (session_id, created_at, model, messages, response)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            rusqlite::params![session_id, created_at, model, messages.to_string(), response.to_string()],
+        )?;
+        Ok(())
+    }
+
+    fn history(&self, session_id: &str) -> rusqlite::Result<Vec<Value>> {
+        let conn = self.conn.lock().unwrap();
+        let mut statement = conn.prepare(
+            "SELECT created_at, model, messages, response FROM conversation_turns
+             WHERE session_id = ?1 ORDER BY id",
+        )?;
+        let turns = statement.query_map([session_id], |row| {
+            let created_at: u64 = row.get(0)?;
+            let model: String = row.get(1)?;
+            let messages: String = row.get(2)?;
+            let response: String = row.get(3)?;
+            Ok(json!({
+                "createdAt": created_at,
+                "model": model,
+                "messages": serde_json::from_str::<Value>(&messages).unwrap_or(Value::Null),
+                "response": serde_json::from_str::<Value>(&response).unwrap_or(Value::Null),
+            }))
+        })?;
+        turns.collect()
+    }
+}
+
+// Collects the frames streamed for one request, and records the turn once the stream is done
+// (or the client went away). The write happens on a blocking thread, off the response path.
+pub struct ConversationRecorder {
+    session_id: String,
+    model: String,
+    messages: Value,
+    output: BytesMut,
+    // Set once the recorder is attached to a response; a request that returns before that
+    // (a dry run, no upstream slot, an upstream error) records nothing
+    attached: bool,
+}
+
+impl ConversationRecorder {
+    // None unless persistence is on and the request has a session id
+    pub fn new(request: &ChatRequest) -> Option<Self> {
+        CONVERSATIONS.as_ref()?;
+        Some(ConversationRecorder {
+            session_id: request.session_id.clone()?,
+            model: request.model.clone(),
+            messages: json!(request.messages),
+            output: BytesMut::new(),
+            attached: false,
+        })
+    }
+}
+
+impl Drop for ConversationRecorder {
+    fn drop(&mut self) {
+        let Some(store) = CONVERSATIONS.as_ref().filter(|_| self.attached) else {
+            return;
+        };
+        let session_id = std::mem::take(&mut self.session_id);
+        let model = std::mem::take(&mut self.model);
+        let messages = self.messages.take();
+        let output = self.output.split().freeze();
+        tokio::task::spawn_blocking(move || {
+            let response = assemble_response(&String::from_utf8_lossy(&output));
+            if let Err(e) = store.record(&session_id, &model, &messages, &response) {
+                warn!("Failed to persist conversation turn for session {}: {}", session_id, e);
+            }
+        });
+    }
+}
+
+// Copy everything written to the client into the recorder
+pub fn record_conversation(response: HttpResponse, mut recorder: ConversationRecorder) -> HttpResponse {
+    recorder.attached = true;
+    response.map_body(|_, mut body| {
+        let stream = futures::stream::poll_fn(move |cx| {
+            let item = Pin::new(&mut body).poll_next(cx);
+            if let Poll::Ready(Some(Ok(chunk))) = &item {
+                recorder.output.extend_from_slice(chunk);
+            }
+            item
+        });
+        BoxBody::new(BodyStream::new(stream))
+    })
+}
+
+pub async fn get_conversation(session: web::Path<String>) -> Result<HttpResponse, ApiError> {
+    let session_id = session.into_inner();
+    let store = CONVERSATIONS
+        .as_ref()
+        .ok_or_else(|| ApiError::not_found("Conversation persistence is not enabled (PERSIST=true)"))?;
+
+    let lookup_id = session_id.clone();
+    let turns = web::block(move || store.history(&lookup_id))
+        .await
+        .map_err(|e| ApiError::internal(format!("Failed to read conversation: {}", e)))?
+        .map_err(|e| ApiError::internal(format!("Failed to read conversation: {}", e)))?;
+    if turns.is_empty() {
+        return Err(ApiError::not_found(format!("No conversation for session '{}'", session_id)));
+    }
+    Ok(HttpResponse::Ok().json(json!({ "sessionId": session_id, "turns": turns })))
+}
+
+// Open the store now rather than on the first request
+pub fn open_conversation_store() {
+    lazy_static::initialize(&CONVERSATIONS);
+}
@@ -0,0 +1,1231 @@
+//! Conversion of provider streams into AI SDK data stream frames
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+use actix_web::HttpRequest;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use base64::prelude::*;
+use log::{error, info, warn};
+
+use crate::chunk_info;
+use crate::error::ApiError;
+use crate::pipeline::execute_server_tool;
+use crate::request::ToolCallFormat;
+use crate::session::Session;
+
+// Provider event types a converter skipped. Shared between the converter state and
+// ConversionCheck, so a stream that converted to nothing can say what it did receive.
+#[derive(Debug, Default, Clone)]
+pub struct UnhandledEvents(Arc<Mutex<BTreeSet<String>>>);
+
+impl UnhandledEvents {
+    fn record(&self, event_type: &str) {
+        let mut events = self.0.lock().unwrap();
+        if !events.contains(event_type) {
+            events.insert(event_type.to_string());
+        }
+    }
+
+    pub fn list(&self) -> String {
+        self.0.lock().unwrap().iter().cloned().collect::<Vec<_>>().join(", ")
+    }
+}
+
+// Token usage accumulated over a stream. Providers may report it in pieces (Anthropic sends
+// prompt tokens in message_start and completion tokens in message_delta), so partial reports
+// are merged field by field rather than replacing what was already seen.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Usage {
+    prompt_tokens: Option<u64>,
+    completion_tokens: Option<u64>,
+    cache_creation_input_tokens: Option<u64>,
+    cache_read_input_tokens: Option<u64>,
+}
+
+impl Usage {
+    fn from_anthropic(usage: &Value) -> Self {
+        let field = |name: &str| usage.get(name).and_then(|v| v.as_u64());
+        Usage {
+            prompt_tokens: field("input_tokens"),
+            completion_tokens: field("output_tokens"),
+            cache_creation_input_tokens: field("cache_creation_input_tokens"),
+            cache_read_input_tokens: field("cache_read_input_tokens"),
+        }
+    }
+
+    // Missing fields never clear a known value, and since later reports are cumulative a
+    // smaller (e.g. zero placeholder) value never replaces a larger one
+    fn merge(&mut self, other: Usage) {
+        let merge_field = |current: &mut Option<u64>, update: Option<u64>| {
+            *current = (*current).max(update);
+        };
+        merge_field(&mut self.prompt_tokens, other.prompt_tokens);
+        merge_field(&mut self.completion_tokens, other.completion_tokens);
+        merge_field(&mut self.cache_creation_input_tokens, other.cache_creation_input_tokens);
+        merge_field(&mut self.cache_read_input_tokens, other.cache_read_input_tokens);
+    }
+
+    fn from_openai(usage: &Value) -> Self {
+        let field = |name: &str| usage.get(name).and_then(|v| v.as_u64());
+        Usage {
+            prompt_tokens: field("prompt_tokens"),
+            completion_tokens: field("completion_tokens"),
+            cache_read_input_tokens: usage
+                .pointer("/prompt_tokens_details/cached_tokens")
+                .and_then(|v| v.as_u64()),
+            ..Default::default()
+        }
+    }
+
+    fn to_ai_sdk(self) -> Value {
+        let mut usage = json!({
+            "promptTokens": self.prompt_tokens.unwrap_or(0),
+            "completionTokens": self.completion_tokens.unwrap_or(0),
+        });
+        if let Some(tokens) = self.cache_creation_input_tokens {
+            usage["cacheCreationInputTokens"] = json!(tokens);
+        }
+        if let Some(tokens) = self.cache_read_input_tokens {
+            usage["cacheReadInputTokens"] = json!(tokens);
+        }
+        usage
+    }
+}
+
+// Per-stream state carried between Anthropic events: usage arrives split across
+// message_start (input) and message_delta (output), and the stop reason comes before message_stop
+#[derive(Debug, Default)]
+pub struct AnthropicStreamState {
+    pub frames: FrameWriter,
+    // Prefilled assistant text, sent as the first text frame once the message starts
+    pub prefill: Option<String>,
+    pub usage: Usage,
+    pub stop_reason: Option<String>,
+    pub sources: SentSources,
+    pub unhandled: UnhandledEvents,
+    // tool_use blocks still streaming their input, by content block index
+    pub tool_uses: HashMap<u64, ToolCallAccumulator>,
+    // Set once the stream has failed and its error and finish frames have been sent
+    pub failed: bool,
+}
+
+impl AnthropicStreamState {
+    fn record_usage(&mut self, usage: &Value) {
+        self.usage.merge(Usage::from_anthropic(usage));
+    }
+
+    fn finish_frame(&self) -> String {
+        let finish_reason = match self.stop_reason.as_deref() {
+            Some("end_turn") | Some("stop_sequence") | None => "stop",
+            Some("max_tokens") => "length",
+            Some("tool_use") => "tool-calls",
+            Some("refusal") => "content-filter",
+            Some(_) => "other",
+        };
+        self.frames.finish(finish_reason, self.usage)
+    }
+
+    // A source frame for a citation with a URL (web search results); citations of documents
+    // the client sent have no URL and are skipped
+    fn citation_frame(&mut self, citation: &Value) -> String {
+        let Some(url) = citation.get("url").and_then(|u| u.as_str()) else {
+            return String::new();
+        };
+        let title = citation.get("title").and_then(|t| t.as_str());
+        self.sources.frame(self.frames, url, title)
+    }
+
+    // The tool call frame for a finished tool_use block, sent where the block ended so text
+    // before and after the call keeps its place in the message
+    fn tool_use_frame(&self, tool_use: ToolCallAccumulator) -> String {
+        info!("Sending tool call: id={}, name={}, args={}", tool_use.id, tool_use.name, tool_use.arguments);
+        self.frames.tool_call_from_text(&tool_use.id, &tool_use.name, &tool_use.arguments)
+    }
+}
+
+// AI SDK version the client speaks, which decides the stream protocol it reads. v4 clients read
+// the data stream protocol, the `<prefix>:<JSON>` frames built by FrameWriter; v5 clients read
+// the UI message stream, SSE events of JSON chunks, re-encoded from those frames by
+// output::UiMessageStream.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "Value", into = "String")]
+pub enum SdkVersion {
+    V4,
+    #[default]
+    V5,
+}
+
+impl SdkVersion {
+    // "5", "v5" and full package versions like "4.3.16" all work; only the major matters
+    fn parse(version: &str) -> Option<Self> {
+        let version = version.trim();
+        let version = version.strip_prefix(['v', 'V']).unwrap_or(version);
+        match version.split('.').next() {
+            Some("4") => Some(SdkVersion::V4),
+            Some("5") => Some(SdkVersion::V5),
+            _ => None,
+        }
+    }
+
+    // The version from the X-AI-SDK-Version header, if the client sent one
+    pub(crate) fn from_header(req: &HttpRequest) -> Result<Option<Self>, ApiError> {
+        let Some(value) = req.headers().get("X-AI-SDK-Version") else {
+            return Ok(None);
+        };
+        let value = value.to_str().unwrap_or_default();
+        Self::parse(value).map(Some).ok_or_else(|| ApiError::bad_request(unsupported_sdk_version(value)))
+    }
+}
+
+pub fn unsupported_sdk_version(version: &str) -> String {
+    format!("Unsupported AI SDK version '{}'. Supported versions: 4, 5", version)
+}
+
+impl TryFrom<Value> for SdkVersion {
+    type Error = String;
+
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        let version = match value {
+            Value::String(version) => version,
+            other => other.to_string(),
+        };
+        Self::parse(&version).ok_or_else(|| unsupported_sdk_version(&version))
+    }
+}
+
+impl From<SdkVersion> for String {
+    fn from(version: SdkVersion) -> Self {
+        match version {
+            SdkVersion::V4 => "4".to_string(),
+            SdkVersion::V5 => "5".to_string(),
+        }
+    }
+}
+
+// Builds the AI SDK data stream frames of one response. Each frame is `<prefix>:<JSON>\n`;
+// JSON-encoding the payload escapes newlines and control characters, so content can't break
+// the line framing.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FrameWriter {
+    // One id per response, shared by all its steps
+    message_id: uuid::Uuid,
+}
+
+impl FrameWriter {
+    pub fn new() -> Self {
+        FrameWriter { message_id: uuid::Uuid::new_v4() }
+    }
+
+    pub fn message_id(&self) -> String {
+        format!("msg-{}", self.message_id.simple())
+    }
+
+    fn frame(prefix: char, payload: &impl Serialize) -> String {
+        format!("{}:{}\n", prefix, serde_json::to_string(payload).unwrap_or_default())
+    }
+
+    // Text frame: 0:"text content". Empty deltas (e.g. OpenAI's first chunk) produce no frame;
+    // whitespace-only text is real content and is kept.
+    pub fn text(&self, text: &str) -> String {
+        if text.is_empty() {
+            return String::new();
+        }
+        Self::frame('0', &text)
+    }
+
+    // Start step frame: f:{"messageId":...}. Each upstream response is one step of the
+    // client's agentic loop, so every stream opens with one.
+    pub fn start_step(&self) -> String {
+        Self::frame('f', &json!({ "messageId": self.message_id() }))
+    }
+
+    // Closes the step and the message: e:{"finishReason","usage","isContinued"} followed by
+    // the finish message frame d:{"finishReason":...,"usage":{...}}. A content-filter stop is
+    // preceded by an informational error frame, since the text sent so far is likely cut short.
+    pub fn finish(&self, finish_reason: &str, usage: Usage) -> String {
+        self.finish_with_fingerprint(finish_reason, usage, None)
+    }
+
+    // As finish, with the provider's system fingerprint (OpenAI's system_fingerprint) in the
+    // finish message frame as systemFingerprint, so clients can tell when the backend changed
+    fn finish_with_fingerprint(&self, finish_reason: &str, usage: Usage, system_fingerprint: Option<&str>) -> String {
+        let mut frames = String::new();
+        if finish_reason == "content-filter" {
+            warn!("Provider stopped the response for content filtering");
+            frames.push_str(&self.error(CONTENT_FILTER_NOTICE));
+        }
+        let usage = usage.to_ai_sdk();
+        frames.push_str(&Self::frame(
+            'e',
+            &json!({ "finishReason": finish_reason, "usage": usage, "isContinued": false }),
+        ));
+        let mut finish = json!({ "finishReason": finish_reason, "usage": usage });
+        if let Some(system_fingerprint) = system_fingerprint {
+            finish["systemFingerprint"] = json!(system_fingerprint);
+        }
+        frames.push_str(&Self::frame('d', &finish));
+        frames
+    }
+
+    // Error frame: 3:"message"
+    pub fn error(&self, message: &str) -> String {
+        Self::frame('3', &message)
+    }
+
+    // A provider error reported inside a 200 stream: an error frame followed by the finish
+    // frames, so the client sees a failed generation rather than a silently truncated one
+    pub fn stream_error(&self, error: &Value, usage: Usage) -> String {
+        let message = error.get("message").and_then(|m| m.as_str()).unwrap_or("unknown error");
+        let message = match error.get("type").and_then(|t| t.as_str()) {
+            Some(error_type) => format!("{}: {}", error_type, message),
+            None => message.to_string(),
+        };
+        error!("Provider error in stream: {}", message);
+
+        format!("{}{}", self.error(&message), self.finish("error", usage))
+    }
+
+    // Tool call frame: 9:{"toolCallId","toolName","args"}
+    pub fn tool_call(&self, id: &str, name: &str, args: &Value) -> String {
+        Self::frame('9', &json!({ "toolCallId": id, "toolName": name, "args": args }))
+    }
+
+    // Tool call frame for the argument JSON a model streamed. No arguments at all mean {}.
+    // Arguments that don't parse (a stream cut off mid-call, or malformed model output) are
+    // flagged with argsError and passed on raw as argsText instead of being replaced with {}, so
+    // the client can retry rather than run the tool with empty args.
+    pub fn tool_call_from_text(&self, id: &str, name: &str, arguments: &str) -> String {
+        if arguments.trim().is_empty() {
+            return self.tool_call(id, name, &json!({}));
+        }
+        match serde_json::from_str::<Value>(arguments) {
+            Ok(args) => self.tool_call(id, name, &args),
+            Err(e) => {
+                warn!("Invalid arguments for tool call {} ({}): {}: {}", id, name, e, arguments);
+                Self::frame(
+                    '9',
+                    &json!({
+                        "toolCallId": id,
+                        "toolName": name,
+                        "args": {},
+                        "argsError": true,
+                        "argsText": arguments,
+                    }),
+                )
+            }
+        }
+    }
+
+    // Streamed tool call start: b:{"toolCallId","toolName"}, before its argument deltas
+    fn tool_call_start(&self, id: &str, name: &str) -> String {
+        Self::frame('b', &json!({ "toolCallId": id, "toolName": name }))
+    }
+
+    // Streamed tool call argument delta: c:{"toolCallId","argsTextDelta"}. Empty deltas
+    // produce no frame.
+    pub fn tool_call_delta(&self, id: &str, args_text_delta: &str) -> String {
+        if args_text_delta.is_empty() {
+            return String::new();
+        }
+        Self::frame('c', &json!({ "toolCallId": id, "argsTextDelta": args_text_delta }))
+    }
+
+    // Tool result frame for server-executed tools: a:{"toolCallId","result"}
+    fn tool_result(&self, id: &str, result: &Value) -> String {
+        Self::frame('a', &json!({ "toolCallId": id, "result": result }))
+    }
+
+    // Data frame: 2:[...]
+    fn data(&self, data: &Value) -> String {
+        Self::frame('2', data)
+    }
+
+    // Message annotation frame: 8:[...]
+    pub fn annotation(&self, annotations: &Value) -> String {
+        Self::frame('8', annotations)
+    }
+
+    // Source frame for a cited URL: h:{"sourceType":"url","id","url","title"}
+    fn source(&self, url: &str, title: Option<&str>) -> String {
+        let mut source = json!({
+            "sourceType": "url",
+            "id": format!("src-{}", uuid::Uuid::new_v4().simple()),
+            "url": url,
+        });
+        if let Some(title) = title.filter(|title| !title.is_empty()) {
+            source["title"] = json!(title);
+        }
+        Self::frame('h', &source)
+    }
+}
+
+// URLs already sent as source frames in a stream. Models cite the same page many times; the
+// client gets one source frame per URL.
+#[derive(Debug, Default)]
+pub struct SentSources(HashSet<String>);
+
+impl SentSources {
+    fn frame(&mut self, frames: FrameWriter, url: &str, title: Option<&str>) -> String {
+        if url.is_empty() || !self.0.insert(url.to_string()) {
+            return String::new();
+        }
+        frames.source(url, title)
+    }
+}
+
+pub const CONTENT_FILTER_NOTICE: &str =
+    "content_filter: The response was stopped by the provider's content filter; the text above may be incomplete.";
+
+// OpenAI finish_reason values in AI SDK terms
+pub fn openai_finish_reason(finish_reason: Option<&str>) -> &'static str {
+    match finish_reason {
+        Some("stop") | None => "stop",
+        Some("length") => "length",
+        Some("tool_calls") | Some("function_call") => "tool-calls",
+        Some("content_filter") => "content-filter",
+        Some(_) => "other",
+    }
+}
+
+// Undecoded bytes of an SSE stream after its last complete line. Network chunks can end
+// mid-line, or in the middle of a multi-byte UTF-8 character, so only whole lines are decoded
+// and the rest waits for the next chunk. A trailing partial line when the stream ends is an
+// incomplete event, which SSE says to discard.
+#[derive(Debug, Default)]
+pub struct LineBuffer {
+    pending: Vec<u8>,
+}
+
+impl LineBuffer {
+    // Append `chunk` and take every complete line buffered so far, newlines included
+    pub fn push(&mut self, chunk: &[u8]) -> String {
+        self.pending.extend_from_slice(chunk);
+        let Some(newline) = self.pending.iter().rposition(|&b| b == b'\n') else {
+            return String::new();
+        };
+        let lines: Vec<u8> = self.pending.drain(..=newline).collect();
+        // A newline byte never occurs inside a multi-byte character, so the lines are whole
+        String::from_utf8_lossy(&lines).into_owned()
+    }
+}
+
+// The data of an SSE `data:` line. The space after the colon is optional, and only one is
+// part of the framing.
+pub fn sse_data(line: &str) -> Option<&str> {
+    let data = line.strip_prefix("data:")?;
+    Some(data.strip_prefix(' ').unwrap_or(data))
+}
+
+// A transport failure while reading the provider stream, in the provider error shape
+pub fn upstream_stream_error(e: &reqwest::Error) -> Value {
+    // A stream that went silent for READ_TIMEOUT_SECS, or ran past the request's total timeout
+    if e.is_timeout() {
+        return json!({
+            "type": "timeout_error",
+            "message": "The provider stopped sending data or the response took longer than the timeout allows",
+        });
+    }
+    json!({ "type": "stream_error", "message": e.to_string() })
+}
+
+pub fn convert_anthropic_to_ai_sdk(chunk: &str, state: &mut AnthropicStreamState) -> String {
+    // Convert Anthropic streaming format to AI SDK v5 format
+    let mut result = String::new();
+
+    for line in chunk.lines() {
+        // Only data lines carry events. `event:` lines repeat the type the data already has,
+        // and blank lines and `:` comments are SSE framing.
+        let Some(data_part) = line.strip_prefix("data:") else {
+            continue;
+        };
+        let data_part = data_part.trim_start();
+        if data_part == "[DONE]" {
+            // No special end marker needed in AI SDK v5
+            continue;
+        }
+
+        if let Ok(parsed) = serde_json::from_str::<Value>(data_part) {
+            result.push_str(&convert_anthropic_event(&parsed, state));
+        }
+    }
+
+    result
+}
+
+pub fn convert_anthropic_event(parsed: &Value, state: &mut AnthropicStreamState) -> String {
+    let mut result = String::new();
+
+    // Keep-alives: nothing to convert, log or report as unhandled
+    if parsed.get("type").and_then(|t| t.as_str()) == Some("ping") {
+        return result;
+    }
+
+    chunk_info!("Anthropic parsed data: {}", serde_json::to_string(parsed).unwrap_or_default());
+    // Convert Anthropic delta format to AI SDK v5 format
+    if let Some(event_type) = parsed.get("type").and_then(|t| t.as_str()) {
+        match event_type {
+            "content_block_delta" => {
+                if let Some(delta) = parsed.get("delta") {
+                    if let Some(text) = delta.get("text").and_then(|t| t.as_str()) {
+                        chunk_info!("Anthropic text delta: {}", text);
+                        result.push_str(&state.frames.text(text));
+                    } else if let Some(partial_json) = delta.get("partial_json").and_then(|j| j.as_str()) {
+                        let index = parsed.get("index").and_then(|i| i.as_u64()).unwrap_or(0);
+                        if let Some(tool_use) = state.tool_uses.get_mut(&index) {
+                            tool_use.arguments.push_str(partial_json);
+                        }
+                    } else if let Some(citation) = delta.get("citation") {
+                        // citations_delta: a citation for the text block being streamed
+                        result.push_str(&state.citation_frame(citation));
+                    }
+                }
+            }
+            // A tool call; its input arrives as input_json_delta pieces
+            "content_block_start" if parsed.pointer("/content_block/type") == Some(&json!("tool_use")) => {
+                let index = parsed.get("index").and_then(|i| i.as_u64()).unwrap_or(0);
+                let field = |name: &str| {
+                    parsed
+                        .pointer(&format!("/content_block/{}", name))
+                        .and_then(|v| v.as_str())
+                        .unwrap_or_default()
+                        .to_string()
+                };
+                state.tool_uses.insert(
+                    index,
+                    ToolCallAccumulator { id: field("id"), name: field("name"), arguments: String::new() },
+                );
+            }
+            // Text blocks can start with their citations already attached
+            "content_block_start" => {
+                let citations = parsed
+                    .pointer("/content_block/citations")
+                    .and_then(|c| c.as_array())
+                    .filter(|citations| !citations.is_empty());
+                match citations {
+                    Some(citations) => {
+                        for citation in citations {
+                            result.push_str(&state.citation_frame(citation));
+                        }
+                    }
+                    None => state.unhandled.record(event_type),
+                }
+            }
+            "message_start" => {
+                if let Some(usage) = parsed.get("message").and_then(|m| m.get("usage")) {
+                    state.record_usage(usage);
+                }
+                if let Some(prefill) = state.prefill.take() {
+                    result.push_str(&state.frames.text(&prefill));
+                }
+            }
+            "message_delta" => {
+                if let Some(stop_reason) = parsed
+                    .get("delta")
+                    .and_then(|d| d.get("stop_reason"))
+                    .and_then(|r| r.as_str())
+                {
+                    state.stop_reason = Some(stop_reason.to_string());
+                }
+                if let Some(usage) = parsed.get("usage") {
+                    state.record_usage(usage);
+                }
+            }
+            "content_block_stop" => {
+                let index = parsed.get("index").and_then(|i| i.as_u64()).unwrap_or(0);
+                match state.tool_uses.remove(&index) {
+                    Some(tool_use) => result.push_str(&state.tool_use_frame(tool_use)),
+                    None => state.unhandled.record(event_type),
+                }
+            }
+            "message_stop" => {
+                result.push_str(&state.finish_frame());
+            }
+            "error" => {
+                let error = parsed.get("error").cloned().unwrap_or(Value::Null);
+                result.push_str(&state.frames.stream_error(&error, state.usage));
+            }
+            other => {
+                // Skip other events for now
+                state.unhandled.record(other);
+            }
+        }
+    }
+
+    result
+}
+
+// A single decoded message from the AWS event-stream binary framing
+pub struct EventStreamMessage {
+    headers: HashMap<String, String>,
+    payload: Vec<u8>,
+}
+
+// Decode one event-stream message from the front of `buf`.
+// Layout: total_len(u32) headers_len(u32) prelude_crc(u32) headers payload message_crc(u32).
+// Returns Ok(None) when the buffer doesn't hold a complete message yet.
+pub fn decode_event_stream_message(buf: &[u8]) -> Result<Option<(EventStreamMessage, usize)>, String> {
+    const PRELUDE_LEN: usize = 12;
+    const CRC_LEN: usize = 4;
+
+    if buf.len() < PRELUDE_LEN {
+        return Ok(None);
+    }
+
+    let read_u32 = |bytes: &[u8]| u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    let total_len = read_u32(&buf[0..4]) as usize;
+    let headers_len = read_u32(&buf[4..8]) as usize;
+    let prelude_crc = read_u32(&buf[8..12]);
+
+    if crc32fast::hash(&buf[0..8]) != prelude_crc {
+        return Err("event-stream prelude checksum mismatch".to_string());
+    }
+    if total_len < PRELUDE_LEN + headers_len + CRC_LEN {
+        return Err(format!("invalid event-stream message length {}", total_len));
+    }
+    if buf.len() < total_len {
+        return Ok(None);
+    }
+
+    let message_crc = read_u32(&buf[total_len - CRC_LEN..total_len]);
+    if crc32fast::hash(&buf[..total_len - CRC_LEN]) != message_crc {
+        return Err("event-stream message checksum mismatch".to_string());
+    }
+
+    // Headers: name_len(u8) name value_type(u8) value. Only string values are kept,
+    // but every type has to be walked to find the next header.
+    let mut headers = HashMap::new();
+    let header_bytes = &buf[PRELUDE_LEN..PRELUDE_LEN + headers_len];
+    let mut pos = 0;
+    while pos < header_bytes.len() {
+        let name_len = header_bytes[pos] as usize;
+        pos += 1;
+        let name = header_bytes
+            .get(pos..pos + name_len)
+            .ok_or("truncated event-stream header name")?;
+        let name = String::from_utf8_lossy(name).to_string();
+        pos += name_len;
+
+        let value_type = *header_bytes.get(pos).ok_or("truncated event-stream header type")?;
+        pos += 1;
+        let value_len = match value_type {
+            0 | 1 => 0, // bool true / false
+            2 => 1,     // byte
+            3 => 2,     // short
+            4 => 4,     // int
+            5 | 8 => 8, // long / timestamp
+            9 => 16,    // uuid
+            6 | 7 => {
+                // byte array / string, prefixed by a u16 length
+                let len_bytes = header_bytes
+                    .get(pos..pos + 2)
+                    .ok_or("truncated event-stream header length")?;
+                pos += 2;
+                u16::from_be_bytes([len_bytes[0], len_bytes[1]]) as usize
+            }
+            other => return Err(format!("unknown event-stream header type {}", other)),
+        };
+        let value = header_bytes
+            .get(pos..pos + value_len)
+            .ok_or("truncated event-stream header value")?;
+        if value_type == 7 {
+            headers.insert(name, String::from_utf8_lossy(value).to_string());
+        }
+        pos += value_len;
+    }
+
+    let payload = buf[PRELUDE_LEN + headers_len..total_len - CRC_LEN].to_vec();
+    Ok(Some((EventStreamMessage { headers, payload }, total_len)))
+}
+
+pub fn convert_bedrock_to_ai_sdk(buffer: &mut Vec<u8>, state: &mut AnthropicStreamState) -> String {
+    // Convert Bedrock event-stream frames to AI SDK v5 format.
+    // Each "chunk" event carries {"bytes": "<base64 Anthropic event JSON>"}.
+    let mut result = String::new();
+    // Nothing after a failure reaches the client
+    if state.failed {
+        buffer.clear();
+        return result;
+    }
+
+    loop {
+        let (message, consumed) = match decode_event_stream_message(buffer) {
+            Ok(Some(decoded)) => decoded,
+            Ok(None) => break,
+            Err(e) => {
+                // The framing is unrecoverable once corrupted, so drop what we have
+                error!("Failed to decode Bedrock event stream: {}", e);
+                buffer.clear();
+                let message = format!("Invalid Bedrock event stream: {}", e);
+                let error = json!({ "type": "stream_error", "message": message });
+                result.push_str(&state.frames.stream_error(&error, state.usage));
+                state.failed = true;
+                break;
+            }
+        };
+        buffer.drain(..consumed);
+
+        let message_type = message.headers.get(":message-type").map(String::as_str);
+        if message_type == Some("exception") || message_type == Some("error") {
+            let exception_type = message
+                .headers
+                .get(":exception-type")
+                .or_else(|| message.headers.get(":error-code"))
+                .cloned()
+                .unwrap_or_else(|| "bedrock_error".to_string());
+            error!(
+                "Bedrock stream exception {}: {}",
+                exception_type,
+                String::from_utf8_lossy(&message.payload)
+            );
+            // Exception payloads are {"message": ...}
+            let message = serde_json::from_slice::<Value>(&message.payload)
+                .ok()
+                .and_then(|payload| payload.get("message").and_then(|m| m.as_str()).map(str::to_string))
+                .unwrap_or_else(|| String::from_utf8_lossy(&message.payload).to_string());
+            let error = json!({ "type": exception_type, "message": message });
+            result.push_str(&state.frames.stream_error(&error, state.usage));
+            buffer.clear();
+            state.failed = true;
+            break;
+        }
+
+        if message.headers.get(":event-type").map(String::as_str) != Some("chunk") {
+            continue;
+        }
+
+        let event = serde_json::from_slice::<Value>(&message.payload)
+            .ok()
+            .and_then(|payload| payload.get("bytes").and_then(|b| b.as_str()).map(str::to_string))
+            .and_then(|encoded| BASE64_STANDARD.decode(encoded).ok())
+            .and_then(|decoded| serde_json::from_slice::<Value>(&decoded).ok());
+
+        match event {
+            Some(event) => result.push_str(&convert_anthropic_event(&event, state)),
+            None => error!("Failed to decode Bedrock chunk payload"),
+        }
+    }
+
+    result
+}
+
+#[derive(Debug, Clone)]
+pub struct ToolCallAccumulator {
+    id: String,
+    name: String,
+    arguments: String,
+}
+
+// Per-stream options and bookkeeping for the OpenAI converter
+#[derive(Debug)]
+pub struct OpenAiStreamState {
+    tool_call_format: ToolCallFormat,
+    // Send b:/c: frames for tool calls as they form
+    tool_call_streaming: bool,
+    session: Session,
+    frames: FrameWriter,
+    // Tool calls being streamed, by the tool call's index. Per stream, so two streams in the
+    // same session can't mix up each other's arguments.
+    tool_calls: HashMap<u64, ToolCallAccumulator>,
+    pub unhandled: UnhandledEvents,
+    usage: Usage,
+    finish_reason: Option<String>,
+    system_fingerprint: Option<String>,
+    sources: SentSources,
+    // Set once a finish frame has been sent (at [DONE], or early for an in-stream error)
+    finished: bool,
+}
+
+impl OpenAiStreamState {
+    pub fn new(tool_call_format: ToolCallFormat, tool_call_streaming: bool, session: Session, frames: FrameWriter) -> Self {
+        OpenAiStreamState {
+            tool_call_format,
+            tool_call_streaming: tool_call_streaming && tool_call_format == ToolCallFormat::AiSdk,
+            session,
+            frames,
+            tool_calls: HashMap::new(),
+            unhandled: UnhandledEvents::default(),
+            usage: Usage::default(),
+            finish_reason: None,
+            system_fingerprint: None,
+            sources: SentSources::default(),
+            finished: false,
+        }
+    }
+
+    fn has_pending_tool_calls(&self) -> bool {
+        !self.tool_calls.is_empty()
+    }
+
+    // Frames for the tool calls accumulated so far, in the order the model made them. Sent at
+    // [DONE], or earlier when the model goes back to text after a call so the two stay in order.
+    fn flush_tool_calls(&mut self) -> String {
+        let mut frames = String::new();
+        let mut tool_calls: Vec<(u64, ToolCallAccumulator)> = self.tool_calls.drain().collect();
+        tool_calls.sort_by_key(|(index, _)| *index);
+
+        // Tools the server executes itself
+        let tool_results: HashMap<String, Value> = tool_calls
+            .iter()
+            .filter_map(|(_, tool_call)| {
+                let args = serde_json::from_str::<Value>(&tool_call.arguments).ok()?;
+                let result = execute_server_tool(&self.session, &tool_call.name, &args)?;
+                Some((tool_call.id.clone(), result))
+            })
+            .collect();
+
+        match self.tool_call_format {
+            ToolCallFormat::AiSdk => {
+                for (_, tool_call) in tool_calls {
+                    info!("Sending tool call: id={}, name={}, args={}",
+                          tool_call.id, tool_call.name, tool_call.arguments);
+
+                    // Send complete tool call in AI SDK format
+                    frames.push_str(&self.frames.tool_call_from_text(&tool_call.id, &tool_call.name, &tool_call.arguments));
+
+                    // AI SDK tool result frame for server-executed tools
+                    if let Some(tool_result) = tool_results.get(&tool_call.id) {
+                        frames.push_str(&self.frames.tool_result(&tool_call.id, tool_result));
+                    }
+                }
+            }
+            ToolCallFormat::OpenAI => {
+                if !tool_calls.is_empty() {
+                    // OpenAI tool_calls shape, arguments left as the raw JSON string,
+                    // sent as an AI SDK data frame (2:)
+                    let openai_tool_calls: Vec<Value> = tool_calls
+                        .into_iter()
+                        .map(|(_, tool_call)| {
+                            info!("Sending OpenAI-format tool call: id={}, name={}, args={}",
+                                  tool_call.id, tool_call.name, tool_call.arguments);
+                            json!({
+                                "id": tool_call.id,
+                                "type": "function",
+                                "function": {
+                                    "name": tool_call.name,
+                                    "arguments": tool_call.arguments
+                                }
+                            })
+                        })
+                        .collect();
+                    frames.push_str(&self.frames.data(&json!([{ "tool_calls": openai_tool_calls }])));
+                }
+            }
+        }
+        frames
+    }
+
+    // The stream failed: tool calls accumulated so far are incomplete, so drop them and end
+    // the message with error frames
+    pub fn fail(&mut self, error: &Value) -> String {
+        self.tool_calls.clear();
+        self.finished = true;
+        self.frames.stream_error(error, self.usage)
+    }
+}
+
+// Mistral streams each tool call whole in a single delta, without OpenAI's per-call `index`,
+// and may send `arguments` as a JSON object instead of a string
+#[derive(Debug)]
+pub struct MistralStreamState {
+    pub openai: OpenAiStreamState,
+    pub tool_calls_seen: u64,
+}
+
+// Rewrite Mistral's chunks into the OpenAI shape and reuse the OpenAI converter
+pub fn convert_mistral_to_ai_sdk(chunk: &str, state: &mut MistralStreamState) -> String {
+    let mut normalized = String::new();
+
+    for line in chunk.lines() {
+        let Some(data_part) = sse_data(line) else {
+            continue;
+        };
+        let Ok(mut parsed) = serde_json::from_str::<Value>(data_part) else {
+            // [DONE] and anything unparseable go through unchanged
+            normalized.push_str(line);
+            normalized.push('\n');
+            continue;
+        };
+
+        if let Some(tool_calls) = parsed
+            .pointer_mut("/choices/0/delta/tool_calls")
+            .and_then(|t| t.as_array_mut())
+        {
+            for tool_call in tool_calls {
+                if tool_call.get("index").and_then(|i| i.as_u64()).is_none() {
+                    tool_call["index"] = json!(state.tool_calls_seen);
+                }
+                state.tool_calls_seen += 1;
+                if let Some(arguments) = tool_call.pointer_mut("/function/arguments") {
+                    if !arguments.is_string() {
+                        *arguments = json!(arguments.to_string());
+                    }
+                }
+            }
+        }
+
+        normalized.push_str(&format!("data: {}\n", parsed));
+    }
+
+    convert_openai_to_ai_sdk(&normalized, &mut state.openai)
+}
+
+// Undecoded bytes of a Cohere stream, up to the last complete line
+#[derive(Debug, Default)]
+pub struct CohereStreamState {
+    pub frames: FrameWriter,
+    pub buffer: Vec<u8>,
+    pub unhandled: UnhandledEvents,
+}
+
+// Convert the complete lines in the buffer. text-generation events become text frames and
+// stream-end the finish frames.
+pub fn convert_cohere_to_ai_sdk(state: &mut CohereStreamState) -> String {
+    let mut result = String::new();
+
+    while let Some(newline) = state.buffer.iter().position(|&b| b == b'\n') {
+        let line: Vec<u8> = state.buffer.drain(..=newline).collect();
+        let line = String::from_utf8_lossy(&line);
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let Ok(event) = serde_json::from_str::<Value>(line) else {
+            warn!("Skipping malformed Cohere event: {}", line);
+            continue;
+        };
+        chunk_info!("Cohere parsed data: {}", event);
+
+        match event.get("event_type").and_then(|t| t.as_str()) {
+            Some("text-generation") => {
+                if let Some(text) = event.get("text").and_then(|t| t.as_str()) {
+                    result.push_str(&state.frames.text(text));
+                }
+            }
+            Some("stream-end") => {
+                let billed = event.pointer("/response/meta/billed_units");
+                let usage = Usage {
+                    prompt_tokens: billed.and_then(|b| b.get("input_tokens")).and_then(|v| v.as_u64()),
+                    completion_tokens: billed.and_then(|b| b.get("output_tokens")).and_then(|v| v.as_u64()),
+                    ..Default::default()
+                };
+                let finish_reason = match event.get("finish_reason").and_then(|r| r.as_str()) {
+                    Some("COMPLETE") | None => "stop",
+                    Some("MAX_TOKENS") => "length",
+                    Some("ERROR_TOXIC") => "content-filter",
+                    Some(reason) if reason.starts_with("ERROR") => "error",
+                    Some(_) => "other",
+                };
+                result.push_str(&state.frames.finish(finish_reason, usage));
+            }
+            Some("stream-start") => {}
+            other => state.unhandled.record(other.unwrap_or("<missing event_type>")),
+        }
+    }
+    result
+}
+
+pub fn convert_openai_to_ai_sdk(chunk: &str, state: &mut OpenAiStreamState) -> String {
+    // Convert OpenAI streaming format to AI SDK v5 format
+    let mut result = String::new();
+
+    for line in chunk.lines() {
+        if let Some(data_part) = sse_data(line) {
+            if data_part == "[DONE]" {
+                // Send the tool calls still pending, then finish
+                result.push_str(&state.flush_tool_calls());
+                if !state.finished {
+                    state.finished = true;
+                    result.push_str(&state.frames.finish_with_fingerprint(
+                        openai_finish_reason(state.finish_reason.as_deref()),
+                        state.usage,
+                        state.system_fingerprint.as_deref(),
+                    ));
+                }
+                continue;
+            }
+
+            if let Ok(parsed) = serde_json::from_str::<Value>(data_part) {
+                chunk_info!("OpenAI parsed data: {}", serde_json::to_string(&parsed).unwrap_or_default());
+                // Mid-stream failure: {"error":{"message":...,"type":...}}. Tool calls
+                // accumulated so far are incomplete, so drop them.
+                if let Some(error) = parsed.get("error") {
+                    result.push_str(&state.fail(error));
+                    continue;
+                }
+                if let Some(usage) = parsed.get("usage").filter(|u| u.is_object()) {
+                    state.usage.merge(Usage::from_openai(usage));
+                }
+                if let Some(finish_reason) = parsed.pointer("/choices/0/finish_reason").and_then(|r| r.as_str()) {
+                    state.finish_reason = Some(finish_reason.to_string());
+                }
+                if let Some(system_fingerprint) = parsed.get("system_fingerprint").and_then(|f| f.as_str()) {
+                    state.system_fingerprint = Some(system_fingerprint.to_string());
+                }
+                // Convert OpenAI delta format to AI SDK v5 format
+                if parsed.pointer("/choices/0/delta").is_none() {
+                    let object = parsed.get("object").and_then(|o| o.as_str()).unwrap_or("chunk");
+                    state.unhandled.record(&format!("{} without delta", object));
+                }
+                if let Some(choices) = parsed.get("choices").and_then(|c| c.as_array()) {
+                    if let Some(choice) = choices.first() {
+                        if let Some(delta) = choice.get("delta") {
+                            // Handle text content
+                            if let Some(content) = delta.get("content").and_then(|c| c.as_str()) {
+                                if !content.is_empty() && state.has_pending_tool_calls() {
+                                    result.push_str(&state.flush_tool_calls());
+                                }
+                                result.push_str(&state.frames.text(content));
+                            }
+
+                            // Requested logprobs for this delta's tokens, as a data frame
+                            if let Some(content) = choice.pointer("/logprobs/content").and_then(|c| c.as_array()) {
+                                if !content.is_empty() {
+                                    result.push_str(&state.frames.data(&json!([{ "type": "logprobs", "content": content }])));
+                                }
+                            }
+
+                            // Web search models cite pages as url_citation annotations
+                            if let Some(annotations) = delta.get("annotations").and_then(|a| a.as_array()) {
+                                for citation in annotations.iter().filter_map(|a| a.get("url_citation")) {
+                                    let url = citation.get("url").and_then(|u| u.as_str()).unwrap_or_default();
+                                    let title = citation.get("title").and_then(|t| t.as_str());
+                                    result.push_str(&state.sources.frame(state.frames, url, title));
+                                }
+                            }
+
+                            // Handle tool calls
+                            if let Some(tool_calls) = delta.get("tool_calls").and_then(|t| t.as_array()) {
+                                chunk_info!("Found tool_calls in delta: {:?}", tool_calls);
+
+                                for tool_call in tool_calls {
+                                    let index = tool_call.get("index")
+                                        .and_then(|i| i.as_u64())
+                                        .unwrap_or(0);
+                                    // First chunk has id, type and function name
+                                    if let Some(id) = tool_call.get("id").and_then(|i| i.as_str()) {
+                                        if let Some(function) = tool_call.get("function") {
+                                            let name = function.get("name")
+                                                .and_then(|n| n.as_str())
+                                                .unwrap_or("");
+                                            let arguments = function.get("arguments")
+                                                .and_then(|a| a.as_str())
+                                                .unwrap_or("");
+
+                                            info!("Tool call init: id={}, name={}, args_start={}",
+                                                  id, name, arguments);
+                                            if state.tool_call_streaming {
+                                                result.push_str(&state.frames.tool_call_start(id, name));
+                                                result.push_str(&state.frames.tool_call_delta(id, arguments));
+                                            }
+
+                                            state.tool_calls.insert(index, ToolCallAccumulator {
+                                                id: id.to_string(),
+                                                name: name.to_string(),
+                                                arguments: arguments.to_string(),
+                                            });
+                                        }
+                                    } else if let Some(function) = tool_call.get("function") {
+                                        // Subsequent chunks only have incremental arguments
+                                        if let Some(arguments) = function.get("arguments").and_then(|a| a.as_str()) {
+                                            if let Some(tc) = state.tool_calls.get_mut(&index) {
+                                                tc.arguments.push_str(arguments);
+                                                if state.tool_call_streaming {
+                                                    result.push_str(&state.frames.tool_call_delta(&tc.id, arguments));
+                                                }
+                                                chunk_info!("Tool call append: index={}, args_chunk={}",
+                                                      index, arguments);
+                                            }
+                                        }
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn line_buffer_holds_back_partial_lines() {
+        let mut buffer = LineBuffer::default();
+        assert_eq!(buffer.push(b"data: {\"a\""), "");
+        assert_eq!(buffer.push(b":1}\ndata: {"), "data: {\"a\":1}\n");
+        assert_eq!(buffer.push(b"}\n"), "data: {}\n");
+    }
+
+    #[test]
+    fn anthropic_text_delta_becomes_text_frame() {
+        let mut state = AnthropicStreamState::default();
+        let chunk = "event: content_block_delta\n\
+                     data: {\"type\":\"content_block_delta\",\"index\":0,\"delta\":{\"type\":\"text_delta\",\"text\":\"Hi\"}}\n";
+        assert_eq!(convert_anthropic_to_ai_sdk(chunk, &mut state), "0:\"Hi\"\n");
+    }
+
+    #[test]
+    fn bedrock_exceptions_end_the_stream_with_error_and_finish_frames() {
+        let mut state = AnthropicStreamState::default();
+        let mut buffer = include_bytes!("../tests/fixtures/bedrock_exception.eventstream").to_vec();
+        let output = convert_bedrock_to_ai_sdk(&mut buffer, &mut state);
+        let frames: Vec<(&str, Value)> = output
+            .lines()
+            .map(|line| {
+                let (prefix, payload) = line.split_once(':').unwrap();
+                (prefix, serde_json::from_str(payload).unwrap())
+            })
+            .collect();
+        let prefixes: Vec<&str> = frames.iter().map(|(prefix, _)| *prefix).collect();
+        // The text after the exception is never converted
+        assert_eq!(prefixes, ["0", "3", "e", "d"]);
+        assert_eq!(frames[0].1, "Hello");
+        assert_eq!(
+            frames[1].1,
+            "throttlingException: Too many requests, please wait before trying again. You have sent \"too many\" tokens."
+        );
+        assert_eq!(frames[3].1["finishReason"], "error");
+        assert!(buffer.is_empty());
+        assert_eq!(convert_bedrock_to_ai_sdk(&mut b"more".to_vec(), &mut state), "");
+    }
+
+    #[test]
+    fn anthropic_ping_events_are_skipped() {
+        let mut state = AnthropicStreamState::default();
+        let stream = "event: ping\n\
+                      data: {\"type\": \"ping\"}\n\
+                      \n\
+                      : keep-alive\n\
+                      event: content_block_delta\n\
+                      data: {\"type\":\"content_block_delta\",\"index\":0,\"delta\":{\"type\":\"text_delta\",\"text\":\"Hi\"}}\n\
+                      \n\
+                      event: ping\n\
+                      data:{\"type\":\"ping\"}\n\
+                      \n";
+        assert_eq!(convert_anthropic_to_ai_sdk(stream, &mut state), "0:\"Hi\"\n");
+        assert_eq!(convert_anthropic_to_ai_sdk("event: ping\ndata: {\"type\": \"ping\"}\n\n", &mut state), "");
+        assert_eq!(state.unhandled.list(), "");
+    }
+
+    #[test]
+    fn anthropic_prefill_is_sent_at_message_start() {
+        let mut state = AnthropicStreamState {
+            prefill: Some("{".to_string()),
+            ..Default::default()
+        };
+        let chunk = "data: {\"type\":\"message_start\",\"message\":{\"usage\":{\"input_tokens\":3}}}\n";
+        assert!(convert_anthropic_to_ai_sdk(chunk, &mut state).ends_with("0:\"{\"\n"));
+        assert_eq!(state.usage.prompt_tokens, Some(3));
+    }
+
+    #[test]
+    fn openai_content_delta_becomes_text_frame() {
+        let mut state = OpenAiStreamState::new(
+            ToolCallFormat::AiSdk,
+            false,
+            Session::new(None),
+            FrameWriter::default(),
+        );
+        let chunk = "data: {\"choices\":[{\"index\":0,\"delta\":{\"content\":\"Hello\"}}]}\n";
+        assert_eq!(convert_openai_to_ai_sdk(chunk, &mut state), "0:\"Hello\"\n");
+    }
+
+    #[test]
+    fn streamed_openai_tool_calls_are_emitted_in_the_requested_format() {
+        let stream = include_str!("../tests/fixtures/openai_tool_call.sse");
+        let convert = |format: ToolCallFormat| {
+            let mut state = OpenAiStreamState::new(format, false, Session::new(None), FrameWriter::default());
+            let output = convert_openai_to_ai_sdk(stream, &mut state);
+            output
+                .lines()
+                .map(|line| {
+                    let (prefix, payload) = line.split_once(':').unwrap();
+                    (prefix.to_string(), serde_json::from_str::<Value>(payload).unwrap())
+                })
+                .filter(|(prefix, _)| prefix != "e" && prefix != "d")
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            convert(ToolCallFormat::AiSdk),
+            [(
+                "9".to_string(),
+                json!({ "toolCallId": "call_1", "toolName": "executeSQL", "args": { "sql": "SELECT 1" } })
+            )]
+        );
+        assert_eq!(
+            convert(ToolCallFormat::OpenAI),
+            [(
+                "2".to_string(),
+                json!([{ "tool_calls": [{
+                    "id": "call_1",
+                    "type": "function",
+                    "function": { "name": "executeSQL", "arguments": "{\"sql\":\"SELECT 1\"}" }
+                }] }])
+            )]
+        );
+    }
+
+    #[test]
+    fn openai_data_lines_without_a_space_are_read() {
+        let mut state = OpenAiStreamState::new(
+            ToolCallFormat::AiSdk,
+            false,
+            Session::new(None),
+            FrameWriter::default(),
+        );
+        let stream = "data:{\"choices\":[{\"index\":0,\"delta\":{\"content\":\"Hello\"}}]}\n\
+                      data:{\"choices\":[{\"index\":0,\"delta\":{},\"finish_reason\":\"stop\"}]}\n\
+                      data:[DONE]\n";
+        let output = convert_openai_to_ai_sdk(stream, &mut state);
+        assert!(output.starts_with("0:\"Hello\"\ne:"), "{}", output);
+        assert!(output.contains("d:{\"finishReason\":\"stop\""), "{}", output);
+    }
+
+    #[test]
+    fn mistral_data_lines_without_a_space_are_read() {
+        let mut state = MistralStreamState {
+            openai: OpenAiStreamState::new(ToolCallFormat::AiSdk, false, Session::new(None), FrameWriter::default()),
+            tool_calls_seen: 0,
+        };
+        let stream = "data:{\"choices\":[{\"index\":0,\"delta\":{\"tool_calls\":[{\"id\":\"call_1\",\
+                      \"function\":{\"name\":\"executeSQL\",\"arguments\":{\"sql\":\"SELECT 1\"}}}]}}]}\n\
+                      data:{\"choices\":[{\"index\":0,\"delta\":{},\"finish_reason\":\"tool_calls\"}]}\n\
+                      data:[DONE]\n";
+        let output = convert_mistral_to_ai_sdk(stream, &mut state);
+        let tool_call = output.lines().find_map(|line| line.strip_prefix("9:")).expect("a tool call frame");
+        assert_eq!(
+            serde_json::from_str::<Value>(tool_call).unwrap(),
+            json!({ "toolCallId": "call_1", "toolName": "executeSQL", "args": { "sql": "SELECT 1" } })
+        );
+        assert!(output.contains("d:{\"finishReason\":\"tool-calls\""), "{}", output);
+    }
+
+    #[test]
+    fn partial_usage_reports_are_merged() {
+        let mut state = OpenAiStreamState::new(
+            ToolCallFormat::AiSdk,
+            false,
+            Session::new(None),
+            FrameWriter::default(),
+        );
+        // Prompt tokens mid-stream, then completion tokens with a zero placeholder for the prompt
+        let stream = "data: {\"choices\":[{\"index\":0,\"delta\":{\"content\":\"Hi\"}}],\"usage\":{\"prompt_tokens\":12}}\n\
+                      data: {\"choices\":[{\"index\":0,\"delta\":{},\"finish_reason\":\"stop\"}],\
+                      \"usage\":{\"prompt_tokens\":0,\"completion_tokens\":6}}\n\
+                      data: [DONE]\n";
+        let output = convert_openai_to_ai_sdk(stream, &mut state);
+        let usage = json!({ "promptTokens": 12, "completionTokens": 6 });
+        for prefix in ["e:", "d:"] {
+            let frame = output.lines().find_map(|line| line.strip_prefix(prefix)).unwrap();
+            assert_eq!(serde_json::from_str::<Value>(frame).unwrap()["usage"], usage);
+        }
+    }
+}
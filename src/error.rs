@@ -0,0 +1,127 @@
+//! The error type of every endpoint, and the JSON error responses it becomes
+
+use std::fmt;
+
+use actix_web::{http::StatusCode, HttpResponse, ResponseError};
+use serde_json::json;
+
+use crate::validate::FieldError;
+
+// Every error response has the same JSON shape: {"error": {"code", "message", "type"}}, where
+// code is the HTTP status and type a stable machine-readable category
+#[derive(Debug)]
+pub struct ApiError {
+    pub status: StatusCode,
+    pub error_type: &'static str,
+    pub message: String,
+    // Sent as Retry-After, in seconds
+    pub retry_after: Option<u64>,
+    // Field-level problems with the request body, sent as "errors"
+    pub errors: Vec<FieldError>,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, error_type: &'static str, message: impl Into<String>) -> Self {
+        ApiError {
+            status,
+            error_type,
+            message: message.into(),
+            retry_after: None,
+            errors: Vec::new(),
+        }
+    }
+
+    pub fn bad_request(message: impl Into<String>) -> Self {
+        ApiError::new(StatusCode::BAD_REQUEST, "invalid_request_error", message)
+    }
+
+    // A request body with the wrong shape, with every problem found
+    pub fn invalid_fields(errors: Vec<FieldError>) -> Self {
+        let problems: Vec<String> = errors
+            .iter()
+            .map(|error| match error.field.as_str() {
+                "" => error.message.clone(),
+                field => format!("{} {}", field, error.message),
+            })
+            .collect();
+        ApiError {
+            errors,
+            ..ApiError::bad_request(format!("Invalid request: {}", problems.join("; ")))
+        }
+    }
+
+    pub fn not_found(message: impl Into<String>) -> Self {
+        ApiError::new(StatusCode::NOT_FOUND, "not_found", message)
+    }
+
+    pub fn internal(message: impl Into<String>) -> Self {
+        ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "server_error", message)
+    }
+
+    pub fn unauthorized(message: impl Into<String>) -> Self {
+        ApiError::new(StatusCode::UNAUTHORIZED, "authentication_error", message)
+    }
+
+    pub fn forbidden(message: impl Into<String>) -> Self {
+        ApiError::new(StatusCode::FORBIDDEN, "permission_error", message)
+    }
+
+    pub fn unsupported_media_type(message: impl Into<String>) -> Self {
+        ApiError::new(StatusCode::UNSUPPORTED_MEDIA_TYPE, "invalid_request_error", message)
+    }
+
+    pub fn bad_gateway(message: impl Into<String>) -> Self {
+        ApiError::new(StatusCode::BAD_GATEWAY, "upstream_error", message)
+    }
+
+    // The provider call failed (an error status, outage or overload), so another model may work
+    pub fn is_upstream_failure(&self) -> bool {
+        matches!(self.error_type, "upstream_error" | "overloaded_error")
+    }
+
+    // The provider itself is overloaded (Anthropic's 529) and retrying didn't help
+    pub fn upstream_overloaded(provider: &str) -> Self {
+        ApiError::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "overloaded_error",
+            format!("The {} API is overloaded right now. Try again shortly.", provider),
+        )
+    }
+
+    pub fn service_unavailable(message: impl Into<String>, retry_after: u64) -> Self {
+        ApiError {
+            retry_after: Some(retry_after),
+            ..ApiError::new(StatusCode::SERVICE_UNAVAILABLE, "overloaded_error", message)
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        self.status
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut response = HttpResponse::build(self.status);
+        if let Some(retry_after) = self.retry_after {
+            response.insert_header(("Retry-After", retry_after.to_string()));
+        }
+        let mut body = json!({
+            "error": {
+                "code": self.status.as_u16(),
+                "message": self.message,
+                "type": self.error_type,
+            }
+        });
+        if !self.errors.is_empty() {
+            body["errors"] = json!(self.errors);
+        }
+        response.json(body)
+    }
+}
@@ -0,0 +1,259 @@
+//! Limits on what a request may do: allowed models, the tool loop guard, the step limit and
+//! session token budgets
+
+use std::collections::HashSet;
+use std::env;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task::Poll;
+
+use actix_web::{
+    body::{BodyStream, BoxBody, MessageBody},
+    http::header::HeaderName,
+    http::StatusCode,
+    HttpResponse,
+};
+use bytes::Bytes;
+use log::{info, warn};
+use serde_json::{json, Value};
+
+use crate::convert::{FrameWriter, LineBuffer, Usage};
+use crate::error::ApiError;
+use crate::providers::Provider;
+use crate::request::{ChatMessage, ChatRequest};
+use crate::session::SessionStore;
+use crate::stream::frames_response;
+use crate::usage;
+
+// Models clients may request, from ALLOWED_MODELS: comma-separated names, where a trailing *
+// matches any model starting with the rest (e.g. "gpt-4o-mini,claude-3-5-haiku-*"). Unset allows
+// every model.
+lazy_static::lazy_static! {
+    static ref ALLOWED_MODELS: Option<Vec<String>> = env::var("ALLOWED_MODELS").ok().map(|v| {
+        v.split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(str::to_string)
+            .collect()
+    });
+}
+
+fn model_allowed(model: &str, allowed: &[String]) -> bool {
+    allowed.iter().any(|entry| match entry.strip_suffix('*') {
+        Some(prefix) => model.get(..prefix.len()).is_some_and(|start| start.eq_ignore_ascii_case(prefix)),
+        None => model.eq_ignore_ascii_case(entry),
+    })
+}
+
+pub fn check_model_allowed(model: &str) -> Result<(), ApiError> {
+    match ALLOWED_MODELS.as_deref() {
+        Some(allowed) if !model_allowed(model, allowed) => {
+            info!("Rejected request for model {} not in ALLOWED_MODELS", model);
+            Err(ApiError::forbidden(format!(
+                "Model '{}' is not permitted on this server. Permitted models: {}",
+                model,
+                allowed.join(", ")
+            )))
+        }
+        _ => Ok(()),
+    }
+}
+
+// How many identical consecutive tool calls are allowed before the loop is broken (0 disables)
+pub fn max_tool_repeats() -> usize {
+    env::var("MAX_TOOL_REPEATS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(3)
+}
+
+// Find the tool call at the end of the conversation and how many times it was made in a row
+// with the same arguments, across both toolInvocations and legacy tool_calls messages. Only the
+// current loop counts: a new user message starts the count over.
+pub fn trailing_tool_call_repeats(messages: &[ChatMessage]) -> Option<(String, usize)> {
+    let loop_start = messages.iter().rposition(|msg| msg.role == "user").map_or(0, |index| index + 1);
+    let mut calls: Vec<(String, Value)> = Vec::new();
+    for msg in &messages[loop_start..] {
+        if let Some(tool_invocations) = &msg.tool_invocations {
+            for invocation in tool_invocations {
+                let tool_name = invocation.get("toolName").and_then(|v| v.as_str()).unwrap_or("");
+                let args = invocation.get("args").cloned().unwrap_or(json!({}));
+                calls.push((tool_name.to_string(), args));
+            }
+        }
+        if let Some(tool_calls) = &msg.tool_calls {
+            for tool_call in tool_calls {
+                let function = tool_call.get("function");
+                let tool_name = function
+                    .and_then(|f| f.get("name"))
+                    .and_then(|v| v.as_str())
+                    .unwrap_or("");
+                let args = function
+                    .and_then(|f| f.get("arguments"))
+                    .and_then(|v| v.as_str())
+                    .and_then(|a| serde_json::from_str::<Value>(a).ok())
+                    .unwrap_or(json!({}));
+                calls.push((tool_name.to_string(), args));
+            }
+        }
+    }
+
+    let last = calls.last()?.clone();
+    let repeats = calls.iter().rev().take_while(|call| **call == last).count();
+    Some((last.0, repeats))
+}
+
+// Steps of the client's agentic loop since the last user message: each assistant message with
+// tool calls is one, or one per distinct step of its toolInvocations
+pub fn tool_steps_taken(messages: &[ChatMessage]) -> u32 {
+    let since_user = messages.iter().rev().take_while(|msg| msg.role != "user");
+    let steps = since_user.filter(|msg| msg.role == "assistant").map(|msg| {
+        let tool_call_step = msg.tool_calls.as_ref().is_some_and(|calls| !calls.is_empty()) as usize;
+        let invocation_steps = msg.tool_invocations.as_ref().map_or(0, |invocations| {
+            let steps: HashSet<Option<u64>> =
+                invocations.iter().map(|invocation| invocation.get("step").and_then(Value::as_u64)).collect();
+            steps.len()
+        });
+        tool_call_step.max(invocation_steps)
+    });
+    steps.sum::<usize>().try_into().unwrap_or(u32::MAX)
+}
+
+pub fn step_limit_response(frames: FrameWriter, max_steps: u32) -> HttpResponse {
+    let annotation = json!([{ "type": "max-steps", "maxSteps": max_steps }]);
+    let text = format!("Stopped: the conversation reached its limit of {} tool steps.", max_steps);
+    frames_response(format!(
+        "{}{}{}{}",
+        frames.start_step(),
+        frames.annotation(&annotation),
+        frames.text(&text),
+        frames.finish("max-steps", Usage::default())
+    ))
+}
+
+// The final answer of a loop at its step limit finishes with "max-steps". Should the model call
+// a tool anyway, the stream ends there: the call is left out and the upstream is dropped.
+pub fn finish_at_step_limit(response: HttpResponse, frames: FrameWriter) -> HttpResponse {
+    let mut lines = LineBuffer::default();
+    let mut ended = false;
+    response.map_body(|_, mut body| {
+        let stream = futures::stream::poll_fn(move |cx| loop {
+            if ended {
+                return Poll::Ready(None);
+            }
+            let chunk = match Pin::new(&mut body).poll_next(cx) {
+                Poll::Ready(Some(Ok(chunk))) => chunk,
+                poll => return poll,
+            };
+            let mut output = String::new();
+            for line in lines.push(&chunk).lines() {
+                match line.split_once(':') {
+                    Some(("9" | "b", _)) => {
+                        warn!("Model called a tool past the step limit; ending the stream");
+                        output.push_str(&frames.finish("max-steps", Usage::default()));
+                        ended = true;
+                        break;
+                    }
+                    Some((prefix @ ("e" | "d"), payload)) => match serde_json::from_str::<Value>(payload) {
+                        Ok(mut finish) if finish.get("finishReason").is_some() => {
+                            finish["finishReason"] = json!("max-steps");
+                            output.push_str(&format!("{}:{}\n", prefix, finish));
+                        }
+                        _ => output.push_str(&format!("{}\n", line)),
+                    },
+                    _ => output.push_str(&format!("{}\n", line)),
+                }
+            }
+            if !output.is_empty() {
+                return Poll::Ready(Some(Ok(Bytes::from(output))));
+            }
+        });
+        BoxBody::new(BodyStream::new(stream))
+    })
+}
+
+pub fn tool_loop_guard_response(frames: FrameWriter, tool_name: &str, repeats: usize) -> HttpResponse {
+    // AI SDK message annotation (8:) so the client can tell why the loop stopped
+    let annotation = json!([{
+        "type": "tool-loop-guard",
+        "toolName": tool_name,
+        "repeats": repeats,
+    }]);
+    let text = format!(
+        "Stopped: the {} tool was called {} times in a row with the same arguments.",
+        tool_name, repeats
+    );
+    frames_response(format!(
+        "{}{}{}{}",
+        frames.start_step(),
+        frames.annotation(&annotation),
+        frames.text(&text),
+        frames.finish("stop", Usage::default())
+    ))
+}
+
+// Tokens (prompt plus completion) each session has used, counted from the finish frames of its
+// responses. Like other session state, it's forgotten once the session has been idle for
+// SESSION_TTL_SECS.
+lazy_static::lazy_static! {
+    static ref SESSION_USAGE: Mutex<SessionStore<u64>> = Mutex::new(SessionStore::from_env());
+}
+
+// SESSION_TOKEN_BUDGET for every session, or the client's maxSessionTokens, whichever is lower
+pub fn session_token_budget(request: &ChatRequest) -> Option<u64> {
+    let server_budget = env::var("SESSION_TOKEN_BUDGET")
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .filter(|budget| *budget > 0);
+    match (server_budget, request.max_session_tokens) {
+        (Some(server_budget), Some(client_budget)) => Some(server_budget.min(client_budget)),
+        (server_budget, client_budget) => server_budget.or(client_budget),
+    }
+}
+
+pub fn session_tokens_used(session_id: &str) -> u64 {
+    SESSION_USAGE.lock().unwrap().get(session_id).copied().unwrap_or(0)
+}
+
+// 429 with a budget_exceeded error frame, so the chat UI can show why the session stopped
+pub fn session_budget_exceeded_response(frames: FrameWriter, budget: u64) -> HttpResponse {
+    let message = format!("budget_exceeded: This session has used its budget of {} tokens", budget);
+    let mut response = frames_response(format!(
+        "{}{}{}",
+        frames.start_step(),
+        frames.error(&message),
+        frames.finish("error", Usage::default())
+    ));
+    *response.status_mut() = StatusCode::TOO_MANY_REQUESTS;
+    response.headers_mut().insert(HeaderName::from_static("x-session-tokens-remaining"), 0.into());
+    response
+}
+
+// Count the usage in the streamed finish frames towards GET /usage and the session's total
+pub fn track_usage(response: HttpResponse, provider: Provider, model: String, session_id: Option<String>) -> HttpResponse {
+    let mut lines = LineBuffer::default();
+    response.map_body(|_, mut body| {
+        let stream = futures::stream::poll_fn(move |cx| {
+            let item = Pin::new(&mut body).poll_next(cx);
+            if let Poll::Ready(Some(Ok(chunk))) = &item {
+                let finishes = lines
+                    .push(chunk)
+                    .lines()
+                    .filter_map(|line| line.strip_prefix("d:"))
+                    .filter_map(|payload| serde_json::from_str::<Value>(payload).ok())
+                    .collect::<Vec<_>>();
+                for finish in finishes {
+                    let [prompt_tokens, completion_tokens] =
+                        ["promptTokens", "completionTokens"].map(|field| finish["usage"][field].as_u64().unwrap_or(0));
+                    usage::record_usage(provider.name(), &model, session_id.as_deref(), prompt_tokens, completion_tokens);
+                    let tokens = prompt_tokens + completion_tokens;
+                    if let Some(session_id) = session_id.as_ref().filter(|_| tokens > 0) {
+                        *SESSION_USAGE.lock().unwrap().get_or_default(session_id) += tokens;
+                    }
+                }
+            }
+            item
+        });
+        BoxBody::new(BodyStream::new(stream))
+    })
+}
@@ -0,0 +1,59 @@
+//! The tell backend: an AI SDK compatible chat proxy in front of several LLM providers. The
+//! binary in main.rs only reads the startup configuration and runs the server; everything it
+//! serves lives here, so the pieces can be tested directly.
+
+use std::env;
+
+pub mod admin;
+pub mod batch;
+pub mod cancel;
+pub mod chat;
+pub mod client_ip;
+pub mod compare;
+pub mod config;
+pub mod conversations;
+pub mod convert;
+pub mod error;
+pub mod guards;
+pub mod messages;
+pub mod metrics;
+pub mod mock;
+pub mod output;
+pub mod pipeline;
+pub mod providers;
+pub mod request;
+pub mod resume;
+#[cfg(feature = "router")]
+pub mod router;
+pub mod routes;
+pub mod session;
+pub mod slots;
+pub mod stream;
+#[cfg(feature = "test-stub")]
+pub mod stub;
+pub mod templates;
+pub mod tokens;
+pub mod tools;
+pub mod transform;
+pub mod trim;
+pub mod usage;
+pub mod validate;
+pub mod ws;
+
+// Per-chunk stream logging (raw provider chunks, parsed events, converted frames) is expensive
+// and floods the logs, so it is off unless LOG_STREAM_CHUNKS=true
+lazy_static::lazy_static! {
+    static ref LOG_STREAM_CHUNKS: bool = env::var("LOG_STREAM_CHUNKS")
+        .map(|v| v.eq_ignore_ascii_case("true") || v == "1")
+        .unwrap_or(false);
+}
+
+// info! for per-chunk messages; the arguments aren't evaluated when the flag is off
+macro_rules! chunk_info {
+    ($($arg:tt)*) => {
+        if *$crate::LOG_STREAM_CHUNKS {
+            log::info!($($arg)*);
+        }
+    };
+}
+pub(crate) use chunk_info;
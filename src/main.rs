@@ -1,17 +1,26 @@
 use std::env;
+use std::sync::Arc;
+use std::time::Instant;
 
 use actix_cors::Cors;
-use actix_web::{middleware::Logger, web, App, Error, HttpResponse, HttpServer, Responder};
+use actix_web::{middleware::Logger, web, App, HttpServer};
 use actix_web_prom::PrometheusMetricsBuilder;
-
-use serde::{Deserialize, Serialize};
-use serde_json::{json, Value};
-
-use reqwest::Client;
-use tokio_stream::StreamExt;
-
-use bytes::Bytes;
-use log::{error, info};
+use arc_swap::ArcSwap;
+use log::{error, info, warn};
+
+use backend::client_ip::client_ip;
+use backend::config::{CorsSettings, ServerSettings, TellConfig, CORS_EXPOSED_HEADERS};
+use backend::conversations::open_conversation_store;
+use backend::messages::load_system_prompt_prefix;
+use backend::metrics::custom_metrics;
+use backend::mock::{load_mock_scenarios, mock_provider_enabled};
+use backend::providers::{load_provider_headers, warm_up_providers};
+#[cfg(feature = "router")]
+use backend::router::PromptRouter;
+use backend::routes::{self, OptionalEndpoints, STARTED_AT};
+use backend::templates::{load_templates, TEMPLATES};
+use backend::tools::{load_tools, TOOLS};
+use backend::transform::load_transforms;
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
@@ -19,16 +28,120 @@ async fn main() -> std::io::Result<()> {
     dotenv::dotenv().ok();
 
     env_logger::init();
+    let _ = STARTED_AT.set(Instant::now());
 
-    // metrics
+    // metrics, served by the middleware itself at /metrics
     let prometheus = PrometheusMetricsBuilder::new("api")
         .endpoint("/metrics")
         .build()
-        .unwrap();
+        .map_err(|e| {
+            error!("Failed to set up metrics: {}", e);
+            std::io::Error::other(e.to_string())
+        })?;
+    for metric in custom_metrics() {
+        if let Err(e) = prometheus.registry.register(metric) {
+            error!("Failed to register custom metric: {}", e);
+        }
+    }
+
+    // Per-provider/per-model request overrides; a broken config file stops startup
+    let config = TellConfig::from_env().map_err(|e| {
+        error!("Invalid TELL_CONFIG: {}", e);
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, e)
+    })?;
+    // Swappable, so POST /admin/reload can replace it under running requests
+    let config = web::Data::new(ArcSwap::from_pointee(config));
+    // Tool definitions offered to the models; a broken tools file stops startup too
+    let tools = load_tools().map_err(|e| {
+        error!("Invalid TOOLS_FILE: {}", e);
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, e)
+    })?;
+    TOOLS.store(Arc::new(tools));
+    // Prompt templates; a broken template file stops startup too
+    let templates = load_templates().map_err(|e| {
+        error!("Invalid TEMPLATES_DIR: {}", e);
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, e)
+    })?;
+    TEMPLATES.store(Arc::new(templates));
+    // Canned responses for mock/ models and MOCK_PROVIDER=true; a broken script stops startup
+    load_mock_scenarios().map_err(|e| {
+        error!("Invalid MOCK_SCRIPT: {}", e);
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, e)
+    })?;
+    // Server-side system prompt prefix; an unreadable SYSTEM_PROMPT_PREFIX_FILE stops startup
+    load_system_prompt_prefix().map_err(|e| {
+        error!("Invalid SYSTEM_PROMPT_PREFIX_FILE: {}", e);
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, e)
+    })?;
+    // An ANTHROPIC_VERSION that isn't a version, or OpenAI scope values that can't be sent as
+    // headers, stop startup too
+    load_provider_headers().map_err(|e| {
+        error!("Invalid provider headers: {}", e);
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, e)
+    })?;
+    // Unknown STREAM_TRANSFORMS names or invalid REDACT_PATTERNS stop startup too
+    load_transforms().map_err(|e| {
+        error!("Invalid STREAM_TRANSFORMS: {}", e);
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, e)
+    })?;
+    if mock_provider_enabled() {
+        warn!("MOCK_PROVIDER is enabled: every chat request gets a canned response, no provider is called");
+    }
+    // Optional prompt classifier behind /route, loaded once if ROUTER_MODEL is set
+    #[cfg(feature = "router")]
+    let router = PromptRouter::from_env()
+        .map_err(|e| {
+            error!("Invalid ROUTER_MODEL: {}", e);
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, e)
+        })?
+        .map(web::Data::new);
+    #[cfg(not(feature = "router"))]
+    if env::var("ROUTER_MODEL").is_ok() {
+        let message = "ROUTER_MODEL is set, but this build doesn't include the router feature (build with --features router)";
+        error!("{}", message);
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, message));
+    }
 
-    HttpServer::new(move || {
+    // CORS methods and headers; a bad CORS_ALLOWED_METHODS/CORS_ALLOWED_HEADERS stops startup
+    let cors = CorsSettings::from_env().map_err(|e| {
+        error!("Invalid CORS settings: {}", e);
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, e)
+    })?;
+    // Keep-alive, request timeout, workers and h2c; a bad value stops startup
+    let server_settings = ServerSettings::from_env().map_err(|e| {
+        error!("Invalid server settings: {}", e);
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, e)
+    })?;
+
+    // Developer-only endpoints, off unless DEBUG_ENDPOINTS=true
+    let debug_endpoints = env::var("DEBUG_ENDPOINTS")
+        .map(|v| v.eq_ignore_ascii_case("true") || v == "1")
+        .unwrap_or(false);
+    if debug_endpoints {
+        warn!("DEBUG_ENDPOINTS is enabled; don't use this in production");
+    }
+    let optional_endpoints = OptionalEndpoints {
+        // Admin endpoints only exist when ADMIN_TOKEN is set
+        admin: env::var("ADMIN_TOKEN").is_ok_and(|token| !token.is_empty()),
+        debug: debug_endpoints,
+        #[cfg(feature = "router")]
+        router,
+    };
+    // Open the conversation store (if PERSIST is set) now rather than on the first request
+    open_conversation_store();
+    // Prime the upstream connection pool in the background; startup doesn't wait for it
+    if env::var("WARMUP").map(|v| v.eq_ignore_ascii_case("true") || v == "1").unwrap_or(false) {
+        actix_web::rt::spawn(warm_up_providers());
+    }
+
+    let server = HttpServer::new(move || {
         App::new()
-            .wrap(Logger::default())
+            .app_data(config.clone())
+            // Logger::default()'s format, with the client address resolved through trusted proxies
+            .wrap(
+                Logger::new(r#"%{client_ip}xi "%r" %s %b "%{Referer}i" "%{User-Agent}i" %T"#)
+                    .custom_request_replace("client_ip", |req| client_ip(req.peer_addr(), req.headers())),
+            )
             .wrap(prometheus.clone())
             .wrap(
                 Cors::default()
@@ -41,740 +154,26 @@ async fn main() -> std::io::Result<()> {
                         origin.as_bytes().ends_with(b".azurecontainerapps.io") ||
                         origin.as_bytes().starts_with(b"http://localhost")
                     })
-                    .allowed_methods(vec!["GET", "POST", "OPTIONS"])
-                    .allowed_headers(vec![
-                        actix_web::http::header::CONTENT_TYPE,
-                        actix_web::http::header::AUTHORIZATION,
-                        actix_web::http::header::ORIGIN,
-                    ])
-                    .expose_headers(vec![actix_web::http::header::CONTENT_TYPE])
+                    .allowed_methods(cors.methods.clone())
+                    .allowed_headers(cors.headers.clone())
+                    .expose_headers(CORS_EXPOSED_HEADERS.iter().copied())
                     .supports_credentials()
                     .max_age(3600),
             )
-            .route("/", web::get().to(health_check))
-            .route("/health", web::get().to(health_check))
-            .route(
-                "/metrics",
-                web::get().to(|| async { HttpResponse::Ok().finish() }),
-            )
-            .route("/sdk-chat", web::post().to(sdk_chat))
-            .default_service(web::route().to(not_found))
+            .configure(|cfg| routes::configure(cfg, &optional_endpoints))
     })
-    .bind("0.0.0.0:3010")?
-    .run()
-    .await
-}
-
-async fn health_check() -> impl Responder {
-    HttpResponse::Ok().body("healthy")
-}
-
-async fn not_found() -> impl Responder {
-    HttpResponse::NotFound().body("Not found")
-}
-
-#[derive(Debug, Deserialize)]
-struct ChatMessage {
-    role: String,
-    #[serde(default)]
-    content: Option<String>,
-    // AI SDK v5 includes tool calls and results in messages
-    #[serde(default, rename = "tool_calls")]
-    tool_calls: Option<Vec<serde_json::Value>>,
-    #[serde(default, rename = "tool_call_id")]
-    tool_call_id: Option<String>,
-    #[serde(default)]
-    name: Option<String>,
-    // AI SDK v5 also includes tool invocations (results) in assistant messages
-    #[serde(default, rename = "toolInvocations")]
-    tool_invocations: Option<Vec<serde_json::Value>>,
-}
-
-#[derive(Debug, Deserialize)]
-struct ChatRequest {
-    messages: Vec<ChatMessage>,
-    #[serde(default = "default_model")]
-    model: String,
-    #[serde(default = "default_temperature")]
-    temperature: f32,
-    #[serde(default, rename = "maxSteps")]
-    max_steps: Option<u32>,
-}
-
-fn default_model() -> String {
-    "claude-3-5-sonnet-20241022".to_string()
-}
-
-fn default_temperature() -> f32 {
-    0.2
-}
-
-#[derive(Debug, Serialize)]
-struct ToolInputSchema {
-    #[serde(rename = "type")]
-    schema_type: String,
-    properties: serde_json::Map<String, Value>,
-    required: Vec<String>,
-}
-
-#[derive(Debug, Serialize)]
-struct Tool {
-    name: String,
-    description: String,
-    input_schema: ToolInputSchema,
-}
-
-fn create_tools() -> Vec<Tool> {
-    let mut execute_sql_properties = serde_json::Map::new();
-    execute_sql_properties.insert(
-        "sql".to_string(),
-        json!({
-            "type": "string",
-            "description": "The complete DuckDB-compatible SQL query. CRITICAL: Use proper SQL syntax only - no English phrases! Use: = (not 'equals'), < (not 'less than'), > (not 'greater than'), BETWEEN x AND y (not 'IS BETWEEN' or 'is around'), LIKE '%pattern%' (not 'contains'), IS NULL/IS NOT NULL only. Example: WHERE age BETWEEN 20 AND 30 (correct), NOT WHERE age IS BETWEEN 20 AND 30 (wrong)"
-        })
-    );
-
-    let mut add_transformation_properties = serde_json::Map::new();
-    add_transformation_properties.insert(
-        "sql".to_string(),
-        json!({
-            "type": "string",
-            "description": "The SQL query for the transformation. Use 'previous_step' to reference the output of the last transformation, or reference other transformation outputs by their alias names."
-        })
-    );
-    add_transformation_properties.insert(
-        "outputAlias".to_string(),
-        json!({
-            "type": "string",
-            "description": "A meaningful name for this transformation step using underscores (e.g., 'filtered_data', 'high_value_orders', 'aggregated_results')"
-        })
-    );
-
-    let mut create_visualization_properties = serde_json::Map::new();
-    create_visualization_properties.insert(
-        "type".to_string(),
-        json!({
-            "type": "string",
-            "description": "The type of chart to create: 'bar', 'line', 'scatter', 'pie', 'area', or 'heatmap'. IMPORTANT: Different chart types require different data structures - bar/pie charts need aggregated/grouped data, while scatter plots need raw x,y pairs."
-        })
-    );
-    create_visualization_properties.insert(
-        "title".to_string(),
-        json!({
-            "type": "string",
-            "description": "A descriptive title for the visualization"
-        })
-    );
-    create_visualization_properties.insert(
-        "xAxis".to_string(),
-        json!({
-            "type": "string",
-            "description": "The column name to use for the x-axis (or category column for pie charts)"
-        })
-    );
-    create_visualization_properties.insert(
-        "yAxis".to_string(),
-        json!({
-            "type": "string",
-            "description": "The column name to use for the y-axis (or value column for pie charts). For bar/pie charts, this should typically be an aggregated value (COUNT, SUM, AVG, etc.)"
-        })
-    );
-    create_visualization_properties.insert(
-        "sql".to_string(),
-        json!({
-            "type": "string",
-            "description": "Optional custom SQL query to fetch chart-specific data. CRITICAL: Provide aggregated SQL for bar/pie charts! Examples: Bar chart: 'SELECT category, COUNT(*) as count FROM table GROUP BY category LIMIT 20', Pie chart: 'SELECT region, SUM(sales) as total FROM table GROUP BY region', Line chart: 'SELECT date, AVG(value) as avg_value FROM table GROUP BY date ORDER BY date', Scatter: 'SELECT x_col, y_col FROM table LIMIT 1000'. If not provided, a basic query will be generated based on chart type."
-        })
-    );
-    create_visualization_properties.insert(
-        "description".to_string(),
-        json!({
-            "type": "string",
-            "description": "Optional description explaining what the visualization shows"
-        })
-    );
-
-    vec![
-        Tool {
-            name: "executeSQL".to_string(),
-            description: "Run a SQL query for immediate results without adding it to the transformation pipeline. Use for exploratory queries, data inspection, or when users want to see results right away.".to_string(),
-            input_schema: ToolInputSchema {
-                schema_type: "object".to_string(),
-                properties: execute_sql_properties,
-                required: vec!["sql".to_string()],
-            },
-        },
-        Tool {
-            name: "addTransformation".to_string(),
-            description: "Add a SQL transformation step to the data pipeline. Use when users want to filter, transform, or process data as part of their workflow.".to_string(),
-            input_schema: ToolInputSchema {
-                schema_type: "object".to_string(),
-                properties: add_transformation_properties,
-                required: vec!["sql".to_string(), "outputAlias".to_string()],
-            },
-        },
-        Tool {
-            name: "createVisualization".to_string(),
-            description: "Create a data visualization (chart) from query results. Use when users ask to visualize, chart, graph, or plot data. Supports bar charts, line charts, scatter plots, pie charts, area charts, and heatmaps.".to_string(),
-            input_schema: ToolInputSchema {
-                schema_type: "object".to_string(),
-                properties: create_visualization_properties,
-                required: vec!["type".to_string(), "xAxis".to_string(), "yAxis".to_string()],
-            },
-        },
-    ]
-}
-
-async fn sdk_chat(body: web::Bytes) -> Result<HttpResponse, Error> {
-    info!("Raw request body: {}", String::from_utf8_lossy(&body));
-
-    let request: ChatRequest = serde_json::from_slice(&body)
-        .map_err(|e| actix_web::error::ErrorBadRequest(format!("Invalid JSON: {}", e)))?;
-
-    info!("Parsed request: model={}, messages={}, temperature={}, max_steps={:?}",
-          request.model, request.messages.len(), request.temperature, request.max_steps);
-
-    // Determine provider based on model name
-    let is_claude = request.model.to_lowercase().starts_with("claude");
-
-    if is_claude {
-        handle_anthropic_request(request).await
-    } else {
-        handle_openai_request(request).await
-    }
-}
-
-async fn handle_anthropic_request(request: ChatRequest) -> Result<HttpResponse, Error> {
-    // Mock response disabled - using actual API
-
-    let api_key = env::var("ANTHROPIC_API_KEY")
-        .map_err(|_| actix_web::error::ErrorInternalServerError("ANTHROPIC_API_KEY not set"))?;
-
-    let client = Client::new();
-    let tools = create_tools();
-
-    // Convert messages to Anthropic format
-    // AI SDK v5 sends tool results embedded in assistant messages with toolInvocations
-    // We need to convert these appropriately for each provider
-    let messages: Vec<Value> = request
-        .messages
-        .into_iter()
-        .flat_map(|msg| {
-            let mut result_messages = Vec::new();
-
-            // First, add the main message (user or assistant)
-            let mut message = json!({
-                "role": msg.role,
-            });
-
-            // Add content if present
-            if let Some(content) = msg.content {
-                message["content"] = json!(content);
-            }
-
-            // Add tool_calls if present (assistant messages with tool calls)
-            if let Some(tool_calls) = msg.tool_calls {
-                message["tool_calls"] = json!(tool_calls);
-            }
-
-            // Add tool_call_id if present (tool result messages - legacy format)
-            if let Some(tool_call_id) = msg.tool_call_id {
-                message["tool_call_id"] = json!(tool_call_id);
-            }
-
-            // Add name if present (for tool results, name = tool name)
-            if let Some(name) = msg.name {
-                message["name"] = json!(name);
-            }
-
-            result_messages.push(message);
-
-            // If this is an assistant message with toolInvocations (AI SDK v5 format),
-            // we need to handle them appropriately for Anthropic
-            if let Some(ref tool_invocations) = msg.tool_invocations {
-                // First, reconstruct tool_calls for the assistant message
-                let tool_calls: Vec<Value> = tool_invocations.iter().map(|invocation| {
-                    let tool_call_id = invocation.get("toolCallId").and_then(|v| v.as_str()).unwrap_or("");
-                    let tool_name = invocation.get("toolName").and_then(|v| v.as_str()).unwrap_or("");
-                    let args = invocation.get("args").cloned().unwrap_or(json!({}));
-
-                    json!({
-                        "id": tool_call_id,
-                        "type": "function",
-                        "function": {
-                            "name": tool_name,
-                            "arguments": serde_json::to_string(&args).unwrap_or_else(|_| "{}".to_string())
-                        }
-                    })
-                }).collect();
-
-                // Update the assistant message to include tool_calls
-                if !tool_calls.is_empty() {
-                    result_messages[0]["tool_calls"] = json!(tool_calls);
-                }
-
-                // Then add tool result messages (Anthropic uses user role for tool results)
-                for invocation in tool_invocations {
-                    if let Some(_tool_call_id) = invocation.get("toolCallId").and_then(|v| v.as_str()) {
-                        if let Some(result) = invocation.get("result") {
-                            // Anthropic format for tool results
-                            let tool_result_message = json!({
-                                "role": "user", // Anthropic treats tool results as user messages
-                                "content": serde_json::to_string(&result).unwrap_or_else(|_| "{}".to_string())
-                            });
-                            result_messages.push(tool_result_message);
-                        }
-                    }
-                }
-            }
-
-            result_messages
-        })
-        .collect();
-
-    let mut request_body = json!({
-        "model": request.model,
-        "messages": messages,
-        // "temperature": request.temperature,
-        "stream": true,
-        "max_tokens": 4096
-    });
-
-    // Add tools if any
-    if !tools.is_empty() {
-        request_body["tools"] = json!(tools);
-        info!("Added {} tools to Anthropic request", tools.len());
-        info!("Tools: {}", serde_json::to_string_pretty(&tools).unwrap_or_default());
-        if let Some(max_steps) = request.max_steps {
-            request_body["max_tokens"] = json!(max_steps * 1000); // Rough estimation
-        }
-    }
-
-    info!("Sending request to Anthropic: {}", serde_json::to_string_pretty(&request_body).unwrap_or_default());
-
-    let response = client
-        .post("https://api.anthropic.com/v1/messages")
-        .header("Content-Type", "application/json")
-        .header("Authorization", format!("Bearer {}", api_key))
-        .header("Anthropic-Version", "2023-06-01")
-        .json(&request_body)
-        .send()
-        .await
-        .map_err(|e| {
-            error!("Failed to call Anthropic API: {}", e);
-            actix_web::error::ErrorBadGateway(format!("Anthropic API error: {}", e))
-        })?;
-
-    let status = response.status();
-    if !status.is_success() {
-        let error_text = response.text().await.unwrap_or_default();
-        error!("Anthropic API error {}: {}", status, error_text);
-        return Err(actix_web::error::ErrorBadGateway(format!(
-            "Anthropic API error: {}",
-            status
-        )));
-    }
-
-    // Convert Anthropic streaming response to AI SDK format
-    let stream = response.bytes_stream();
-    let ai_sdk_stream = stream.map(|chunk_result| {
-        match chunk_result {
-            Ok(chunk) => {
-                // Parse Anthropic SSE format and convert to AI SDK format
-                let chunk_str = String::from_utf8_lossy(&chunk);
-                info!("Anthropic raw chunk: {}", chunk_str);
-                let converted = convert_anthropic_to_ai_sdk(&chunk_str);
-                if !converted.is_empty() {
-                    info!("Converted to AI SDK: {}", converted);
-                }
-                Ok::<Bytes, reqwest::Error>(Bytes::from(converted))
-            }
-            Err(e) => {
-                let error_msg = format!(
-                    "data: {{\"type\":\"error\",\"error\":\"Stream error: {}\"}}\n\n",
-                    e
-                );
-                Ok(Bytes::from(error_msg))
-            }
-        }
-    });
-
-    Ok(HttpResponse::Ok()
-        .insert_header(("Content-Type", "text/event-stream"))
-        .insert_header(("Cache-Control", "no-cache"))
-        .insert_header(("Connection", "keep-alive"))
-        .insert_header(("Access-Control-Allow-Origin", "*"))
-        .streaming(ai_sdk_stream))
-}
-
-async fn handle_openai_request(request: ChatRequest) -> Result<HttpResponse, Error> {
-    // Check if Azure OpenAI is configured (takes priority)
-    let use_azure = env::var("AZURE_OPENAI_ENDPOINT").is_ok();
-
-    let (api_endpoint, api_key, auth_header) = if use_azure {
-        let endpoint = env::var("AZURE_OPENAI_ENDPOINT")
-            .map_err(|_| actix_web::error::ErrorInternalServerError("AZURE_OPENAI_ENDPOINT not set"))?;
-        let key = env::var("AZURE_OPENAI_KEY")
-            .map_err(|_| actix_web::error::ErrorInternalServerError("AZURE_OPENAI_KEY not set"))?;
-        let deployment = env::var("AZURE_OPENAI_DEPLOYMENT")
-            .unwrap_or_else(|_| "gpt-4o".to_string()); // Default deployment name
-
-        let url = format!("{}/openai/deployments/{}/chat/completions?api-version=2024-08-01-preview",
-            endpoint.trim_end_matches('/'), deployment);
-        info!("Using Azure OpenAI endpoint: {}", url);
-        (url, key, "api-key")
-    } else {
-        let key = env::var("OPENAI_API_KEY")
-            .map_err(|_| actix_web::error::ErrorInternalServerError("OPENAI_API_KEY not set"))?;
-        ("https://api.openai.com/v1/chat/completions".to_string(), key, "Authorization")
+    .keep_alive(server_settings.keep_alive)
+    .client_request_timeout(server_settings.client_request_timeout);
+    let server = match server_settings.workers {
+        Some(workers) => server.workers(workers),
+        None => server,
     };
-
-    let client = Client::new();
-    let tools = create_tools();
-
-    // Convert messages to OpenAI format
-    // AI SDK v5 sends tool results embedded in assistant messages with toolInvocations
-    // We need to convert these to OpenAI's format: separate "tool" role messages
-    let messages: Vec<Value> = request
-        .messages
-        .into_iter()
-        .flat_map(|msg| {
-            let mut result_messages = Vec::new();
-
-            // First, add the main message (user or assistant)
-            let mut message = json!({
-                "role": msg.role,
-            });
-
-            // Add content if present
-            if let Some(content) = msg.content {
-                message["content"] = json!(content);
-            }
-
-            // Add tool_calls if present (assistant messages with tool calls)
-            if let Some(tool_calls) = msg.tool_calls {
-                message["tool_calls"] = json!(tool_calls);
-            }
-
-            // Add tool_call_id if present (tool result messages - legacy format)
-            if let Some(tool_call_id) = msg.tool_call_id {
-                message["tool_call_id"] = json!(tool_call_id);
-            }
-
-            // Add name if present (for tool results, name = tool name)
-            if let Some(name) = msg.name {
-                message["name"] = json!(name);
-            }
-
-            result_messages.push(message);
-
-            // If this is an assistant message with toolInvocations (AI SDK v5 format),
-            // we need to:
-            // 1. Add the assistant message with tool_calls reconstructed from toolInvocations
-            // 2. Add separate "tool" role messages for each result
-            if let Some(ref tool_invocations) = msg.tool_invocations {
-                // First, reconstruct tool_calls for the assistant message
-                let tool_calls: Vec<Value> = tool_invocations.iter().map(|invocation| {
-                    let tool_call_id = invocation.get("toolCallId").and_then(|v| v.as_str()).unwrap_or("");
-                    let tool_name = invocation.get("toolName").and_then(|v| v.as_str()).unwrap_or("");
-                    let args = invocation.get("args").cloned().unwrap_or(json!({}));
-
-                    json!({
-                        "id": tool_call_id,
-                        "type": "function",
-                        "function": {
-                            "name": tool_name,
-                            "arguments": serde_json::to_string(&args).unwrap_or_else(|_| "{}".to_string())
-                        }
-                    })
-                }).collect();
-
-                // Update the assistant message to include tool_calls
-                if !tool_calls.is_empty() {
-                    result_messages[0]["tool_calls"] = json!(tool_calls);
-                }
-
-                // Then add tool result messages
-                for invocation in tool_invocations {
-                    if let Some(tool_call_id) = invocation.get("toolCallId").and_then(|v| v.as_str()) {
-                        if let Some(result) = invocation.get("result") {
-                            // OpenAI expects tool results as separate messages with role: "tool"
-                            let tool_result_message = json!({
-                                "role": "tool",
-                                "tool_call_id": tool_call_id,
-                                "content": serde_json::to_string(&result).unwrap_or_else(|_| "{}".to_string())
-                            });
-                            result_messages.push(tool_result_message);
-                        }
-                    }
-                }
-            }
-
-            result_messages
-        })
-        .collect();
-
-    let mut request_body = json!({
-        "messages": messages,
-        "stream": true
-    });
-
-    // Azure OpenAI doesn't need model in request body (it's in the URL path)
-    // But regular OpenAI does need it
-    if !use_azure {
-        request_body["model"] = json!(request.model);
-    }
-
-    // Only add temperature for models that support it
-    // o1, o3, and gpt-5 models don't support custom temperature
-    let is_o1_or_o3_model = request.model.starts_with("o1") || request.model.starts_with("o3");
-    let is_gpt5_model = request.model.starts_with("gpt-5");
-
-    // Only add temperature for models that support it
-    if !is_o1_or_o3_model && !is_gpt5_model && request.temperature != 0.0 {
-        request_body["temperature"] = json!(request.temperature);
-    }
-    // Don't send temperature parameter for o1, o3, or gpt-5 models at all
-
-    // Add tools if any (convert to OpenAI function format)
-    // o1 and o3 models don't support tools
-    if !tools.is_empty() && !is_o1_or_o3_model {
-        let openai_tools: Vec<Value> = tools
-            .into_iter()
-            .map(|tool| {
-                json!({
-                    "type": "function",
-                    "function": {
-                        "name": tool.name,
-                        "description": tool.description,
-                        "parameters": tool.input_schema
-                    }
-                })
-            })
-            .collect();
-        request_body["tools"] = json!(openai_tools);
-        info!("Added {} tools to OpenAI request", openai_tools.len());
-        info!("Tools: {}", serde_json::to_string_pretty(&openai_tools).unwrap_or_default());
-    }
-
-    info!("Sending request to {}: {}", if use_azure { "Azure OpenAI" } else { "OpenAI" },
-        serde_json::to_string_pretty(&request_body).unwrap_or_default());
-
-    let mut req = client
-        .post(&api_endpoint)
-        .header("Content-Type", "application/json");
-
-    // Set auth header based on provider
-    req = if use_azure {
-        req.header("api-key", &api_key)
+    let bind_addr = env::var("BIND_ADDR").unwrap_or_else(|_| "0.0.0.0:3010".to_string());
+    let server = if server_settings.http2_cleartext {
+        info!("Accepting HTTP/1.1 and cleartext HTTP/2 on {}", bind_addr);
+        server.bind_auto_h2c(bind_addr)?
     } else {
-        req.header("Authorization", format!("Bearer {}", api_key))
+        server.bind(bind_addr)?
     };
-
-    let response = req
-        .json(&request_body)
-        .send()
-        .await
-        .map_err(|e| {
-            error!("Failed to call OpenAI API: {}", e);
-            actix_web::error::ErrorBadGateway(format!("OpenAI API error: {}", e))
-        })?;
-
-    let status = response.status();
-    if !status.is_success() {
-        let error_text = response.text().await.unwrap_or_default();
-        error!("OpenAI API error {}: {}", status, error_text);
-        return Err(actix_web::error::ErrorBadGateway(format!(
-            "OpenAI API error: {}",
-            status
-        )));
-    }
-
-    // Convert OpenAI streaming response to AI SDK format
-    let stream = response.bytes_stream();
-    let ai_sdk_stream = stream.map(|chunk_result| {
-        match chunk_result {
-            Ok(chunk) => {
-                // Parse OpenAI SSE format and convert to AI SDK format
-                let chunk_str = String::from_utf8_lossy(&chunk);
-                info!("OpenAI raw chunk: {}", chunk_str);
-                let converted = convert_openai_to_ai_sdk(&chunk_str);
-                if !converted.is_empty() {
-                    info!("Converted to AI SDK: {}", converted);
-                }
-                Ok::<Bytes, reqwest::Error>(Bytes::from(converted))
-            }
-            Err(e) => {
-                let error_msg = format!(
-                    "data: {{\"type\":\"error\",\"error\":\"Stream error: {}\"}}\n\n",
-                    e
-                );
-                Ok(Bytes::from(error_msg))
-            }
-        }
-    });
-
-    Ok(HttpResponse::Ok()
-        .insert_header(("Content-Type", "text/event-stream"))
-        .insert_header(("Cache-Control", "no-cache"))
-        .insert_header(("Connection", "keep-alive"))
-        .insert_header(("Access-Control-Allow-Origin", "*"))
-        .streaming(ai_sdk_stream))
-}
-
-fn convert_anthropic_to_ai_sdk(chunk: &str) -> String {
-    // Convert Anthropic streaming format to AI SDK v5 format
-    let mut result = String::new();
-
-    for line in chunk.lines() {
-        if line.starts_with("data: ") {
-            let data_part = &line[6..];
-            if data_part == "[DONE]" {
-                // No special end marker needed in AI SDK v5
-                continue;
-            }
-
-            if let Ok(parsed) = serde_json::from_str::<Value>(data_part) {
-                info!("Anthropic parsed data: {}", serde_json::to_string(&parsed).unwrap_or_default());
-                // Convert Anthropic delta format to AI SDK v5 format
-                if let Some(event_type) = parsed.get("type").and_then(|t| t.as_str()) {
-                    match event_type {
-                        "content_block_delta" => {
-                            if let Some(delta) = parsed.get("delta") {
-                                if let Some(text) = delta.get("text").and_then(|t| t.as_str()) {
-                                    info!("Anthropic text delta: {}", text);
-                                    // AI SDK v5 format: 0:"text content"
-                                    result.push_str(&format!(
-                                        "0:{}\n",
-                                        serde_json::to_string(text).unwrap_or_default()
-                                    ));
-                                }
-                            }
-                        }
-                        "message_stop" => {
-                            // No special end marker needed in AI SDK v5
-                        }
-                        _ => {
-                            // Skip other events for now
-                        }
-                    }
-                }
-            }
-        }
-    }
-
-    result
-}
-
-// Store tool call accumulator state
-use std::collections::HashMap;
-use std::sync::Mutex;
-
-lazy_static::lazy_static! {
-    static ref TOOL_CALLS: Mutex<HashMap<String, ToolCallAccumulator>> = Mutex::new(HashMap::new());
-}
-
-#[derive(Debug, Clone)]
-struct ToolCallAccumulator {
-    id: String,
-    name: String,
-    arguments: String,
-}
-
-fn convert_openai_to_ai_sdk(chunk: &str) -> String {
-    // Convert OpenAI streaming format to AI SDK v5 format
-    let mut result = String::new();
-
-    for line in chunk.lines() {
-        if line.starts_with("data: ") {
-            let data_part = &line[6..];
-            if data_part == "[DONE]" {
-                // Send accumulated tool calls when done
-                let mut tool_calls = TOOL_CALLS.lock().unwrap();
-                for (_, tool_call) in tool_calls.drain() {
-                    // Parse the complete arguments
-                    let args = serde_json::from_str::<Value>(&tool_call.arguments)
-                        .unwrap_or_else(|_| json!({}));
-
-                    info!("Sending tool call: id={}, name={}, args={}",
-                          tool_call.id, tool_call.name, tool_call.arguments);
-
-                    // Send complete tool call in AI SDK format
-                    result.push_str(&format!(
-                        "9:{}\n",
-                        serde_json::to_string(&json!({
-                            "toolCallId": tool_call.id,
-                            "toolName": tool_call.name,
-                            "args": args
-                        })).unwrap_or_default()
-                    ));
-                }
-                continue;
-            }
-
-            if let Ok(parsed) = serde_json::from_str::<Value>(data_part) {
-                info!("OpenAI parsed data: {}", serde_json::to_string(&parsed).unwrap_or_default());
-                // Convert OpenAI delta format to AI SDK v5 format
-                if let Some(choices) = parsed.get("choices").and_then(|c| c.as_array()) {
-                    if let Some(choice) = choices.first() {
-                        if let Some(delta) = choice.get("delta") {
-                            // Handle text content
-                            if let Some(content) = delta.get("content").and_then(|c| c.as_str()) {
-                                // AI SDK v5 format: 0:"text content"
-                                result.push_str(&format!(
-                                    "0:{}\n",
-                                    serde_json::to_string(content).unwrap_or_default()
-                                ));
-                            }
-
-                            // Handle tool calls
-                            if let Some(tool_calls) = delta.get("tool_calls").and_then(|t| t.as_array()) {
-                                info!("Found tool_calls in delta: {:?}", tool_calls);
-                                let mut tc_map = TOOL_CALLS.lock().unwrap();
-
-                                for tool_call in tool_calls {
-                                    let index = tool_call.get("index")
-                                        .and_then(|i| i.as_u64())
-                                        .unwrap_or(0);
-                                    let key = format!("tc_{}", index);
-
-                                    // First chunk has id, type and function name
-                                    if let Some(id) = tool_call.get("id").and_then(|i| i.as_str()) {
-                                        if let Some(function) = tool_call.get("function") {
-                                            let name = function.get("name")
-                                                .and_then(|n| n.as_str())
-                                                .unwrap_or("");
-                                            let arguments = function.get("arguments")
-                                                .and_then(|a| a.as_str())
-                                                .unwrap_or("");
-
-                                            info!("Tool call init: id={}, name={}, args_start={}",
-                                                  id, name, arguments);
-
-                                            tc_map.insert(key.clone(), ToolCallAccumulator {
-                                                id: id.to_string(),
-                                                name: name.to_string(),
-                                                arguments: arguments.to_string(),
-                                            });
-                                        }
-                                    } else if let Some(function) = tool_call.get("function") {
-                                        // Subsequent chunks only have incremental arguments
-                                        if let Some(arguments) = function.get("arguments").and_then(|a| a.as_str()) {
-                                            if let Some(tc) = tc_map.get_mut(&key) {
-                                                tc.arguments.push_str(arguments);
-                                                info!("Tool call append: key={}, args_chunk={}",
-                                                      key, arguments);
-                                            }
-                                        }
-                                    }
-                                }
-                            }
-                        }
-                    }
-                }
-            }
-        }
-    }
-
-    result
+    server.run().await
 }
@@ -0,0 +1,278 @@
+//! Checks and rewrites of the messages before they are sent: roles, lengths, images and the
+//! server system prompt
+
+use std::env;
+
+use actix_web::HttpRequest;
+use log::info;
+
+use crate::error::ApiError;
+use crate::providers::openai::model_capabilities;
+use crate::providers::{Provider, OPENAI};
+use crate::request::{ChatMessage, ChatRequest, ResponseFormat};
+use crate::tools::{create_tools, ToolChoice};
+
+// Provider-specific checks and rewrites of the messages, before a request is built for `provider`
+pub fn prepare_messages(provider: Provider, request: &mut ChatRequest) -> Result<(), ApiError> {
+    normalize_roles(provider, &mut request.messages)?;
+    enforce_message_length(provider, &mut request.messages)?;
+    validate_image_inputs(provider, &request.model, &request.messages)?;
+    validate_tool_choice(provider, request)?;
+    validate_response_format(provider, request)
+}
+
+// Images only go to models that can see them, and each provider has limits on how they're sent
+fn validate_image_inputs(provider: Provider, model: &str, messages: &[ChatMessage]) -> Result<(), ApiError> {
+    for (index, message) in messages.iter().enumerate() {
+        for image in message.images() {
+            if !is_vision_model(model) {
+                return Err(ApiError::bad_request(format!(
+                    "Model '{}' does not accept image inputs (message {} has an image attachment)",
+                    model, index
+                )));
+            }
+            let is_remote = image.url.starts_with("https://") || image.url.starts_with("http://");
+            if image.base64_data().is_none() && !is_remote {
+                return Err(ApiError::bad_request(format!(
+                    "Image attachment in message {} must be a base64 data: URL or an http(s) URL",
+                    index
+                )));
+            }
+            if is_remote && provider == Provider::Bedrock {
+                return Err(ApiError::bad_request(format!(
+                    "Bedrock only accepts inline images; send the image in message {} as a base64 data: URL",
+                    index
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn is_vision_model(model: &str) -> bool {
+    // Drop "<provider>/" prefixes and Bedrock's "us.anthropic." style prefixes
+    let model = model.to_lowercase();
+    let model = model.rsplit('/').next().unwrap_or_default();
+    let model = model.split_once("anthropic.").map_or(model, |(_, rest)| rest);
+
+    const TEXT_ONLY: &[&str] = &["claude-3-5-haiku", "gpt-3.5", "gpt-4-0", "o1-mini", "o1-preview", "o3-mini"];
+    const VISION: &[&str] = &[
+        "claude-3", "claude-sonnet-4", "claude-opus-4", "claude-haiku-4", "gpt-4o", "chatgpt-4o",
+        "gpt-4-turbo", "gpt-4.1", "gpt-4.5", "gpt-5", "o1", "o3", "o4", "pixtral", "mistral-medium",
+        "mistral-small",
+    ];
+    !TEXT_ONLY.iter().any(|prefix| model.starts_with(prefix))
+        && VISION.iter().any(|prefix| model.starts_with(prefix))
+}
+
+// OpenAI rejects json_object mode unless a message mentions JSON, so fail early with a clear 400
+fn validate_response_format(provider: Provider, request: &ChatRequest) -> Result<(), ApiError> {
+    if !matches!(provider, Provider::OpenAI(_)) || request.response_format != Some(ResponseFormat::JsonObject) {
+        return Ok(());
+    }
+    let mentions_json = request
+        .messages
+        .iter()
+        .filter_map(|message| message.content.as_deref())
+        .any(|content| content.to_lowercase().contains("json"));
+    if mentions_json {
+        Ok(())
+    } else {
+        Err(ApiError::bad_request(
+            "responseFormat json_object requires a message that asks for JSON",
+        ))
+    }
+}
+
+// A toolChoice that forces a tool call needs that tool to be sent to the model
+fn validate_tool_choice(provider: Provider, request: &ChatRequest) -> Result<(), ApiError> {
+    let Some(tool_choice) = request.tool_choice.as_ref().filter(|choice| choice.forces_tool_call()) else {
+        return Ok(());
+    };
+    let tools = create_tools();
+    let sends_tools = match provider {
+        Provider::Cohere => false,
+        Provider::OpenAI(compatible) => model_capabilities(compatible.upstream_model(&request.model)).supports_tools,
+        Provider::Anthropic | Provider::Bedrock | Provider::Mistral => !tools.is_empty(),
+    };
+    if !sends_tools {
+        return Err(ApiError::bad_request(format!(
+            "toolChoice {} needs tools, and {} isn't sent any",
+            serde_json::to_string(tool_choice).unwrap_or_default(),
+            request.model
+        )));
+    }
+    match tool_choice {
+        ToolChoice::Tool { name } if !tools.iter().any(|tool| tool.name == *name) => {
+            let names: Vec<&str> = tools.iter().map(|tool| tool.name.as_str()).collect();
+            Err(ApiError::bad_request(format!(
+                "toolChoice names unknown tool '{}'. Available tools: {}",
+                name,
+                names.join(", ")
+            )))
+        }
+        _ => Ok(()),
+    }
+}
+
+// Anthropic has no JSON mode: ask for JSON with an extra system message instead
+pub fn apply_json_instruction(request: &mut ChatRequest) {
+    if let Some(instruction) = request.response_format.as_ref().and_then(ResponseFormat::json_instruction) {
+        request.messages.push(ChatMessage {
+            role: "system".to_string(),
+            content: Some(instruction),
+            ..Default::default()
+        });
+    }
+}
+
+// Base system prompt (guardrails, formatting rules) from SYSTEM_PROMPT_PREFIX, or the file at
+// SYSTEM_PROMPT_PREFIX_FILE. Unlike SYSTEM_PROMPT it can't be bypassed.
+static SYSTEM_PROMPT_PREFIX: std::sync::OnceLock<Option<String>> = std::sync::OnceLock::new();
+
+// Read the prefix at startup, so an unreadable SYSTEM_PROMPT_PREFIX_FILE stops the server
+pub fn load_system_prompt_prefix() -> Result<(), String> {
+    let _ = SYSTEM_PROMPT_PREFIX.set(read_system_prompt_prefix()?);
+    Ok(())
+}
+
+fn read_system_prompt_prefix() -> Result<Option<String>, String> {
+    let prefix = match (env::var("SYSTEM_PROMPT_PREFIX"), env::var("SYSTEM_PROMPT_PREFIX_FILE")) {
+        (Ok(prefix), _) => prefix,
+        (Err(_), Ok(path)) => std::fs::read_to_string(&path).map_err(|e| format!("can't read {}: {}", path, e))?,
+        (Err(_), Err(_)) => return Ok(None),
+    };
+    Ok(Some(prefix.trim().to_string()).filter(|prefix| !prefix.is_empty()))
+}
+
+// Prepend the server-enforced system prompts: SYSTEM_PROMPT_PREFIX first, then SYSTEM_PROMPT,
+// then the client's own system messages. Trusted callers may skip SYSTEM_PROMPT (but not the
+// prefix) with the X-Bypass-System-Prompt header, but only when ALLOW_SYSTEM_BYPASS=true.
+pub fn apply_server_system_prompt(req: &HttpRequest, messages: &mut Vec<ChatMessage>) {
+    apply_configured_system_prompt(req, messages);
+
+    let prefix = SYSTEM_PROMPT_PREFIX.get_or_init(|| read_system_prompt_prefix().unwrap_or_default());
+    if let Some(prefix) = prefix {
+        messages.insert(
+            0,
+            ChatMessage {
+                role: "system".to_string(),
+                content: Some(prefix.clone()),
+                ..Default::default()
+            },
+        );
+    }
+}
+
+fn apply_configured_system_prompt(req: &HttpRequest, messages: &mut Vec<ChatMessage>) {
+    let system_prompt = match env::var("SYSTEM_PROMPT") {
+        Ok(prompt) if !prompt.is_empty() => prompt,
+        _ => return,
+    };
+
+    let bypass_requested = req
+        .headers()
+        .get("X-Bypass-System-Prompt")
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.eq_ignore_ascii_case("true") || v == "1");
+    if bypass_requested {
+        let bypass_allowed = env::var("ALLOW_SYSTEM_BYPASS")
+            .map(|v| v.eq_ignore_ascii_case("true") || v == "1")
+            .unwrap_or(false);
+        if bypass_allowed {
+            info!("Server system prompt bypassed via X-Bypass-System-Prompt");
+            return;
+        }
+        info!("Ignoring X-Bypass-System-Prompt: ALLOW_SYSTEM_BYPASS is not enabled");
+    }
+
+    messages.insert(
+        0,
+        ChatMessage {
+            role: "system".to_string(),
+            content: Some(system_prompt),
+            ..Default::default()
+        },
+    );
+}
+
+// Roles each provider accepts. Anthropic's system messages become the top-level system field.
+fn accepted_roles(provider: Provider) -> &'static [&'static str] {
+    match provider {
+        Provider::Anthropic | Provider::Bedrock | Provider::Cohere => &["system", "user", "assistant"],
+        Provider::OpenAI(compatible) if *compatible == OPENAI => &["system", "developer", "user", "assistant", "tool"],
+        Provider::OpenAI(_) | Provider::Mistral => &["system", "user", "assistant", "tool"],
+    }
+}
+
+// Drop messages identical to the one before them (role, content, tool calls and all); a repeat
+// with any difference, or with another message in between, is kept. Returns how many were dropped.
+pub fn dedupe_consecutive_messages(messages: &mut Vec<ChatMessage>) -> usize {
+    let before = messages.len();
+    messages.dedup();
+    before - messages.len()
+}
+
+// Lowercase roles and map common aliases ("human", "ai", ...), then reject any role the
+// provider doesn't accept instead of forwarding it to fail there
+fn normalize_roles(provider: Provider, messages: &mut [ChatMessage]) -> Result<(), ApiError> {
+    let accepted = accepted_roles(provider);
+    for (index, message) in messages.iter_mut().enumerate() {
+        let role = match message.role.trim().to_lowercase().as_str() {
+            "human" => "user".to_string(),
+            "ai" | "bot" | "model" => "assistant".to_string(),
+            role => role.to_string(),
+        };
+        if !accepted.contains(&role.as_str()) {
+            return Err(ApiError::bad_request(format!(
+                "Message {} has role '{}', which {} doesn't accept. Accepted roles: {}",
+                index,
+                message.role,
+                provider.name(),
+                accepted.join(", ")
+            )));
+        }
+        message.role = role;
+    }
+    Ok(())
+}
+
+// Per-provider cap on a single message's content, in characters, e.g. MAX_MESSAGE_CHARS_OPENAI.
+// Over-long messages are rejected unless MESSAGE_LENGTH_POLICY=truncate.
+fn enforce_message_length(provider: Provider, messages: &mut [ChatMessage]) -> Result<(), ApiError> {
+    let var = format!("MAX_MESSAGE_CHARS_{}", provider.name().to_uppercase());
+    let max_chars = match env::var(&var).ok().and_then(|v| v.parse::<usize>().ok()) {
+        Some(max_chars) => max_chars,
+        None => return Ok(()),
+    };
+    let truncate = env::var("MESSAGE_LENGTH_POLICY")
+        .map(|policy| policy.eq_ignore_ascii_case("truncate"))
+        .unwrap_or(false);
+
+    for (index, msg) in messages.iter_mut().enumerate() {
+        let Some(content) = msg.content.as_mut() else {
+            continue;
+        };
+        let length = content.chars().count();
+        if length <= max_chars {
+            continue;
+        }
+
+        if truncate {
+            info!("Truncating message {} from {} to {} characters ({})", index, length, max_chars, var);
+            let cut = content
+                .char_indices()
+                .nth(max_chars)
+                .map(|(byte_index, _)| byte_index)
+                .unwrap_or(content.len());
+            content.truncate(cut);
+        } else {
+            return Err(ApiError::bad_request(format!(
+                "Message at index {} is {} characters long, which exceeds the {} limit of {} characters",
+                index, length, provider.name(), max_chars
+            )));
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,47 @@
+//! Prometheus metrics beyond the per-endpoint ones the actix-web-prom middleware keeps
+
+use prometheus::{core::Collector, IntCounterVec, IntGauge, IntGaugeVec, Opts};
+
+// Custom metrics, registered with the actix-web-prom registry at startup
+lazy_static::lazy_static! {
+    pub static ref CLIENT_CANCELLED_STREAMS: IntCounterVec = IntCounterVec::new(
+        Opts::new("client_cancelled_streams_total", "Streams dropped by the client before the upstream finished")
+            .namespace("api"),
+        &["provider"]
+    )
+    .unwrap();
+    pub static ref STREAM_WRITES: IntCounterVec = IntCounterVec::new(
+        Opts::new("stream_writes_total", "Chunks written to clients on /sdk-chat streams")
+            .namespace("api"),
+        &["provider"]
+    )
+    .unwrap();
+    pub static ref UPSTREAM_IN_FLIGHT: IntGauge = IntGauge::with_opts(
+        Opts::new("upstream_in_flight", "Upstream provider requests currently in flight, including their streams")
+            .namespace("api")
+    )
+    .unwrap();
+    pub static ref UPSTREAM_RATE_LIMIT_REMAINING: IntGaugeVec = IntGaugeVec::new(
+        Opts::new("upstream_ratelimit_remaining", "Rate limit left as of the last upstream response")
+            .namespace("api"),
+        &["provider", "limit"]
+    )
+    .unwrap();
+    pub static ref STEP_LIMIT_STOPS: IntCounterVec = IntCounterVec::new(
+        Opts::new("step_limit_stops_total", "Agentic loops stopped for reaching maxSteps")
+            .namespace("api"),
+        &["outcome"]
+    )
+    .unwrap();
+}
+
+// Every custom metric, for registering with the metrics middleware's registry
+pub fn custom_metrics() -> [Box<dyn Collector>; 5] {
+    [
+        Box::new(CLIENT_CANCELLED_STREAMS.clone()),
+        Box::new(STREAM_WRITES.clone()),
+        Box::new(UPSTREAM_IN_FLIGHT.clone()),
+        Box::new(UPSTREAM_RATE_LIMIT_REMAINING.clone()),
+        Box::new(STEP_LIMIT_STOPS.clone()),
+    ]
+}
@@ -0,0 +1,143 @@
+//! The mock provider: canned streams for frontend development, without calling a provider
+
+use std::collections::HashMap;
+use std::env;
+use std::time::Duration;
+
+use actix_web::HttpResponse;
+use bytes::Bytes;
+use log::info;
+use serde::Deserialize;
+use serde_json::Value;
+use tokio_stream::StreamExt;
+
+use crate::convert::{FrameWriter, Usage};
+use crate::error::ApiError;
+
+// Mock provider for frontend development: "mock" and "mock/<scenario>" models, or every model
+// with MOCK_PROVIDER=true, stream a canned response with delays between the frames instead of
+// calling a provider. Scenarios come from the MOCK_SCRIPT file (TOML if it ends in .toml,
+// otherwise JSON), or the built-in text, tool-call and error ones.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MockScenario {
+    #[serde(default = "default_mock_delay_ms", rename = "delayMs")]
+    delay_ms: u64,
+    events: Vec<MockEvent>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+enum MockEvent {
+    Text(String),
+    ToolCall { name: String, args: Value },
+    // Ends the stream with an error frame
+    Error(String),
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MockScript {
+    scenarios: HashMap<String, MockScenario>,
+}
+
+fn default_mock_delay_ms() -> u64 {
+    40
+}
+
+const DEFAULT_MOCK_SCENARIO: &str = "text";
+
+const BUILTIN_MOCK_SCRIPT: &str = r#"{ "scenarios": {
+    "text": { "events": [
+        { "text": "This is a mock response" }, { "text": " from the tell backend." },
+        { "text": " No provider was called." }
+    ] },
+    "tool-call": { "events": [
+        { "text": "Let me look at the data." },
+        { "toolCall": { "name": "executeSQL", "args": { "sql": "SELECT * FROM data LIMIT 10" } } }
+    ] },
+    "error": { "events": [
+        { "text": "Starting to answer" },
+        { "error": "mock_error: The mock provider failed on purpose" }
+    ] }
+} }"#;
+
+static MOCK_SCENARIOS: std::sync::OnceLock<HashMap<String, MockScenario>> = std::sync::OnceLock::new();
+
+// Read the scenarios at startup, so a broken MOCK_SCRIPT stops the server
+pub fn load_mock_scenarios() -> Result<(), String> {
+    let _ = MOCK_SCENARIOS.set(read_mock_script()?);
+    Ok(())
+}
+
+fn read_mock_script() -> Result<HashMap<String, MockScenario>, String> {
+    let Ok(path) = env::var("MOCK_SCRIPT") else {
+        let script: MockScript = serde_json::from_str(BUILTIN_MOCK_SCRIPT).expect("built-in mock script is valid");
+        return Ok(script.scenarios);
+    };
+    let contents = std::fs::read_to_string(&path).map_err(|e| format!("can't read {}: {}", path, e))?;
+    let script: MockScript = if path.ends_with(".toml") {
+        toml::from_str(&contents).map_err(|e| format!("{}: {}", path, e))?
+    } else {
+        serde_json::from_str(&contents).map_err(|e| format!("{}: {}", path, e))?
+    };
+    if let Some((name, _)) = script.scenarios.iter().find(|(_, scenario)| scenario.events.is_empty()) {
+        return Err(format!("{}: scenario '{}' has no events", path, name));
+    }
+    info!("Loaded {} mock scenario(s) from {}", script.scenarios.len(), path);
+    Ok(script.scenarios)
+}
+
+pub fn mock_provider_enabled() -> bool {
+    env::var("MOCK_PROVIDER")
+        .map(|v| v.eq_ignore_ascii_case("true") || v == "1")
+        .unwrap_or(false)
+}
+
+// The scenario a request gets, or None when it should go to a real provider
+pub fn mock_scenario(model: &str) -> Result<Option<&'static MockScenario>, ApiError> {
+    let name = match model.split_once('/') {
+        Some((prefix, name)) if prefix.eq_ignore_ascii_case("mock") => name,
+        _ if model.eq_ignore_ascii_case("mock") || mock_provider_enabled() => DEFAULT_MOCK_SCENARIO,
+        _ => return Ok(None),
+    };
+    let scenarios = MOCK_SCENARIOS.get_or_init(|| read_mock_script().unwrap_or_default());
+    let scenario = scenarios.get(name).ok_or_else(|| {
+        let mut known: Vec<&str> = scenarios.keys().map(String::as_str).collect();
+        known.sort_unstable();
+        ApiError::bad_request(format!("Unknown mock scenario '{}'. Known scenarios: {}", name, known.join(", ")))
+    })?;
+    info!("Serving mock scenario '{}' for model {}", name, model);
+    Ok(Some(scenario))
+}
+
+// Stream the scenario's frames, each after the scenario's delay
+pub fn mock_response(frames: FrameWriter, scenario: &'static MockScenario) -> HttpResponse {
+    let mut chunks = vec![frames.start_step()];
+    let mut finish_reason = "stop";
+    for (index, event) in scenario.events.iter().enumerate() {
+        match event {
+            MockEvent::Text(text) => chunks.push(frames.text(text)),
+            MockEvent::ToolCall { name, args } => {
+                chunks.push(frames.tool_call(&format!("call_mock_{}", index), name, args));
+                finish_reason = "tool-calls";
+            }
+            MockEvent::Error(message) => {
+                chunks.push(frames.error(message));
+                finish_reason = "error";
+                break;
+            }
+        }
+    }
+    chunks.push(frames.finish(finish_reason, Usage::default()));
+
+    let delay = Duration::from_millis(scenario.delay_ms);
+    let stream = futures::stream::iter(chunks).then(move |chunk| async move {
+        actix_web::rt::time::sleep(delay).await;
+        Ok::<Bytes, actix_web::Error>(Bytes::from(chunk))
+    });
+    HttpResponse::Ok()
+        .insert_header(("Content-Type", "text/event-stream"))
+        .insert_header(("Cache-Control", "no-cache"))
+        .streaming(stream)
+}
@@ -0,0 +1,278 @@
+//! Client protocols other than the AI SDK data stream: the converted frames re-encoded as the
+//! AI SDK 5 UI message stream, or as plain text or JSON lines for frontends that don't use the
+//! AI SDK
+
+use std::pin::Pin;
+use std::task::Poll;
+
+use actix_web::{
+    body::{BodyStream, BoxBody, MessageBody},
+    http::header::{HeaderName, HeaderValue, CONTENT_TYPE},
+    web, HttpRequest, HttpResponse,
+};
+use bytes::Bytes;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::convert::{LineBuffer, SdkVersion};
+use crate::stream::is_event_stream;
+use crate::error::ApiError;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    // The AI SDK stream for the client's version: the data stream frames for v4, the UI
+    // message stream for v5
+    #[default]
+    AiSdk,
+    // Just the text, as it streams; tool calls and other frames are left out
+    PlainText,
+    // One JSON object per line, {"type": ..., ...}, for every frame
+    JsonLines,
+}
+
+#[derive(Deserialize)]
+struct FormatQuery {
+    format: Option<String>,
+}
+
+// Event types of the JSON lines format by AI SDK frame prefix
+const JSON_LINES_TYPES: &[(&str, &str)] = &[
+    ("0", "text"),
+    ("9", "tool-call"),
+    ("b", "tool-call-start"),
+    ("c", "tool-call-delta"),
+    ("a", "tool-result"),
+    ("2", "data"),
+    ("8", "annotation"),
+    ("h", "source"),
+    ("3", "error"),
+    ("f", "step-start"),
+    ("e", "step-finish"),
+    ("d", "finish"),
+];
+
+impl OutputFormat {
+    // From the X-Output-Format header or the format query parameter (the header wins); the AI
+    // SDK stream when neither is sent
+    pub fn from_request(req: &HttpRequest) -> Result<Self, ApiError> {
+        let header = req.headers().get("X-Output-Format").and_then(|v| v.to_str().ok()).map(str::to_string);
+        let query = web::Query::<FormatQuery>::from_query(req.query_string()).ok().and_then(|query| query.0.format);
+        let Some(format) = header.or(query) else {
+            return Ok(OutputFormat::default());
+        };
+        match format.trim().to_ascii_lowercase().as_str() {
+            "ai-sdk" => Ok(OutputFormat::AiSdk),
+            "text" | "plain" => Ok(OutputFormat::PlainText),
+            "jsonl" | "json-lines" | "ndjson" => Ok(OutputFormat::JsonLines),
+            other => Err(ApiError::bad_request(format!(
+                "Unsupported output format '{}'. Supported formats: ai-sdk, text, jsonl",
+                other
+            ))),
+        }
+    }
+
+    fn content_type(self) -> &'static str {
+        match self {
+            OutputFormat::AiSdk => "text/event-stream",
+            OutputFormat::PlainText => "text/plain; charset=utf-8",
+            OutputFormat::JsonLines => "application/x-ndjson",
+        }
+    }
+
+    // The client protocol's encoding of one AI SDK frame; empty for frames it leaves out
+    fn encode(self, prefix: &str, payload: Value) -> String {
+        match self {
+            OutputFormat::AiSdk => format!("{}:{}\n", prefix, payload),
+            OutputFormat::PlainText => match (prefix, payload) {
+                ("0", Value::String(text)) => text,
+                ("3", Value::String(message)) => format!("\n\nError: {}\n", message),
+                _ => String::new(),
+            },
+            OutputFormat::JsonLines => {
+                let Some((_, event_type)) = JSON_LINES_TYPES.iter().find(|(frame, _)| *frame == prefix) else {
+                    return String::new();
+                };
+                let event = match payload {
+                    Value::Object(mut fields) => {
+                        fields.insert("type".to_string(), json!(event_type));
+                        Value::Object(fields)
+                    }
+                    payload => {
+                        let key = match prefix {
+                            "0" => "text",
+                            "3" => "message",
+                            _ => "data",
+                        };
+                        json!({ "type": event_type, key: payload })
+                    }
+                };
+                format!("{}\n", event)
+            }
+        }
+    }
+
+    // Re-encode a streamed /sdk-chat response for the client protocol. Responses that aren't
+    // streams (errors, dry runs) are left alone.
+    pub fn apply(self, response: HttpResponse, version: SdkVersion) -> HttpResponse {
+        let ui_message_stream = self == OutputFormat::AiSdk && version == SdkVersion::V5;
+        if (self == OutputFormat::AiSdk && !ui_message_stream) || !is_event_stream(&response) {
+            return response;
+        }
+        let mut response = response.map_body(|_, mut body| {
+            let mut lines = LineBuffer::default();
+            let mut ui_stream = UiMessageStream::default();
+            let mut ended = false;
+            let stream = futures::stream::poll_fn(move |cx| loop {
+                let chunk = match Pin::new(&mut body).poll_next(cx) {
+                    Poll::Ready(Some(Ok(chunk))) => chunk,
+                    Poll::Ready(Some(Err(e))) => return Poll::Ready(Some(Err(e))),
+                    // The UI message stream ends with a [DONE] event
+                    Poll::Ready(None) if ui_message_stream && !ended => {
+                        ended = true;
+                        return Poll::Ready(Some(Ok(Bytes::from(ui_stream.end()))));
+                    }
+                    Poll::Ready(None) => return Poll::Ready(None),
+                    Poll::Pending => return Poll::Pending,
+                };
+                let encoded: String = lines
+                    .push(&chunk)
+                    .lines()
+                    .filter_map(|line| line.split_once(':'))
+                    .filter_map(|(prefix, payload)| Some((prefix, serde_json::from_str::<Value>(payload).ok()?)))
+                    .map(|(prefix, payload)| {
+                        if ui_message_stream {
+                            ui_stream.encode(prefix, payload)
+                        } else {
+                            self.encode(prefix, payload)
+                        }
+                    })
+                    .collect();
+                // Frames this format leaves out make no write at all
+                if !encoded.is_empty() {
+                    return Poll::Ready(Some(Ok(Bytes::from(encoded))));
+                }
+            });
+            BoxBody::new(BodyStream::new(stream))
+        });
+        response.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static(self.content_type()));
+        if ui_message_stream {
+            response
+                .headers_mut()
+                .insert(HeaderName::from_static("x-vercel-ai-ui-message-stream"), HeaderValue::from_static("v1"));
+        }
+        response
+    }
+}
+
+// The AI SDK 5 UI message stream: one SSE event per chunk, `data: {"type":...}`, ending with
+// `data: [DONE]`. Text and reasoning come as parts with an id, opened with a -start chunk and
+// closed with an -end chunk, so the encoder tracks the part that is open. Data and annotation
+// frames become data-item and data-annotation parts, one per array item.
+#[derive(Debug, Default)]
+struct UiMessageStream {
+    started: bool,
+    // The open text part and how many have been opened
+    text_part: Option<String>,
+    text_parts: usize,
+}
+
+impl UiMessageStream {
+    fn event(chunk: &Value) -> String {
+        format!("data: {}\n\n", chunk)
+    }
+
+    // The chunk closing the open text part, if any
+    fn end_text(&mut self) -> String {
+        match self.text_part.take() {
+            Some(id) => Self::event(&json!({ "type": "text-end", "id": id })),
+            None => String::new(),
+        }
+    }
+
+    fn data_parts(data_type: &str, items: Value) -> String {
+        let items = match items {
+            Value::Array(items) => items,
+            item => vec![item],
+        };
+        items.iter().map(|item| Self::event(&json!({ "type": data_type, "data": item }))).collect()
+    }
+
+    fn encode(&mut self, prefix: &str, payload: Value) -> String {
+        let mut events = String::new();
+        if !matches!(prefix, "0" | "2" | "8") {
+            events.push_str(&self.end_text());
+        }
+        match (prefix, payload) {
+            ("0", Value::String(text)) => {
+                let id = match &self.text_part {
+                    Some(id) => id.clone(),
+                    None => {
+                        self.text_parts += 1;
+                        let id = format!("text-{}", self.text_parts);
+                        events.push_str(&Self::event(&json!({ "type": "text-start", "id": id })));
+                        self.text_part = Some(id.clone());
+                        id
+                    }
+                };
+                events.push_str(&Self::event(&json!({ "type": "text-delta", "id": id, "delta": text })));
+            }
+            ("f", step) => {
+                if !self.started {
+                    self.started = true;
+                    events.push_str(&Self::event(&json!({ "type": "start", "messageId": step["messageId"] })));
+                }
+                events.push_str(&Self::event(&json!({ "type": "start-step" })));
+            }
+            ("e", _) => events.push_str(&Self::event(&json!({ "type": "finish-step" }))),
+            ("d", finish) => events.push_str(&Self::event(&json!({ "type": "finish", "messageMetadata": finish }))),
+            ("b", start) => events.push_str(&Self::event(&json!({
+                "type": "tool-input-start",
+                "toolCallId": start["toolCallId"],
+                "toolName": start["toolName"],
+            }))),
+            ("c", delta) => events.push_str(&Self::event(&json!({
+                "type": "tool-input-delta",
+                "toolCallId": delta["toolCallId"],
+                "inputTextDelta": delta["argsTextDelta"],
+            }))),
+            // Arguments that didn't parse are passed on as the raw text
+            ("9", tool_call) if tool_call["argsError"] == true => events.push_str(&Self::event(&json!({
+                "type": "tool-input-error",
+                "toolCallId": tool_call["toolCallId"],
+                "toolName": tool_call["toolName"],
+                "input": tool_call["argsText"],
+                "errorText": "The tool call arguments are not valid JSON",
+            }))),
+            ("9", tool_call) => events.push_str(&Self::event(&json!({
+                "type": "tool-input-available",
+                "toolCallId": tool_call["toolCallId"],
+                "toolName": tool_call["toolName"],
+                "input": tool_call["args"],
+            }))),
+            ("a", result) => events.push_str(&Self::event(&json!({
+                "type": "tool-output-available",
+                "toolCallId": result["toolCallId"],
+                "output": result["result"],
+            }))),
+            ("h", source) => {
+                let mut chunk = json!({ "type": "source-url", "sourceId": source["id"], "url": source["url"] });
+                if let Some(title) = source.get("title") {
+                    chunk["title"] = title.clone();
+                }
+                events.push_str(&Self::event(&chunk));
+            }
+            ("2", data) => events.push_str(&Self::data_parts("data-item", data)),
+            ("8", annotations) => events.push_str(&Self::data_parts("data-annotation", annotations)),
+            ("3", Value::String(message)) => {
+                events.push_str(&Self::event(&json!({ "type": "error", "errorText": message })))
+            }
+            _ => {}
+        }
+        events
+    }
+
+    // Closes a text part the stream left open, then the [DONE] event
+    fn end(&mut self) -> String {
+        format!("{}data: [DONE]\n\n", self.end_text())
+    }
+}
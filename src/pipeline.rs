@@ -0,0 +1,113 @@
+//! The addTransformation tool, run on the server: SQL transformation pipelines per session
+
+use std::sync::Mutex;
+
+use actix_web::{web, HttpResponse};
+use serde::Serialize;
+use serde_json::{json, Value};
+use log::info;
+
+use crate::error::ApiError;
+use crate::session::{Session, SessionStore};
+
+// Transformation pipelines built by addTransformation calls, per session. Each step can refer
+// to the step before it as previous_step, or to any earlier step by its output alias.
+#[derive(Debug, Clone, Serialize)]
+pub struct PipelineStep {
+    #[serde(rename = "outputAlias")]
+    output_alias: String,
+    sql: String,
+    // The step as a standalone query, with earlier steps inlined as CTEs
+    #[serde(rename = "resolvedSql")]
+    resolved_sql: String,
+}
+
+lazy_static::lazy_static! {
+    static ref PIPELINES: Mutex<SessionStore<Vec<PipelineStep>>> = Mutex::new(SessionStore::from_env());
+}
+
+// Run a tool call on the server, returning its result, or None for tools the client executes
+pub fn execute_server_tool(session: &Session, tool_name: &str, args: &Value) -> Option<Value> {
+    match tool_name {
+        // An ephemeral session's pipeline could never be read back, so leave it to the client
+        "addTransformation" if !session.ephemeral => Some(add_transformation(&session.id, args)),
+        _ => None,
+    }
+}
+
+fn add_transformation(session_id: &str, args: &Value) -> Value {
+    let sql = args.get("sql").and_then(|v| v.as_str()).unwrap_or_default().trim();
+    let output_alias = args.get("outputAlias").and_then(|v| v.as_str()).unwrap_or_default();
+
+    let mut pipelines = PIPELINES.lock().unwrap();
+    let steps = pipelines.get_or_default(session_id);
+
+    let error = if sql.is_empty() {
+        Some("sql is required".to_string())
+    } else if !is_sql_identifier(output_alias) || output_alias.eq_ignore_ascii_case("previous_step") {
+        Some(format!("outputAlias '{}' must be a plain identifier other than previous_step", output_alias))
+    } else if steps.iter().any(|step| step.output_alias.eq_ignore_ascii_case(output_alias)) {
+        Some(format!("outputAlias '{}' is already used by an earlier step", output_alias))
+    } else {
+        None
+    };
+    if let Some(error) = error {
+        info!("Rejected addTransformation for session {}: {}", session_id, error);
+        return json!({ "error": error, "pipeline": steps.as_slice() });
+    }
+
+    let resolved_sql = resolve_pipeline_sql(steps, sql);
+    steps.push(PipelineStep {
+        output_alias: output_alias.to_string(),
+        sql: sql.to_string(),
+        resolved_sql,
+    });
+    info!("Added transformation '{}' to session {} ({} steps)", output_alias, session_id, steps.len());
+
+    json!({ "pipeline": steps.as_slice() })
+}
+
+// Inline the earlier steps as CTEs so previous_step and alias references resolve. Each step's
+// own previous_step is bound to the step before it.
+fn resolve_pipeline_sql(steps: &[PipelineStep], sql: &str) -> String {
+    let Some(last) = steps.last() else {
+        return sql.to_string();
+    };
+    let ctes: Vec<String> = steps
+        .iter()
+        .enumerate()
+        .map(|(i, step)| {
+            let body = match i.checked_sub(1) {
+                Some(prev) => with_previous_step(&steps[prev].output_alias, &step.sql),
+                None => step.sql.clone(),
+            };
+            format!("{} AS ({})", step.output_alias, body)
+        })
+        .collect();
+    let query = with_previous_step(&last.output_alias, sql);
+    format!("WITH {}, {}", ctes.join(", "), &query["WITH ".len()..])
+}
+
+// Prefix `sql` with a previous_step CTE, merging into its own WITH clause if it has one
+fn with_previous_step(previous_alias: &str, sql: &str) -> String {
+    let cte = format!("previous_step AS (SELECT * FROM {})", previous_alias);
+    match sql.get(..5) {
+        Some(keyword) if keyword.eq_ignore_ascii_case("with ") => format!("WITH {}, {}", cte, &sql[5..]),
+        _ => format!("WITH {} {}", cte, sql),
+    }
+}
+
+fn is_sql_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+pub async fn get_pipeline(session: web::Path<String>) -> Result<HttpResponse, ApiError> {
+    let session_id = session.into_inner();
+    let mut pipelines = PIPELINES.lock().unwrap();
+    let steps = pipelines.get(&session_id).ok_or_else(|| {
+        ApiError::not_found(format!("No pipeline for session '{}'", session_id))
+    })?;
+    Ok(HttpResponse::Ok().json(json!({ "sessionId": session_id, "steps": steps })))
+}
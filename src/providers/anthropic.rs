@@ -0,0 +1,370 @@
+//! Anthropic Messages API
+
+use std::env;
+use std::sync::OnceLock;
+
+use actix_web::HttpResponse;
+use serde_json::{json, Value};
+use tokio_stream::{Stream, StreamExt};
+use bytes::Bytes;
+use log::{error, info};
+
+use crate::chunk_info;
+use crate::error::ApiError;
+use crate::messages::apply_json_instruction;
+use crate::request::{ChatMessage, ChatRequest};
+use crate::convert::{
+    convert_anthropic_to_ai_sdk, upstream_stream_error, AnthropicStreamState, FrameWriter,
+    LineBuffer,
+};
+use crate::stream::ai_sdk_stream_response;
+use crate::tools::create_tools;
+use super::{upstream_timeout, Provider, RateLimitHeaders, UpstreamRequest, HTTP_CLIENT};
+
+// The anthropic-version header sent with every request, from ANTHROPIC_VERSION
+const DEFAULT_ANTHROPIC_VERSION: &str = "2023-06-01";
+static ANTHROPIC_VERSION: OnceLock<String> = OnceLock::new();
+
+// Read and check ANTHROPIC_VERSION at startup; Anthropic's versions are dates, e.g. 2023-06-01
+pub fn load_anthropic_version() -> Result<&'static str, String> {
+    let version = env::var("ANTHROPIC_VERSION").unwrap_or_else(|_| DEFAULT_ANTHROPIC_VERSION.to_string());
+    let version = version.trim();
+    let is_date = version.len() == 10
+        && version.char_indices().all(|(i, c)| if i == 4 || i == 7 { c == '-' } else { c.is_ascii_digit() });
+    if !is_date {
+        return Err(format!(
+            "'{}' is not an Anthropic API version; expected a date such as {}",
+            version, DEFAULT_ANTHROPIC_VERSION
+        ));
+    }
+    Ok(ANTHROPIC_VERSION.get_or_init(|| version.to_string()))
+}
+
+pub fn anthropic_version() -> &'static str {
+    ANTHROPIC_VERSION.get().map_or(DEFAULT_ANTHROPIC_VERSION, String::as_str)
+}
+
+// Anthropic API base URL, overridable to point at a proxy, gateway or local mock server
+pub fn anthropic_base_url() -> String {
+    env::var("ANTHROPIC_BASE_URL")
+        .map(|url| url.trim_end_matches('/').to_string())
+        .unwrap_or_else(|_| "https://api.anthropic.com".to_string())
+}
+
+pub async fn count_anthropic_tokens(request: ChatRequest) -> Result<u64, ApiError> {
+    let api_key = env::var("ANTHROPIC_API_KEY")
+        .map_err(|_| ApiError::internal("ANTHROPIC_API_KEY not set"))?;
+
+    let (system, messages) = split_anthropic_system(convert_messages_to_anthropic(request.messages));
+    let mut request_body = json!({
+        "model": request.model,
+        "messages": messages,
+        "tools": create_tools(),
+    });
+    if let Some(system) = system {
+        request_body["system"] = system;
+    }
+
+    let response = HTTP_CLIENT
+        .post(format!("{}/v1/messages/count_tokens", anthropic_base_url()))
+        .header("Content-Type", "application/json")
+        .header("x-api-key", &api_key)
+        .header("anthropic-version", anthropic_version())
+        .json(&request_body)
+        .timeout(upstream_timeout(None))
+        .send()
+        .await
+        .map_err(|e| {
+            error!("Failed to call Anthropic count tokens API: {}", e);
+            ApiError::bad_gateway(format!("Anthropic API error: {}", e))
+        })?;
+
+    let status = response.status();
+    if !status.is_success() {
+        let error_text = response.text().await.unwrap_or_default();
+        error!("Anthropic count tokens API error {}: {}", status, error_text);
+        return Err(ApiError::bad_gateway(format!(
+            "Anthropic API error: {}",
+            status
+        )));
+    }
+
+    let parsed: Value = response.json().await.map_err(|e| {
+        ApiError::bad_gateway(format!("Anthropic API error: {}", e))
+    })?;
+    parsed
+        .get("input_tokens")
+        .and_then(|t| t.as_u64())
+        .ok_or_else(|| ApiError::bad_gateway("Anthropic API error: missing input_tokens"))
+}
+
+pub fn build_anthropic_request(mut request: ChatRequest) -> Result<UpstreamRequest, ApiError> {
+    let api_key = env::var("ANTHROPIC_API_KEY")
+        .map_err(|_| ApiError::internal("ANTHROPIC_API_KEY not set"))?;
+
+    let tools = create_tools();
+    apply_json_instruction(&mut request);
+
+    let messages = convert_messages_to_anthropic(request.messages);
+    let (mut system, mut messages) = split_anthropic_system(messages);
+
+    if request.prompt_caching {
+        apply_anthropic_prompt_caching(&mut system, &mut messages);
+        info!("Prompt caching enabled for Anthropic request");
+    }
+
+    let mut request_body = json!({
+        "model": request.model,
+        "messages": messages,
+        // "temperature": request.temperature,
+        "stream": true,
+        "max_tokens": 4096
+    });
+
+    if let Some(system) = system {
+        request_body["system"] = system;
+    }
+
+    // Add tools if any
+    if !tools.is_empty() {
+        request_body["tools"] = json!(tools);
+        if let Some(tool_choice) = &request.tool_choice {
+            request_body["tool_choice"] = tool_choice.to_anthropic();
+        }
+        info!("Added {} tools to Anthropic request", tools.len());
+        info!("Tools: {}", serde_json::to_string_pretty(&tools).unwrap_or_default());
+        if let Some(max_steps) = request.max_steps {
+            request_body["max_tokens"] = json!(max_steps * 1000); // Rough estimation
+        }
+    }
+    if let Some(max_tokens) = request.max_tokens {
+        request_body["max_tokens"] = json!(max_tokens);
+    }
+
+    let mut upstream = UpstreamRequest::new("Anthropic", format!("{}/v1/messages", anthropic_base_url()), request_body)
+        .timeout(upstream_timeout(request.max_steps))
+        .header("Content-Type", "application/json")
+        .header("x-api-key", api_key)
+        .header("anthropic-version", anthropic_version());
+
+    if request.prompt_caching {
+        upstream = upstream.header("anthropic-beta", "prompt-caching-2024-07-31");
+    }
+    Ok(upstream)
+}
+
+pub async fn handle_anthropic_request(upstream: UpstreamRequest, frames: FrameWriter) -> Result<HttpResponse, ApiError> {
+    let prefill = anthropic_prefill(&upstream.body);
+    let response = upstream.send().await?;
+    let rate_limits = RateLimitHeaders::from_response(Provider::Anthropic, &response);
+    let mut response = anthropic_stream_response(response.bytes_stream(), prefill, frames);
+    rate_limits.apply(&mut response);
+    Ok(response)
+}
+
+// Convert an Anthropic SSE stream to the AI SDK stream response
+pub fn anthropic_stream_response<S>(stream: S, prefill: Option<String>, frames: FrameWriter) -> HttpResponse
+where
+    S: Stream<Item = Result<Bytes, reqwest::Error>> + 'static,
+{
+    let mut lines = LineBuffer::default();
+    let mut state = AnthropicStreamState { frames, prefill, ..Default::default() };
+    let unhandled = state.unhandled.clone();
+    // Stop at the first transport error, once the client has been told about it
+    let mut failed = false;
+    let ai_sdk_stream = stream.map_while(move |chunk_result| {
+        if failed {
+            return None;
+        }
+        Some(match chunk_result {
+            Ok(chunk) => {
+                // Parse Anthropic SSE format and convert to AI SDK format
+                chunk_info!("Anthropic raw chunk: {}", String::from_utf8_lossy(&chunk));
+                let chunk_str = lines.push(&chunk);
+                let converted = convert_anthropic_to_ai_sdk(&chunk_str, &mut state);
+                if !converted.is_empty() {
+                    chunk_info!("Converted to AI SDK: {}", converted);
+                }
+                Ok::<Bytes, reqwest::Error>(Bytes::from(converted))
+            }
+            Err(e) => {
+                failed = true;
+                Ok(Bytes::from(state.frames.stream_error(&upstream_stream_error(&e), state.usage)))
+            }
+        })
+    });
+
+    ai_sdk_stream_response(ai_sdk_stream, Provider::Anthropic, unhandled, frames)
+}
+
+// Anthropic takes the system prompt as a top-level field rather than a message role
+pub fn split_anthropic_system(messages: Vec<Value>) -> (Option<Value>, Vec<Value>) {
+    let mut system_blocks = Vec::new();
+    let mut rest = Vec::new();
+
+    for message in messages {
+        if message.get("role").and_then(|r| r.as_str()) == Some("system") {
+            if let Some(text) = message.get("content").and_then(|c| c.as_str()) {
+                system_blocks.push(json!({ "type": "text", "text": text }));
+            }
+        } else {
+            rest.push(message);
+        }
+    }
+
+    let system = if system_blocks.is_empty() {
+        None
+    } else {
+        Some(json!(system_blocks))
+    };
+    (system, rest)
+}
+
+// Mark the system prompt and the first message as cache breakpoints. Everything up to
+// a breakpoint is cached, so this covers the long, stable prefix of the conversation.
+pub fn apply_anthropic_prompt_caching(system: &mut Option<Value>, messages: &mut [Value]) {
+    if let Some(last_block) = system
+        .as_mut()
+        .and_then(|s| s.as_array_mut())
+        .and_then(|blocks| blocks.last_mut())
+    {
+        last_block["cache_control"] = json!({ "type": "ephemeral" });
+    }
+
+    if let Some(first_message) = messages.first_mut() {
+        if let Some(text) = first_message.get("content").and_then(|c| c.as_str()).map(str::to_string) {
+            first_message["content"] = json!([{
+                "type": "text",
+                "text": text,
+                "cache_control": { "type": "ephemeral" }
+            }]);
+        } else if let Some(last_block) = first_message
+            .get_mut("content")
+            .and_then(|c| c.as_array_mut())
+            .and_then(|blocks| blocks.last_mut())
+        {
+            // Already content blocks (e.g. with images)
+            last_block["cache_control"] = json!({ "type": "ephemeral" });
+        }
+    }
+}
+
+// A trailing assistant message is a prefill: Anthropic continues it rather than starting a new
+// turn, and only streams the continuation. Its text is re-emitted ahead of the stream.
+pub fn anthropic_prefill(body: &Value) -> Option<String> {
+    let last = body.get("messages")?.as_array()?.last()?;
+    if last.get("role").and_then(|r| r.as_str()) != Some("assistant") {
+        return None;
+    }
+    let text = match last.get("content")? {
+        Value::String(text) => text.clone(),
+        Value::Array(blocks) => blocks
+            .iter()
+            .filter(|block| block.get("type").and_then(|t| t.as_str()) == Some("text"))
+            .filter_map(|block| block.get("text").and_then(|t| t.as_str()))
+            .collect(),
+        _ => return None,
+    };
+    Some(text).filter(|text| !text.is_empty())
+}
+
+pub fn convert_messages_to_anthropic(messages: Vec<ChatMessage>) -> Vec<Value> {
+    // Convert messages to Anthropic format
+    // AI SDK v5 sends tool results embedded in assistant messages with toolInvocations
+    // We need to convert these appropriately for each provider
+    messages
+        .into_iter()
+        .flat_map(|msg| {
+            let mut result_messages = Vec::new();
+
+            // First, add the main message (user or assistant)
+            let mut message = json!({
+                "role": msg.role,
+            });
+
+            // Images become content blocks ahead of the text, as Anthropic recommends
+            let images: Vec<Value> = msg
+                .images()
+                .map(|image| match image.base64_data() {
+                    Some((media_type, data)) => json!({
+                        "type": "image",
+                        "source": { "type": "base64", "media_type": media_type, "data": data }
+                    }),
+                    None => json!({
+                        "type": "image",
+                        "source": { "type": "url", "url": image.url }
+                    }),
+                })
+                .collect();
+
+            // Add content if present
+            if !images.is_empty() {
+                let mut blocks = images;
+                if let Some(content) = msg.content.filter(|c| !c.is_empty()) {
+                    blocks.push(json!({ "type": "text", "text": content }));
+                }
+                message["content"] = json!(blocks);
+            } else if let Some(content) = msg.content {
+                message["content"] = json!(content);
+            }
+
+            // Add tool_calls if present (assistant messages with tool calls)
+            if let Some(tool_calls) = msg.tool_calls {
+                message["tool_calls"] = json!(tool_calls);
+            }
+
+            // Add tool_call_id if present (tool result messages - legacy format)
+            if let Some(tool_call_id) = msg.tool_call_id {
+                message["tool_call_id"] = json!(tool_call_id);
+            }
+
+            // Add name if present (for tool results, name = tool name)
+            if let Some(name) = msg.name {
+                message["name"] = json!(name);
+            }
+
+            result_messages.push(message);
+
+            // If this is an assistant message with toolInvocations (AI SDK v5 format),
+            // we need to handle them appropriately for Anthropic
+            if let Some(ref tool_invocations) = msg.tool_invocations {
+                // First, reconstruct tool_calls for the assistant message
+                let tool_calls: Vec<Value> = tool_invocations.iter().map(|invocation| {
+                    let tool_call_id = invocation.get("toolCallId").and_then(|v| v.as_str()).unwrap_or("");
+                    let tool_name = invocation.get("toolName").and_then(|v| v.as_str()).unwrap_or("");
+                    let args = invocation.get("args").cloned().unwrap_or(json!({}));
+
+                    json!({
+                        "id": tool_call_id,
+                        "type": "function",
+                        "function": {
+                            "name": tool_name,
+                            "arguments": serde_json::to_string(&args).unwrap_or_else(|_| "{}".to_string())
+                        }
+                    })
+                }).collect();
+
+                // Update the assistant message to include tool_calls
+                if !tool_calls.is_empty() {
+                    result_messages[0]["tool_calls"] = json!(tool_calls);
+                }
+
+                // Then add tool result messages (Anthropic uses user role for tool results)
+                for invocation in tool_invocations {
+                    if let Some(_tool_call_id) = invocation.get("toolCallId").and_then(|v| v.as_str()) {
+                        if let Some(result) = invocation.get("result") {
+                            // Anthropic format for tool results
+                            let tool_result_message = json!({
+                                "role": "user", // Anthropic treats tool results as user messages
+                                "content": serde_json::to_string(&result).unwrap_or_else(|_| "{}".to_string())
+                            });
+                            result_messages.push(tool_result_message);
+                        }
+                    }
+                }
+            }
+
+            result_messages
+        })
+        .collect()
+}
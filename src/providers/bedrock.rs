@@ -0,0 +1,160 @@
+//! Claude models on AWS Bedrock, through the Anthropic request and event conversions
+
+use std::env;
+use std::time::SystemTime;
+
+use actix_web::HttpResponse;
+use serde_json::json;
+use tokio_stream::StreamExt;
+use aws_config::{Region, SdkConfig};
+use aws_credential_types::provider::ProvideCredentials;
+use aws_sigv4::http_request::{sign, SignableBody, SignableRequest, SigningSettings};
+use aws_sigv4::sign::v4;
+use tokio::sync::OnceCell;
+use bytes::Bytes;
+use log::{error, info};
+
+use crate::chunk_info;
+use crate::error::ApiError;
+use crate::messages::apply_json_instruction;
+use crate::request::ChatRequest;
+use crate::convert::{
+    convert_bedrock_to_ai_sdk, upstream_stream_error, AnthropicStreamState, FrameWriter,
+};
+use crate::stream::ai_sdk_stream_response;
+use crate::tools::create_tools;
+use super::{upstream_timeout, Provider, UpstreamRequest};
+use super::anthropic::{anthropic_prefill, convert_messages_to_anthropic, split_anthropic_system};
+
+pub static AWS_CONFIG: OnceCell<SdkConfig> = OnceCell::const_new();
+
+// Built unsigned; handle_bedrock_request signs it with SigV4 just before sending
+pub fn build_bedrock_request(mut request: ChatRequest) -> Result<UpstreamRequest, ApiError> {
+    let region = env::var("BEDROCK_REGION")
+        .map_err(|_| ApiError::internal("BEDROCK_REGION not set"))?;
+
+    let tools = create_tools();
+    apply_json_instruction(&mut request);
+
+    let messages = convert_messages_to_anthropic(request.messages);
+    let (system, messages) = split_anthropic_system(messages);
+
+    // Bedrock takes the model from the URL and the API version in the body
+    let mut request_body = json!({
+        "anthropic_version": "bedrock-2023-05-31",
+        "messages": messages,
+        "max_tokens": 4096
+    });
+
+    if let Some(system) = system {
+        request_body["system"] = system;
+    }
+
+    if !tools.is_empty() {
+        request_body["tools"] = json!(tools);
+        if let Some(tool_choice) = &request.tool_choice {
+            request_body["tool_choice"] = tool_choice.to_anthropic();
+        }
+        info!("Added {} tools to Bedrock request", tools.len());
+        if let Some(max_steps) = request.max_steps {
+            request_body["max_tokens"] = json!(max_steps * 1000); // Rough estimation
+        }
+    }
+    if let Some(max_tokens) = request.max_tokens {
+        request_body["max_tokens"] = json!(max_tokens);
+    }
+
+    // Model ids contain ':' (and ARNs contain '/'), which must be escaped in the path
+    let model_id = request.model.replace(':', "%3A").replace('/', "%2F");
+    let url = format!(
+        "https://bedrock-runtime.{}.amazonaws.com/model/{}/invoke-with-response-stream",
+        region, model_id
+    );
+    Ok(UpstreamRequest::new("Bedrock", url, request_body)
+        .timeout(upstream_timeout(request.max_steps))
+        .header("content-type", "application/json")
+        .header("accept", "application/vnd.amazon.eventstream"))
+}
+
+// Sign the exact bytes we are about to send with SigV4, adding the signing headers
+pub async fn sign_bedrock_request(upstream: &mut UpstreamRequest) -> Result<(), ApiError> {
+    let region = env::var("BEDROCK_REGION")
+        .map_err(|_| ApiError::internal("BEDROCK_REGION not set"))?;
+
+    // Load the AWS config (env vars, profile, IMDS, ...) once and reuse it
+    let aws_config = AWS_CONFIG
+        .get_or_init(|| aws_config::from_env().region(Region::new(region.clone())).load())
+        .await;
+    let credentials = aws_config
+        .credentials_provider()
+        .ok_or_else(|| ApiError::internal("AWS credentials not configured"))?
+        .provide_credentials()
+        .await
+        .map_err(|e| {
+            error!("Failed to load AWS credentials: {}", e);
+            ApiError::internal("AWS credentials not available")
+        })?;
+
+    let body = upstream.body_bytes()?;
+    let identity = credentials.into();
+    let signing_params = v4::SigningParams::builder()
+        .identity(&identity)
+        .region(&region)
+        .name("bedrock")
+        .time(SystemTime::now())
+        .settings(SigningSettings::default())
+        .build()
+        .map_err(|e| ApiError::internal(format!("Failed to sign request: {}", e)))?
+        .into();
+    let signable_request = SignableRequest::new(
+        "POST",
+        &upstream.url,
+        upstream.headers.iter().map(|(name, value)| (name.as_str(), value.as_str())),
+        SignableBody::Bytes(&body),
+    )
+    .map_err(|e| ApiError::internal(format!("Failed to sign request: {}", e)))?;
+    let (signing_instructions, _signature) = sign(signable_request, &signing_params)
+        .map_err(|e| ApiError::internal(format!("Failed to sign request: {}", e)))?
+        .into_parts();
+
+    for (name, value) in signing_instructions.headers() {
+        upstream.headers.push((name.to_string(), value.to_string()));
+    }
+    Ok(())
+}
+
+pub async fn handle_bedrock_request(mut upstream: UpstreamRequest, frames: FrameWriter) -> Result<HttpResponse, ApiError> {
+    sign_bedrock_request(&mut upstream).await?;
+    let prefill = anthropic_prefill(&upstream.body);
+    let response = upstream.send().await?;
+
+    // Bedrock wraps the Anthropic events in the binary AWS event-stream framing.
+    // Frames can span network chunks, so keep the undecoded bytes between chunks.
+    let stream = response.bytes_stream();
+    let mut buffer: Vec<u8> = Vec::new();
+    let mut state = AnthropicStreamState { frames, prefill, ..Default::default() };
+    let unhandled = state.unhandled.clone();
+    // Stop at the first transport error, once the client has been told about it
+    let mut failed = false;
+    let ai_sdk_stream = stream.map_while(move |chunk_result| {
+        if failed {
+            return None;
+        }
+        Some(match chunk_result {
+            Ok(chunk) => {
+                buffer.extend_from_slice(&chunk);
+                let converted = convert_bedrock_to_ai_sdk(&mut buffer, &mut state);
+                if !converted.is_empty() {
+                    chunk_info!("Converted to AI SDK: {}", converted);
+                }
+                Ok::<Bytes, reqwest::Error>(Bytes::from(converted))
+            }
+            Err(e) => {
+                failed = true;
+                Ok(Bytes::from(state.frames.stream_error(&upstream_stream_error(&e), state.usage)))
+            }
+        })
+    });
+
+    Ok(ai_sdk_stream_response(ai_sdk_stream, Provider::Bedrock, unhandled, frames))
+}
@@ -0,0 +1,118 @@
+//! Cohere chat API
+
+use std::env;
+
+use actix_web::HttpResponse;
+use serde_json::{json, Value};
+use tokio_stream::StreamExt;
+use bytes::Bytes;
+use log::info;
+
+use crate::chunk_info;
+use crate::error::ApiError;
+use crate::request::{ChatMessage, ChatRequest, DEFAULT_TEMPERATURE};
+use crate::convert::{
+    convert_cohere_to_ai_sdk, upstream_stream_error, CohereStreamState, FrameWriter, Usage,
+};
+use crate::stream::ai_sdk_stream_response;
+use super::{upstream_timeout, Provider, UpstreamRequest};
+
+// Cohere's v1 chat API takes the latest user turn as `message`, earlier turns as `chat_history`
+// and the system prompt as `preamble`. Its tool format differs from the others, so no tools
+// are sent, and its temperature range is 0.0..=1.0.
+pub fn cohere_base_url() -> String {
+    env::var("COHERE_BASE_URL")
+        .map(|url| url.trim_end_matches('/').to_string())
+        .unwrap_or_else(|_| "https://api.cohere.com/v1".to_string())
+}
+
+pub fn build_cohere_request(request: ChatRequest) -> Result<UpstreamRequest, ApiError> {
+    let api_key = env::var("COHERE_API_KEY")
+        .map_err(|_| ApiError::internal("COHERE_API_KEY not set"))?;
+    let base_url = cohere_base_url();
+
+    let (preamble, chat_history, message) = convert_messages_to_cohere(request.messages)?;
+
+    let temperature = request.temperature.unwrap_or(DEFAULT_TEMPERATURE);
+    let clamped = temperature.clamp(0.0, 1.0);
+    if clamped != temperature {
+        info!("Clamped temperature {} to {} for Cohere", temperature, clamped);
+    }
+
+    let mut request_body = json!({
+        "model": request.model,
+        "message": message,
+        "chat_history": chat_history,
+        "temperature": clamped,
+        "stream": true
+    });
+    if let Some(preamble) = preamble {
+        request_body["preamble"] = json!(preamble);
+    }
+    if let Some(max_tokens) = request.max_tokens {
+        request_body["max_tokens"] = json!(max_tokens);
+    }
+
+    Ok(UpstreamRequest::new("Cohere", format!("{}/chat", base_url), request_body)
+        .timeout(upstream_timeout(request.max_steps))
+        .header("Content-Type", "application/json")
+        .header("Authorization", format!("Bearer {}", api_key)))
+}
+
+// Split messages into Cohere's (preamble, chat_history, message)
+pub fn convert_messages_to_cohere(messages: Vec<ChatMessage>) -> Result<(Option<String>, Vec<Value>, String), ApiError> {
+    let mut system = Vec::new();
+    let mut turns = Vec::new();
+    for msg in messages {
+        let content = msg.content.unwrap_or_default();
+        match msg.role.as_str() {
+            "system" => system.push(content),
+            "assistant" => turns.push(("CHATBOT", content)),
+            _ => turns.push(("USER", content)),
+        }
+    }
+
+    let message = match turns.pop() {
+        Some(("USER", content)) => content,
+        _ => return Err(ApiError::bad_request("Cohere requires the last message to be from the user")),
+    };
+    let chat_history = turns
+        .into_iter()
+        .map(|(role, content)| json!({ "role": role, "message": content }))
+        .collect();
+    let preamble = (!system.is_empty()).then(|| system.join("\n\n"));
+    Ok((preamble, chat_history, message))
+}
+
+pub async fn handle_cohere_request(upstream: UpstreamRequest, frames: FrameWriter) -> Result<HttpResponse, ApiError> {
+    let response = upstream.send().await?;
+
+    // Cohere streams newline-delimited JSON events, which can span network chunks
+    let stream = response.bytes_stream();
+    let mut state = CohereStreamState { frames, ..Default::default() };
+    let unhandled = state.unhandled.clone();
+    // Stop at the first transport error, once the client has been told about it
+    let mut failed = false;
+    let ai_sdk_stream = stream.map_while(move |chunk_result| {
+        if failed {
+            return None;
+        }
+        Some(match chunk_result {
+            Ok(chunk) => {
+                chunk_info!("Cohere raw chunk: {}", String::from_utf8_lossy(&chunk));
+                state.buffer.extend_from_slice(&chunk);
+                let converted = convert_cohere_to_ai_sdk(&mut state);
+                if !converted.is_empty() {
+                    chunk_info!("Converted to AI SDK: {}", converted);
+                }
+                Ok::<Bytes, reqwest::Error>(Bytes::from(converted))
+            }
+            Err(e) => {
+                failed = true;
+                Ok(Bytes::from(frames.stream_error(&upstream_stream_error(&e), Usage::default())))
+            }
+        })
+    });
+
+    Ok(ai_sdk_stream_response(ai_sdk_stream, Provider::Cohere, unhandled, frames))
+}
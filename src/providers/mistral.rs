@@ -0,0 +1,116 @@
+//! Mistral (La Plateforme)
+
+use std::env;
+
+use actix_web::HttpResponse;
+use serde_json::json;
+use tokio_stream::StreamExt;
+use bytes::Bytes;
+use log::info;
+
+use crate::chunk_info;
+use crate::error::ApiError;
+use crate::request::{ChatRequest, ToolCallFormat, DEFAULT_TEMPERATURE};
+use crate::session::Session;
+use crate::convert::{
+    convert_mistral_to_ai_sdk, upstream_stream_error, FrameWriter, LineBuffer, MistralStreamState,
+    OpenAiStreamState,
+};
+use crate::stream::ai_sdk_stream_response;
+use crate::tools::create_tools;
+use super::{upstream_timeout, Provider, UpstreamRequest};
+use super::openai::{convert_messages_to_openai, convert_tools_to_openai};
+
+// Mistral (La Plateforme) speaks an OpenAI-like chat completions API, so requests are built from
+// the OpenAI message and tool conversions. It doesn't accept stream_options or the newer OpenAI
+// token fields, and its temperature range is 0.0..=1.5.
+pub fn mistral_base_url() -> String {
+    env::var("MISTRAL_BASE_URL")
+        .map(|url| url.trim_end_matches('/').to_string())
+        .unwrap_or_else(|_| "https://api.mistral.ai/v1".to_string())
+}
+
+pub fn build_mistral_request(request: ChatRequest) -> Result<UpstreamRequest, ApiError> {
+    let api_key = env::var("MISTRAL_API_KEY")
+        .map_err(|_| ApiError::internal("MISTRAL_API_KEY not set"))?;
+    let base_url = mistral_base_url();
+
+    let tools = convert_tools_to_openai(create_tools());
+    let messages = convert_messages_to_openai(request.messages);
+
+    let temperature = request.temperature.unwrap_or(DEFAULT_TEMPERATURE);
+    let clamped = temperature.clamp(0.0, 1.5);
+    if clamped != temperature {
+        info!("Clamped temperature {} to {} for Mistral", temperature, clamped);
+    }
+
+    let mut request_body = json!({
+        "model": request.model,
+        "messages": messages,
+        "temperature": clamped,
+        "stream": true
+    });
+    if let Some(max_tokens) = request.max_tokens {
+        request_body["max_tokens"] = json!(max_tokens);
+    }
+    if let Some(response_format) = &request.response_format {
+        request_body["response_format"] = json!(response_format);
+    }
+    if let Some(seed) = request.seed {
+        request_body["random_seed"] = json!(seed);
+    }
+    if !tools.is_empty() {
+        info!("Added {} tools to Mistral request", tools.len());
+        request_body["tools"] = json!(tools);
+        if let Some(tool_choice) = &request.tool_choice {
+            request_body["tool_choice"] = tool_choice.to_openai();
+        }
+    }
+
+    Ok(UpstreamRequest::new("Mistral", format!("{}/chat/completions", base_url), request_body)
+        .timeout(upstream_timeout(request.max_steps))
+        .header("Content-Type", "application/json")
+        .header("Authorization", format!("Bearer {}", api_key)))
+}
+
+pub async fn handle_mistral_request(
+    upstream: UpstreamRequest,
+    tool_call_format: ToolCallFormat,
+    tool_call_streaming: bool,
+    session: Session,
+    frames: FrameWriter,
+) -> Result<HttpResponse, ApiError> {
+    let response = upstream.send().await?;
+
+    let stream = response.bytes_stream();
+    let mut lines = LineBuffer::default();
+    let mut state = MistralStreamState {
+        openai: OpenAiStreamState::new(tool_call_format, tool_call_streaming, session, frames),
+        tool_calls_seen: 0,
+    };
+    let unhandled = state.openai.unhandled.clone();
+    // Stop at the first transport error, once the client has been told about it
+    let mut failed = false;
+    let ai_sdk_stream = stream.map_while(move |chunk_result| {
+        if failed {
+            return None;
+        }
+        Some(match chunk_result {
+            Ok(chunk) => {
+                chunk_info!("Mistral raw chunk: {}", String::from_utf8_lossy(&chunk));
+                let chunk_str = lines.push(&chunk);
+                let converted = convert_mistral_to_ai_sdk(&chunk_str, &mut state);
+                if !converted.is_empty() {
+                    chunk_info!("Converted to AI SDK: {}", converted);
+                }
+                Ok::<Bytes, reqwest::Error>(Bytes::from(converted))
+            }
+            Err(e) => {
+                failed = true;
+                Ok(Bytes::from(state.openai.fail(&upstream_stream_error(&e))))
+            }
+        })
+    });
+
+    Ok(ai_sdk_stream_response(ai_sdk_stream, Provider::Mistral, unhandled, frames))
+}
@@ -0,0 +1,743 @@
+//! Providers, routing a model to one, and sending requests to it
+
+pub mod anthropic;
+pub mod bedrock;
+pub mod cohere;
+pub mod mistral;
+pub mod openai;
+
+use std::env;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use actix_web::{HttpRequest, HttpResponse};
+use serde_json::{json, Value};
+use reqwest::Client;
+use log::{error, info, warn};
+
+use crate::error::ApiError;
+use crate::metrics::UPSTREAM_RATE_LIMIT_REMAINING;
+use crate::request::ChatRequest;
+use crate::session::Session;
+use crate::config::TellConfig;
+use crate::convert::FrameWriter;
+use anthropic::{anthropic_base_url, anthropic_version, build_anthropic_request, handle_anthropic_request};
+use bedrock::{build_bedrock_request, handle_bedrock_request};
+use cohere::{build_cohere_request, cohere_base_url, handle_cohere_request};
+use mistral::{build_mistral_request, handle_mistral_request, mistral_base_url};
+use openai::{build_openai_request, handle_openai_request, model_capabilities, openai_scope_headers};
+
+// Send the request to its provider and convert the response stream
+pub async fn stream_from_provider(
+    provider: Provider,
+    request: ChatRequest,
+    req: &HttpRequest,
+    config: &TellConfig,
+    frames: FrameWriter,
+) -> Result<HttpResponse, ApiError> {
+    #[cfg(feature = "test-stub")]
+    if let Some(events) = crate::stub::stub_script(&request.model)? {
+        return Ok(crate::stub::stub_response(events, frames));
+    }
+    let tool_call_format = request.tool_call_format;
+    let tool_call_streaming = request.tool_call_streaming;
+    let session = Session::new(request.session_id.as_deref());
+    let upstream = build_upstream_request(provider, request, openai_scope_headers(req), config)?;
+    match provider {
+        Provider::Anthropic => handle_anthropic_request(upstream, frames).await,
+        Provider::Bedrock => handle_bedrock_request(upstream, frames).await,
+        Provider::OpenAI(compatible) => {
+            handle_openai_request(upstream, compatible, tool_call_format, tool_call_streaming, session, frames).await
+        }
+        Provider::Mistral => {
+            handle_mistral_request(upstream, tool_call_format, tool_call_streaming, session, frames).await
+        }
+        Provider::Cohere => handle_cohere_request(upstream, frames).await,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Provider {
+    Anthropic,
+    Bedrock,
+    OpenAI(&'static OpenAiCompatible),
+    Mistral,
+    Cohere,
+}
+
+impl Provider {
+    pub fn name(self) -> &'static str {
+        match self {
+            Provider::Anthropic => "anthropic",
+            Provider::Bedrock => "bedrock",
+            Provider::OpenAI(compatible) => compatible.name,
+            Provider::Mistral => "mistral",
+            Provider::Cohere => "cohere",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthStyle {
+    // Authorization: Bearer <key>
+    Bearer,
+    // The key as-is in a provider-specific header
+    Header(&'static str),
+}
+
+// An OpenAI-compatible chat completions API. Models are routed to it with a "<prefix>/<model>"
+// name, and the prefix is stripped before the model is sent upstream.
+#[derive(Debug, PartialEq, Eq)]
+pub struct OpenAiCompatible {
+    name: &'static str,
+    prefix: &'static str,
+    base_url: &'static str,
+    auth: AuthStyle,
+    key_env: &'static str,
+}
+
+impl OpenAiCompatible {
+    // Base URL (including any /v1 prefix), overridable with <NAME>_BASE_URL
+    fn base_url(&self) -> String {
+        env::var(format!("{}_BASE_URL", self.name.to_uppercase()))
+            .map(|url| url.trim_end_matches('/').to_string())
+            .unwrap_or_else(|_| self.base_url.to_string())
+    }
+
+    // The model name as the provider knows it
+    pub fn upstream_model<'a>(&self, model: &'a str) -> &'a str {
+        if model.len() >= self.prefix.len() && model[..self.prefix.len()].eq_ignore_ascii_case(self.prefix) {
+            &model[self.prefix.len()..]
+        } else {
+            model
+        }
+    }
+}
+
+// Unprefixed OpenAI model names (gpt-4o, o3-mini, ...) also route here
+pub const OPENAI: OpenAiCompatible = OpenAiCompatible {
+    name: "openai",
+    prefix: "openai/",
+    base_url: "https://api.openai.com/v1",
+    auth: AuthStyle::Bearer,
+    key_env: "OPENAI_API_KEY",
+};
+
+// Adding an OpenAI-compatible provider is a new entry here
+pub const OPENAI_COMPATIBLE_PROVIDERS: &[OpenAiCompatible] = &[
+    OPENAI,
+    OpenAiCompatible {
+        name: "groq",
+        prefix: "groq/",
+        base_url: "https://api.groq.com/openai/v1",
+        auth: AuthStyle::Bearer,
+        key_env: "GROQ_API_KEY",
+    },
+    OpenAiCompatible {
+        name: "together",
+        prefix: "together/",
+        base_url: "https://api.together.xyz/v1",
+        auth: AuthStyle::Bearer,
+        key_env: "TOGETHER_API_KEY",
+    },
+    OpenAiCompatible {
+        name: "fireworks",
+        prefix: "fireworks/",
+        base_url: "https://api.fireworks.ai/inference/v1",
+        auth: AuthStyle::Bearer,
+        key_env: "FIREWORKS_API_KEY",
+    },
+];
+
+// Every provider, in the order they are listed in errors
+pub fn known_providers() -> impl Iterator<Item = Provider> {
+    [Provider::Anthropic, Provider::Bedrock]
+        .into_iter()
+        .chain(OPENAI_COMPATIBLE_PROVIDERS.iter().map(Provider::OpenAI))
+        .chain([Provider::Mistral, Provider::Cohere])
+}
+
+// Model name prefixes we know how to route, checked case-insensitively
+pub const MODEL_PREFIXES: &[(&str, Provider)] = &[
+    ("claude", Provider::Anthropic),
+    ("gpt-", Provider::OpenAI(&OPENAI)),
+    ("chatgpt-", Provider::OpenAI(&OPENAI)),
+    ("o1", Provider::OpenAI(&OPENAI)),
+    ("o3", Provider::OpenAI(&OPENAI)),
+    ("o4", Provider::OpenAI(&OPENAI)),
+    ("mistral-", Provider::Mistral),
+    ("codestral-", Provider::Mistral),
+    ("command-", Provider::Cohere),
+];
+
+#[derive(Debug)]
+pub enum ProviderError {
+    UnknownModel(String),
+    UnknownProvider(String),
+}
+
+impl fmt::Display for ProviderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProviderError::UnknownModel(model) => {
+                let prefixes: Vec<&str> = OPENAI_COMPATIBLE_PROVIDERS
+                    .iter()
+                    .map(|compatible| compatible.prefix)
+                    .chain(MODEL_PREFIXES.iter().map(|(prefix, _)| *prefix))
+                    .collect();
+                write!(
+                    f,
+                    "Unknown model '{}': no provider matches. Recognized model prefixes: {}",
+                    model,
+                    prefixes.join(", ")
+                )
+            }
+            ProviderError::UnknownProvider(name) => {
+                let names: Vec<&str> = known_providers().map(Provider::name).collect();
+                write!(f, "Unknown provider '{}'. Known providers: {}", name, names.join(", "))
+            }
+        }
+    }
+}
+
+impl From<ProviderError> for ApiError {
+    fn from(e: ProviderError) -> Self {
+        ApiError::bad_request(e.to_string())
+    }
+}
+
+// The provider a model routes to, and a description of the rule that matched
+#[derive(Debug)]
+pub struct Route {
+    pub provider: Provider,
+    pub rule: String,
+}
+
+pub fn resolve_provider(model: &str) -> Result<Route, ProviderError> {
+    // Explicit "<provider>/<model>" names for OpenAI-compatible providers
+    let model_lower = model.to_lowercase();
+    #[cfg(feature = "test-stub")]
+    if model_lower.starts_with(crate::stub::STUB_PREFIX) {
+        return Ok(Route {
+            provider: Provider::Anthropic,
+            rule: format!("stub prefix '{}'", crate::stub::STUB_PREFIX),
+        });
+    }
+    if let Some(compatible) = OPENAI_COMPATIBLE_PROVIDERS
+        .iter()
+        .find(|compatible| model_lower.starts_with(compatible.prefix))
+    {
+        return Ok(Route {
+            provider: Provider::OpenAI(compatible),
+            rule: format!("provider prefix '{}'", compatible.prefix),
+        });
+    }
+
+    // Claude through AWS Bedrock when a region is configured and a Bedrock model id is used
+    if env::var("BEDROCK_REGION").is_ok() && is_bedrock_model_id(model) {
+        return Ok(Route {
+            provider: Provider::Bedrock,
+            rule: "Bedrock model id with BEDROCK_REGION set".to_string(),
+        });
+    }
+
+    MODEL_PREFIXES
+        .iter()
+        .find(|(prefix, _)| model_lower.starts_with(prefix))
+        .map(|(prefix, provider)| Route {
+            provider: *provider,
+            rule: format!("model prefix '{}'", prefix),
+        })
+        .ok_or_else(|| {
+            error!("No provider matches model '{}'", model);
+            ProviderError::UnknownModel(model.to_string())
+        })
+}
+
+// The X-Provider header forces a provider, skipping model-based routing
+pub fn provider_override(req: &HttpRequest) -> Result<Option<Route>, ProviderError> {
+    let Some(name) = req.headers().get("X-Provider").and_then(|v| v.to_str().ok()) else {
+        return Ok(None);
+    };
+    let name = name.trim();
+    known_providers()
+        .find(|provider| provider.name().eq_ignore_ascii_case(name))
+        .map(|provider| {
+            Some(Route {
+                provider,
+                rule: "X-Provider header".to_string(),
+            })
+        })
+        .ok_or_else(|| ProviderError::UnknownProvider(name.to_string()))
+}
+
+// Bedrock model ids look like "anthropic.claude-3-5-sonnet-20241022-v2:0", optionally
+// prefixed with a cross-region inference profile ("us.", "eu.", ...) or given as a full ARN
+pub fn is_bedrock_model_id(model: &str) -> bool {
+    model.starts_with("arn:aws:bedrock:") || model.contains("anthropic.claude")
+}
+
+// Total time allowed for an upstream call, including streaming the whole response.
+// Agentic requests (maxSteps > 1) get the larger AGENTIC_TIMEOUT_SECS budget.
+pub fn upstream_timeout(max_steps: Option<u32>) -> Duration {
+    let (var, default_secs) = if max_steps.is_some_and(|steps| steps > 1) {
+        ("AGENTIC_TIMEOUT_SECS", 600)
+    } else {
+        ("UPSTREAM_TIMEOUT_SECS", 120)
+    };
+    let secs = env::var(var)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(default_secs);
+    Duration::from_secs(secs)
+}
+
+// Whether the credentials `provider` needs are set. Bedrock takes its credentials from the AWS
+// chain, so a region is all that can be checked here.
+pub fn provider_configured(provider: Provider) -> bool {
+    let set = |name: &str| env::var(name).is_ok_and(|value| !value.trim().is_empty());
+    match provider {
+        Provider::Anthropic => set("ANTHROPIC_API_KEY"),
+        Provider::Bedrock => set("BEDROCK_REGION"),
+        Provider::OpenAI(compatible) if *compatible == OPENAI => {
+            set(compatible.key_env) || (set("AZURE_OPENAI_ENDPOINT") && set("AZURE_OPENAI_KEY"))
+        }
+        Provider::OpenAI(compatible) => set(compatible.key_env),
+        Provider::Mistral => set("MISTRAL_API_KEY"),
+        Provider::Cohere => set("COHERE_API_KEY"),
+    }
+}
+
+// Read and check the per-provider headers sent with every request (ANTHROPIC_VERSION,
+// OPENAI_ORGANIZATION, OPENAI_PROJECT) and log the values in effect
+pub fn load_provider_headers() -> Result<(), String> {
+    let version = anthropic::load_anthropic_version()?;
+    info!("Anthropic API version: {}", version);
+    for (header, value) in openai::check_openai_scope()? {
+        info!("OpenAI {}: {}", header, value);
+    }
+    Ok(())
+}
+
+lazy_static::lazy_static! {
+    // One client for every upstream call, so DNS lookups and TLS connections are pooled
+    // across requests instead of being set up for each one
+    pub static ref HTTP_CLIENT: Client = Client::builder()
+        .connect_timeout(connect_timeout())
+        .read_timeout(read_timeout())
+        .build()
+        .expect("build the upstream HTTP client");
+}
+
+// Time allowed to open a connection to a provider, CONNECT_TIMEOUT_SECS (default 10), so an
+// unreachable upstream fails fast instead of using up the whole request timeout
+fn connect_timeout() -> Duration {
+    Duration::from_secs(env::var("CONNECT_TIMEOUT_SECS").ok().and_then(|v| v.parse().ok()).unwrap_or(10))
+}
+
+// Longest silence allowed while waiting for a response or between two chunks of a stream,
+// READ_TIMEOUT_SECS (default 120). A stalled stream ends with a timeout_error frame.
+fn read_timeout() -> Duration {
+    Duration::from_secs(env::var("READ_TIMEOUT_SECS").ok().and_then(|v| v.parse().ok()).unwrap_or(120))
+}
+
+// With WARMUP=true, list the models of every provider that has a key configured. This opens
+// a pooled connection to each before the first chat request, and a rejected key shows up in
+// the startup logs. Bedrock (signed requests) and Azure OpenAI aren't warmed up.
+pub async fn warm_up_providers() {
+    let mut targets = Vec::new();
+    if let Ok(key) = env::var("ANTHROPIC_API_KEY") {
+        let req = HTTP_CLIENT
+            .get(format!("{}/v1/models", anthropic_base_url()))
+            .header("x-api-key", key)
+            .header("anthropic-version", anthropic_version());
+        targets.push(("anthropic", req));
+    }
+    for compatible in OPENAI_COMPATIBLE_PROVIDERS {
+        if let Ok(key) = env::var(compatible.key_env) {
+            let req = HTTP_CLIENT.get(format!("{}/models", compatible.base_url()));
+            let req = match compatible.auth {
+                AuthStyle::Bearer => req.bearer_auth(key),
+                AuthStyle::Header(name) => req.header(name, key),
+            };
+            targets.push((compatible.name, req));
+        }
+    }
+    if let Ok(key) = env::var("MISTRAL_API_KEY") {
+        targets.push(("mistral", HTTP_CLIENT.get(format!("{}/models", mistral_base_url())).bearer_auth(key)));
+    }
+    if let Ok(key) = env::var("COHERE_API_KEY") {
+        targets.push(("cohere", HTTP_CLIENT.get(format!("{}/models", cohere_base_url())).bearer_auth(key)));
+    }
+
+    let warmups = targets.into_iter().map(|(name, req)| async move {
+        let started = Instant::now();
+        match req.timeout(Duration::from_secs(10)).send().await {
+            Ok(response) if response.status().is_success() => {
+                info!("Warmed up {} in {:?}", name, started.elapsed());
+            }
+            Ok(response) if matches!(response.status().as_u16(), 401 | 403) => {
+                warn!("Warmup: {} rejected the API key ({})", name, response.status());
+            }
+            Ok(response) => warn!("Warmup: {} answered {}", name, response.status()),
+            Err(e) => warn!("Warmup: couldn't reach {}: {}", name, e),
+        }
+    });
+    futures::future::join_all(warmups).await;
+}
+
+// A provider request, fully built but not yet sent. Keeping the two apart lets dry runs
+// return exactly what would be sent upstream.
+#[derive(Debug)]
+pub struct UpstreamRequest {
+    // Provider name used in logs and error messages
+    name: &'static str,
+    url: String,
+    headers: Vec<(String, String)>,
+    pub body: Value,
+    timeout: Option<Duration>,
+}
+
+// Upstream rate-limit headers passed back to the client, as (upstream header, client header,
+// gauge label). OpenAI-compatible providers send x-ratelimit-remaining-<limit>, Anthropic
+// anthropic-ratelimit-<limit>-remaining.
+pub const RATE_LIMIT_HEADERS: &[(&str, &str, &str)] = &[
+    ("x-ratelimit-remaining-requests", "x-upstream-ratelimit-remaining-requests", "requests"),
+    ("x-ratelimit-remaining-tokens", "x-upstream-ratelimit-remaining-tokens", "tokens"),
+    ("anthropic-ratelimit-requests-remaining", "x-upstream-ratelimit-remaining-requests", "requests"),
+    ("anthropic-ratelimit-tokens-remaining", "x-upstream-ratelimit-remaining-tokens", "tokens"),
+    ("anthropic-ratelimit-input-tokens-remaining", "x-upstream-ratelimit-remaining-input-tokens", "input_tokens"),
+    ("anthropic-ratelimit-output-tokens-remaining", "x-upstream-ratelimit-remaining-output-tokens", "output_tokens"),
+];
+
+// The rate-limit headers of an upstream response, renamed for the client, plus its retry-after
+// as x-upstream-retry-after. Remaining counts also go to the api_upstream_ratelimit_remaining gauge.
+pub struct RateLimitHeaders(Vec<(&'static str, String)>);
+
+impl RateLimitHeaders {
+    pub fn from_response(provider: Provider, response: &reqwest::Response) -> Self {
+        let header = |name: &str| response.headers().get(name).and_then(|v| v.to_str().ok());
+        let mut headers = Vec::new();
+        for (upstream_name, client_name, limit) in RATE_LIMIT_HEADERS {
+            let Some(value) = header(upstream_name) else {
+                continue;
+            };
+            if let Ok(remaining) = value.parse::<i64>() {
+                UPSTREAM_RATE_LIMIT_REMAINING.with_label_values(&[provider.name(), limit]).set(remaining);
+            }
+            headers.push((*client_name, value.to_string()));
+        }
+        if let Some(retry_after) = header("retry-after") {
+            headers.push(("x-upstream-retry-after", retry_after.to_string()));
+        }
+        RateLimitHeaders(headers)
+    }
+
+    pub fn apply(self, response: &mut HttpResponse) {
+        for (name, value) in self.0 {
+            if let Ok(value) = actix_web::http::header::HeaderValue::from_str(&value) {
+                response.headers_mut().insert(actix_web::http::header::HeaderName::from_static(name), value);
+            }
+        }
+    }
+}
+
+// Upstream statuses worth retrying: transient server errors, plus Anthropic's 529 overloaded
+pub const RETRYABLE_STATUSES: &[u16] = &[500, 502, 503, 504, 529];
+
+// Retries after the first attempt, UPSTREAM_RETRIES (2 by default)
+pub fn upstream_retries() -> u32 {
+    env::var("UPSTREAM_RETRIES").ok().and_then(|v| v.parse().ok()).unwrap_or(2)
+}
+
+// UPSTREAM_RETRY_BASE_MS (500 by default), doubled for each further retry
+pub fn retry_delay(attempt: u32) -> Duration {
+    let base: u64 = env::var("UPSTREAM_RETRY_BASE_MS").ok().and_then(|v| v.parse().ok()).unwrap_or(500);
+    Duration::from_millis(base.saturating_mul(1 << attempt.min(16)))
+}
+
+// Credential headers, redacted in dry-run output
+pub const SECRET_HEADERS: &[&str] = &["authorization", "x-api-key", "api-key"];
+
+impl UpstreamRequest {
+    fn new(name: &'static str, url: String, body: Value) -> Self {
+        UpstreamRequest { name, url, headers: Vec::new(), body, timeout: None }
+    }
+
+    fn header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    // Add a header, replacing any existing one of the same name
+    pub fn set_header(&mut self, name: &str, value: &str) {
+        self.headers.retain(|(existing, _)| !existing.eq_ignore_ascii_case(name));
+        self.headers.push((name.to_string(), value.to_string()));
+    }
+
+    fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    fn body_bytes(&self) -> Result<Vec<u8>, ApiError> {
+        serde_json::to_vec(&self.body)
+            .map_err(|e| ApiError::internal(format!("Failed to encode request: {}", e)))
+    }
+
+    // What a dry run returns: the provider, URL, headers (credentials redacted) and body
+    pub fn dry_run_json(&self, provider: Provider) -> Value {
+        let headers: serde_json::Map<String, Value> = self
+            .headers
+            .iter()
+            .map(|(name, value)| {
+                let value = if SECRET_HEADERS.contains(&name.to_lowercase().as_str()) {
+                    "[redacted]"
+                } else {
+                    value.as_str()
+                };
+                (name.clone(), json!(value))
+            })
+            .collect();
+        json!({
+            "provider": provider.name(),
+            "url": self.url,
+            "headers": headers,
+            "body": self.body,
+        })
+    }
+
+    // Send the request, retrying retryable statuses with exponential backoff. Connection
+    // failures and other non-2xx statuses become 502s; a provider still overloaded (529) after
+    // the retries becomes an overloaded_error.
+    async fn send(self) -> Result<reqwest::Response, ApiError> {
+        info!("Sending request to {}: {}", self.name, serde_json::to_string_pretty(&self.body).unwrap_or_default());
+        let body = self.body_bytes()?;
+        let retries = upstream_retries();
+
+        let mut attempt = 0;
+        loop {
+            let mut req = HTTP_CLIENT.post(&self.url);
+            for (name, value) in &self.headers {
+                req = req.header(name, value);
+            }
+            if let Some(timeout) = self.timeout {
+                req = req.timeout(timeout);
+            }
+
+            let response = req
+                .body(body.clone())
+                .send()
+                .await
+                .map_err(|e| {
+                    error!("Failed to call {} API: {}", self.name, e);
+                    ApiError::bad_gateway(format!("{} API error: {}", self.name, e))
+                })?;
+
+            let status = response.status();
+            if status.is_success() {
+                return Ok(response);
+            }
+            let retry_after = response
+                .headers()
+                .get("retry-after")
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.trim().parse::<u64>().ok());
+            let error_text = response.text().await.unwrap_or_default();
+            if RETRYABLE_STATUSES.contains(&status.as_u16()) && attempt < retries {
+                let delay = retry_delay(attempt);
+                warn!("{} API returned {}, retrying in {:?} ({}/{})", self.name, status, delay, attempt + 1, retries);
+                actix_web::rt::time::sleep(delay).await;
+                attempt += 1;
+                continue;
+            }
+
+            error!("{} API error {}: {}", self.name, status, error_text);
+            if status.as_u16() == 529 {
+                return Err(ApiError::upstream_overloaded(self.name));
+            }
+            // Pass on when the provider says to try again, e.g. on a 429
+            return Err(ApiError {
+                retry_after,
+                ..ApiError::bad_gateway(format!("{} API error: {}", self.name, status))
+            });
+        }
+    }
+}
+
+// The provider request for `request`, with the TELL_CONFIG overrides for its provider and model
+pub fn build_upstream_request(
+    provider: Provider,
+    request: ChatRequest,
+    scope_headers: Vec<(&'static str, String)>,
+    config: &TellConfig,
+) -> Result<UpstreamRequest, ApiError> {
+    let model = request.model.clone();
+    let client_fields = client_set_fields(&request);
+    let extra_headers = checked_extra_headers(provider, &request)?;
+    if request.seed.is_some() && matches!(provider, Provider::Anthropic | Provider::Bedrock | Provider::Cohere) {
+        info!("Ignoring seed for {}, which doesn't support it", provider.name());
+    }
+    let mut upstream = match provider {
+        Provider::Anthropic => build_anthropic_request(request),
+        Provider::Bedrock => build_bedrock_request(request),
+        Provider::OpenAI(compatible) => build_openai_request(request, compatible, scope_headers),
+        Provider::Mistral => build_mistral_request(request),
+        Provider::Cohere => build_cohere_request(request),
+    }?;
+    for (name, value) in &extra_headers {
+        upstream.set_header(name, value);
+    }
+    config.apply(provider, &model, &client_fields, &mut upstream);
+    check_constraints(provider, &model, &upstream.body)?;
+    Ok(upstream)
+}
+
+// A rule a provider enforces on the request body. The assembled request, config overrides
+// included, is checked against them, so a combination the provider would reject is a 400 that
+// says why instead of the provider's own error.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Constraint {
+    Required(&'static str),
+    // The field isn't accepted, and the one to use instead, if any
+    Rejected(&'static str, Option<&'static str>),
+    // Either field, not both
+    Exclusive(&'static str, &'static str),
+    // The first field is only accepted alongside the second
+    Needs(&'static str, &'static str),
+    Range(&'static str, f64, f64),
+}
+
+const CLAUDE: &[&str] = &["anthropic", "bedrock"];
+
+// Constraints by provider ("openai" covers every OpenAI-compatible provider) and model prefix.
+// OpenAI-compatible models also get the ones their capability table entry implies.
+const PARAMETER_CONSTRAINTS: &[(&[&str], &str, Constraint)] = &[
+    (CLAUDE, "", Constraint::Required("max_tokens")),
+    (CLAUDE, "", Constraint::Range("temperature", 0.0, 1.0)),
+    // Claude Sonnet 4.5, Haiku 4.5 and Opus 4.1 take a temperature or top_p, not both
+    (CLAUDE, "claude-sonnet-4-5", Constraint::Exclusive("temperature", "top_p")),
+    (CLAUDE, "claude-haiku-4-5", Constraint::Exclusive("temperature", "top_p")),
+    (CLAUDE, "claude-opus-4-1", Constraint::Exclusive("temperature", "top_p")),
+    (&["openai"], "", Constraint::Range("temperature", 0.0, 2.0)),
+    (&["openai"], "", Constraint::Needs("tool_choice", "tools")),
+    (&["openai"], "", Constraint::Needs("parallel_tool_calls", "tools")),
+    (&["openai"], "", Constraint::Needs("top_logprobs", "logprobs")),
+    (&["mistral"], "", Constraint::Range("temperature", 0.0, 1.5)),
+    (&["cohere"], "", Constraint::Range("temperature", 0.0, 1.0)),
+];
+
+// Every constraint on requests for `model` on `provider`
+fn model_constraints(provider: Provider, model: &str) -> Vec<Constraint> {
+    let (key, model) = match provider {
+        Provider::OpenAI(compatible) => ("openai", compatible.upstream_model(model).to_lowercase()),
+        // Bedrock ids carry a region and vendor prefix: us.anthropic.claude-sonnet-4-5-...
+        Provider::Bedrock => ("bedrock", model.rsplit("anthropic.").next().unwrap_or(model).to_lowercase()),
+        provider => (provider.name(), model.to_lowercase()),
+    };
+    let mut constraints: Vec<Constraint> = PARAMETER_CONSTRAINTS
+        .iter()
+        .filter(|(providers, prefix, _)| providers.contains(&key) && model.starts_with(prefix))
+        .map(|(_, _, constraint)| *constraint)
+        .collect();
+    if key == "openai" {
+        constraints.extend(model_capabilities(&model).constraints());
+    }
+    constraints
+}
+
+fn check_constraints(provider: Provider, model: &str, body: &Value) -> Result<(), ApiError> {
+    let set = |field: &str| body.get(field).is_some_and(|value| !value.is_null());
+    let problems: Vec<String> = model_constraints(provider, model)
+        .into_iter()
+        .filter_map(|constraint| match constraint {
+            Constraint::Required(field) if !set(field) => Some(format!("{} is required", field)),
+            Constraint::Rejected(field, instead) if set(field) => Some(match instead {
+                Some(instead) => format!("{} isn't accepted; use {} instead", field, instead),
+                None => format!("{} isn't accepted", field),
+            }),
+            Constraint::Exclusive(first, second) if set(first) && set(second) => {
+                Some(format!("{} and {} can't be set together", first, second))
+            }
+            Constraint::Needs(field, needed) if set(field) && !set(needed) => {
+                Some(format!("{} is only accepted with {}", field, needed))
+            }
+            Constraint::Range(field, min, max) => match body.get(field).and_then(Value::as_f64) {
+                Some(value) if value < min || value > max => {
+                    Some(format!("{} must be between {} and {}, not {}", field, min, max, value))
+                }
+                _ => None,
+            },
+            _ => None,
+        })
+        .collect();
+    if problems.is_empty() {
+        return Ok(());
+    }
+    Err(ApiError::bad_request(format!(
+        "The request for {} on {} would be rejected: {}",
+        model,
+        provider.name(),
+        problems.join("; ")
+    )))
+}
+
+// Headers the server always sets itself, which extraHeaders can't replace even when allowed
+const RESERVED_HEADERS: &[&str] = &["content-type", "content-length", "host"];
+
+// The client's extraHeaders for `provider` that ALLOWED_UPSTREAM_HEADERS (comma-separated,
+// case-insensitive, empty by default) permits. Others are dropped with a warning.
+fn checked_extra_headers(provider: Provider, request: &ChatRequest) -> Result<Vec<(String, String)>, ApiError> {
+    if let Some(unknown) = request.extra_headers.keys().find(|name| known_providers().all(|p| p.name() != *name)) {
+        let names: Vec<&str> = known_providers().map(Provider::name).collect();
+        return Err(ApiError::bad_request(format!(
+            "extraHeaders has unknown provider '{}'. Known providers: {}",
+            unknown,
+            names.join(", ")
+        )));
+    }
+    let Some(headers) = request.extra_headers.get(provider.name()) else {
+        return Ok(Vec::new());
+    };
+    let allowed = env::var("ALLOWED_UPSTREAM_HEADERS").unwrap_or_default();
+    let allowed: Vec<String> = allowed.split(',').map(|name| name.trim().to_lowercase()).collect();
+
+    let mut checked = Vec::new();
+    for (name, value) in headers {
+        let lower = name.to_lowercase();
+        let reserved = SECRET_HEADERS.contains(&lower.as_str()) || RESERVED_HEADERS.contains(&lower.as_str());
+        if reserved || !allowed.contains(&lower) {
+            warn!("Dropping extra header '{}' for {}: not on ALLOWED_UPSTREAM_HEADERS", name, provider.name());
+            continue;
+        }
+        if reqwest::header::HeaderValue::from_str(value).is_err() {
+            return Err(ApiError::bad_request(format!("extraHeaders: invalid value for header '{}'", name)));
+        }
+        checked.push((lower, value.clone()));
+    }
+    Ok(checked)
+}
+
+// Upstream body fields the client set explicitly, which config overrides leave alone
+pub fn client_set_fields(request: &ChatRequest) -> Vec<&'static str> {
+    let mut fields = Vec::new();
+    if request.temperature.is_some() {
+        fields.push("temperature");
+    }
+    if request.max_tokens.is_some() {
+        fields.extend(["max_tokens", "max_completion_tokens"]);
+    }
+    if request.response_format.is_some() {
+        fields.push("response_format");
+    }
+    if request.tool_choice.is_some() {
+        fields.push("tool_choice");
+    }
+    if request.parallel_tool_calls.is_some() {
+        fields.push("parallel_tool_calls");
+    }
+    if request.seed.is_some() {
+        fields.extend(["seed", "random_seed"]);
+    }
+    fields
+}
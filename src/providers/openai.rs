@@ -0,0 +1,518 @@
+//! OpenAI and OpenAI-compatible chat completions APIs
+
+use std::env;
+
+use actix_web::{HttpRequest, HttpResponse};
+use serde_json::{json, Value};
+use tokio_stream::StreamExt;
+use bytes::Bytes;
+use log::info;
+
+use crate::chunk_info;
+use crate::error::ApiError;
+use crate::request::{ChatMessage, ChatRequest, ToolCallFormat, DEFAULT_TEMPERATURE};
+use crate::session::Session;
+use crate::convert::{
+    convert_openai_to_ai_sdk, upstream_stream_error, FrameWriter, LineBuffer, OpenAiStreamState,
+};
+use crate::stream::ai_sdk_stream_response;
+use crate::tools::{create_tools, Tool, ToolChoice, ToolChoiceMode};
+use super::{
+    upstream_timeout, AuthStyle, Constraint, OpenAiCompatible, Provider, RateLimitHeaders, UpstreamRequest,
+    OPENAI,
+};
+
+// OpenAI rejects temperatures outside 0.0..=2.0, so clamp instead of forwarding a 400
+pub fn openai_temperature(requested: Option<f32>) -> f32 {
+    let temperature = requested.unwrap_or(DEFAULT_TEMPERATURE);
+    let clamped = temperature.clamp(0.0, 2.0);
+    if clamped != temperature {
+        info!("Clamped temperature {} to {}", temperature, clamped);
+    }
+    clamped
+}
+
+// OpenAI-Organization / OpenAI-Project billing scope headers, from OPENAI_ORGANIZATION and
+// OPENAI_PROJECT. With ALLOW_OPENAI_SCOPE_OVERRIDE=true a request may set its own values by
+// sending those same headers; no other client headers are forwarded.
+pub fn openai_scope_headers(req: &HttpRequest) -> Vec<(&'static str, String)> {
+    let allow_override = env::var("ALLOW_OPENAI_SCOPE_OVERRIDE")
+        .map(|v| v.eq_ignore_ascii_case("true") || v == "1")
+        .unwrap_or(false);
+
+    [("OpenAI-Organization", "OPENAI_ORGANIZATION"), ("OpenAI-Project", "OPENAI_PROJECT")]
+        .into_iter()
+        .filter_map(|(header, var)| {
+            let from_request = allow_override
+                .then(|| req.headers().get(header).and_then(|v| v.to_str().ok()))
+                .flatten()
+                .filter(|v| !v.is_empty())
+                .map(str::to_string);
+            let value = from_request.or_else(|| env::var(var).ok().filter(|v| !v.is_empty()))?;
+            Some((header, value))
+        })
+        .collect()
+}
+
+// Check OPENAI_ORGANIZATION and OPENAI_PROJECT at startup, since a value that can't be sent as a
+// header would otherwise fail every OpenAI request. Returns the ones that are set.
+pub fn check_openai_scope() -> Result<Vec<(&'static str, String)>, String> {
+    let mut scope = Vec::new();
+    for (header, var) in [("OpenAI-Organization", "OPENAI_ORGANIZATION"), ("OpenAI-Project", "OPENAI_PROJECT")] {
+        let Some(value) = env::var(var).ok().filter(|v| !v.is_empty()) else {
+            continue;
+        };
+        if reqwest::header::HeaderValue::from_str(&value).is_err() || value.trim() != value {
+            return Err(format!("{} can't be sent as the {} header", var, header));
+        }
+        scope.push((header, value));
+    }
+    Ok(scope)
+}
+
+// What to do with the tools for a model that can't take them, per UNSUPPORTED_TOOLS: "drop"
+// sends the request without them (the default), "reject" answers with a 400, and "prompt"
+// describes them in a system message instead, for the model to answer with a call in text
+fn handle_unsupported_tools(request: &mut ChatRequest, tools: &[Tool]) -> Result<(), ApiError> {
+    let policy = env::var("UNSUPPORTED_TOOLS").unwrap_or_default().to_lowercase();
+    match policy.as_str() {
+        "reject" => Err(ApiError::bad_request(format!(
+            "Model {} can't use tools. Send toolChoice \"none\" to call it without them.",
+            request.model
+        ))),
+        "prompt" => {
+            info!("Model {} can't use tools; describing them in the system prompt instead", request.model);
+            let position = request.messages.iter().take_while(|message| message.role == "system").count();
+            let message = ChatMessage {
+                role: "system".to_string(),
+                content: Some(tools_prompt(tools)),
+                ..Default::default()
+            };
+            request.messages.insert(position, message);
+            Ok(())
+        }
+        _ => {
+            info!("Model {} can't use tools; sending the request without them", request.model);
+            Ok(())
+        }
+    }
+}
+
+// The tools as a text protocol: each tool with its parameters, and how to call one
+fn tools_prompt(tools: &[Tool]) -> String {
+    let mut prompt = String::from("You can't call tools directly, but these tools are available:\n");
+    for tool in tools {
+        prompt.push_str(&format!(
+            "\n- {}: {}\n  Parameters (JSON schema): {}\n",
+            tool.name,
+            tool.description,
+            json!(tool.input_schema)
+        ));
+    }
+    prompt.push_str(
+        "\nTo use a tool, reply with only a JSON object of the form {\"tool\": \"<name>\", \"args\": {...}} \
+         and nothing else. The result will be sent back to you. Otherwise, answer normally.",
+    );
+    prompt
+}
+
+// What an OpenAI-compatible model accepts. Models not in MODEL_CAPABILITIES support everything.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ModelCapabilities {
+    supports_temperature: bool,
+    pub supports_tools: bool,
+    pub supports_system_role: bool,
+    supports_streaming: bool,
+    supports_logprobs: bool,
+    // Reasoning and newer models reject max_tokens and take max_completion_tokens instead
+    uses_max_completion_tokens: bool,
+}
+
+impl ModelCapabilities {
+    const FULL: ModelCapabilities = ModelCapabilities {
+        supports_temperature: true,
+        supports_tools: true,
+        supports_system_role: true,
+        supports_streaming: true,
+        supports_logprobs: true,
+        uses_max_completion_tokens: false,
+    };
+    const REASONING: ModelCapabilities = ModelCapabilities {
+        supports_temperature: false,
+        supports_tools: false,
+        supports_logprobs: false,
+        uses_max_completion_tokens: true,
+        ..ModelCapabilities::FULL
+    };
+
+    // The request field for the completion token limit
+    pub fn max_tokens_field(&self) -> &'static str {
+        if self.uses_max_completion_tokens {
+            "max_completion_tokens"
+        } else {
+            "max_tokens"
+        }
+    }
+
+    // The body fields the model rejects, for checking requests whose overrides add them back
+    pub fn constraints(&self) -> Vec<Constraint> {
+        let mut constraints = Vec::new();
+        if !self.supports_temperature {
+            constraints.extend([Constraint::Rejected("temperature", None), Constraint::Rejected("top_p", None)]);
+        }
+        if !self.supports_logprobs {
+            constraints.extend([Constraint::Rejected("logprobs", None), Constraint::Rejected("top_logprobs", None)]);
+        }
+        if self.uses_max_completion_tokens {
+            constraints.push(Constraint::Rejected("max_tokens", Some("max_completion_tokens")));
+        }
+        constraints
+    }
+}
+
+// Capability quirks by model prefix; the longest matching prefix wins
+pub const MODEL_CAPABILITIES: &[(&str, ModelCapabilities)] = &[
+    ("o1", ModelCapabilities::REASONING),
+    ("o1-pro", ModelCapabilities { supports_streaming: false, ..ModelCapabilities::REASONING }),
+    ("o3", ModelCapabilities::REASONING),
+    ("o3-pro", ModelCapabilities { supports_streaming: false, ..ModelCapabilities::REASONING }),
+    ("o4", ModelCapabilities { uses_max_completion_tokens: true, ..ModelCapabilities::FULL }),
+    (
+        "gpt-5",
+        ModelCapabilities {
+            supports_temperature: false,
+            supports_logprobs: false,
+            uses_max_completion_tokens: true,
+            ..ModelCapabilities::FULL
+        },
+    ),
+];
+
+// The first o1 releases reject the system role; NO_SYSTEM_ROLE_MODELS overrides the list
+pub const DEFAULT_NO_SYSTEM_ROLE_MODELS: &str = "o1-mini,o1-preview";
+
+pub fn model_capabilities(model: &str) -> ModelCapabilities {
+    let model = model.to_lowercase();
+    let mut capabilities = MODEL_CAPABILITIES
+        .iter()
+        .filter(|(prefix, _)| model.starts_with(prefix))
+        .max_by_key(|(prefix, _)| prefix.len())
+        .map_or(ModelCapabilities::FULL, |(_, capabilities)| *capabilities);
+
+    let no_system_role = env::var("NO_SYSTEM_ROLE_MODELS")
+        .unwrap_or_else(|_| DEFAULT_NO_SYSTEM_ROLE_MODELS.to_string());
+    capabilities.supports_system_role = !no_system_role
+        .split(',')
+        .map(|prefix| prefix.trim().to_lowercase())
+        .any(|prefix| !prefix.is_empty() && model.starts_with(&prefix));
+
+    // MAX_COMPLETION_TOKENS_MODELS, when set, replaces the table's choice of token limit field
+    if let Ok(prefixes) = env::var("MAX_COMPLETION_TOKENS_MODELS") {
+        capabilities.uses_max_completion_tokens = prefixes
+            .split(',')
+            .map(|prefix| prefix.trim().to_lowercase())
+            .any(|prefix| !prefix.is_empty() && model.starts_with(&prefix));
+    }
+    capabilities
+}
+
+// For models without the system role: system messages are folded into the first user message
+pub fn fold_system_messages(messages: Vec<Value>) -> Vec<Value> {
+    let (system, mut messages): (Vec<Value>, Vec<Value>) =
+        messages.into_iter().partition(|message| message["role"] == "system");
+    let system_text = system
+        .iter()
+        .filter_map(|message| message["content"].as_str())
+        .filter(|content| !content.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n\n");
+    if system_text.is_empty() {
+        return messages;
+    }
+
+    match messages.iter_mut().find(|message| message["role"] == "user") {
+        Some(user) => {
+            user["content"] = match user["content"].take() {
+                Value::String(content) => json!(format!("{}\n\n{}", system_text, content)),
+                Value::Array(mut parts) => {
+                    parts.insert(0, json!({ "type": "text", "text": system_text }));
+                    Value::Array(parts)
+                }
+                _ => json!(system_text),
+            };
+        }
+        None => messages.insert(0, json!({ "role": "user", "content": system_text })),
+    }
+    messages
+}
+
+pub fn build_openai_request(
+    mut request: ChatRequest,
+    compatible: &'static OpenAiCompatible,
+    scope_headers: Vec<(&'static str, String)>,
+) -> Result<UpstreamRequest, ApiError> {
+    // Check if Azure OpenAI is configured (takes priority for OpenAI itself)
+    let use_azure = *compatible == OPENAI && env::var("AZURE_OPENAI_ENDPOINT").is_ok();
+    let model = compatible.upstream_model(&request.model).to_string();
+    let capabilities = model_capabilities(&model);
+    if !capabilities.supports_streaming {
+        return Err(ApiError::bad_request(format!("Model {} does not support streaming", request.model)));
+    }
+
+    let (api_endpoint, api_key, auth) = if use_azure {
+        let endpoint = env::var("AZURE_OPENAI_ENDPOINT")
+            .map_err(|_| ApiError::internal("AZURE_OPENAI_ENDPOINT not set"))?;
+        let key = env::var("AZURE_OPENAI_KEY")
+            .map_err(|_| ApiError::internal("AZURE_OPENAI_KEY not set"))?;
+        let deployment = env::var("AZURE_OPENAI_DEPLOYMENT")
+            .unwrap_or_else(|_| "gpt-4o".to_string()); // Default deployment name
+
+        let url = format!("{}/openai/deployments/{}/chat/completions?api-version=2024-08-01-preview",
+            endpoint.trim_end_matches('/'), deployment);
+        info!("Using Azure OpenAI endpoint: {}", url);
+        (url, key, AuthStyle::Header("api-key"))
+    } else {
+        let key = env::var(compatible.key_env).map_err(|_| {
+            ApiError::internal(format!("{} not set", compatible.key_env))
+        })?;
+        (format!("{}/chat/completions", compatible.base_url()), key, compatible.auth)
+    };
+
+    let tools = create_tools();
+    let wants_tools = !tools.is_empty() && request.tool_choice != Some(ToolChoice::Mode(ToolChoiceMode::None));
+    if wants_tools && !capabilities.supports_tools {
+        handle_unsupported_tools(&mut request, &tools)?;
+    }
+
+    let mut messages = convert_messages_to_openai(request.messages);
+    if !capabilities.supports_system_role {
+        messages = fold_system_messages(messages);
+    }
+
+    let mut request_body = json!({
+        "messages": messages,
+        "stream": true
+    });
+
+    // Azure OpenAI doesn't need model in request body (it's in the URL path)
+    // But regular OpenAI does need it
+    if !use_azure {
+        request_body["model"] = json!(model);
+    }
+
+    // Reasoning models reject a custom temperature, so don't send one at all
+    if capabilities.supports_temperature {
+        request_body["temperature"] = json!(openai_temperature(request.temperature));
+    }
+
+    if let Some(max_tokens) = request.max_tokens {
+        request_body[capabilities.max_tokens_field()] = json!(max_tokens);
+    }
+    if let Some(response_format) = &request.response_format {
+        request_body["response_format"] = json!(response_format);
+    }
+    if let Some(seed) = request.seed {
+        request_body["seed"] = json!(seed);
+    }
+    // Other OpenAI-compatible providers reject or reinterpret these, so only OpenAI gets them
+    if request.logprobs {
+        if *compatible == OPENAI && capabilities.supports_logprobs {
+            request_body["logprobs"] = json!(true);
+            if let Some(top_logprobs) = request.top_logprobs {
+                request_body["top_logprobs"] = json!(top_logprobs.min(20));
+            }
+        } else {
+            info!("Ignoring logprobs for {} model {}", compatible.name, model);
+        }
+    }
+
+    // Add tools if any (convert to OpenAI function format)
+    if !tools.is_empty() && capabilities.supports_tools {
+        let openai_tools = convert_tools_to_openai(tools);
+        request_body["tools"] = json!(openai_tools);
+        if let Some(tool_choice) = &request.tool_choice {
+            request_body["tool_choice"] = tool_choice.to_openai();
+        }
+        // Only valid alongside tools
+        if let Some(parallel_tool_calls) = request.parallel_tool_calls {
+            request_body["parallel_tool_calls"] = json!(parallel_tool_calls);
+        }
+        info!("Added {} tools to {} request", openai_tools.len(), compatible.name);
+        info!("Tools: {}", serde_json::to_string_pretty(&openai_tools).unwrap_or_default());
+    }
+
+    let mut upstream = UpstreamRequest::new(compatible.name, api_endpoint, request_body)
+        .timeout(upstream_timeout(request.max_steps))
+        .header("Content-Type", "application/json");
+
+    // Set auth header based on provider
+    upstream = match auth {
+        AuthStyle::Bearer => upstream.header("Authorization", format!("Bearer {}", api_key)),
+        AuthStyle::Header(name) => upstream.header(name, api_key),
+    };
+
+    // Billing scope headers only mean something to OpenAI itself
+    if *compatible == OPENAI && !use_azure {
+        for (name, value) in scope_headers {
+            upstream = upstream.header(name, value);
+        }
+    }
+    Ok(upstream)
+}
+
+pub async fn handle_openai_request(
+    upstream: UpstreamRequest,
+    compatible: &'static OpenAiCompatible,
+    tool_call_format: ToolCallFormat,
+    tool_call_streaming: bool,
+    session: Session,
+    frames: FrameWriter,
+) -> Result<HttpResponse, ApiError> {
+    let response = upstream.send().await?;
+    let rate_limits = RateLimitHeaders::from_response(Provider::OpenAI(compatible), &response);
+
+    // Convert OpenAI streaming response to AI SDK format
+    let stream = response.bytes_stream();
+    let mut lines = LineBuffer::default();
+    let mut state = OpenAiStreamState::new(tool_call_format, tool_call_streaming, session, frames);
+    let unhandled = state.unhandled.clone();
+    // Stop at the first transport error, once the client has been told about it
+    let mut failed = false;
+    let ai_sdk_stream = stream.map_while(move |chunk_result| {
+        if failed {
+            return None;
+        }
+        Some(match chunk_result {
+            Ok(chunk) => {
+                // Parse OpenAI SSE format and convert to AI SDK format
+                chunk_info!("OpenAI raw chunk: {}", String::from_utf8_lossy(&chunk));
+                let chunk_str = lines.push(&chunk);
+                let converted = convert_openai_to_ai_sdk(&chunk_str, &mut state);
+                if !converted.is_empty() {
+                    chunk_info!("Converted to AI SDK: {}", converted);
+                }
+                Ok::<Bytes, reqwest::Error>(Bytes::from(converted))
+            }
+            Err(e) => {
+                failed = true;
+                Ok(Bytes::from(state.fail(&upstream_stream_error(&e))))
+            }
+        })
+    });
+
+    let mut response = ai_sdk_stream_response(ai_sdk_stream, Provider::OpenAI(compatible), unhandled, frames);
+    rate_limits.apply(&mut response);
+    Ok(response)
+}
+
+pub fn convert_messages_to_openai(messages: Vec<ChatMessage>) -> Vec<Value> {
+    // Convert messages to OpenAI format
+    // AI SDK v5 sends tool results embedded in assistant messages with toolInvocations
+    // We need to convert these to OpenAI's format: separate "tool" role messages
+    messages
+        .into_iter()
+        .flat_map(|msg| {
+            let mut result_messages = Vec::new();
+
+            // First, add the main message (user or assistant)
+            let mut message = json!({
+                "role": msg.role,
+            });
+
+            // Images turn the content into a list of text and image_url parts
+            let images: Vec<Value> = msg
+                .images()
+                .map(|image| json!({ "type": "image_url", "image_url": { "url": image.url } }))
+                .collect();
+
+            // Add content if present
+            if !images.is_empty() {
+                let mut parts = Vec::new();
+                if let Some(content) = msg.content.filter(|c| !c.is_empty()) {
+                    parts.push(json!({ "type": "text", "text": content }));
+                }
+                parts.extend(images);
+                message["content"] = json!(parts);
+            } else if let Some(content) = msg.content {
+                message["content"] = json!(content);
+            }
+
+            // Add tool_calls if present (assistant messages with tool calls)
+            if let Some(tool_calls) = msg.tool_calls {
+                message["tool_calls"] = json!(tool_calls);
+            }
+
+            // Add tool_call_id if present (tool result messages - legacy format)
+            if let Some(tool_call_id) = msg.tool_call_id {
+                message["tool_call_id"] = json!(tool_call_id);
+            }
+
+            // Add name if present (for tool results, name = tool name)
+            if let Some(name) = msg.name {
+                message["name"] = json!(name);
+            }
+
+            result_messages.push(message);
+
+            // If this is an assistant message with toolInvocations (AI SDK v5 format),
+            // we need to:
+            // 1. Add the assistant message with tool_calls reconstructed from toolInvocations
+            // 2. Add separate "tool" role messages for each result
+            if let Some(ref tool_invocations) = msg.tool_invocations {
+                // First, reconstruct tool_calls for the assistant message
+                let tool_calls: Vec<Value> = tool_invocations.iter().map(|invocation| {
+                    let tool_call_id = invocation.get("toolCallId").and_then(|v| v.as_str()).unwrap_or("");
+                    let tool_name = invocation.get("toolName").and_then(|v| v.as_str()).unwrap_or("");
+                    let args = invocation.get("args").cloned().unwrap_or(json!({}));
+
+                    json!({
+                        "id": tool_call_id,
+                        "type": "function",
+                        "function": {
+                            "name": tool_name,
+                            "arguments": serde_json::to_string(&args).unwrap_or_else(|_| "{}".to_string())
+                        }
+                    })
+                }).collect();
+
+                // Update the assistant message to include tool_calls
+                if !tool_calls.is_empty() {
+                    result_messages[0]["tool_calls"] = json!(tool_calls);
+                }
+
+                // Then add tool result messages
+                for invocation in tool_invocations {
+                    if let Some(tool_call_id) = invocation.get("toolCallId").and_then(|v| v.as_str()) {
+                        if let Some(result) = invocation.get("result") {
+                            // OpenAI expects tool results as separate messages with role: "tool"
+                            let tool_result_message = json!({
+                                "role": "tool",
+                                "tool_call_id": tool_call_id,
+                                "content": serde_json::to_string(&result).unwrap_or_else(|_| "{}".to_string())
+                            });
+                            result_messages.push(tool_result_message);
+                        }
+                    }
+                }
+            }
+
+            result_messages
+        })
+        .collect()
+}
+
+pub fn convert_tools_to_openai(tools: Vec<Tool>) -> Vec<Value> {
+    tools
+        .into_iter()
+        .map(|tool| {
+            json!({
+                "type": "function",
+                "function": {
+                    "name": tool.name,
+                    "description": tool.description,
+                    "parameters": tool.input_schema
+                }
+            })
+        })
+        .collect()
+}
@@ -0,0 +1,196 @@
+//! The /sdk-chat request body
+
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::convert::SdkVersion;
+use crate::tools::ToolChoice;
+use crate::trim::TrimStrategy;
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ChatMessage {
+    pub role: String,
+    #[serde(default)]
+    pub content: Option<String>,
+    // AI SDK v5 includes tool calls and results in messages
+    #[serde(default, rename = "tool_calls")]
+    pub tool_calls: Option<Vec<serde_json::Value>>,
+    #[serde(default, rename = "tool_call_id")]
+    pub tool_call_id: Option<String>,
+    #[serde(default)]
+    pub name: Option<String>,
+    // AI SDK v5 also includes tool invocations (results) in assistant messages
+    #[serde(default, rename = "toolInvocations")]
+    pub tool_invocations: Option<Vec<serde_json::Value>>,
+    // Files attached by useChat; image attachments are forwarded to vision models
+    #[serde(default, rename = "experimental_attachments")]
+    pub attachments: Option<Vec<Attachment>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Attachment {
+    #[serde(default, rename = "contentType")]
+    pub content_type: Option<String>,
+    // A data: URL with the base64 contents, or a remote http(s) URL
+    pub url: String,
+}
+
+impl ChatMessage {
+    pub fn images(&self) -> impl Iterator<Item = &Attachment> {
+        self.attachments
+            .iter()
+            .flatten()
+            .filter(|attachment| attachment.is_image())
+    }
+}
+
+impl Attachment {
+    pub fn is_image(&self) -> bool {
+        match &self.content_type {
+            Some(content_type) => content_type.starts_with("image/"),
+            None => self.url.starts_with("data:image/"),
+        }
+    }
+
+    // Split "data:image/png;base64,<data>" into ("image/png", "<data>")
+    pub fn base64_data(&self) -> Option<(&str, &str)> {
+        let (header, data) = self.url.strip_prefix("data:")?.split_once(',')?;
+        let media_type = header.strip_suffix(";base64")?;
+        Some((media_type, data))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatRequest {
+    pub messages: Vec<ChatMessage>,
+    #[serde(default = "default_model")]
+    pub model: String,
+    // None when the client didn't send one, so an explicit 0.0 is kept as-is
+    #[serde(default)]
+    pub temperature: Option<f32>,
+    #[serde(default, rename = "maxSteps")]
+    pub max_steps: Option<u32>,
+    // Completion token budget, forwarded under the field name the provider/model expects
+    #[serde(default, rename = "maxTokens")]
+    pub max_tokens: Option<u32>,
+    // Anthropic only: mark the system prompt and first message as cacheable
+    #[serde(default, rename = "promptCaching")]
+    pub prompt_caching: bool,
+    // How completed tool calls are sent to the client
+    #[serde(default, rename = "toolCallFormat")]
+    pub tool_call_format: ToolCallFormat,
+    // OpenAI-compatible and Mistral, ai-sdk tool call format only: also stream each tool call
+    // as it forms, as a b: start frame and c: argument deltas ahead of the complete 9: frame
+    #[serde(default, rename = "toolCallStreaming")]
+    pub tool_call_streaming: bool,
+    // Keys server-side state such as the addTransformation pipeline. Also accepted as the
+    // X-Session-Id header.
+    #[serde(default, rename = "sessionId")]
+    pub session_id: Option<String>,
+    // Token budget for the whole session; SESSION_TOKEN_BUDGET caps it when both are set
+    #[serde(default, rename = "maxSessionTokens")]
+    pub max_session_tokens: Option<u64>,
+    // JSON mode / structured outputs. Forwarded to OpenAI-compatible providers, emulated with a
+    // system instruction for Anthropic models.
+    #[serde(default, rename = "responseFormat")]
+    pub response_format: Option<ResponseFormat>,
+    // "auto", "none", "required" or {"name": "<tool>"} to force a call to that tool
+    #[serde(default, rename = "toolChoice")]
+    pub tool_choice: Option<ToolChoice>,
+    // OpenAI-compatible only: false makes the model call at most one tool per turn
+    #[serde(default, rename = "parallelToolCalls")]
+    pub parallel_tool_calls: Option<bool>,
+    // Stream protocol version of the client's AI SDK. Also accepted as the X-AI-SDK-Version
+    // header; v5 when neither is sent.
+    #[serde(default, rename = "aiSdkVersion")]
+    pub ai_sdk_version: Option<SdkVersion>,
+    // Models to try in order when the provider call fails before anything is streamed
+    #[serde(default, rename = "fallbackModels", skip_serializing_if = "Vec::is_empty")]
+    pub fallback_models: Vec<String>,
+    // Hard deadline for this request in milliseconds. Also accepted as the X-Timeout-Ms header.
+    #[serde(default, rename = "timeoutMs")]
+    pub timeout_ms: Option<u64>,
+    // OpenAI only: stream per-token log probabilities as data frames, with up to topLogprobs
+    // alternatives per token. Ignored by providers and models that don't support them.
+    #[serde(default)]
+    pub logprobs: bool,
+    #[serde(default, rename = "topLogprobs", alias = "top_logprobs")]
+    pub top_logprobs: Option<u8>,
+    // Sampling seed for reproducible output, forwarded to OpenAI-compatible providers and
+    // Mistral (as random_seed). Ignored by the others.
+    #[serde(default)]
+    pub seed: Option<i64>,
+    // Extra upstream headers per provider name, e.g. {"anthropic": {"anthropic-beta": "..."}}.
+    // Only headers on ALLOWED_UPSTREAM_HEADERS are sent.
+    #[serde(default, rename = "extraHeaders", skip_serializing_if = "HashMap::is_empty")]
+    pub extra_headers: HashMap<String, HashMap<String, String>>,
+    // false answers with one JSON object assembled from the stream instead of streaming it.
+    // Defaults to what the Accept header asks for.
+    #[serde(default)]
+    pub stream: Option<bool>,
+    // What to do when the conversation is too long for the model's context window
+    #[serde(default, rename = "trimStrategy")]
+    pub trim_strategy: TrimStrategy,
+    // A prompt template from TEMPLATES_DIR to put ahead of the messages, filled in with variables
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub template: Option<String>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub variables: HashMap<String, String>,
+    // Collapse identical consecutive messages, as sent by clients that resend a turn
+    #[serde(default, rename = "dedupeMessages")]
+    pub dedupe_messages: bool,
+}
+
+// OpenAI's response_format shape
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ResponseFormat {
+    Text,
+    JsonObject,
+    JsonSchema { json_schema: JsonSchemaFormat },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct JsonSchemaFormat {
+    pub name: String,
+    pub schema: Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub strict: Option<bool>,
+}
+
+impl ResponseFormat {
+    // System instruction standing in for JSON mode on providers without a native field
+    pub fn json_instruction(&self) -> Option<String> {
+        const JSON_ONLY: &str = "Respond only with a single valid JSON object, without any other text or code fences.";
+        match self {
+            ResponseFormat::Text => None,
+            ResponseFormat::JsonObject => Some(JSON_ONLY.to_string()),
+            ResponseFormat::JsonSchema { json_schema } => Some(format!(
+                "{} The object must conform to this JSON schema:\n{}",
+                JSON_ONLY, json_schema.schema
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ToolCallFormat {
+    // AI SDK tool call frame: 9:{"toolCallId","toolName","args"}
+    #[default]
+    #[serde(rename = "ai-sdk")]
+    AiSdk,
+    // OpenAI tool_calls array in a data frame: 2:[{"tool_calls":[...]}]
+    #[serde(rename = "openai")]
+    OpenAI,
+}
+
+fn default_model() -> String {
+    "claude-3-5-sonnet-20241022".to_string()
+}
+
+// Used when the client doesn't send a temperature
+pub const DEFAULT_TEMPERATURE: f32 = 0.2;
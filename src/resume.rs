@@ -0,0 +1,128 @@
+//! Resumable streams: responses buffered by message id, so a client that lost its connection
+//! can replay them with GET /resume/{messageId} and follow the rest as it is produced
+
+use std::collections::HashMap;
+use std::env;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use actix_web::{
+    body::{BodyStream, BoxBody, MessageBody},
+    web, HttpRequest, HttpResponse,
+};
+use bytes::Bytes;
+use futures::future::poll_fn;
+use futures::stream;
+use log::{info, warn};
+use tokio::sync::Notify;
+
+use crate::convert::SdkVersion;
+use crate::output::OutputFormat;
+use crate::stream::is_event_stream;
+use crate::error::ApiError;
+
+// Streams by message id. A stream is kept for RESUME_TTL_SECS (default 300) after it ends, and
+// at most MAX_RESUMABLE_STREAMS (default 1000) are kept at a time.
+lazy_static::lazy_static! {
+    static ref RESUMABLE: Mutex<HashMap<String, Arc<ResumableStream>>> = Mutex::new(HashMap::new());
+}
+
+#[derive(Default)]
+struct ResumableStream {
+    state: Mutex<Buffered>,
+    changed: Notify,
+}
+
+#[derive(Default)]
+struct Buffered {
+    chunks: Vec<Bytes>,
+    // When the upstream stream ended; nothing is added after that
+    finished_at: Option<Instant>,
+}
+
+pub fn resumable_streams_enabled() -> bool {
+    env::var("RESUMABLE_STREAMS").map(|v| v.eq_ignore_ascii_case("true") || v == "1").unwrap_or(false)
+}
+
+fn resume_ttl() -> Duration {
+    Duration::from_secs(env::var("RESUME_TTL_SECS").ok().and_then(|v| v.parse().ok()).unwrap_or(300))
+}
+
+// Read `response` to the end in the background, whether or not the client stays connected, and
+// send the client the buffered copy instead. Responses that aren't streams are left alone.
+pub fn make_resumable(message_id: &str, response: HttpResponse) -> HttpResponse {
+    if !is_event_stream(&response) {
+        return response;
+    }
+
+    let resumable = Arc::new(ResumableStream::default());
+    {
+        let mut streams = RESUMABLE.lock().unwrap();
+        let ttl = resume_ttl();
+        streams.retain(|_, stream| stream.state.lock().unwrap().finished_at.is_none_or(|at| at.elapsed() < ttl));
+        let capacity = env::var("MAX_RESUMABLE_STREAMS").ok().and_then(|v| v.parse().ok()).unwrap_or(1000);
+        if streams.len() >= capacity {
+            warn!("{} resumable streams already kept (MAX_RESUMABLE_STREAMS); {} can't be resumed", capacity, message_id);
+            return response;
+        }
+        streams.insert(message_id.to_string(), resumable.clone());
+    }
+
+    let (response, body) = response.into_parts();
+    let buffer = resumable.clone();
+    actix_web::rt::spawn(async move {
+        let mut body = Box::pin(body);
+        while let Some(chunk) = poll_fn(|cx| body.as_mut().poll_next(cx)).await {
+            let Ok(chunk) = chunk else {
+                break;
+            };
+            buffer.state.lock().unwrap().chunks.push(chunk);
+            buffer.changed.notify_waiters();
+        }
+        buffer.state.lock().unwrap().finished_at = Some(Instant::now());
+        buffer.changed.notify_waiters();
+    });
+    response.set_body(replay(resumable))
+}
+
+// Everything buffered so far, then the rest as it arrives
+fn replay(resumable: Arc<ResumableStream>) -> BoxBody {
+    let chunks = stream::unfold((resumable, 0), |(resumable, next)| async move {
+        loop {
+            // Registered before the check, so a chunk added in between still wakes us
+            let notify = resumable.clone();
+            let changed = notify.changed.notified();
+            {
+                let state = resumable.state.lock().unwrap();
+                if let Some(chunk) = state.chunks.get(next) {
+                    let chunk = chunk.clone();
+                    drop(state);
+                    return Some((Ok::<_, actix_web::Error>(chunk), (resumable, next + 1)));
+                }
+                if state.finished_at.is_some() {
+                    return None;
+                }
+            }
+            changed.await;
+        }
+    });
+    BoxBody::new(BodyStream::new(chunks))
+}
+
+// GET /resume/{messageId}: the frames of a stream from its start, following it to the end if
+// it is still running, in the stream protocol of the X-AI-SDK-Version header
+pub async fn resume(req: HttpRequest, message_id: web::Path<String>) -> Result<HttpResponse, ApiError> {
+    let sdk_version = SdkVersion::from_header(&req)?.unwrap_or_default();
+    let message_id = message_id.into_inner();
+    let resumable = RESUMABLE.lock().unwrap().get(&message_id).cloned();
+    let resumable = resumable
+        .filter(|stream| stream.state.lock().unwrap().finished_at.is_none_or(|at| at.elapsed() < resume_ttl()))
+        .ok_or_else(|| ApiError::not_found(format!("No resumable stream with message id {}", message_id)))?;
+    info!("Resuming stream {}", message_id);
+    let response = HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .insert_header(("X-Message-Id", message_id))
+        .body(replay(resumable));
+    Ok(OutputFormat::AiSdk.apply(response, sdk_version))
+}
@@ -0,0 +1,109 @@
+//! POST /route: a TorchScript classifier recommending a model for a prompt (the router feature)
+
+use std::env;
+
+use actix_web::{web, HttpResponse};
+use log::{error, info};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tiktoken_rs::o200k_base_singleton;
+
+use crate::error::ApiError;
+use crate::request::ChatMessage;
+
+// Longest input, in tokens, given to the routing classifier; the rest of the message is dropped
+const ROUTER_MAX_TOKENS: usize = 512;
+
+// A small TorchScript classifier that picks the cheapest adequate model for a prompt. It takes
+// the o200k token ids of the text as an int64 tensor of shape [1, n] and returns one logit per
+// model in ROUTER_LABELS (comma-separated model ids, in the classifier's class order).
+pub struct PromptRouter {
+    module: tch::CModule,
+    labels: Vec<String>,
+}
+
+impl PromptRouter {
+    pub fn from_env() -> Result<Option<Self>, String> {
+        let Ok(path) = env::var("ROUTER_MODEL") else {
+            return Ok(None);
+        };
+        let labels: Vec<String> = env::var("ROUTER_LABELS")
+            .unwrap_or_default()
+            .split(',')
+            .map(|label| label.trim().to_string())
+            .filter(|label| !label.is_empty())
+            .collect();
+        if labels.is_empty() {
+            return Err("ROUTER_LABELS must list the model id for each classifier output".to_string());
+        }
+        let mut module = tch::CModule::load(&path).map_err(|e| format!("can't load {}: {}", path, e))?;
+        module.set_eval();
+        info!("Loaded routing classifier from {} choosing between {}", path, labels.join(", "));
+        Ok(Some(PromptRouter { module, labels }))
+    }
+
+    // Probability of each label for `text`
+    fn scores(&self, text: &str) -> Result<Vec<f32>, String> {
+        let ids: Vec<i64> = o200k_base_singleton()
+            .encode_with_special_tokens(text)
+            .into_iter()
+            .take(ROUTER_MAX_TOKENS)
+            .map(i64::from)
+            .collect();
+        let input = tch::Tensor::from_slice(&ids).unsqueeze(0);
+        let logits = tch::no_grad(|| self.module.forward_ts(&[input])).map_err(|e| e.to_string())?;
+        let probabilities = logits.softmax(-1, tch::Kind::Float).flatten(0, -1);
+        let scores = Vec::<f32>::try_from(&probabilities).map_err(|e| e.to_string())?;
+        if scores.len() != self.labels.len() {
+            return Err(format!(
+                "classifier returned {} scores for {} labels",
+                scores.len(),
+                self.labels.len()
+            ));
+        }
+        Ok(scores)
+    }
+}
+
+#[derive(Deserialize)]
+struct RouteRequest {
+    messages: Vec<ChatMessage>,
+}
+
+// POST /route: recommend a model for the conversation's last user message
+pub async fn route_prompt(body: web::Bytes, router: web::Data<PromptRouter>) -> Result<HttpResponse, ApiError> {
+    let request: RouteRequest = serde_json::from_slice(&body)
+        .map_err(|e| ApiError::bad_request(format!("Invalid JSON: {}", e)))?;
+    let text = request
+        .messages
+        .iter()
+        .rev()
+        .find(|message| message.role == "user")
+        .and_then(|message| message.content.clone())
+        .filter(|content| !content.trim().is_empty())
+        .ok_or_else(|| ApiError::bad_request("No user message to route"))?;
+
+    let scores = web::block(move || router.scores(&text).map(|scores| (router, scores)))
+        .await
+        .map_err(|e| ApiError::internal(format!("Routing failed: {}", e)))?;
+    let (router, scores) = scores.map_err(|e| {
+        error!("Routing classifier failed: {}", e);
+        ApiError::internal(format!("Routing failed: {}", e))
+    })?;
+
+    let best = scores
+        .iter()
+        .enumerate()
+        .max_by(|a, b| a.1.total_cmp(b.1))
+        .map(|(index, _)| index)
+        .unwrap_or_default();
+    let model = &router.labels[best];
+    info!("Routed prompt to {} ({:.2})", model, scores[best]);
+    let scores: serde_json::Map<String, Value> = router
+        .labels
+        .iter()
+        .zip(&scores)
+        .map(|(label, score)| (label.clone(), json!(score)))
+        .collect();
+    Ok(HttpResponse::Ok().json(json!({ "model": model, "scores": scores })))
+}
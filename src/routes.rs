@@ -0,0 +1,170 @@
+//! The routes of the server, and the responses that don't belong to any one endpoint
+
+use std::time::Instant;
+
+use actix_web::{http::StatusCode, web, HttpRequest, HttpResponse, Responder, ResponseError};
+use serde_json::{json, Value};
+
+use crate::admin::{admin_reload, debug_echo};
+use crate::batch::batch;
+use crate::cancel::cancel_request;
+use crate::chat::sdk_chat;
+use crate::compare::compare;
+use crate::conversations::get_conversation;
+use crate::error::ApiError;
+use crate::pipeline::get_pipeline;
+use crate::providers::{known_providers, provider_configured};
+use crate::resume::resume;
+#[cfg(feature = "router")]
+use crate::router::{route_prompt, PromptRouter};
+use crate::tokens::count_tokens;
+use crate::usage::get_usage;
+use crate::ws::ws_chat;
+
+async fn health_check() -> impl Responder {
+    HttpResponse::Ok().body("healthy")
+}
+
+// Set at startup, for the uptime in /status
+pub static STARTED_AT: std::sync::OnceLock<Instant> = std::sync::OnceLock::new();
+
+// GET /status: which providers have credentials set (never the values), the version and the
+// uptime. Makes no upstream calls, so it answers 200 even when every provider is down.
+async fn status() -> impl Responder {
+    let providers: serde_json::Map<String, Value> = known_providers()
+        .map(|provider| (provider.name().to_string(), json!({ "configured": provider_configured(provider) })))
+        .collect();
+    HttpResponse::Ok().json(json!({
+        "providers": providers,
+        "version": env!("CARGO_PKG_VERSION"),
+        "uptime": STARTED_AT.get().map_or(0, |started| started.elapsed().as_secs()),
+    }))
+}
+
+async fn not_found(req: HttpRequest) -> impl Responder {
+    ApiError::not_found(format!("No route for {}", req.path())).error_response()
+}
+
+// Fallback for a known path hit with a method it doesn't support. OPTIONS (that isn't a CORS
+// preflight, which the Cors middleware answers) lists the methods; anything else is a 405.
+fn allow_only(methods: &'static [&'static str]) -> actix_web::Route {
+    web::route().to(move |req: HttpRequest| async move {
+        let allow = methods
+            .iter()
+            .copied()
+            .chain(std::iter::once("OPTIONS"))
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        if req.method() == actix_web::http::Method::OPTIONS {
+            return HttpResponse::NoContent().insert_header(("Allow", allow)).finish();
+        }
+        let message = format!("{} is not supported on {}; allowed: {}", req.method(), req.path(), allow);
+        let mut response =
+            ApiError::new(StatusCode::METHOD_NOT_ALLOWED, "method_not_allowed", message).error_response();
+        if let Ok(allow) = actix_web::http::header::HeaderValue::from_str(&allow) {
+            response.headers_mut().insert(actix_web::http::header::ALLOW, allow);
+        }
+        response
+    })
+}
+
+// Endpoints that only exist in some configurations
+#[derive(Clone)]
+pub struct OptionalEndpoints {
+    // POST /admin/reload, when ADMIN_TOKEN is set
+    pub admin: bool,
+    // POST /debug/echo, when DEBUG_ENDPOINTS=true
+    pub debug: bool,
+    // POST /route, when ROUTER_MODEL is set
+    #[cfg(feature = "router")]
+    pub router: Option<web::Data<PromptRouter>>,
+}
+
+// Every route of the server; unknown paths get a 404 and known paths a 405 for other methods
+pub fn configure(cfg: &mut web::ServiceConfig, optional: &OptionalEndpoints) {
+    cfg.service(
+        web::resource(["/", "/health"])
+            .route(web::get().to(health_check))
+            .default_service(allow_only(&["GET"])),
+    )
+    .service(
+        web::resource("/status")
+            .route(web::get().to(status))
+            .default_service(allow_only(&["GET"])),
+    )
+    .service(
+        web::resource("/sdk-chat")
+            .route(web::post().to(sdk_chat))
+            .default_service(allow_only(&["POST"])),
+    )
+    .service(
+        web::resource("/ws-chat")
+            .route(web::get().to(ws_chat))
+            .default_service(allow_only(&["GET"])),
+    )
+    .service(
+        web::resource("/count-tokens")
+            .route(web::post().to(count_tokens))
+            .default_service(allow_only(&["POST"])),
+    )
+    .service(
+        web::resource("/pipeline/{session}")
+            .route(web::get().to(get_pipeline))
+            .default_service(allow_only(&["GET"])),
+    )
+    .service(
+        web::resource("/batch")
+            .route(web::post().to(batch))
+            .default_service(allow_only(&["POST"])),
+    )
+    .service(
+        web::resource("/compare")
+            .route(web::post().to(compare))
+            .default_service(allow_only(&["POST"])),
+    )
+    .service(
+        web::resource("/usage")
+            .route(web::get().to(get_usage))
+            .default_service(allow_only(&["GET"])),
+    )
+    .service(
+        web::resource("/cancel/{requestId}")
+            .route(web::post().to(cancel_request))
+            .default_service(allow_only(&["POST"])),
+    )
+    .service(
+        web::resource("/resume/{messageId}")
+            .route(web::get().to(resume))
+            .default_service(allow_only(&["GET"])),
+    )
+    .service(
+        web::resource("/conversations/{session}")
+            .route(web::get().to(get_conversation))
+            .default_service(allow_only(&["GET"])),
+    );
+    #[cfg(feature = "router")]
+    if let Some(router) = &optional.router {
+        cfg.service(
+            web::resource("/route")
+                .app_data(router.clone())
+                .route(web::post().to(route_prompt))
+                .default_service(allow_only(&["POST"])),
+        );
+    }
+    if optional.admin {
+        cfg.service(
+            web::resource("/admin/reload")
+                .route(web::post().to(admin_reload))
+                .default_service(allow_only(&["POST"])),
+        );
+    }
+    if optional.debug {
+        cfg.service(
+            web::resource("/debug/echo")
+                .route(web::post().to(debug_echo))
+                .default_service(allow_only(&["POST"])),
+        );
+    }
+    cfg.default_service(web::route().to(not_found));
+}
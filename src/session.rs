@@ -0,0 +1,88 @@
+//! Sessions, the key for server-side conversational state, and a store for that state
+
+use std::collections::HashMap;
+use std::env;
+use std::time::{Duration, Instant};
+
+use log::info;
+
+// Key for server-side conversational state. Clients pass it as sessionId or X-Session-Id;
+// without one the request gets an ephemeral id, whose state lives only for that request.
+#[derive(Debug, Clone)]
+pub struct Session {
+    pub id: String,
+    pub ephemeral: bool,
+}
+
+impl Session {
+    pub fn new(id: Option<&str>) -> Self {
+        match id {
+            Some(id) => Session { id: id.to_string(), ephemeral: false },
+            None => Session { id: format!("ephemeral-{}", uuid::Uuid::new_v4()), ephemeral: true },
+        }
+    }
+}
+
+// Server-side state per session. Sessions idle for longer than SESSION_TTL_SECS (default one
+// hour) are dropped, and beyond MAX_SESSIONS (default 10000) the least recently used session
+// is evicted to make room. Eviction runs whenever the store is accessed.
+pub struct SessionStore<T> {
+    entries: HashMap<String, SessionEntry<T>>,
+    ttl: Duration,
+    capacity: usize,
+}
+
+struct SessionEntry<T> {
+    value: T,
+    last_used: Instant,
+}
+
+impl<T: Default> SessionStore<T> {
+    pub fn from_env() -> Self {
+        let env_number = |var: &str, default: u64| {
+            env::var(var).ok().and_then(|v| v.parse().ok()).unwrap_or(default)
+        };
+        SessionStore {
+            entries: HashMap::new(),
+            ttl: Duration::from_secs(env_number("SESSION_TTL_SECS", 3600)),
+            capacity: env_number("MAX_SESSIONS", 10_000).max(1) as usize,
+        }
+    }
+
+    pub fn get(&mut self, session_id: &str) -> Option<&T> {
+        self.evict_expired();
+        let entry = self.entries.get_mut(session_id)?;
+        entry.last_used = Instant::now();
+        Some(&entry.value)
+    }
+
+    pub fn get_or_default(&mut self, session_id: &str) -> &mut T {
+        self.evict_expired();
+        if !self.entries.contains_key(session_id) && self.entries.len() >= self.capacity {
+            self.evict_least_recently_used();
+        }
+        let entry = self.entries.entry(session_id.to_string()).or_insert_with(|| SessionEntry {
+            value: T::default(),
+            last_used: Instant::now(),
+        });
+        entry.last_used = Instant::now();
+        &mut entry.value
+    }
+
+    fn evict_expired(&mut self) {
+        let ttl = self.ttl;
+        self.entries.retain(|_, entry| entry.last_used.elapsed() < ttl);
+    }
+
+    fn evict_least_recently_used(&mut self) {
+        let oldest = self
+            .entries
+            .iter()
+            .min_by_key(|(_, entry)| entry.last_used)
+            .map(|(id, _)| id.clone());
+        if let Some(id) = oldest {
+            info!("Evicting session {} to stay within MAX_SESSIONS", id);
+            self.entries.remove(&id);
+        }
+    }
+}
@@ -0,0 +1,50 @@
+//! The cap on concurrent upstream requests
+
+use std::env;
+use std::sync::Arc;
+
+use log::warn;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::error::ApiError;
+use crate::metrics::UPSTREAM_IN_FLIGHT;
+
+// Cap on concurrent upstream requests, MAX_CONCURRENT_UPSTREAM (64 by default). A request
+// holds its slot until its response has been streamed to the client.
+lazy_static::lazy_static! {
+    static ref UPSTREAM_SLOTS: Arc<Semaphore> = Arc::new(Semaphore::new(
+        env::var("MAX_CONCURRENT_UPSTREAM")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(64)
+    ));
+}
+
+pub struct UpstreamSlot {
+    _permit: OwnedSemaphorePermit,
+}
+
+impl UpstreamSlot {
+    // Fails fast with a 503 when every slot is taken, rather than queueing
+    pub fn acquire() -> Result<Self, ApiError> {
+        let permit = UPSTREAM_SLOTS.clone().try_acquire_owned().map_err(|_| {
+            warn!("Rejecting request: MAX_CONCURRENT_UPSTREAM reached");
+            ApiError::service_unavailable("Too many concurrent upstream requests, retry shortly", 1)
+        })?;
+        UPSTREAM_IN_FLIGHT.inc();
+        Ok(UpstreamSlot { _permit: permit })
+    }
+
+    // Queues until a slot is free
+    pub async fn wait() -> Self {
+        let permit = UPSTREAM_SLOTS.clone().acquire_owned().await.expect("upstream semaphore is never closed");
+        UPSTREAM_IN_FLIGHT.inc();
+        UpstreamSlot { _permit: permit }
+    }
+}
+
+impl Drop for UpstreamSlot {
+    fn drop(&mut self) {
+        UPSTREAM_IN_FLIGHT.dec();
+    }
+}
@@ -0,0 +1,383 @@
+//! The streamed /sdk-chat response: conversion checks, transforms, size cap, coalescing and metrics
+
+use std::env;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use actix_web::{
+    body::{BodyStream, BoxBody, MessageBody},
+    http::header::CONTENT_TYPE,
+    HttpResponse,
+};
+use tokio_stream::{Stream, StreamExt};
+use tokio::time::Sleep;
+use bytes::{Bytes, BytesMut};
+use log::{info, warn};
+use serde_json::{json, Value};
+
+use crate::metrics::{CLIENT_CANCELLED_STREAMS, STREAM_WRITES};
+use crate::convert::{FrameWriter, UnhandledEvents, Usage};
+use crate::providers::Provider;
+use crate::transform::{transform_chain, Transformed};
+
+// Whether `response` is an SSE stream rather than a JSON body (errors, dry runs, buffered turns)
+pub fn is_event_stream(response: &HttpResponse) -> bool {
+    response
+        .headers()
+        .get(CONTENT_TYPE)
+        .is_some_and(|content_type| content_type.as_bytes().starts_with(b"text/event-stream"))
+}
+
+// Build the SSE response for a converted upstream stream
+pub fn ai_sdk_stream_response<S>(
+    stream: S,
+    provider: Provider,
+    unhandled: UnhandledEvents,
+    frames: FrameWriter,
+) -> HttpResponse
+where
+    S: Stream<Item = Result<Bytes, reqwest::Error>> + 'static,
+{
+    let stream = ConversionCheck::new(stream, provider, unhandled, frames);
+    let stream = StreamCap::new(Transformed::new(stream, transform_chain(), frames), frames);
+    let stream = tokio_stream::once(Ok(Bytes::from(frames.start_step()))).chain(stream);
+    // CORS headers, like on every other response, come from the Cors middleware
+    HttpResponse::Ok()
+        .insert_header(("Content-Type", "text/event-stream"))
+        .insert_header(("Cache-Control", "no-cache"))
+        .insert_header(("Connection", "keep-alive"))
+        .streaming(UpstreamStream::new(Coalesce::new(stream), provider))
+}
+
+// A stream that ends without producing a single AI SDK frame reaches the client as an empty
+// response, which is almost always a converter bug. Log it with the unhandled event types,
+// and with STRICT_CONVERSION=1 also send the client a diagnostic error frame.
+pub struct ConversionCheck<S> {
+    inner: Pin<Box<S>>,
+    provider: Provider,
+    unhandled: UnhandledEvents,
+    frames: FrameWriter,
+    produced_output: bool,
+    done: bool,
+}
+
+impl<S> ConversionCheck<S> {
+    fn new(inner: S, provider: Provider, unhandled: UnhandledEvents, frames: FrameWriter) -> Self {
+        ConversionCheck {
+            inner: Box::pin(inner),
+            provider,
+            unhandled,
+            frames,
+            produced_output: false,
+            done: false,
+        }
+    }
+}
+
+impl<S, E> Stream for ConversionCheck<S>
+where
+    S: Stream<Item = Result<Bytes, E>>,
+{
+    type Item = Result<Bytes, E>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.done {
+            return Poll::Ready(None);
+        }
+
+        let poll = self.inner.as_mut().poll_next(cx);
+        match &poll {
+            Poll::Ready(Some(Ok(frames))) if !frames.is_empty() => self.produced_output = true,
+            Poll::Ready(None) if !self.produced_output => {
+                self.done = true;
+                let unhandled = self.unhandled.list();
+                warn!(
+                    "{} stream produced no AI SDK frames; unhandled event types: [{}]",
+                    self.provider.name(),
+                    unhandled
+                );
+                let strict = env::var("STRICT_CONVERSION")
+                    .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
+                    .unwrap_or(false);
+                if strict {
+                    let message = format!(
+                        "The {} response could not be converted: no frames were produced. Unhandled event types: [{}]",
+                        self.provider.name(),
+                        unhandled
+                    );
+                    return Poll::Ready(Some(Ok(Bytes::from(self.frames.error(&message)))));
+                }
+            }
+            _ => {}
+        }
+        poll
+    }
+}
+
+// Caps the bytes streamed to the client at MAX_STREAM_BYTES so a model stuck generating
+// can't stream forever. The frame that would cross the cap is replaced by a finish frame
+// with finishReason "length" and the stream ends, which drops (and cancels) the upstream.
+pub struct StreamCap<S> {
+    inner: Pin<Box<S>>,
+    frames: FrameWriter,
+    max_bytes: Option<usize>,
+    streamed: usize,
+    cut: bool,
+}
+
+impl<S> StreamCap<S> {
+    fn new(inner: S, frames: FrameWriter) -> Self {
+        StreamCap {
+            inner: Box::pin(inner),
+            frames,
+            max_bytes: env::var("MAX_STREAM_BYTES").ok().and_then(|v| v.parse().ok()),
+            streamed: 0,
+            cut: false,
+        }
+    }
+}
+
+impl<S, E> Stream for StreamCap<S>
+where
+    S: Stream<Item = Result<Bytes, E>>,
+{
+    type Item = Result<Bytes, E>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.cut {
+            return Poll::Ready(None);
+        }
+
+        let poll = self.inner.as_mut().poll_next(cx);
+        if let (Poll::Ready(Some(Ok(frames))), Some(max_bytes)) = (&poll, self.max_bytes) {
+            if self.streamed + frames.len() > max_bytes {
+                info!("Stream reached MAX_STREAM_BYTES ({}), cutting it off", max_bytes);
+                self.cut = true;
+                // Keep the whole frames (lines) that still fit
+                let budget = max_bytes - self.streamed;
+                let fits = frames[..budget]
+                    .iter()
+                    .rposition(|&b| b == b'\n')
+                    .map_or(0, |pos| pos + 1);
+                let mut cut = BytesMut::from(&frames[..fits]);
+                cut.extend_from_slice(self.frames.finish("length", Usage::default()).as_bytes());
+                return Poll::Ready(Some(Ok(cut.freeze())));
+            }
+            self.streamed += frames.len();
+        }
+        poll
+    }
+}
+
+// Optional coalescing of converted frames into fewer, larger writes. Providers send many tiny
+// deltas; with STREAM_COALESCE_MS set, frames are held for up to that long (or until
+// STREAM_COALESCE_BYTES are buffered) and flushed together. Frames are only ever appended
+// whole, so a write never ends mid-line. Disabled (every frame written as-is) by default.
+pub struct Coalesce<S> {
+    inner: Pin<Box<S>>,
+    delay: Duration,
+    max_bytes: usize,
+    buffer: BytesMut,
+    deadline: Option<Pin<Box<Sleep>>>,
+    pending_error: Option<reqwest::Error>,
+    inner_done: bool,
+}
+
+impl<S> Coalesce<S> {
+    fn new(inner: S) -> Self {
+        let env_number = |var: &str, default: u64| {
+            env::var(var).ok().and_then(|v| v.parse().ok()).unwrap_or(default)
+        };
+        let delay = Duration::from_millis(env_number("STREAM_COALESCE_MS", 0));
+        // A zero threshold flushes every frame immediately
+        let max_bytes = if delay.is_zero() {
+            0
+        } else {
+            env_number("STREAM_COALESCE_BYTES", 4096) as usize
+        };
+
+        Coalesce {
+            inner: Box::pin(inner),
+            delay,
+            max_bytes,
+            buffer: BytesMut::new(),
+            deadline: None,
+            pending_error: None,
+            inner_done: false,
+        }
+    }
+
+    fn flush(&mut self) -> Bytes {
+        self.deadline = None;
+        self.buffer.split().freeze()
+    }
+}
+
+impl<S> Stream for Coalesce<S>
+where
+    S: Stream<Item = Result<Bytes, reqwest::Error>>,
+{
+    type Item = Result<Bytes, reqwest::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        loop {
+            if let Some(e) = this.pending_error.take() {
+                return Poll::Ready(Some(Err(e)));
+            }
+            if this.inner_done {
+                if this.buffer.is_empty() {
+                    return Poll::Ready(None);
+                }
+                return Poll::Ready(Some(Ok(this.flush())));
+            }
+
+            match this.inner.as_mut().poll_next(cx) {
+                Poll::Ready(Some(Ok(frames))) => {
+                    if frames.is_empty() {
+                        continue;
+                    }
+                    if this.buffer.is_empty() && frames.len() >= this.max_bytes {
+                        return Poll::Ready(Some(Ok(frames)));
+                    }
+                    this.buffer.extend_from_slice(&frames);
+                    if this.buffer.len() >= this.max_bytes {
+                        return Poll::Ready(Some(Ok(this.flush())));
+                    }
+                    if this.deadline.is_none() {
+                        this.deadline = Some(Box::pin(tokio::time::sleep(this.delay)));
+                    }
+                }
+                Poll::Ready(Some(Err(e))) => {
+                    // Deliver what was already converted before the error
+                    if this.buffer.is_empty() {
+                        return Poll::Ready(Some(Err(e)));
+                    }
+                    this.pending_error = Some(e);
+                    return Poll::Ready(Some(Ok(this.flush())));
+                }
+                Poll::Ready(None) => {
+                    this.inner_done = true;
+                }
+                Poll::Pending => {
+                    if let Some(deadline) = this.deadline.as_mut() {
+                        if deadline.as_mut().poll(cx).is_ready() {
+                            return Poll::Ready(Some(Ok(this.flush())));
+                        }
+                    }
+                    return Poll::Pending;
+                }
+            }
+        }
+    }
+}
+
+// Actix drops the response body when the client disconnects. Owning the upstream stream here
+// means that drop also drops the reqwest response, aborting the upstream request instead of
+// letting it keep generating (and billing) tokens. Streams dropped before the upstream
+// finished are counted as client cancellations.
+pub struct UpstreamStream<S> {
+    inner: Pin<Box<S>>,
+    provider: Provider,
+    finished: bool,
+}
+
+impl<S> UpstreamStream<S> {
+    fn new(inner: S, provider: Provider) -> Self {
+        UpstreamStream {
+            inner: Box::pin(inner),
+            provider,
+            finished: false,
+        }
+    }
+}
+
+impl<S, E> Stream for UpstreamStream<S>
+where
+    S: Stream<Item = Result<Bytes, E>>,
+{
+    type Item = S::Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let poll = self.inner.as_mut().poll_next(cx);
+        match poll {
+            Poll::Ready(None) => self.finished = true,
+            Poll::Ready(Some(Ok(_))) => STREAM_WRITES.with_label_values(&[self.provider.name()]).inc(),
+            _ => {}
+        }
+        poll
+    }
+}
+
+impl<S> Drop for UpstreamStream<S> {
+    fn drop(&mut self) {
+        if !self.finished {
+            info!("Client disconnected mid-stream, cancelling {} request", self.provider.name());
+            CLIENT_CANCELLED_STREAMS
+                .with_label_values(&[self.provider.name()])
+                .inc();
+        }
+    }
+}
+
+// Keep `guard` alive until the response body has been fully sent or dropped
+pub fn hold_until_streamed<T: 'static>(response: HttpResponse, guard: T) -> HttpResponse {
+    response.map_body(|_, mut body| {
+        let stream = futures::stream::poll_fn(move |cx| {
+            let _ = &guard;
+            Pin::new(&mut body).poll_next(cx)
+        });
+        BoxBody::new(BodyStream::new(stream))
+    })
+}
+
+// Emit `frames` ahead of an already-built streaming response body
+pub fn prepend_to_stream(response: HttpResponse, frames: Bytes) -> HttpResponse {
+    response.map_body(|_, mut body| {
+        let rest = futures::stream::poll_fn(move |cx| Pin::new(&mut body).poll_next(cx));
+        let stream = tokio_stream::once(Ok(frames)).chain(rest);
+        BoxBody::new(BodyStream::new(stream))
+    })
+}
+
+// A complete AI SDK stream the server produced itself, without calling a provider
+pub fn frames_response(body: String) -> HttpResponse {
+    HttpResponse::Ok()
+        .insert_header(("Content-Type", "text/event-stream"))
+        .insert_header(("Cache-Control", "no-cache"))
+        .body(body)
+}
+
+// The assistant turn as the client saw it: text, tool calls, usage, the finish reason and the
+// first error frame, if any
+pub fn assemble_response(output: &str) -> Value {
+    let mut text = String::new();
+    let mut tool_calls = Vec::new();
+    let mut usage = Value::Null;
+    let mut finish_reason = Value::Null;
+    let mut error = Value::Null;
+    for (prefix, payload) in output.lines().filter_map(|line| line.split_once(':')) {
+        let Ok(payload) = serde_json::from_str::<Value>(payload) else {
+            continue;
+        };
+        match prefix {
+            "0" => text.push_str(payload.as_str().unwrap_or_default()),
+            "9" => tool_calls.push(payload),
+            "3" if error.is_null() => error = payload,
+            "d" => {
+                usage = payload["usage"].clone();
+                finish_reason = payload["finishReason"].clone();
+            }
+            _ => {}
+        }
+    }
+    json!({
+        "text": text,
+        "toolCalls": tool_calls,
+        "usage": usage,
+        "finishReason": finish_reason,
+        "error": error,
+    })
+}
@@ -0,0 +1,131 @@
+//! In-process stub provider for tests, built with the test-stub feature: `stub/<script>` models
+//! are routed like Claude models, then answered from a canned Anthropic stream instead of a
+//! request, so the whole handler path runs without a socket
+
+use actix_web::HttpResponse;
+use bytes::Bytes;
+use futures::stream;
+
+use crate::convert::FrameWriter;
+use crate::providers::anthropic::anthropic_stream_response;
+use crate::error::ApiError;
+
+pub const STUB_PREFIX: &str = "stub/";
+
+// Anthropic SSE events, each sent as its own chunk
+const STUB_SCRIPTS: &[(&str, &[&str])] = &[
+    (
+        "text",
+        &[
+            r#"{"type":"message_start","message":{"id":"msg_stub","type":"message","role":"assistant","content":[],"model":"stub","usage":{"input_tokens":10,"output_tokens":1}}}"#,
+            r#"{"type":"content_block_start","index":0,"content_block":{"type":"text","text":""}}"#,
+            r#"{"type":"content_block_delta","index":0,"delta":{"type":"text_delta","text":"Hello from "}}"#,
+            r#"{"type":"content_block_delta","index":0,"delta":{"type":"text_delta","text":"the stub."}}"#,
+            r#"{"type":"content_block_stop","index":0}"#,
+            r#"{"type":"message_delta","delta":{"stop_reason":"end_turn"},"usage":{"output_tokens":5}}"#,
+            r#"{"type":"message_stop"}"#,
+        ],
+    ),
+    (
+        "tool-call",
+        &[
+            r#"{"type":"message_start","message":{"id":"msg_stub","type":"message","role":"assistant","content":[],"model":"stub","usage":{"input_tokens":10,"output_tokens":1}}}"#,
+            r#"{"type":"content_block_start","index":0,"content_block":{"type":"text","text":""}}"#,
+            r#"{"type":"content_block_delta","index":0,"delta":{"type":"text_delta","text":"Let me check."}}"#,
+            r#"{"type":"content_block_stop","index":0}"#,
+            r#"{"type":"content_block_start","index":1,"content_block":{"type":"tool_use","id":"toolu_stub","name":"executeSQL","input":{}}}"#,
+            r#"{"type":"content_block_delta","index":1,"delta":{"type":"input_json_delta","partial_json":"{\"sql\":\"SELECT 1\"}"}}"#,
+            r#"{"type":"content_block_stop","index":1}"#,
+            r#"{"type":"message_delta","delta":{"stop_reason":"tool_use"},"usage":{"output_tokens":12}}"#,
+            r#"{"type":"message_stop"}"#,
+        ],
+    ),
+    (
+        "error",
+        &[
+            r#"{"type":"message_start","message":{"id":"msg_stub","type":"message","role":"assistant","content":[],"model":"stub","usage":{"input_tokens":10,"output_tokens":1}}}"#,
+            r#"{"type":"content_block_start","index":0,"content_block":{"type":"text","text":""}}"#,
+            r#"{"type":"content_block_delta","index":0,"delta":{"type":"text_delta","text":"Starting"}}"#,
+            r#"{"type":"error","error":{"type":"api_error","message":"The stub failed on purpose"}}"#,
+        ],
+    ),
+];
+
+// The script a stub/ model names; None for every other model
+pub fn stub_script(model: &str) -> Result<Option<&'static [&'static str]>, ApiError> {
+    let Some(name) = model.strip_prefix(STUB_PREFIX) else {
+        return Ok(None);
+    };
+    STUB_SCRIPTS.iter().find(|(script, _)| *script == name).map(|(_, events)| Some(*events)).ok_or_else(|| {
+        let known: Vec<&str> = STUB_SCRIPTS.iter().map(|(script, _)| *script).collect();
+        ApiError::bad_request(format!("Unknown stub script '{}'. Known scripts: {}", name, known.join(", ")))
+    })
+}
+
+// The script's events through the Anthropic converter, as handle_anthropic_request does with a
+// provider's stream
+pub fn stub_response(events: &'static [&'static str], frames: FrameWriter) -> HttpResponse {
+    let chunks = events.iter().map(|event| Ok::<_, reqwest::Error>(Bytes::from(format!("data: {}\n\n", event))));
+    anthropic_stream_response(stream::iter(chunks), None, frames)
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{test, web, App};
+    use arc_swap::ArcSwap;
+    use serde_json::{json, Value};
+
+    use crate::config::TellConfig;
+    use crate::chat::sdk_chat;
+
+    async fn chat(model: &str) -> (u16, String) {
+        let config = web::Data::new(ArcSwap::from_pointee(TellConfig::default()));
+        let app =
+            test::init_service(App::new().app_data(config).route("/sdk-chat", web::post().to(sdk_chat))).await;
+        let request = test::TestRequest::post()
+            .uri("/sdk-chat")
+            .set_json(json!({ "model": model, "messages": [{ "role": "user", "content": "Hi" }], "aiSdkVersion": 4 }))
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        let status = response.status().as_u16();
+        (status, String::from_utf8(test::read_body(response).await.to_vec()).unwrap())
+    }
+
+    fn frames(body: &str) -> Vec<(&str, Value)> {
+        body.lines()
+            .filter_map(|line| line.split_once(':'))
+            .map(|(prefix, payload)| (prefix, serde_json::from_str(payload).unwrap()))
+            .collect()
+    }
+
+    fn text(frames: &[(&str, Value)]) -> String {
+        frames.iter().filter(|(prefix, _)| *prefix == "0").filter_map(|(_, text)| text.as_str()).collect()
+    }
+
+    #[actix_web::test]
+    async fn stub_scripts_run_through_the_handler() {
+        let (status, body) = chat("stub/text").await;
+        assert_eq!(status, 200);
+        let streamed = frames(&body);
+        assert_eq!(text(&streamed), "Hello from the stub.");
+        let (_, finish) = streamed.last().unwrap();
+        assert_eq!(finish["finishReason"], "stop");
+        assert_eq!(finish["usage"], json!({ "promptTokens": 10, "completionTokens": 5 }));
+
+        let (_, body) = chat("stub/tool-call").await;
+        let streamed = frames(&body);
+        let (_, tool_call) = streamed.iter().find(|(prefix, _)| *prefix == "9").unwrap();
+        assert_eq!(tool_call["toolName"], "executeSQL");
+        assert_eq!(tool_call["args"], json!({ "sql": "SELECT 1" }));
+        assert_eq!(streamed.last().unwrap().1["finishReason"], "tool-calls");
+
+        let (_, body) = chat("stub/error").await;
+        let streamed = frames(&body);
+        assert_eq!(text(&streamed), "Starting");
+        assert!(streamed.iter().any(|(prefix, _)| *prefix == "3"));
+        assert_eq!(streamed.last().unwrap().1["finishReason"], "error");
+
+        let (status, _) = chat("stub/nope").await;
+        assert_eq!(status, 400);
+    }
+}
@@ -0,0 +1,154 @@
+//! Named prompt templates from TEMPLATES_DIR: a system prompt and seed messages with
+//! {{variable}} placeholders, filled in from the request and put ahead of its messages
+
+use std::collections::{BTreeSet, HashMap};
+use std::env;
+
+use arc_swap::ArcSwap;
+use log::info;
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::validate::FieldError;
+use crate::error::ApiError;
+use crate::request::{ChatMessage, ChatRequest};
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PromptTemplate {
+    #[serde(default)]
+    system: Option<String>,
+    // Conversation turns sent ahead of the client's messages, e.g. few-shot examples
+    #[serde(default)]
+    messages: Vec<TemplateMessage>,
+    // Values for variables the request may leave out; every other variable is required
+    #[serde(default)]
+    defaults: HashMap<String, String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TemplateMessage {
+    role: String,
+    content: String,
+}
+
+// Templates by name, loaded at startup and replaced by POST /admin/reload
+lazy_static::lazy_static! {
+    pub static ref TEMPLATES: ArcSwap<HashMap<String, PromptTemplate>> = ArcSwap::from_pointee(HashMap::new());
+    static ref PLACEHOLDER: Regex = Regex::new(r"\{\{\s*([A-Za-z_][A-Za-z0-9_]*)\s*\}\}").unwrap();
+}
+
+// Read every template in TEMPLATES_DIR, one per file named after it (TOML if it ends in .toml,
+// JSON if it ends in .json; other files are skipped). No templates when it isn't set.
+pub fn load_templates() -> Result<HashMap<String, PromptTemplate>, String> {
+    let Ok(dir) = env::var("TEMPLATES_DIR") else {
+        return Ok(HashMap::new());
+    };
+    let entries = std::fs::read_dir(&dir).map_err(|e| format!("can't read {}: {}", dir, e))?;
+    let mut templates = HashMap::new();
+    for entry in entries {
+        let path = entry.map_err(|e| format!("can't read {}: {}", dir, e))?.path();
+        let (Some(name), Some(extension)) = (path.file_stem().and_then(|s| s.to_str()), path.extension()) else {
+            continue;
+        };
+        if extension != "toml" && extension != "json" {
+            continue;
+        }
+        let contents =
+            std::fs::read_to_string(&path).map_err(|e| format!("can't read {}: {}", path.display(), e))?;
+        let template: PromptTemplate = if extension == "toml" {
+            toml::from_str(&contents).map_err(|e| format!("{}: {}", path.display(), e))?
+        } else {
+            serde_json::from_str(&contents).map_err(|e| format!("{}: {}", path.display(), e))?
+        };
+        template.validate().map_err(|e| format!("{}: {}", path.display(), e))?;
+        if templates.insert(name.to_string(), template).is_some() {
+            return Err(format!("template '{}' is defined twice in {}", name, dir));
+        }
+    }
+    info!("Loaded {} prompt template(s) from {}", templates.len(), dir);
+    Ok(templates)
+}
+
+impl PromptTemplate {
+    fn validate(&self) -> Result<(), String> {
+        if self.system.is_none() && self.messages.is_empty() {
+            return Err("a template needs a system prompt or messages".to_string());
+        }
+        for (index, message) in self.messages.iter().enumerate() {
+            if !["system", "user", "assistant"].contains(&message.role.as_str()) {
+                return Err(format!(
+                    "messages[{}] role must be system, user or assistant, not '{}'",
+                    index, message.role
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    fn texts(&self) -> impl Iterator<Item = &str> {
+        self.system.iter().map(String::as_str).chain(self.messages.iter().map(|message| message.content.as_str()))
+    }
+
+    // Every variable the template uses, in name order
+    fn variables(&self) -> BTreeSet<&str> {
+        self.texts()
+            .flat_map(|text| PLACEHOLDER.captures_iter(text).map(|captures| captures.get(1).unwrap().as_str()))
+            .collect()
+    }
+}
+
+fn render(text: &str, values: &HashMap<&str, &str>) -> String {
+    PLACEHOLDER.replace_all(text, |captures: &regex::Captures| values[&captures[1]].to_string()).into_owned()
+}
+
+// Expand the request's template, if it names one, into messages ahead of its own. Unknown
+// templates and required variables the request leaves out are a 400.
+pub fn apply_template(request: &mut ChatRequest) -> Result<(), ApiError> {
+    let Some(name) = request.template.take() else {
+        return Ok(());
+    };
+    let variables = std::mem::take(&mut request.variables);
+    let templates = TEMPLATES.load();
+    let template = templates.get(&name).ok_or_else(|| {
+        let mut known: Vec<&str> = templates.keys().map(String::as_str).collect();
+        known.sort_unstable();
+        let known = if known.is_empty() { "none".to_string() } else { known.join(", ") };
+        ApiError::invalid_fields(vec![FieldError::new(
+            "template",
+            format!("'{}' is not a known template. Known templates: {}", name, known),
+        )])
+    })?;
+
+    let mut values = HashMap::new();
+    let mut missing = Vec::new();
+    for variable in template.variables() {
+        match variables.get(variable).or_else(|| template.defaults.get(variable)) {
+            Some(value) => {
+                values.insert(variable, value.as_str());
+            }
+            None => missing.push(FieldError::new(
+                format!("variables.{}", variable),
+                format!("is required by template '{}'", name),
+            )),
+        }
+    }
+    if !missing.is_empty() {
+        return Err(ApiError::invalid_fields(missing));
+    }
+
+    let system = template.system.iter().map(|system| ("system", system));
+    let seed = template.messages.iter().map(|message| (message.role.as_str(), &message.content));
+    let expanded: Vec<ChatMessage> = system
+        .chain(seed)
+        .map(|(role, content)| ChatMessage {
+            role: role.to_string(),
+            content: Some(render(content, &values)),
+            ..Default::default()
+        })
+        .collect();
+    info!("Expanded template {} into {} message(s)", name, expanded.len());
+    request.messages.splice(0..0, expanded);
+    Ok(())
+}
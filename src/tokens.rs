@@ -0,0 +1,106 @@
+//! Prompt token counts: POST /count-tokens, and the local estimate other checks use
+
+use actix_web::{web, HttpRequest, HttpResponse};
+use log::info;
+use serde_json::{json, Value};
+use tiktoken_rs::tokenizer::{get_tokenizer, Tokenizer};
+use tiktoken_rs::{
+    cl100k_base_singleton, o200k_base_singleton, p50k_base_singleton, p50k_edit_singleton,
+    r50k_base_singleton,
+};
+
+use crate::error::ApiError;
+use crate::messages::{apply_server_system_prompt, prepare_messages};
+use crate::providers::anthropic::{convert_messages_to_anthropic, count_anthropic_tokens};
+use crate::providers::openai::{
+    convert_messages_to_openai, convert_tools_to_openai, fold_system_messages, model_capabilities,
+};
+use crate::providers::{resolve_provider, Provider};
+use crate::templates::apply_template;
+use crate::tools::create_tools;
+use crate::validate::parse_chat_request;
+
+pub async fn count_tokens(req: HttpRequest, body: web::Bytes) -> Result<HttpResponse, ApiError> {
+    let mut request = parse_chat_request(&body)?;
+
+    let provider = resolve_provider(&request.model)?.provider;
+    let model = request.model.clone();
+    // Count the messages as chat would send them: template, role fixes and server system prompt
+    apply_template(&mut request)?;
+    prepare_messages(provider, &mut request)?;
+    apply_server_system_prompt(&req, &mut request.messages);
+
+    let prompt_tokens = match provider {
+        Provider::Anthropic => count_anthropic_tokens(request).await?,
+        // Bedrock has no token counting in the streaming API, so estimate locally
+        Provider::Bedrock => {
+            let messages = convert_messages_to_anthropic(request.messages);
+            count_tokens_locally(&model, &messages, &json!(create_tools()))
+        }
+        Provider::OpenAI(compatible) => {
+            let upstream_model = compatible.upstream_model(&model);
+            let capabilities = model_capabilities(upstream_model);
+            let tools = if capabilities.supports_tools {
+                json!(convert_tools_to_openai(create_tools()))
+            } else {
+                json!([])
+            };
+            let mut messages = convert_messages_to_openai(request.messages);
+            if !capabilities.supports_system_role {
+                messages = fold_system_messages(messages);
+            }
+            count_tokens_locally(upstream_model, &messages, &tools)
+        }
+        // Mistral's tokenizer isn't available locally, so estimate with tiktoken
+        Provider::Mistral => {
+            let messages = convert_messages_to_openai(request.messages);
+            count_tokens_locally(&model, &messages, &json!(convert_tools_to_openai(create_tools())))
+        }
+        // Likewise for Cohere, which is sent no tools
+        Provider::Cohere => {
+            let messages = convert_messages_to_openai(request.messages);
+            count_tokens_locally(&model, &messages, &json!([]))
+        }
+    };
+
+    info!("Counted {} prompt tokens for model={} provider={}", prompt_tokens, model, provider.name());
+
+    Ok(HttpResponse::Ok().json(json!({
+        "provider": provider.name(),
+        "model": model,
+        "promptTokens": prompt_tokens,
+    })))
+}
+
+// Estimate prompt tokens with the tokenizer matching the model, following OpenAI's
+// accounting of ~3 tokens of framing per message plus 3 to prime the reply
+pub fn count_tokens_locally(model: &str, messages: &[Value], tools: &Value) -> u64 {
+    let bpe = match get_tokenizer(model) {
+        Some(Tokenizer::Cl100kBase) => cl100k_base_singleton(),
+        Some(Tokenizer::P50kBase) => p50k_base_singleton(),
+        Some(Tokenizer::P50kEdit) => p50k_edit_singleton(),
+        Some(Tokenizer::R50kBase) | Some(Tokenizer::Gpt2) => r50k_base_singleton(),
+        // Unknown models (including Claude) get the newest encoding as an estimate
+        Some(Tokenizer::O200kBase) | None => o200k_base_singleton(),
+    };
+    let count = |text: &str| bpe.encode_with_special_tokens(text).len() as u64;
+
+    let mut total = 3;
+    for message in messages {
+        total += 3;
+        if let Some(fields) = message.as_object() {
+            for value in fields.values() {
+                total += match value {
+                    Value::String(text) => count(text),
+                    other => count(&other.to_string()),
+                };
+            }
+        }
+    }
+
+    if tools.as_array().is_some_and(|tools| !tools.is_empty()) {
+        total += count(&tools.to_string());
+    }
+
+    total
+}
@@ -0,0 +1,239 @@
+//! Tool definitions offered to the models: the built-in ones, or those from TOOLS_FILE
+
+use std::env;
+use std::collections::HashSet;
+
+use arc_swap::ArcSwap;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use log::info;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ToolInputSchema {
+    #[serde(rename = "type")]
+    schema_type: String,
+    properties: serde_json::Map<String, Value>,
+    #[serde(default)]
+    required: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Tool {
+    pub name: String,
+    pub description: String,
+    pub input_schema: ToolInputSchema,
+}
+
+// The tools offered to every model: the TOOLS_FILE definitions if one was loaded (at startup or
+// by POST /admin/reload), otherwise the built-in ones
+lazy_static::lazy_static! {
+    pub static ref TOOLS: ArcSwap<Vec<Tool>> = ArcSwap::from_pointee(builtin_tools());
+}
+
+pub fn create_tools() -> Vec<Tool> {
+    Vec::clone(&TOOLS.load())
+}
+
+// Whether and which tool the model has to call: "auto" (its own choice, the default), "none",
+// "required" (any tool) or {"name"} for one tool in particular
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum ToolChoice {
+    Mode(ToolChoiceMode),
+    Tool { name: String },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ToolChoiceMode {
+    Auto,
+    None,
+    Required,
+}
+
+impl ToolChoice {
+    // OpenAI's (and Mistral's) tool_choice
+    pub fn to_openai(&self) -> Value {
+        match self {
+            ToolChoice::Mode(ToolChoiceMode::Auto) => json!("auto"),
+            ToolChoice::Mode(ToolChoiceMode::None) => json!("none"),
+            ToolChoice::Mode(ToolChoiceMode::Required) => json!("required"),
+            ToolChoice::Tool { name } => json!({ "type": "function", "function": { "name": name } }),
+        }
+    }
+
+    // Anthropic's tool_choice, also used on Bedrock
+    pub fn to_anthropic(&self) -> Value {
+        match self {
+            ToolChoice::Mode(ToolChoiceMode::Auto) => json!({ "type": "auto" }),
+            ToolChoice::Mode(ToolChoiceMode::None) => json!({ "type": "none" }),
+            ToolChoice::Mode(ToolChoiceMode::Required) => json!({ "type": "any" }),
+            ToolChoice::Tool { name } => json!({ "type": "tool", "name": name }),
+        }
+    }
+
+    // Whether the model has to call a tool, so the request can't be sent without tools
+    pub fn forces_tool_call(&self) -> bool {
+        !matches!(self, ToolChoice::Mode(ToolChoiceMode::Auto | ToolChoiceMode::None))
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ToolsFile {
+    tools: Vec<Tool>,
+}
+
+// Read the tool definitions from TOOLS_FILE (TOML if it ends in .toml, otherwise JSON), or
+// fall back to the built-in ones when it isn't set
+pub fn load_tools() -> Result<Vec<Tool>, String> {
+    let Ok(path) = env::var("TOOLS_FILE") else {
+        return Ok(builtin_tools());
+    };
+    let contents = std::fs::read_to_string(&path).map_err(|e| format!("can't read {}: {}", path, e))?;
+    let file: ToolsFile = if path.ends_with(".toml") {
+        toml::from_str(&contents).map_err(|e| format!("{}: {}", path, e))?
+    } else {
+        serde_json::from_str(&contents).map_err(|e| format!("{}: {}", path, e))?
+    };
+    validate_tools(&file.tools).map_err(|e| format!("{}: {}", path, e))?;
+    info!("Loaded {} tool definition(s) from {}", file.tools.len(), path);
+    Ok(file.tools)
+}
+
+pub fn validate_tools(tools: &[Tool]) -> Result<(), String> {
+    if tools.is_empty() {
+        return Err("no tools defined".to_string());
+    }
+    let mut names = HashSet::new();
+    for tool in tools {
+        if tool.name.is_empty() {
+            return Err("a tool has an empty name".to_string());
+        }
+        if !names.insert(tool.name.as_str()) {
+            return Err(format!("tool '{}' is defined twice", tool.name));
+        }
+        if tool.description.trim().is_empty() {
+            return Err(format!("tool '{}' has no description", tool.name));
+        }
+        let schema = &tool.input_schema;
+        if schema.schema_type != "object" {
+            return Err(format!(
+                "tool '{}' input_schema type must be \"object\", not \"{}\"",
+                tool.name, schema.schema_type
+            ));
+        }
+        for (property, definition) in &schema.properties {
+            if !definition.get("type").is_some_and(Value::is_string) {
+                return Err(format!("tool '{}' property '{}' needs a string \"type\"", tool.name, property));
+            }
+        }
+        if let Some(missing) = schema.required.iter().find(|name| !schema.properties.contains_key(*name)) {
+            return Err(format!("tool '{}' requires '{}', which isn't one of its properties", tool.name, missing));
+        }
+    }
+    Ok(())
+}
+
+pub fn builtin_tools() -> Vec<Tool> {
+    let mut execute_sql_properties = serde_json::Map::new();
+    execute_sql_properties.insert(
+        "sql".to_string(),
+        json!({
+            "type": "string",
+            "description": "The complete DuckDB-compatible SQL query. CRITICAL: Use proper SQL syntax only - no English phrases! Use: = (not 'equals'), < (not 'less than'), > (not 'greater than'), BETWEEN x AND y (not 'IS BETWEEN' or 'is around'), LIKE '%pattern%' (not 'contains'), IS NULL/IS NOT NULL only. Example: WHERE age BETWEEN 20 AND 30 (correct), NOT WHERE age IS BETWEEN 20 AND 30 (wrong)"
+        })
+    );
+
+    let mut add_transformation_properties = serde_json::Map::new();
+    add_transformation_properties.insert(
+        "sql".to_string(),
+        json!({
+            "type": "string",
+            "description": "The SQL query for the transformation. Use 'previous_step' to reference the output of the last transformation, or reference other transformation outputs by their alias names."
+        })
+    );
+    add_transformation_properties.insert(
+        "outputAlias".to_string(),
+        json!({
+            "type": "string",
+            "description": "A meaningful name for this transformation step using underscores (e.g., 'filtered_data', 'high_value_orders', 'aggregated_results')"
+        })
+    );
+
+    let mut create_visualization_properties = serde_json::Map::new();
+    create_visualization_properties.insert(
+        "type".to_string(),
+        json!({
+            "type": "string",
+            "description": "The type of chart to create: 'bar', 'line', 'scatter', 'pie', 'area', or 'heatmap'. IMPORTANT: Different chart types require different data structures - bar/pie charts need aggregated/grouped data, while scatter plots need raw x,y pairs."
+        })
+    );
+    create_visualization_properties.insert(
+        "title".to_string(),
+        json!({
+            "type": "string",
+            "description": "A descriptive title for the visualization"
+        })
+    );
+    create_visualization_properties.insert(
+        "xAxis".to_string(),
+        json!({
+            "type": "string",
+            "description": "The column name to use for the x-axis (or category column for pie charts)"
+        })
+    );
+    create_visualization_properties.insert(
+        "yAxis".to_string(),
+        json!({
+            "type": "string",
+            "description": "The column name to use for the y-axis (or value column for pie charts). For bar/pie charts, this should typically be an aggregated value (COUNT, SUM, AVG, etc.)"
+        })
+    );
+    create_visualization_properties.insert(
+        "sql".to_string(),
+        json!({
+            "type": "string",
+            "description": "Optional custom SQL query to fetch chart-specific data. CRITICAL: Provide aggregated SQL for bar/pie charts! Examples: Bar chart: 'SELECT category, COUNT(*) as count FROM table GROUP BY category LIMIT 20', Pie chart: 'SELECT region, SUM(sales) as total FROM table GROUP BY region', Line chart: 'SELECT date, AVG(value) as avg_value FROM table GROUP BY date ORDER BY date', Scatter: 'SELECT x_col, y_col FROM table LIMIT 1000'. If not provided, a basic query will be generated based on chart type."
+        })
+    );
+    create_visualization_properties.insert(
+        "description".to_string(),
+        json!({
+            "type": "string",
+            "description": "Optional description explaining what the visualization shows"
+        })
+    );
+
+    vec![
+        Tool {
+            name: "executeSQL".to_string(),
+            description: "Run a SQL query for immediate results without adding it to the transformation pipeline. Use for exploratory queries, data inspection, or when users want to see results right away.".to_string(),
+            input_schema: ToolInputSchema {
+                schema_type: "object".to_string(),
+                properties: execute_sql_properties,
+                required: vec!["sql".to_string()],
+            },
+        },
+        Tool {
+            name: "addTransformation".to_string(),
+            description: "Add a SQL transformation step to the data pipeline. Use when users want to filter, transform, or process data as part of their workflow.".to_string(),
+            input_schema: ToolInputSchema {
+                schema_type: "object".to_string(),
+                properties: add_transformation_properties,
+                required: vec!["sql".to_string(), "outputAlias".to_string()],
+            },
+        },
+        Tool {
+            name: "createVisualization".to_string(),
+            description: "Create a data visualization (chart) from query results. Use when users ask to visualize, chart, graph, or plot data. Supports bar charts, line charts, scatter plots, pie charts, area charts, and heatmaps.".to_string(),
+            input_schema: ToolInputSchema {
+                schema_type: "object".to_string(),
+                properties: create_visualization_properties,
+                required: vec!["type".to_string(), "xAxis".to_string(), "yAxis".to_string()],
+            },
+        },
+    ]
+}
@@ -0,0 +1,240 @@
+//! Post-processing of the converted stream: a chain of transforms, named in STREAM_TRANSFORMS,
+//! that can rewrite the text and tool calls before they reach the client
+
+use std::collections::HashSet;
+use std::env;
+use std::pin::Pin;
+use std::sync::{Arc, OnceLock};
+use std::task::{Context, Poll};
+
+use bytes::Bytes;
+use log::info;
+use regex::Regex;
+use serde_json::Value;
+use tokio_stream::Stream;
+
+use crate::convert::{FrameWriter, LineBuffer};
+
+// One step of the chain. Each stream gets its own instance, so a transform can keep state
+// across deltas, such as text held back until a word is complete.
+pub trait StreamTransform {
+    // A text delta; the returned text is streamed in its place, and may be empty
+    fn on_text(&mut self, text: &str) -> String;
+
+    // A complete tool call frame payload ({toolCallId, toolName, args}, plus argsText when the
+    // arguments didn't parse), changed in place
+    fn on_tool_call(&mut self, _tool_call: &mut Value) {}
+
+    // Text still held back when a non-text frame or the end of the stream comes
+    fn flush(&mut self) -> String {
+        String::new()
+    }
+}
+
+type TransformFactory = Box<dyn Fn() -> Box<dyn StreamTransform> + Send + Sync>;
+
+static TRANSFORMS: OnceLock<Vec<TransformFactory>> = OnceLock::new();
+
+// Adding a transform is an implementation of StreamTransform plus an entry here
+fn transform_factory(name: &str) -> Result<TransformFactory, String> {
+    match name {
+        "redact" => {
+            let redactor = Redactor::from_env()?;
+            Ok(Box::new(move || Box::new(redactor.clone())))
+        }
+        _ => Err(format!("unknown transform '{}'. Known transforms: redact", name)),
+    }
+}
+
+// Set up the transforms named in STREAM_TRANSFORMS (comma-separated, applied in order)
+pub fn load_transforms() -> Result<(), String> {
+    let names = env::var("STREAM_TRANSFORMS").unwrap_or_default();
+    let names: Vec<&str> = names.split(',').map(str::trim).filter(|name| !name.is_empty()).collect();
+    let factories = names.iter().map(|name| transform_factory(name)).collect::<Result<Vec<_>, _>>()?;
+    if !factories.is_empty() {
+        info!("Stream transforms: {}", names.join(", "));
+    }
+    let _ = TRANSFORMS.set(factories);
+    Ok(())
+}
+
+// A fresh chain for one stream; empty when no transforms are configured
+pub fn transform_chain() -> Vec<Box<dyn StreamTransform>> {
+    TRANSFORMS.get().map(|factories| factories.iter().map(|factory| factory()).collect()).unwrap_or_default()
+}
+
+// Runs the text (0:) and tool call (9:) frames of a stream through the transforms. Streamed
+// tool call argument deltas (c:) are held back until their tool call is complete, then sent as
+// one delta of the transformed arguments just before its 9: frame, so no raw argument text gets
+// past the transforms. Other frames pass through unchanged, after any text the transforms were
+// still holding. With no transforms the stream passes through untouched.
+pub struct Transformed<S> {
+    inner: Pin<Box<S>>,
+    transforms: Vec<Box<dyn StreamTransform>>,
+    frames: FrameWriter,
+    lines: LineBuffer,
+    // Tool calls whose argument deltas are being held back
+    held_tool_calls: HashSet<String>,
+}
+
+impl<S> Transformed<S> {
+    pub fn new(inner: S, transforms: Vec<Box<dyn StreamTransform>>, frames: FrameWriter) -> Self {
+        Transformed {
+            inner: Box::pin(inner),
+            transforms,
+            frames,
+            lines: LineBuffer::default(),
+            held_tool_calls: HashSet::new(),
+        }
+    }
+
+    fn transform_text(&mut self, text: &str) -> String {
+        self.transforms.iter_mut().fold(text.to_string(), |text, transform| transform.on_text(&text))
+    }
+
+    // Held-back text from each transform, passed on through the ones after it
+    fn flush(&mut self) -> String {
+        let mut text = String::new();
+        for transform in &mut self.transforms {
+            text = transform.on_text(&text) + &transform.flush();
+        }
+        self.frames.text(&text)
+    }
+
+    fn transform_frames(&mut self, chunk: &[u8]) -> String {
+        let mut output = String::new();
+        for line in self.lines.push(chunk).lines() {
+            let Some((prefix, payload)) = line.split_once(':') else {
+                output.push_str(line);
+                output.push('\n');
+                continue;
+            };
+            match (prefix, serde_json::from_str::<Value>(payload)) {
+                ("0", Ok(Value::String(text))) => {
+                    let text = self.transform_text(&text);
+                    output.push_str(&self.frames.text(&text));
+                }
+                ("c", Ok(delta)) => {
+                    if let Some(id) = delta["toolCallId"].as_str() {
+                        self.held_tool_calls.insert(id.to_string());
+                    }
+                }
+                ("9", Ok(mut tool_call)) => {
+                    output.push_str(&self.flush());
+                    for transform in &mut self.transforms {
+                        transform.on_tool_call(&mut tool_call);
+                    }
+                    if let Some(id) = tool_call["toolCallId"].as_str() {
+                        if self.held_tool_calls.remove(id) {
+                            let args_text = match tool_call.get("argsText").and_then(Value::as_str) {
+                                Some(args_text) => args_text.to_string(),
+                                None => tool_call["args"].to_string(),
+                            };
+                            output.push_str(&self.frames.tool_call_delta(id, &args_text));
+                        }
+                    }
+                    output.push_str(&format!("9:{}\n", tool_call));
+                }
+                _ => {
+                    output.push_str(&self.flush());
+                    output.push_str(line);
+                    output.push('\n');
+                }
+            }
+        }
+        output
+    }
+}
+
+impl<S, E> Stream for Transformed<S>
+where
+    S: Stream<Item = Result<Bytes, E>>,
+{
+    type Item = Result<Bytes, E>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.transforms.is_empty() {
+            return self.inner.as_mut().poll_next(cx);
+        }
+        match self.inner.as_mut().poll_next(cx) {
+            Poll::Ready(Some(Ok(chunk))) => {
+                let output = self.transform_frames(&chunk);
+                Poll::Ready(Some(Ok(Bytes::from(output))))
+            }
+            Poll::Ready(None) => {
+                let rest = self.flush();
+                if rest.is_empty() {
+                    Poll::Ready(None)
+                } else {
+                    Poll::Ready(Some(Ok(Bytes::from(rest))))
+                }
+            }
+            poll => poll,
+        }
+    }
+}
+
+// Replaces matches of REDACT_PATTERNS (whitespace-separated regexes; email addresses by
+// default) with REDACT_REPLACEMENT (default "[redacted]"), in text and in tool call arguments.
+// Text is held back up to the last whitespace, so a match split across deltas is still caught.
+#[derive(Clone)]
+pub struct Redactor {
+    patterns: Arc<Vec<Regex>>,
+    replacement: Arc<str>,
+    held: String,
+}
+
+impl Redactor {
+    fn from_env() -> Result<Self, String> {
+        let patterns = env::var("REDACT_PATTERNS")
+            .unwrap_or_else(|_| r"[A-Za-z0-9._%+-]+@[A-Za-z0-9.-]+\.[A-Za-z]{2,}".to_string());
+        let patterns = patterns
+            .split_whitespace()
+            .map(|pattern| Regex::new(pattern).map_err(|e| format!("invalid REDACT_PATTERNS entry '{}': {}", pattern, e)))
+            .collect::<Result<Vec<_>, _>>()?;
+        let replacement = env::var("REDACT_REPLACEMENT").unwrap_or_else(|_| "[redacted]".to_string());
+        Ok(Redactor { patterns: Arc::new(patterns), replacement: replacement.into(), held: String::new() })
+    }
+
+    fn redact(&self, text: &str) -> String {
+        self.patterns.iter().fold(text.to_string(), |text, pattern| {
+            pattern.replace_all(&text, &*self.replacement).into_owned()
+        })
+    }
+
+    fn redact_strings(&self, value: &mut Value) {
+        match value {
+            Value::String(text) => *text = self.redact(text),
+            Value::Array(items) => items.iter_mut().for_each(|item| self.redact_strings(item)),
+            Value::Object(fields) => fields.values_mut().for_each(|field| self.redact_strings(field)),
+            _ => {}
+        }
+    }
+}
+
+impl StreamTransform for Redactor {
+    fn on_text(&mut self, text: &str) -> String {
+        self.held.push_str(text);
+        let Some(end) = self.held.rfind(char::is_whitespace) else {
+            return String::new();
+        };
+        // Up to and including the whitespace character
+        let end = end + self.held[end..].chars().next().map_or(0, char::len_utf8);
+        let complete: String = self.held.drain(..end).collect();
+        self.redact(&complete)
+    }
+
+    fn on_tool_call(&mut self, tool_call: &mut Value) {
+        if let Some(args) = tool_call.get_mut("args") {
+            self.redact_strings(args);
+        }
+        if let Some(args_text) = tool_call.get_mut("argsText") {
+            self.redact_strings(args_text);
+        }
+    }
+
+    fn flush(&mut self) -> String {
+        let held = std::mem::take(&mut self.held);
+        self.redact(&held)
+    }
+}
@@ -0,0 +1,211 @@
+//! Trimming long conversations so the request fits the model's context window
+
+use std::env;
+
+use actix_web::HttpRequest;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::config::TellConfig;
+use crate::convert::FrameWriter;
+use crate::providers::openai::{convert_messages_to_openai, convert_tools_to_openai};
+use crate::providers::{resolve_provider, stream_from_provider, Provider, OPENAI};
+use crate::tools::create_tools;
+use crate::chat::is_dry_run;
+use crate::messages::prepare_messages;
+use crate::request::{ChatMessage, ChatRequest};
+use crate::stream::assemble_response;
+use crate::tokens::count_tokens_locally;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TrimStrategy {
+    // Send the conversation as it is, even when it's too long for the model
+    #[default]
+    None,
+    // Drop the oldest turns until the conversation fits
+    DropOldest,
+    // Drop the oldest turns and add a summary of them from TRIM_SUMMARY_MODEL
+    Summarize,
+}
+
+// Used when the client doesn't send maxTokens, matching the Anthropic request default
+const DEFAULT_RESERVED_OUTPUT_TOKENS: u64 = 4096;
+
+// Completion limit of the summary call, also kept free for the summary in the trimmed request
+const SUMMARY_MAX_TOKENS: u64 = 512;
+
+const SUMMARY_INSTRUCTION: &str = "Summarize the following conversation in a few sentences. Keep the facts, \
+    decisions and open questions that the rest of the conversation may rely on.";
+
+// Context windows, in tokens, of OpenAI models by model prefix; the longest matching prefix wins
+const OPENAI_CONTEXT_WINDOWS: &[(&str, u64)] = &[
+    ("gpt-3.5-turbo", 16_385),
+    ("gpt-4", 8_192),
+    ("gpt-4-turbo", 128_000),
+    ("gpt-4o", 128_000),
+    ("gpt-4.1", 1_047_576),
+    ("gpt-5", 400_000),
+    ("o1", 200_000),
+    ("o1-mini", 128_000),
+    ("o3", 200_000),
+    ("o4-mini", 200_000),
+];
+
+// Context window of `model` in tokens. CONTEXT_WINDOWS (comma-separated `prefix=tokens` pairs,
+// e.g. `llama-3=8192`) overrides the built-in values and covers self-hosted models.
+pub fn context_window(provider: Provider, model: &str) -> u64 {
+    let configured = env::var("CONTEXT_WINDOWS").unwrap_or_default();
+    let overrides = configured.split(',').filter_map(|entry| {
+        let (prefix, tokens) = entry.split_once('=')?;
+        Some((prefix.trim(), tokens.trim().parse::<u64>().ok()?))
+    });
+    if let Some(tokens) = longest_prefix_match(model, overrides) {
+        return tokens;
+    }
+    match provider {
+        Provider::Anthropic | Provider::Bedrock => 200_000,
+        Provider::OpenAI(compatible) if *compatible == OPENAI => {
+            longest_prefix_match(model, OPENAI_CONTEXT_WINDOWS.iter().copied()).unwrap_or(128_000)
+        }
+        Provider::OpenAI(_) | Provider::Mistral | Provider::Cohere => 128_000,
+    }
+}
+
+fn longest_prefix_match<'a>(model: &str, windows: impl Iterator<Item = (&'a str, u64)>) -> Option<u64> {
+    windows
+        .filter(|(prefix, _)| !prefix.is_empty() && model.starts_with(prefix))
+        .max_by_key(|(prefix, _)| prefix.len())
+        .map(|(_, tokens)| tokens)
+}
+
+// Estimated prompt tokens of `messages` for `model`, without the reply priming
+fn estimate_tokens(model: &str, messages: &[ChatMessage]) -> u64 {
+    count_tokens_locally(model, &convert_messages_to_openai(messages.to_vec()), &json!([])).saturating_sub(3)
+}
+
+// Index ranges of the turns that may be dropped, oldest first. A turn starts at a user message
+// and runs up to the next one. System messages and everything from the latest user message on
+// are always kept.
+fn droppable_turns(messages: &[ChatMessage]) -> Vec<Vec<usize>> {
+    let Some(latest_user) = messages.iter().rposition(|message| message.role == "user") else {
+        return Vec::new();
+    };
+    let mut turns: Vec<Vec<usize>> = Vec::new();
+    for (index, message) in messages[..latest_user].iter().enumerate() {
+        if message.role == "system" {
+            continue;
+        }
+        match turns.last_mut() {
+            Some(turn) if message.role != "user" => turn.push(index),
+            _ => turns.push(vec![index]),
+        }
+    }
+    turns
+}
+
+// Trim the oldest turns of `request` per its trimStrategy until the prompt fits the model's
+// context window with room for the completion. Returns how many messages were removed, or None
+// when trimming is off.
+pub async fn trim_history(
+    req: &HttpRequest,
+    provider: Provider,
+    request: &mut ChatRequest,
+    config: &TellConfig,
+) -> Option<usize> {
+    if request.trim_strategy == TrimStrategy::None {
+        return None;
+    }
+    let summarize = request.trim_strategy == TrimStrategy::Summarize;
+    let model = request.model.clone();
+    let tools = json!(convert_tools_to_openai(create_tools()));
+    let mut tokens = count_tokens_locally(&model, &convert_messages_to_openai(request.messages.clone()), &tools);
+    let reserved = request.max_tokens.map_or(DEFAULT_RESERVED_OUTPUT_TOKENS, u64::from)
+        + if summarize { SUMMARY_MAX_TOKENS } else { 0 };
+    let budget = context_window(provider, &model).saturating_sub(reserved);
+    if tokens <= budget {
+        return Some(0);
+    }
+
+    let mut dropped: Vec<usize> = Vec::new();
+    for turn in droppable_turns(&request.messages) {
+        if tokens <= budget {
+            break;
+        }
+        let turn_messages: Vec<ChatMessage> = turn.iter().map(|&index| request.messages[index].clone()).collect();
+        tokens = tokens.saturating_sub(estimate_tokens(&model, &turn_messages));
+        dropped.extend(turn);
+    }
+    if tokens > budget {
+        warn!("Conversation is still ~{} tokens after trimming, over the {} available for {}", tokens, budget, model);
+    }
+    if dropped.is_empty() {
+        return Some(0);
+    }
+
+    let mut kept = Vec::with_capacity(request.messages.len() - dropped.len());
+    let mut removed = Vec::with_capacity(dropped.len());
+    for (index, message) in std::mem::take(&mut request.messages).into_iter().enumerate() {
+        if dropped.contains(&index) {
+            removed.push(message);
+        } else {
+            kept.push(message);
+        }
+    }
+    request.messages = kept;
+    info!("Trimmed the {} oldest messages to fit the context window of {}", removed.len(), model);
+
+    // Dry runs make no upstream calls, so they show the trimmed request without a summary
+    if summarize && !is_dry_run(req) {
+        if let Some(summary) = summarize_messages(req, &removed, config).await {
+            let position = request.messages.iter().take_while(|message| message.role == "system").count();
+            let summary = ChatMessage {
+                role: "system".to_string(),
+                content: Some(format!("Summary of the earlier conversation: {}", summary)),
+                ..Default::default()
+            };
+            request.messages.insert(position, summary);
+        }
+    }
+    Some(removed.len())
+}
+
+// A summary of `messages` from TRIM_SUMMARY_MODEL (default claude-3-5-haiku-20241022), or None
+// when the call fails, in which case the messages are just dropped
+async fn summarize_messages(req: &HttpRequest, messages: &[ChatMessage], config: &TellConfig) -> Option<String> {
+    let model = env::var("TRIM_SUMMARY_MODEL").unwrap_or_else(|_| "claude-3-5-haiku-20241022".to_string());
+    let transcript: Vec<String> = messages
+        .iter()
+        .map(|message| match (&message.content, &message.tool_calls) {
+            (Some(content), _) => format!("{}: {}", message.role, content),
+            (None, Some(tool_calls)) => format!("{}: {}", message.role, json!(tool_calls)),
+            (None, None) => format!("{}:", message.role),
+        })
+        .collect();
+
+    let result = async {
+        let mut request: ChatRequest = serde_json::from_value(json!({
+            "model": model,
+            "messages": [
+                { "role": "system", "content": SUMMARY_INSTRUCTION },
+                { "role": "user", "content": transcript.join("\n") },
+            ],
+            "maxTokens": SUMMARY_MAX_TOKENS,
+        }))
+        .map_err(|e| e.to_string())?;
+        let provider = resolve_provider(&model).map_err(|e| e.to_string())?.provider;
+        prepare_messages(provider, &mut request).map_err(|e| e.to_string())?;
+        let frames = FrameWriter::new();
+        let response = stream_from_provider(provider, request, req, config, frames).await.map_err(|e| e.to_string())?;
+        let body = actix_web::body::to_bytes(response.into_body()).await.map_err(|e| e.to_string())?;
+        let response = assemble_response(&String::from_utf8_lossy(&body));
+        match response["text"].as_str() {
+            Some(text) if response["error"].is_null() && !text.trim().is_empty() => Ok(text.trim().to_string()),
+            _ => Err(format!("no summary in the response: {}", response["error"])),
+        }
+    }
+    .await;
+
+    result.inspect_err(|e| warn!("Couldn't summarize trimmed messages with {}: {}", model, e)).ok()
+}
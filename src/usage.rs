@@ -0,0 +1,192 @@
+//! GET /usage: token and request counts over a recent time window, from an in-memory rolling store
+
+use std::collections::HashMap;
+use std::env;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde_json::{json, Value};
+
+use crate::error::ApiError;
+
+// Usage is kept in one bucket per minute for the last USAGE_WINDOW_MINUTES (default 1440, a
+// day), reused round-robin, so memory stays fixed however long the server runs. A bucket holds
+// at most USAGE_MAX_KEYS (default 1000) provider/model/session combinations; past that, usage
+// still counts towards its provider and model but not its session.
+lazy_static::lazy_static! {
+    static ref USAGE: Mutex<UsageStore> = Mutex::new(UsageStore::from_env());
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct UsageKey {
+    provider: &'static str,
+    model: String,
+    session_id: Option<String>,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct UsageCounts {
+    requests: u64,
+    prompt_tokens: u64,
+    completion_tokens: u64,
+}
+
+impl UsageCounts {
+    fn add(&mut self, other: UsageCounts) {
+        self.requests += other.requests;
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+    }
+
+    fn to_json(self) -> Value {
+        json!({
+            "requests": self.requests,
+            "promptTokens": self.prompt_tokens,
+            "completionTokens": self.completion_tokens,
+            "totalTokens": self.prompt_tokens + self.completion_tokens,
+        })
+    }
+}
+
+#[derive(Debug, Default)]
+struct UsageBucket {
+    minute: u64,
+    counts: HashMap<UsageKey, UsageCounts>,
+}
+
+struct UsageStore {
+    buckets: Vec<UsageBucket>,
+    max_keys: usize,
+}
+
+impl UsageStore {
+    fn from_env() -> Self {
+        let window = env::var("USAGE_WINDOW_MINUTES")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .filter(|window| *window > 0)
+            .unwrap_or(1440);
+        let max_keys = env::var("USAGE_MAX_KEYS").ok().and_then(|v| v.parse().ok()).unwrap_or(1000);
+        UsageStore {
+            buckets: (0..window).map(|_| UsageBucket::default()).collect(),
+            max_keys,
+        }
+    }
+
+    fn window(&self) -> u64 {
+        self.buckets.len() as u64
+    }
+
+    fn record(&mut self, minute: u64, mut key: UsageKey, counts: UsageCounts) {
+        let index = (minute % self.window()) as usize;
+        let bucket = &mut self.buckets[index];
+        if bucket.minute != minute {
+            bucket.minute = minute;
+            bucket.counts.clear();
+        }
+        if !bucket.counts.contains_key(&key) && bucket.counts.len() >= self.max_keys {
+            key.session_id = None;
+        }
+        bucket.counts.entry(key).or_default().add(counts);
+    }
+
+    // Usage in the last `minutes` minutes up to `now`, matching the filter
+    fn totals(&self, now: u64, minutes: u64, filter: &UsageFilter) -> HashMap<(&'static str, String), UsageCounts> {
+        let mut totals: HashMap<(&'static str, String), UsageCounts> = HashMap::new();
+        let buckets = self.buckets.iter().filter(|bucket| bucket.minute <= now && now - bucket.minute < minutes);
+        for bucket in buckets {
+            for (key, counts) in bucket.counts.iter().filter(|(key, _)| filter.matches(key)) {
+                totals.entry((key.provider, key.model.clone())).or_default().add(*counts);
+            }
+        }
+        totals
+    }
+}
+
+#[derive(Debug, Default)]
+struct UsageFilter {
+    provider: Option<String>,
+    model: Option<String>,
+    session_id: Option<String>,
+}
+
+impl UsageFilter {
+    fn matches(&self, key: &UsageKey) -> bool {
+        self.provider.as_deref().is_none_or(|provider| provider.eq_ignore_ascii_case(key.provider))
+            && self.model.as_deref().is_none_or(|model| model == key.model)
+            && self.session_id.as_deref().is_none_or(|session_id| key.session_id.as_deref() == Some(session_id))
+    }
+}
+
+fn current_minute() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() / 60).unwrap_or(0)
+}
+
+// Count one finished response and the tokens in its finish frame
+pub fn record_usage(
+    provider: &'static str,
+    model: &str,
+    session_id: Option<&str>,
+    prompt_tokens: u64,
+    completion_tokens: u64,
+) {
+    let key = UsageKey {
+        provider,
+        model: model.to_string(),
+        session_id: session_id.map(str::to_string),
+    };
+    let counts = UsageCounts { requests: 1, prompt_tokens, completion_tokens };
+    USAGE.lock().unwrap().record(current_minute(), key, counts);
+}
+
+// ?provider=, ?model= and ?session= narrow the counts down; ?minutes= (default the whole
+// window) sets how far back they go. The response has the totals and a per-model breakdown.
+pub async fn get_usage(req: HttpRequest) -> Result<HttpResponse, ApiError> {
+    let mut query = web::Query::<HashMap<String, String>>::from_query(req.query_string())
+        .map_err(|e| ApiError::bad_request(format!("Invalid query: {}", e)))?
+        .into_inner();
+    let filter = UsageFilter {
+        provider: query.remove("provider"),
+        model: query.remove("model"),
+        session_id: query.remove("session"),
+    };
+
+    let store = USAGE.lock().unwrap();
+    let minutes = match query.remove("minutes") {
+        Some(minutes) => minutes
+            .parse::<u64>()
+            .ok()
+            .filter(|minutes| (1..=store.window()).contains(minutes))
+            .ok_or_else(|| {
+                ApiError::bad_request(format!("minutes must be between 1 and {} (USAGE_WINDOW_MINUTES)", store.window()))
+            })?,
+        None => store.window(),
+    };
+    let by_model = store.totals(current_minute(), minutes, &filter);
+    drop(store);
+
+    let mut total = UsageCounts::default();
+    let mut models: Vec<((&'static str, String), UsageCounts)> = by_model.into_iter().collect();
+    models.sort_by(|(a_key, a), (b_key, b)| {
+        (b.prompt_tokens + b.completion_tokens).cmp(&(a.prompt_tokens + a.completion_tokens)).then(a_key.cmp(b_key))
+    });
+    let models: Vec<Value> = models
+        .into_iter()
+        .map(|((provider, model), counts)| {
+            total.add(counts);
+            let mut entry = counts.to_json();
+            entry["provider"] = json!(provider);
+            entry["model"] = json!(model);
+            entry
+        })
+        .collect();
+
+    let mut body = total.to_json();
+    body["windowMinutes"] = json!(minutes);
+    body["models"] = json!(models);
+    if let Some(session_id) = filter.session_id {
+        body["sessionId"] = json!(session_id);
+    }
+    Ok(HttpResponse::Ok().json(body))
+}
@@ -0,0 +1,230 @@
+//! Checks of chat request bodies ahead of deserializing them, so a malformed body gets one
+//! message per wrong field instead of serde's first error
+
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::error::ApiError;
+use crate::request::ChatRequest;
+
+// One problem with the request body. `field` is the path to it, e.g. `messages[1].role`; it is
+// empty when the body as a whole is wrong.
+#[derive(Debug, Clone, Serialize)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+impl FieldError {
+    pub fn new(field: impl Into<String>, message: impl Into<String>) -> Self {
+        FieldError { field: field.into(), message: message.into() }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Expect {
+    String,
+    Bool,
+    Number,
+    Integer { min: i64, max: i64 },
+    OneOf(&'static [&'static str]),
+    StringArray,
+    StringMap,
+    ToolChoice,
+}
+
+// Top-level fields of a chat request with the JSON they take, and whether null stands for
+// leaving them out. Fields with a shape of their own (messages, responseFormat, ...) are
+// checked separately or left to serde.
+const FIELDS: &[(&str, Expect, bool)] = &[
+    ("model", Expect::String, false),
+    // Out-of-range temperatures are clamped to what the provider accepts
+    ("temperature", Expect::Number, true),
+    ("maxSteps", Expect::Integer { min: 1, max: u32::MAX as i64 }, true),
+    ("maxTokens", Expect::Integer { min: 1, max: u32::MAX as i64 }, true),
+    ("promptCaching", Expect::Bool, false),
+    ("toolCallFormat", Expect::OneOf(&["ai-sdk", "openai"]), false),
+    ("toolCallStreaming", Expect::Bool, false),
+    ("sessionId", Expect::String, true),
+    ("maxSessionTokens", Expect::Integer { min: 0, max: i64::MAX }, true),
+    ("parallelToolCalls", Expect::Bool, true),
+    ("fallbackModels", Expect::StringArray, false),
+    ("timeoutMs", Expect::Integer { min: 0, max: i64::MAX }, true),
+    ("logprobs", Expect::Bool, false),
+    ("topLogprobs", Expect::Integer { min: 0, max: 20 }, true),
+    ("top_logprobs", Expect::Integer { min: 0, max: 20 }, true),
+    ("seed", Expect::Integer { min: i64::MIN, max: i64::MAX }, true),
+    ("toolChoice", Expect::ToolChoice, true),
+    ("trimStrategy", Expect::OneOf(&["none", "drop-oldest", "summarize"]), false),
+    ("stream", Expect::Bool, true),
+    ("template", Expect::String, true),
+    ("variables", Expect::StringMap, false),
+    ("dedupeMessages", Expect::Bool, false),
+];
+
+// Every top-level field a chat request reads, for suggesting the intended one for a typo
+const KNOWN_FIELDS: &[&str] = &[
+    "messages", "model", "temperature", "maxSteps", "maxTokens", "promptCaching", "toolCallFormat",
+    "toolCallStreaming", "sessionId", "maxSessionTokens", "responseFormat", "toolChoice", "parallelToolCalls",
+    "aiSdkVersion", "fallbackModels", "timeoutMs", "logprobs", "topLogprobs", "top_logprobs", "seed",
+    "trimStrategy", "extraHeaders", "stream", "template", "variables",
+    "dedupeMessages",
+];
+
+// A chat request from a request body: invalid JSON is reported as such, and a body with the
+// wrong shape gets every problem found, by field
+pub fn parse_chat_request(body: &[u8]) -> Result<ChatRequest, ApiError> {
+    let value: Value =
+        serde_json::from_slice(body).map_err(|e| ApiError::bad_request(format!("Invalid JSON: {}", e)))?;
+    chat_request_from_value(value)
+}
+
+pub fn chat_request_from_value(value: Value) -> Result<ChatRequest, ApiError> {
+    let errors = validate_chat_request(&value);
+    if !errors.is_empty() {
+        return Err(ApiError::invalid_fields(errors));
+    }
+    serde_json::from_value(value).map_err(|e| ApiError::bad_request(format!("Invalid request: {}", e)))
+}
+
+fn validate_chat_request(body: &Value) -> Vec<FieldError> {
+    let Some(fields) = body.as_object() else {
+        return vec![FieldError::new("", format!("must be a JSON object, not {}", json_type(body)))];
+    };
+    let mut errors = Vec::new();
+
+    // Misspelled fields would otherwise be ignored without a word
+    for name in fields.keys().filter(|name| !KNOWN_FIELDS.contains(&name.as_str())) {
+        if let Some(known) = KNOWN_FIELDS.iter().find(|known| is_likely_typo(name, known)) {
+            if !fields.contains_key(*known) {
+                errors.push(FieldError::new(name.as_str(), format!("is not a known field; did you mean '{}'?", known)));
+            }
+        }
+    }
+
+    match fields.get("messages") {
+        // Already reported as a misspelling when the client sent e.g. "message"
+        None if !fields.keys().any(|name| is_likely_typo(name, "messages")) => {
+            errors.push(FieldError::new("messages", "is required"));
+        }
+        None => {}
+        Some(Value::Array(messages)) => {
+            for (index, message) in messages.iter().enumerate() {
+                validate_message(&format!("messages[{}]", index), message, &mut errors);
+            }
+        }
+        Some(other) => errors.push(FieldError::new("messages", format!("must be an array, not {}", json_type(other)))),
+    }
+
+    for (name, expect, nullable) in FIELDS {
+        match fields.get(*name) {
+            None => {}
+            Some(Value::Null) if *nullable => {}
+            Some(value) => {
+                if let Some(message) = check(value, *expect) {
+                    errors.push(FieldError::new(*name, message));
+                }
+            }
+        }
+    }
+    errors
+}
+
+fn validate_message(path: &str, message: &Value, errors: &mut Vec<FieldError>) {
+    let Some(fields) = message.as_object() else {
+        errors.push(FieldError::new(path, format!("must be an object, not {}", json_type(message))));
+        return;
+    };
+    match fields.get("role") {
+        None => errors.push(FieldError::new(format!("{}.role", path), "is required")),
+        Some(Value::String(role)) if role.trim().is_empty() => {
+            errors.push(FieldError::new(format!("{}.role", path), "must not be empty"));
+        }
+        Some(Value::String(_)) => {}
+        Some(other) => errors.push(FieldError::new(
+            format!("{}.role", path),
+            format!("must be a string, not {}", json_type(other)),
+        )),
+    }
+    match fields.get("content") {
+        None | Some(Value::Null) | Some(Value::String(_)) => {}
+        Some(other) => errors.push(FieldError::new(
+            format!("{}.content", path),
+            format!("must be a string, not {}", json_type(other)),
+        )),
+    }
+    for name in ["tool_calls", "toolInvocations", "experimental_attachments"] {
+        match fields.get(name) {
+            None | Some(Value::Null) | Some(Value::Array(_)) => {}
+            Some(other) => errors.push(FieldError::new(
+                format!("{}.{}", path, name),
+                format!("must be an array, not {}", json_type(other)),
+            )),
+        }
+    }
+}
+
+// Why `value` doesn't match `expect`, if it doesn't
+fn check(value: &Value, expect: Expect) -> Option<String> {
+    match expect {
+        Expect::String if !value.is_string() => Some(format!("must be a string, not {}", json_type(value))),
+        Expect::Bool if !value.is_boolean() => Some(format!("must be true or false, not {}", json_type(value))),
+        Expect::Number if !value.is_number() => Some(format!("must be a number, not {}", json_type(value))),
+        Expect::Integer { min, max } => match value.as_i64() {
+            Some(number) if number < min || number > max => Some(format!("must be between {} and {}", min, max)),
+            Some(_) => None,
+            None if value.is_number() => Some("must be a whole number".to_string()),
+            None => Some(format!("must be a whole number, not {}", json_type(value))),
+        },
+        Expect::OneOf(options) => match value.as_str() {
+            Some(option) if options.contains(&option) => None,
+            _ => Some(format!("must be one of {}", options.join(", "))),
+        },
+        Expect::StringArray => match value.as_array() {
+            Some(items) if items.iter().all(Value::is_string) => None,
+            _ => Some("must be an array of strings".to_string()),
+        },
+        Expect::StringMap => match value.as_object() {
+            Some(fields) if fields.values().all(Value::is_string) => None,
+            _ => Some("must be an object of strings".to_string()),
+        },
+        Expect::ToolChoice => match value {
+            Value::String(mode) if ["auto", "none", "required"].contains(&mode.as_str()) => None,
+            Value::Object(choice) if choice.get("name").is_some_and(Value::is_string) => None,
+            _ => Some("must be \"auto\", \"none\", \"required\" or {\"name\": \"<tool>\"}".to_string()),
+        },
+        _ => None,
+    }
+}
+
+fn json_type(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "a boolean",
+        Value::Number(_) => "a number",
+        Value::String(_) => "a string",
+        Value::Array(_) => "an array",
+        Value::Object(_) => "an object",
+    }
+}
+
+// `name` is `known` in another case or spelling (max_tokens, MaxTokens) or one letter off
+fn is_likely_typo(name: &str, known: &str) -> bool {
+    let normalize = |field: &str| field.replace(['_', '-'], "").to_lowercase();
+    let (name, known) = (normalize(name), normalize(known));
+    name == known || edit_distance(&name, &known) <= 1
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a_char != *b_char);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
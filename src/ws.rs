@@ -0,0 +1,100 @@
+//! GET /ws-chat: /sdk-chat over a WebSocket
+
+use std::sync::Arc;
+
+use actix_web::{body::MessageBody, web, HttpRequest, HttpResponse};
+use arc_swap::ArcSwap;
+use log::{info, warn};
+use tokio_stream::StreamExt;
+
+use crate::chat::{chat, request_id};
+use crate::config::TellConfig;
+use crate::convert::{FrameWriter, LineBuffer};
+use crate::validate::parse_chat_request;
+
+// WebSocket transport for clients behind proxies that break SSE. The first text message is a
+// ChatRequest; it's answered with the same AI SDK frames as /sdk-chat, one frame per text
+// message, and a normal close. Closing the socket cancels the request.
+pub async fn ws_chat(
+    req: HttpRequest,
+    body: web::Payload,
+    config: web::Data<ArcSwap<TellConfig>>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let (response, session, messages) = actix_ws::handle(&req, body)?;
+    actix_web::rt::spawn(ws_chat_session(req, config.load_full(), session, messages));
+    Ok(response)
+}
+
+async fn ws_chat_session(
+    req: HttpRequest,
+    config: Arc<TellConfig>,
+    mut session: actix_ws::Session,
+    mut messages: actix_ws::MessageStream,
+) {
+    let request = loop {
+        match messages.next().await {
+            Some(Ok(actix_ws::Message::Text(text))) => break text,
+            Some(Ok(actix_ws::Message::Ping(bytes))) => {
+                if session.pong(&bytes).await.is_err() {
+                    return;
+                }
+            }
+            Some(Ok(actix_ws::Message::Close(_))) | Some(Err(_)) | None => return,
+            Some(Ok(_)) => {}
+        }
+    };
+    info!("Raw WebSocket request: {}", request);
+
+    let response = match parse_chat_request(request.as_bytes()) {
+        Ok(request) => chat(&req, request, request_id(&req), &config, false).await,
+        Err(e) => Err(e),
+    };
+    // Errors the HTTP endpoint returns as a JSON response become an error frame and an error close
+    let mut body = match response {
+        Ok(response) => response.into_body(),
+        Err(error) => {
+            let frame = FrameWriter::default().error(&format!("{}: {}", error.error_type, error.message));
+            let _ = session.text(frame.trim_end().to_string()).await;
+            let reason = actix_ws::CloseReason {
+                code: actix_ws::CloseCode::Error,
+                description: Some(error.error_type.to_string()),
+            };
+            let _ = session.close(Some(reason)).await;
+            return;
+        }
+    };
+
+    let mut lines = LineBuffer::default();
+    loop {
+        tokio::select! {
+            chunk = futures::future::poll_fn(|cx| std::pin::Pin::new(&mut body).poll_next(cx)) => match chunk {
+                Some(Ok(chunk)) => {
+                    for frame in lines.push(&chunk).lines() {
+                        if session.text(frame.to_string()).await.is_err() {
+                            return;
+                        }
+                    }
+                }
+                Some(Err(e)) => {
+                    warn!("WebSocket response stream failed: {}", e);
+                    break;
+                }
+                None => break,
+            },
+            // Dropping the response body stops the upstream stream, as when an SSE client disconnects
+            message = messages.next() => match message {
+                Some(Ok(actix_ws::Message::Ping(bytes))) => {
+                    if session.pong(&bytes).await.is_err() {
+                        return;
+                    }
+                }
+                Some(Ok(actix_ws::Message::Close(_))) | Some(Err(_)) | None => {
+                    info!("WebSocket client closed the connection mid-stream");
+                    return;
+                }
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+    let _ = session.close(Some(actix_ws::CloseCode::Normal.into())).await;
+}
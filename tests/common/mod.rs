@@ -0,0 +1,314 @@
+// Shared harness for the integration tests: a mock upstream that serves canned provider
+// responses, and the real server binary pointed at it via ANTHROPIC_BASE_URL/OPENAI_BASE_URL.
+#![allow(dead_code)]
+
+use std::collections::{HashMap, VecDeque};
+use std::net::TcpListener;
+use std::process::{Child, Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+use bytes::Bytes;
+use futures::stream;
+use serde_json::Value;
+
+// A canned upstream response. Chunks are written one at a time with `chunk_delay` between them.
+// With `abort` set the connection is dropped after the last chunk instead of ending the body.
+#[derive(Clone)]
+pub struct MockResponse {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub chunks: Vec<Bytes>,
+    pub initial_delay: Duration,
+    pub chunk_delay: Duration,
+    pub abort: bool,
+    pub headers: Vec<(&'static str, &'static str)>,
+}
+
+impl MockResponse {
+    pub fn sse(body: &str) -> Self {
+        MockResponse {
+            status: 200,
+            content_type: "text/event-stream",
+            chunks: vec![Bytes::from(body.to_string())],
+            initial_delay: Duration::ZERO,
+            chunk_delay: Duration::ZERO,
+            abort: false,
+            headers: Vec::new(),
+        }
+    }
+
+    pub fn json(status: u16, body: &str) -> Self {
+        MockResponse {
+            status,
+            content_type: "application/json",
+            chunks: vec![Bytes::from(body.to_string())],
+            initial_delay: Duration::ZERO,
+            chunk_delay: Duration::ZERO,
+            abort: false,
+            headers: Vec::new(),
+        }
+    }
+
+    pub fn chunked(chunks: &[&str], chunk_delay: Duration) -> Self {
+        MockResponse {
+            status: 200,
+            content_type: "text/event-stream",
+            chunks: chunks.iter().map(|c| Bytes::from(c.to_string())).collect(),
+            initial_delay: Duration::ZERO,
+            chunk_delay,
+            abort: false,
+            headers: Vec::new(),
+        }
+    }
+
+    // Raw byte chunks, which may end anywhere, including inside a multi-byte character
+    pub fn chunked_bytes(chunks: &[&[u8]], chunk_delay: Duration) -> Self {
+        MockResponse {
+            chunks: chunks.iter().map(|c| Bytes::copy_from_slice(c)).collect(),
+            ..MockResponse::chunked(&[], chunk_delay)
+        }
+    }
+
+    // Stream the chunks, then fail mid-body
+    pub fn aborted(chunks: &[&str]) -> Self {
+        MockResponse {
+            abort: true,
+            ..MockResponse::chunked(chunks, Duration::from_millis(20))
+        }
+    }
+
+    pub fn delayed(mut self, initial_delay: Duration) -> Self {
+        self.initial_delay = initial_delay;
+        self
+    }
+
+    pub fn with_header(mut self, name: &'static str, value: &'static str) -> Self {
+        self.headers.push((name, value));
+        self
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RecordedRequest {
+    pub path: String,
+    pub headers: HashMap<String, String>,
+    pub body: Value,
+}
+
+#[derive(Default)]
+struct MockState {
+    // Responses per path; the last one is repeated once the queue is down to it
+    responses: Mutex<HashMap<String, VecDeque<MockResponse>>>,
+    requests: Mutex<Vec<RecordedRequest>>,
+}
+
+pub struct MockUpstream {
+    pub base_url: String,
+    state: Arc<MockState>,
+}
+
+impl MockUpstream {
+    pub async fn start(routes: Vec<(&str, Vec<MockResponse>)>) -> Self {
+        let state = Arc::new(MockState::default());
+        {
+            let mut responses = state.responses.lock().unwrap();
+            for (path, queue) in routes {
+                responses.insert(path.to_string(), queue.into());
+            }
+        }
+
+        let data = web::Data::from(state.clone());
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(data.clone())
+                .default_service(web::route().to(mock_handler))
+        })
+        .workers(1)
+        .bind(("127.0.0.1", 0))
+        .expect("bind mock upstream");
+        let addr = server.addrs()[0];
+        actix_web::rt::spawn(server.run());
+
+        MockUpstream {
+            base_url: format!("http://{}", addr),
+            state,
+        }
+    }
+
+    pub fn requests(&self) -> Vec<RecordedRequest> {
+        self.state.requests.lock().unwrap().clone()
+    }
+}
+
+async fn mock_handler(req: HttpRequest, body: Bytes, state: web::Data<MockState>) -> HttpResponse {
+    let path = req.path().to_string();
+    let headers = req
+        .headers()
+        .iter()
+        .map(|(name, value)| {
+            (
+                name.as_str().to_lowercase(),
+                value.to_str().unwrap_or_default().to_string(),
+            )
+        })
+        .collect();
+    state.requests.lock().unwrap().push(RecordedRequest {
+        path: path.clone(),
+        headers,
+        body: serde_json::from_slice(&body).unwrap_or(Value::Null),
+    });
+
+    let response = {
+        let mut responses = state.responses.lock().unwrap();
+        match responses.get_mut(&path) {
+            Some(queue) if queue.len() > 1 => queue.pop_front(),
+            Some(queue) => queue.front().cloned(),
+            None => None,
+        }
+    };
+    let Some(response) = response else {
+        return HttpResponse::NotFound().body(format!("no mock for {}", path));
+    };
+
+    if !response.initial_delay.is_zero() {
+        actix_web::rt::time::sleep(response.initial_delay).await;
+    }
+
+    let chunk_delay = response.chunk_delay;
+    let body = stream::unfold(
+        (response.chunks.into_iter(), true, response.abort),
+        move |(mut chunks, first, abort)| async move {
+            if !first && !chunk_delay.is_zero() {
+                actix_web::rt::time::sleep(chunk_delay).await;
+            }
+            match chunks.next() {
+                Some(chunk) => Some((Ok(chunk), (chunks, false, abort))),
+                None if abort => Some((
+                    Err(actix_web::error::ErrorInternalServerError("mock upstream aborted")),
+                    (chunks, false, false),
+                )),
+                None => None,
+            }
+        },
+    );
+
+    let mut builder = HttpResponse::build(actix_web::http::StatusCode::from_u16(response.status).unwrap());
+    for header in response.headers {
+        builder.insert_header(header);
+    }
+    builder.content_type(response.content_type).streaming(body)
+}
+
+// The server binary under test, killed on drop
+pub struct TestServer {
+    pub base_url: String,
+    child: Child,
+}
+
+impl TestServer {
+    pub async fn start(upstream: &MockUpstream, env: &[(&str, &str)]) -> Self {
+        let port = TcpListener::bind("127.0.0.1:0")
+            .and_then(|listener| listener.local_addr())
+            .map(|addr| addr.port())
+            .expect("find a free port");
+        let bind_addr = format!("127.0.0.1:{}", port);
+
+        let mut command = Command::new(env!("CARGO_BIN_EXE_backend"));
+        command
+            // Run outside the repo so a developer's .env isn't picked up
+            .current_dir(std::env::temp_dir())
+            .env_clear()
+            .env("PATH", std::env::var("PATH").unwrap_or_default())
+            .env("BIND_ADDR", &bind_addr)
+            .env("ANTHROPIC_API_KEY", "test-anthropic-key")
+            .env("ANTHROPIC_BASE_URL", &upstream.base_url)
+            .env("OPENAI_API_KEY", "test-openai-key")
+            .env("OPENAI_BASE_URL", format!("{}/v1", upstream.base_url))
+            .stdout(Stdio::null())
+            .stderr(Stdio::null());
+        for (key, value) in env {
+            command.env(key, value);
+        }
+        let child = command.spawn().expect("spawn server binary");
+
+        let server = TestServer {
+            base_url: format!("http://{}", bind_addr),
+            child,
+        };
+        server.wait_until_healthy().await;
+        server
+    }
+
+    async fn wait_until_healthy(&self) {
+        let client = reqwest::Client::new();
+        for _ in 0..100 {
+            if let Ok(response) = client.get(format!("{}/health", self.base_url)).send().await {
+                if response.status().is_success() {
+                    return;
+                }
+            }
+            actix_web::rt::time::sleep(Duration::from_millis(50)).await;
+        }
+        panic!("server at {} did not become healthy", self.base_url);
+    }
+
+    // The helpers call as an AI SDK 4 client, so streams come back as the data stream frames
+    // parse_frames reads. The v5 UI message stream has tests of its own.
+    pub async fn get(&self, path: &str) -> reqwest::Response {
+        reqwest::Client::new()
+            .get(format!("{}{}", self.base_url, path))
+            .header("X-AI-SDK-Version", "4")
+            .send()
+            .await
+            .unwrap_or_else(|e| panic!("call {}: {}", path, e))
+    }
+
+    pub async fn chat(&self, body: Value) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(format!("{}/sdk-chat", self.base_url))
+            .header("X-AI-SDK-Version", "4")
+            .json(&body)
+            .send()
+            .await
+            .expect("call /sdk-chat")
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+// Split an AI SDK data stream into (prefix, JSON payload) frames
+pub fn parse_frames(body: &str) -> Vec<(String, Value)> {
+    body.lines()
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            let (prefix, payload) = line
+                .split_once(':')
+                .unwrap_or_else(|| panic!("malformed frame: {:?}", line));
+            let payload = serde_json::from_str(payload)
+                .unwrap_or_else(|e| panic!("frame payload is not JSON ({}): {:?}", e, line));
+            (prefix.to_string(), payload)
+        })
+        .collect()
+}
+
+// Drop the step start (f:) and step finish (e:) frames, which carry a generated message id or
+// repeat the finish message, leaving the content and the finish message (d:) frame
+pub fn without_step_frames(frames: Vec<(String, Value)>) -> Vec<(String, Value)> {
+    frames.into_iter().filter(|(prefix, _)| prefix != "f" && prefix != "e").collect()
+}
+
+// Concatenate the text (0:) frames of a stream
+pub fn streamed_text(frames: &[(String, Value)]) -> String {
+    frames
+        .iter()
+        .filter(|(prefix, _)| prefix == "0")
+        .filter_map(|(_, payload)| payload.as_str())
+        .collect()
+}
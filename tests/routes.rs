@@ -0,0 +1,346 @@
+// Routing behaviour that doesn't involve a provider: unknown paths and unsupported methods
+mod common;
+
+use common::{MockUpstream, TestServer};
+use serde_json::{json, Value};
+
+#[actix_web::test]
+async fn wrong_method_on_a_known_path_is_405_with_allow() {
+    let upstream = MockUpstream::start(vec![]).await;
+    let server = TestServer::start(&upstream, &[]).await;
+
+    let response = server.get("/sdk-chat").await;
+    assert_eq!(response.status(), 405);
+    assert_eq!(response.headers()["allow"], "POST, OPTIONS");
+    let body: Value = response.json().await.unwrap();
+    assert_eq!(body["error"]["type"], "method_not_allowed");
+    assert_eq!(body["error"]["code"], 405);
+
+    let response = reqwest::Client::new()
+        .request(reqwest::Method::OPTIONS, format!("{}/count-tokens", server.base_url))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 204);
+    assert_eq!(response.headers()["allow"], "POST, OPTIONS");
+}
+
+#[actix_web::test]
+async fn unknown_paths_are_404() {
+    let upstream = MockUpstream::start(vec![]).await;
+    let server = TestServer::start(&upstream, &[]).await;
+
+    let response = server.get("/no-such-route").await;
+    assert_eq!(response.status(), 404);
+    let body: Value = response.json().await.unwrap();
+    assert_eq!(body["error"]["type"], "not_found");
+    assert_eq!(body["error"]["code"], 404);
+}
+
+async fn preflight(server: &TestServer, path: &str, method: &str, headers: &str) -> reqwest::Response {
+    reqwest::Client::new()
+        .request(reqwest::Method::OPTIONS, format!("{}{}", server.base_url, path))
+        .header("Origin", "http://localhost:3000")
+        .header("Access-Control-Request-Method", method)
+        .header("Access-Control-Request-Headers", headers)
+        .send()
+        .await
+        .unwrap()
+}
+
+#[actix_web::test]
+async fn cors_preflight_allows_every_endpoint_and_feature_header() {
+    let upstream = MockUpstream::start(vec![]).await;
+    let server = TestServer::start(&upstream, &[]).await;
+    let feature_headers =
+        "content-type,x-request-id,x-session-id,x-timeout-ms,x-dry-run,x-provider,x-ai-sdk-version,x-bypass-system-prompt,x-output-format";
+
+    for (path, method) in [
+        ("/health", "GET"),
+        ("/status", "GET"),
+        ("/sdk-chat", "POST"),
+        ("/count-tokens", "POST"),
+        ("/batch", "POST"),
+        ("/compare", "POST"),
+        ("/usage", "GET"),
+        ("/resume/msg-1", "GET"),
+        ("/cancel/abc", "POST"),
+        ("/pipeline/abc", "GET"),
+        ("/conversations/abc", "GET"),
+    ] {
+        let response = preflight(&server, path, method, feature_headers).await;
+        assert_eq!(response.status(), 200, "{}", path);
+        assert_eq!(response.headers()["access-control-allow-origin"], "http://localhost:3000");
+        let allowed = response.headers()["access-control-allow-headers"].to_str().unwrap().to_lowercase();
+        for header in feature_headers.split(',') {
+            assert!(allowed.contains(header), "{} missing {}", path, header);
+        }
+    }
+    assert_eq!(preflight(&server, "/sdk-chat", "DELETE", "content-type").await.status(), 400);
+    assert_eq!(preflight(&server, "/sdk-chat", "POST", "x-custom").await.status(), 400);
+
+    let server = TestServer::start(
+        &upstream,
+        &[("CORS_ALLOWED_METHODS", "GET,POST,PUT,DELETE,OPTIONS"), ("CORS_ALLOWED_HEADERS", "X-Custom")],
+    )
+    .await;
+    assert_eq!(preflight(&server, "/sdk-chat", "DELETE", "content-type").await.status(), 200);
+    let response = preflight(&server, "/sdk-chat", "POST", "x-custom,x-session-id").await;
+    assert_eq!(response.status(), 200);
+
+    // Client code can read the request id and served model off a response
+    let response = reqwest::Client::new()
+        .get(format!("{}/health", server.base_url))
+        .header("Origin", "http://localhost:3000")
+        .send()
+        .await
+        .unwrap();
+    let exposed = response.headers()["access-control-expose-headers"].to_str().unwrap().to_lowercase();
+    assert!(exposed.contains("x-request-id") && exposed.contains("x-served-model"), "{}", exposed);
+}
+
+#[actix_web::test]
+async fn credentialed_streams_echo_the_request_origin() {
+    let upstream = MockUpstream::start(vec![]).await;
+    let server = TestServer::start(&upstream, &[("MOCK_PROVIDER", "true")]).await;
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/sdk-chat", server.base_url))
+        .header("Origin", "https://tell.pages.dev")
+        .header("Cookie", "session=1")
+        .json(&json!({ "model": "mock", "messages": [{ "role": "user", "content": "hi" }] }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()["access-control-allow-origin"], "https://tell.pages.dev");
+    assert_eq!(response.headers()["access-control-allow-credentials"], "true");
+    assert_eq!(response.headers().get_all("access-control-allow-origin").iter().count(), 1);
+}
+
+#[actix_web::test]
+async fn metrics_are_served_in_the_prometheus_text_format() {
+    let upstream = MockUpstream::start(vec![]).await;
+    let server = TestServer::start(&upstream, &[]).await;
+
+    let response = server.get("/metrics").await;
+    assert_eq!(response.status(), 200);
+    let body = response.text().await.unwrap();
+    assert!(body.contains("# TYPE api_upstream_in_flight gauge"), "{}", body);
+    assert!(body.contains("api_upstream_in_flight 0"), "{}", body);
+}
+
+#[actix_web::test]
+async fn cleartext_http2_is_accepted_when_enabled() {
+    let upstream = MockUpstream::start(vec![]).await;
+    let h2c = reqwest::Client::builder().http2_prior_knowledge().build().unwrap();
+
+    let server = TestServer::start(&upstream, &[("HTTP2_CLEARTEXT", "true"), ("KEEP_ALIVE_SECS", "300")]).await;
+    let response = h2c.get(format!("{}/health", server.base_url)).send().await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.version(), reqwest::Version::HTTP_2);
+    // HTTP/1.1 clients still work
+    assert_eq!(server.get("/health").await.version(), reqwest::Version::HTTP_11);
+
+    let server = TestServer::start(&upstream, &[]).await;
+    assert!(h2c.get(format!("{}/health", server.base_url)).send().await.is_err());
+}
+
+#[actix_web::test]
+async fn request_errors_share_the_json_error_shape() {
+    let upstream = MockUpstream::start(vec![]).await;
+    let server = TestServer::start(&upstream, &[]).await;
+
+    let response = server.chat(json!({ "messages": "not a list" })).await;
+    assert_eq!(response.status(), 400);
+    let body: Value = response.json().await.unwrap();
+    assert_eq!(body["error"]["code"], 400);
+    assert_eq!(body["error"]["type"], "invalid_request_error");
+    assert_eq!(body["error"]["message"], "Invalid request: messages must be an array, not a string");
+
+    let response = server
+        .chat(json!({ "model": "llama-3", "messages": [{ "role": "user", "content": "hi" }] }))
+        .await;
+    assert_eq!(response.status(), 400);
+    let body: Value = response.json().await.unwrap();
+    assert!(body["error"]["message"].as_str().unwrap().starts_with("Unknown model 'llama-3'"));
+}
+
+#[actix_web::test]
+async fn debug_echo_is_only_served_with_debug_endpoints() {
+    let upstream = MockUpstream::start(vec![]).await;
+    let body = json!({ "messages": [{ "role": "user", "content": "hi" }], "maxTokens": 64 });
+
+    let server = TestServer::start(&upstream, &[]).await;
+    let response = reqwest::Client::new()
+        .post(format!("{}/debug/echo", server.base_url))
+        .json(&body)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 404);
+
+    let server = TestServer::start(&upstream, &[("DEBUG_ENDPOINTS", "true")]).await;
+    let response = reqwest::Client::new()
+        .post(format!("{}/debug/echo", server.base_url))
+        .json(&body)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let echo: Value = response.json().await.unwrap();
+    assert_eq!(echo["request"]["model"], "claude-3-5-sonnet-20241022");
+    assert_eq!(echo["request"]["maxTokens"], 64);
+    assert_eq!(echo["request"]["messages"][0]["content"], "hi");
+    assert_eq!(echo["request"]["toolCallFormat"], "ai-sdk");
+    assert_eq!(
+        echo["resolved"],
+        json!({ "model": "claude-3-5-sonnet-20241022", "provider": "anthropic", "temperature": 0.2f32, "maxSteps": 1 })
+    );
+}
+
+#[actix_web::test]
+async fn message_roles_are_normalized_or_rejected_by_index() {
+    let upstream = MockUpstream::start(vec![]).await;
+    let server = TestServer::start(&upstream, &[]).await;
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/sdk-chat?dryRun=true", server.base_url))
+        .json(&json!({
+            "model": "claude-3-5-sonnet-20241022",
+            "messages": [{ "role": "Human", "content": "hi" }, { "role": " AI", "content": "hello" }]
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let dry_run: Value = response.json().await.unwrap();
+    let roles: Vec<&Value> = dry_run["body"]["messages"].as_array().unwrap().iter().map(|m| &m["role"]).collect();
+    assert_eq!(roles, vec!["user", "assistant"]);
+
+    let response = server
+        .chat(json!({
+            "model": "claude-3-5-sonnet-20241022",
+            "messages": [{ "role": "user", "content": "hi" }, { "role": "tool", "content": "{}" }]
+        }))
+        .await;
+    assert_eq!(response.status(), 400);
+    let body: Value = response.json().await.unwrap();
+    assert_eq!(
+        body["error"]["message"],
+        "Message 1 has role 'tool', which anthropic doesn't accept. Accepted roles: system, user, assistant"
+    );
+}
+
+#[actix_web::test]
+async fn duplicate_consecutive_messages_are_collapsed_when_asked() {
+    let upstream = MockUpstream::start(vec![]).await;
+    let server = TestServer::start(&upstream, &[]).await;
+    let dry_run_contents = |dedupe: bool| {
+        let request = json!({
+            "model": "gpt-4o",
+            "dedupeMessages": dedupe,
+            "messages": [
+                { "role": "user", "content": "Show sales" },
+                { "role": "user", "content": "Show sales" },
+                { "role": "user", "content": "Show sales!" },
+                { "role": "assistant", "content": "Here they are" },
+                { "role": "user", "content": "Show sales" },
+            ]
+        });
+        let url = format!("{}/sdk-chat?dryRun=true", server.base_url);
+        let response = reqwest::Client::new().post(url).json(&request).send();
+        async move {
+            let dry_run: Value = response.await.unwrap().json().await.unwrap();
+            let messages = dry_run["body"]["messages"].as_array().unwrap().clone();
+            messages.iter().map(|m| m["content"].as_str().unwrap().to_string()).collect::<Vec<_>>()
+        }
+    };
+
+    // Only the exact repeat is dropped; a changed repeat and a later one are kept
+    assert_eq!(dry_run_contents(true).await, ["Show sales", "Show sales!", "Here they are", "Show sales"]);
+    assert_eq!(dry_run_contents(false).await.len(), 5);
+}
+
+#[actix_web::test]
+async fn forwarding_headers_are_only_trusted_from_trusted_proxies() {
+    let upstream = MockUpstream::start(vec![]).await;
+    let echo_client_ip = |server: &TestServer, forwarded_for: &'static str| {
+        let url = format!("{}/debug/echo", server.base_url);
+        async move {
+            let response = reqwest::Client::new()
+                .post(url)
+                .header("X-Forwarded-For", forwarded_for)
+                .json(&json!({ "messages": [] }))
+                .send()
+                .await
+                .unwrap();
+            let echo: Value = response.json().await.unwrap();
+            echo["clientIp"].as_str().unwrap().to_string()
+        }
+    };
+
+    // Without trusted proxies the header is ignored
+    let server = TestServer::start(&upstream, &[("DEBUG_ENDPOINTS", "true")]).await;
+    assert_eq!(echo_client_ip(&server, "203.0.113.7").await, "127.0.0.1");
+    drop(server);
+
+    let server = TestServer::start(
+        &upstream,
+        &[("DEBUG_ENDPOINTS", "true"), ("TRUSTED_PROXIES", "127.0.0.1, 10.0.0.0/8")],
+    )
+    .await;
+    assert_eq!(echo_client_ip(&server, "203.0.113.7, 10.1.2.3").await, "203.0.113.7");
+    // A client can prepend anything; only the address the trusted proxies saw counts
+    assert_eq!(echo_client_ip(&server, "1.1.1.1, 198.51.100.2, 10.1.2.3").await, "198.51.100.2");
+}
+
+#[actix_web::test]
+async fn status_reports_which_provider_keys_are_set_without_their_values() {
+    let upstream = MockUpstream::start(vec![]).await;
+    let server = TestServer::start(&upstream, &[("MISTRAL_API_KEY", "")]).await;
+
+    let response = server.get("/status").await;
+    assert_eq!(response.status(), 200);
+    let text = response.text().await.unwrap();
+    assert!(!text.contains("test-anthropic-key") && !text.contains("test-openai-key"), "{}", text);
+    let body: Value = serde_json::from_str(&text).unwrap();
+    assert_eq!(body["providers"]["anthropic"], json!({ "configured": true }));
+    assert_eq!(body["providers"]["openai"], json!({ "configured": true }));
+    assert_eq!(body["providers"]["mistral"], json!({ "configured": false }));
+    assert_eq!(body["providers"]["bedrock"], json!({ "configured": false }));
+    assert!(body["version"].is_string());
+    assert!(body["uptime"].is_u64());
+    assert!(upstream.requests().is_empty());
+}
+
+#[actix_web::test]
+async fn route_is_only_served_with_a_router_model() {
+    let upstream = MockUpstream::start(vec![]).await;
+    let server = TestServer::start(&upstream, &[]).await;
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/route", server.base_url))
+        .json(&json!({ "messages": [{ "role": "user", "content": "Say hello" }] }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 404);
+}
+
+// Without the router feature a ROUTER_MODEL can't be honoured, so it stops startup
+#[cfg(not(feature = "router"))]
+#[test]
+fn router_model_without_the_router_feature_stops_startup() {
+    let status = std::process::Command::new(env!("CARGO_BIN_EXE_backend"))
+        .current_dir(std::env::temp_dir())
+        .env_clear()
+        .env("BIND_ADDR", "127.0.0.1:0")
+        .env("ROUTER_MODEL", "router.pt")
+        .env("ROUTER_LABELS", "gpt-4o-mini,gpt-4o")
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .status()
+        .expect("run server binary");
+    assert!(!status.success());
+}
@@ -0,0 +1,2879 @@
+// End-to-end tests for /sdk-chat: canned provider streams in, AI SDK frames out
+mod common;
+
+use common::{parse_frames, streamed_text, without_step_frames, MockResponse, MockUpstream, TestServer};
+use serde_json::json;
+
+const ANTHROPIC_TEXT: &str = include_str!("fixtures/anthropic_text.sse");
+const ANTHROPIC_ERROR: &str = include_str!("fixtures/anthropic_error.json");
+const ANTHROPIC_STREAM_ERROR: &str = include_str!("fixtures/anthropic_stream_error.sse");
+const ANTHROPIC_TEXT_AND_TOOL_USE: &str = include_str!("fixtures/anthropic_text_and_tool_use.sse");
+const OPENAI_TEXT: &str = include_str!("fixtures/openai_text.sse");
+const OPENAI_TEXT_AND_TOOL_CALL: &str = include_str!("fixtures/openai_text_and_tool_call.sse");
+const COHERE_TEXT: &str = include_str!("fixtures/cohere_text.jsonl");
+const MISTRAL_TOOL_CALLS: &str = include_str!("fixtures/mistral_tool_calls.sse");
+const OPENAI_ADD_TRANSFORMATION: &str = include_str!("fixtures/openai_add_transformation.sse");
+const OPENAI_PARALLEL_TOOL_CALLS: &str = include_str!("fixtures/openai_parallel_tool_calls.sse");
+const OPENAI_STREAM_ERROR: &str = include_str!("fixtures/openai_stream_error.sse");
+const OPENAI_TOOL_CALL: &str = include_str!("fixtures/openai_tool_call.sse");
+
+fn chat_request(model: &str) -> serde_json::Value {
+    json!({
+        "model": model,
+        "messages": [{ "role": "user", "content": "Say hello" }]
+    })
+}
+
+#[actix_web::test]
+async fn anthropic_text_stream_becomes_text_and_finish_frames() {
+    let upstream = MockUpstream::start(vec![("/v1/messages", vec![MockResponse::sse(ANTHROPIC_TEXT)])]).await;
+    let server = TestServer::start(&upstream, &[]).await;
+
+    let response = server.chat(chat_request("claude-3-5-sonnet-20241022")).await;
+    assert_eq!(response.status(), 200);
+    let frames = parse_frames(&response.text().await.unwrap());
+
+    assert_eq!(streamed_text(&frames), "Hello, world");
+    let (prefix, finish) = frames.last().unwrap();
+    assert_eq!(prefix, "d");
+    assert_eq!(
+        finish,
+        &json!({ "finishReason": "stop", "usage": { "promptTokens": 12, "completionTokens": 6 } })
+    );
+
+    let requests = upstream.requests();
+    assert_eq!(requests.len(), 1);
+    assert_eq!(requests[0].headers.get("x-api-key").map(String::as_str), Some("test-anthropic-key"));
+    assert!(!requests[0].headers.contains_key("authorization"));
+    assert_eq!(requests[0].body["model"], "claude-3-5-sonnet-20241022");
+    assert_eq!(requests[0].body["stream"], true);
+}
+
+#[actix_web::test]
+async fn anthropic_requests_authenticate_with_x_api_key_not_bearer() {
+    let upstream = MockUpstream::start(vec![
+        ("/v1/messages", vec![MockResponse::sse(ANTHROPIC_TEXT)]),
+        ("/v1/messages/count_tokens", vec![MockResponse::json(200, r#"{"input_tokens":12}"#)]),
+    ])
+    .await;
+    let server = TestServer::start(&upstream, &[]).await;
+
+    let response = server.chat(chat_request("claude-3-5-sonnet-20241022")).await;
+    assert_eq!(response.status(), 200);
+    response.text().await.unwrap();
+    let response = reqwest::Client::new()
+        .post(format!("{}/count-tokens", server.base_url))
+        .json(&chat_request("claude-3-5-sonnet-20241022"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+
+    let requests = upstream.requests();
+    assert_eq!(requests.len(), 2);
+    for request in &requests {
+        assert_eq!(request.headers.get("x-api-key").map(String::as_str), Some("test-anthropic-key"));
+        assert!(!request.headers.contains_key("authorization"));
+        assert!(request.headers.contains_key("anthropic-version"));
+    }
+}
+
+#[actix_web::test]
+async fn openai_text_stream_becomes_text_frames() {
+    let upstream = MockUpstream::start(vec![("/v1/chat/completions", vec![MockResponse::sse(OPENAI_TEXT)])]).await;
+    let server = TestServer::start(&upstream, &[]).await;
+
+    let response = server.chat(chat_request("gpt-4o")).await;
+    assert_eq!(response.status(), 200);
+    let frames = without_step_frames(parse_frames(&response.text().await.unwrap()));
+
+    assert_eq!(streamed_text(&frames), "Hello, world");
+    let (finish, text) = frames.split_last().unwrap();
+    assert!(text.iter().all(|(prefix, _)| prefix == "0"));
+    assert_eq!(
+        finish,
+        &("d".to_string(), json!({ "finishReason": "stop", "usage": { "promptTokens": 0, "completionTokens": 0 } }))
+    );
+
+    let requests = upstream.requests();
+    assert_eq!(requests.len(), 1);
+    assert_eq!(requests[0].headers.get("authorization").map(String::as_str), Some("Bearer test-openai-key"));
+    assert_eq!(requests[0].body["model"], "gpt-4o");
+}
+
+#[actix_web::test]
+async fn ws_chat_streams_frames_as_websocket_messages() {
+    use futures::{SinkExt, StreamExt};
+
+    let upstream = MockUpstream::start(vec![("/v1/chat/completions", vec![MockResponse::sse(OPENAI_TEXT)])]).await;
+    let server = TestServer::start(&upstream, &[]).await;
+    let ws_url = format!("{}/ws-chat", server.base_url.replace("http://", "ws://"));
+
+    // Reads messages until the server closes the socket
+    async fn exchange(ws_url: &str, request: &str) -> (Vec<String>, Option<awc::ws::CloseReason>) {
+        let (_, mut socket) = awc::Client::new().ws(ws_url).connect().await.unwrap();
+        socket.send(awc::ws::Message::Text(request.into())).await.unwrap();
+        let mut messages = Vec::new();
+        while let Some(frame) = socket.next().await {
+            match frame.unwrap() {
+                awc::ws::Frame::Text(text) => messages.push(String::from_utf8(text.to_vec()).unwrap()),
+                awc::ws::Frame::Close(reason) => return (messages, reason),
+                _ => {}
+            }
+        }
+        panic!("socket ended without a close frame");
+    }
+
+    let (messages, close) = exchange(&ws_url, &chat_request("gpt-4o").to_string()).await;
+    // One AI SDK frame per message
+    let frames = without_step_frames(messages.iter().flat_map(|message| parse_frames(message)).collect());
+    assert_eq!(frames.len(), messages.len() - 2);
+    assert_eq!(streamed_text(&frames), "Hello, world");
+    assert_eq!(frames.last().unwrap().1["finishReason"], "stop");
+    assert_eq!(close.unwrap().code, awc::ws::CloseCode::Normal);
+
+    let (messages, close) = exchange(&ws_url, "{not json").await;
+    assert_eq!(messages.len(), 1);
+    assert!(messages[0].starts_with("3:\"invalid_request_error: Invalid JSON"), "{}", messages[0]);
+    assert_eq!(close.unwrap().code, awc::ws::CloseCode::Error);
+    assert_eq!(upstream.requests().len(), 1);
+}
+
+#[actix_web::test]
+async fn openai_tool_call_stream_becomes_tool_call_frame() {
+    let upstream = MockUpstream::start(vec![("/v1/chat/completions", vec![MockResponse::sse(OPENAI_TOOL_CALL)])]).await;
+    let server = TestServer::start(&upstream, &[]).await;
+
+    let response = server.chat(chat_request("gpt-4o")).await;
+    assert_eq!(response.status(), 200);
+    let frames = without_step_frames(parse_frames(&response.text().await.unwrap()));
+
+    assert_eq!(
+        frames,
+        vec![
+            (
+                "9".to_string(),
+                json!({ "toolCallId": "call_1", "toolName": "executeSQL", "args": { "sql": "SELECT 1" } })
+            ),
+            (
+                "d".to_string(),
+                json!({ "finishReason": "tool-calls", "usage": { "promptTokens": 0, "completionTokens": 0 } })
+            ),
+        ]
+    );
+}
+
+#[actix_web::test]
+async fn truncated_tool_call_arguments_are_flagged_not_dropped() {
+    // The model ran out of tokens halfway through the arguments
+    let stream = OPENAI_TOOL_CALL
+        .replace(r#"\"SELECT 1\"}"#, r#"\"SELECT"#)
+        .replace(r#""finish_reason":"tool_calls""#, r#""finish_reason":"length""#);
+    let upstream = MockUpstream::start(vec![("/v1/chat/completions", vec![MockResponse::sse(&stream)])]).await;
+    let server = TestServer::start(&upstream, &[]).await;
+
+    let frames = without_step_frames(parse_frames(&server.chat(chat_request("gpt-4o")).await.text().await.unwrap()));
+    assert_eq!(
+        frames[0],
+        (
+            "9".to_string(),
+            json!({
+                "toolCallId": "call_1",
+                "toolName": "executeSQL",
+                "args": {},
+                "argsError": true,
+                "argsText": "{\"sql\":\"SELECT"
+            })
+        )
+    );
+    assert_eq!(frames[1].1["finishReason"], "length");
+}
+
+#[actix_web::test]
+async fn text_and_tool_calls_in_one_turn_keep_their_order() {
+    let upstream = MockUpstream::start(vec![
+        ("/v1/messages", vec![MockResponse::sse(ANTHROPIC_TEXT_AND_TOOL_USE)]),
+        ("/v1/chat/completions", vec![MockResponse::sse(OPENAI_TEXT_AND_TOOL_CALL)]),
+    ])
+    .await;
+    let server = TestServer::start(&upstream, &[]).await;
+
+    for (model, call_id) in [("claude-3-5-sonnet-20241022", "toolu_1"), ("gpt-4o", "call_1")] {
+        let frames = parse_frames(&server.chat(chat_request(model)).await.text().await.unwrap());
+        let prefixes: Vec<&str> = frames.iter().map(|(prefix, _)| prefix.as_str()).collect();
+        assert_eq!(prefixes, ["f", "0", "0", "9", "e", "d"], "{}", model);
+
+        assert_eq!(streamed_text(&frames), "Let me check.");
+        assert_eq!(
+            frames[3].1,
+            json!({ "toolCallId": call_id, "toolName": "executeSQL", "args": { "sql": "SELECT 1" } })
+        );
+        assert_eq!(frames[5].1["finishReason"], "tool-calls");
+    }
+}
+
+#[actix_web::test]
+async fn tool_call_streaming_sends_the_call_as_it_forms() {
+    let upstream = MockUpstream::start(vec![("/v1/chat/completions", vec![MockResponse::sse(OPENAI_TOOL_CALL)])]).await;
+    let server = TestServer::start(&upstream, &[]).await;
+
+    let mut request = chat_request("gpt-4o");
+    request["toolCallStreaming"] = json!(true);
+    let frames = without_step_frames(parse_frames(&server.chat(request).await.text().await.unwrap()));
+
+    assert_eq!(
+        frames[..4],
+        [
+            ("b".to_string(), json!({ "toolCallId": "call_1", "toolName": "executeSQL" })),
+            ("c".to_string(), json!({ "toolCallId": "call_1", "argsTextDelta": "{\"sql\":" })),
+            ("c".to_string(), json!({ "toolCallId": "call_1", "argsTextDelta": "\"SELECT 1\"}" })),
+            (
+                "9".to_string(),
+                json!({ "toolCallId": "call_1", "toolName": "executeSQL", "args": { "sql": "SELECT 1" } })
+            ),
+        ]
+    );
+    assert_eq!(frames[4].0, "d");
+}
+
+#[actix_web::test]
+async fn each_response_is_delimited_as_one_step() {
+    let upstream = MockUpstream::start(vec![
+        ("/v1/messages", vec![MockResponse::sse(ANTHROPIC_TEXT)]),
+        ("/v1/chat/completions", vec![MockResponse::sse(OPENAI_TEXT)]),
+    ])
+    .await;
+    let server = TestServer::start(&upstream, &[]).await;
+
+    let mut message_ids = Vec::new();
+    for model in ["claude-3-5-sonnet-20241022", "gpt-4o"] {
+        let frames = parse_frames(&server.chat(chat_request(model)).await.text().await.unwrap());
+        let prefixes: Vec<&str> = frames.iter().map(|(prefix, _)| prefix.as_str()).collect();
+        assert_eq!(prefixes.first(), Some(&"f"), "{:?}", prefixes);
+        assert_eq!(prefixes[prefixes.len() - 2..], ["e", "d"], "{:?}", prefixes);
+
+        let (_, step_finish) = &frames[frames.len() - 2];
+        let (_, finish) = &frames[frames.len() - 1];
+        assert_eq!(step_finish["finishReason"], finish["finishReason"]);
+        assert_eq!(step_finish["usage"], finish["usage"]);
+        assert_eq!(step_finish["isContinued"], false);
+        message_ids.push(frames[0].1["messageId"].as_str().unwrap().to_string());
+    }
+    assert!(message_ids.iter().all(|id| id.starts_with("msg-")));
+    assert_ne!(message_ids[0], message_ids[1]);
+}
+
+#[actix_web::test]
+async fn upstream_error_status_is_reported_as_bad_gateway() {
+    let upstream = MockUpstream::start(vec![("/v1/messages", vec![MockResponse::json(500, ANTHROPIC_ERROR)])]).await;
+    let server = TestServer::start(&upstream, &[]).await;
+
+    let response = server.chat(chat_request("claude-3-5-sonnet-20241022")).await;
+    assert_eq!(response.status(), 502);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["error"]["code"], 502);
+    assert_eq!(body["error"]["type"], "upstream_error");
+    assert!(body["error"]["message"].as_str().unwrap().contains("Anthropic API error"));
+}
+
+#[actix_web::test]
+async fn anthropic_in_stream_error_becomes_error_and_finish_frames() {
+    let upstream = MockUpstream::start(vec![("/v1/messages", vec![MockResponse::sse(ANTHROPIC_STREAM_ERROR)])]).await;
+    let server = TestServer::start(&upstream, &[]).await;
+
+    let response = server.chat(chat_request("claude-3-5-sonnet-20241022")).await;
+    assert_eq!(response.status(), 200);
+    let frames = without_step_frames(parse_frames(&response.text().await.unwrap()));
+
+    assert_eq!(
+        frames,
+        vec![
+            ("0".to_string(), json!("Hello")),
+            ("3".to_string(), json!("overloaded_error: Overloaded")),
+            (
+                "d".to_string(),
+                json!({ "finishReason": "error", "usage": { "promptTokens": 12, "completionTokens": 1 } })
+            ),
+        ]
+    );
+}
+
+#[actix_web::test]
+async fn openai_in_stream_error_becomes_error_and_finish_frames() {
+    let upstream = MockUpstream::start(vec![("/v1/chat/completions", vec![MockResponse::sse(OPENAI_STREAM_ERROR)])]).await;
+    let server = TestServer::start(&upstream, &[]).await;
+
+    let response = server.chat(chat_request("gpt-4o")).await;
+    assert_eq!(response.status(), 200);
+    let frames = without_step_frames(parse_frames(&response.text().await.unwrap()));
+
+    assert_eq!(
+        frames,
+        vec![
+            ("0".to_string(), json!("Hello")),
+            (
+                "3".to_string(),
+                json!("server_error: The server had an error while processing your request.")
+            ),
+            (
+                "d".to_string(),
+                json!({ "finishReason": "error", "usage": { "promptTokens": 0, "completionTokens": 0 } })
+            ),
+        ]
+    );
+}
+
+#[actix_web::test]
+async fn only_allowlisted_extra_headers_are_forwarded() {
+    let upstream = MockUpstream::start(vec![("/v1/messages", vec![MockResponse::sse(ANTHROPIC_TEXT)])]).await;
+    let server = TestServer::start(&upstream, &[("ALLOWED_UPSTREAM_HEADERS", "anthropic-beta, openai-beta")]).await;
+
+    let mut request = chat_request("claude-3-5-sonnet-20241022");
+    request["extraHeaders"] = json!({
+        "anthropic": {
+            "anthropic-beta": "prompt-caching-2024-07-31",
+            "x-internal-route": "admin",
+            "x-api-key": "someone-elses-key"
+        },
+        "openai": { "OpenAI-Beta": "assistants=v2" }
+    });
+    assert_eq!(server.chat(request).await.status(), 200);
+
+    let headers = &upstream.requests()[0].headers;
+    assert_eq!(headers.get("anthropic-beta").map(String::as_str), Some("prompt-caching-2024-07-31"));
+    assert!(!headers.contains_key("x-internal-route"));
+    assert!(!headers.contains_key("openai-beta"));
+    assert_eq!(headers.get("x-api-key").map(String::as_str), Some("test-anthropic-key"));
+
+    let mut request = chat_request("claude-3-5-sonnet-20241022");
+    request["extraHeaders"] = json!({ "anthropc": { "anthropic-beta": "x" } });
+    assert_eq!(server.chat(request).await.status(), 400);
+}
+
+#[actix_web::test]
+async fn upstream_rate_limit_headers_are_passed_on_and_recorded() {
+    let upstream = MockUpstream::start(vec![
+        (
+            "/v1/chat/completions",
+            vec![MockResponse::sse(OPENAI_TEXT)
+                .with_header("x-ratelimit-remaining-requests", "499")
+                .with_header("x-ratelimit-remaining-tokens", "29000")],
+        ),
+        (
+            "/v1/messages",
+            vec![MockResponse::sse(ANTHROPIC_TEXT).with_header("anthropic-ratelimit-input-tokens-remaining", "80000")],
+        ),
+    ])
+    .await;
+    let server = TestServer::start(&upstream, &[]).await;
+
+    let response = server.chat(chat_request("gpt-4o")).await;
+    assert_eq!(response.headers()["x-upstream-ratelimit-remaining-requests"], "499");
+    assert_eq!(response.headers()["x-upstream-ratelimit-remaining-tokens"], "29000");
+    let response = server.chat(chat_request("claude-3-5-sonnet-20241022")).await;
+    assert_eq!(response.headers()["x-upstream-ratelimit-remaining-input-tokens"], "80000");
+    assert!(response.headers().get("x-upstream-ratelimit-remaining-tokens").is_none());
+
+    let metrics = server.get("/metrics").await.text().await.unwrap();
+    assert!(
+        metrics.contains(r#"api_upstream_ratelimit_remaining{limit="tokens",provider="openai"} 29000"#),
+        "{}",
+        metrics
+    );
+    assert!(
+        metrics.contains(r#"api_upstream_ratelimit_remaining{limit="input_tokens",provider="anthropic"} 80000"#),
+        "{}",
+        metrics
+    );
+}
+
+#[actix_web::test]
+async fn agentic_requests_get_the_larger_timeout_budget() {
+    let slow_response = MockResponse::sse(OPENAI_TEXT).delayed(std::time::Duration::from_millis(1500));
+    let upstream = MockUpstream::start(vec![("/v1/chat/completions", vec![slow_response])]).await;
+    let server = TestServer::start(
+        &upstream,
+        &[("UPSTREAM_TIMEOUT_SECS", "1"), ("AGENTIC_TIMEOUT_SECS", "10")],
+    )
+    .await;
+
+    // Single-shot request: 1s budget, upstream takes 1.5s
+    let response = server.chat(chat_request("gpt-4o")).await;
+    assert_eq!(response.status(), 502);
+
+    // Agentic request: 10s budget
+    let mut agentic = chat_request("gpt-4o");
+    agentic["maxSteps"] = json!(5);
+    let response = server.chat(agentic).await;
+    assert_eq!(response.status(), 200);
+    assert_eq!(streamed_text(&parse_frames(&response.text().await.unwrap())), "Hello, world");
+}
+
+#[actix_web::test]
+async fn prefixed_models_route_to_their_openai_compatible_provider() {
+    let upstream = MockUpstream::start(vec![("/groq/v1/chat/completions", vec![MockResponse::sse(OPENAI_TEXT)])]).await;
+    let groq_base_url = format!("{}/groq/v1", upstream.base_url);
+    let server = TestServer::start(
+        &upstream,
+        &[("GROQ_API_KEY", "test-groq-key"), ("GROQ_BASE_URL", &groq_base_url)],
+    )
+    .await;
+
+    let response = server.chat(chat_request("groq/llama-3.3-70b-versatile")).await;
+    assert_eq!(response.status(), 200);
+    assert_eq!(streamed_text(&parse_frames(&response.text().await.unwrap())), "Hello, world");
+
+    let requests = upstream.requests();
+    assert_eq!(requests.len(), 1);
+    assert_eq!(requests[0].headers.get("authorization").map(String::as_str), Some("Bearer test-groq-key"));
+    assert_eq!(requests[0].body["model"], "llama-3.3-70b-versatile");
+}
+
+#[actix_web::test]
+async fn explain_routing_streams_the_matched_rule_first() {
+    let upstream = MockUpstream::start(vec![("/groq/v1/chat/completions", vec![MockResponse::sse(OPENAI_TEXT)])]).await;
+    let groq_base_url = format!("{}/groq/v1", upstream.base_url);
+    let server = TestServer::start(
+        &upstream,
+        &[
+            ("EXPLAIN_ROUTING", "1"),
+            ("GROQ_API_KEY", "test-groq-key"),
+            ("GROQ_BASE_URL", &groq_base_url),
+        ],
+    )
+    .await;
+
+    let response = server.chat(chat_request("groq/llama-3.3-70b-versatile")).await;
+    assert_eq!(response.status(), 200);
+    let frames = parse_frames(&response.text().await.unwrap());
+
+    assert_eq!(
+        frames[0],
+        (
+            "8".to_string(),
+            json!([{
+                "type": "routing",
+                "model": "groq/llama-3.3-70b-versatile",
+                "provider": "groq",
+                "rule": "provider prefix 'groq/'"
+            }])
+        )
+    );
+    assert_eq!(streamed_text(&frames), "Hello, world");
+}
+
+#[actix_web::test]
+async fn coalesced_streams_keep_frames_whole() {
+    let events: Vec<&str> = ANTHROPIC_TEXT.split_inclusive("\n\n").collect();
+    let upstream = MockUpstream::start(vec![(
+        "/v1/messages",
+        vec![MockResponse::chunked(&events, std::time::Duration::from_millis(5))],
+    )])
+    .await;
+    let server = TestServer::start(
+        &upstream,
+        &[("STREAM_COALESCE_MS", "50"), ("STREAM_COALESCE_BYTES", "16")],
+    )
+    .await;
+
+    let response = server.chat(chat_request("claude-3-5-sonnet-20241022")).await;
+    assert_eq!(response.status(), 200);
+    let frames = parse_frames(&response.text().await.unwrap());
+
+    assert_eq!(streamed_text(&frames), "Hello, world");
+    assert_eq!(frames.last().unwrap().0, "d");
+}
+
+#[actix_web::test]
+async fn interrupted_streams_can_be_resumed_by_message_id() {
+    let events: Vec<&str> = ANTHROPIC_TEXT.split_inclusive("\n\n").collect();
+    let upstream = MockUpstream::start(vec![(
+        "/v1/messages",
+        vec![MockResponse::chunked(&events, std::time::Duration::from_millis(50))],
+    )])
+    .await;
+    let server = TestServer::start(&upstream, &[("RESUMABLE_STREAMS", "true")]).await;
+
+    // The client goes away right after the stream starts; the upstream call carries on
+    let response = server.chat(chat_request("claude-3-5-sonnet-20241022")).await;
+    assert_eq!(response.status(), 200);
+    let message_id = response.headers()["x-message-id"].to_str().unwrap().to_string();
+    drop(response);
+
+    let resumed = server.get(&format!("/resume/{}", message_id)).await;
+    assert_eq!(resumed.status(), 200);
+    let frames = parse_frames(&resumed.text().await.unwrap());
+    assert_eq!(frames[0], ("f".to_string(), json!({ "messageId": message_id })));
+    assert_eq!(streamed_text(&frames), "Hello, world");
+    assert_eq!(frames.last().unwrap().0, "d");
+    assert_eq!(upstream.requests().len(), 1);
+
+    assert_eq!(server.get("/resume/msg-unknown").await.status(), 404);
+}
+
+#[actix_web::test]
+async fn streams_past_the_byte_cap_are_cut_with_a_length_finish() {
+    let upstream = MockUpstream::start(vec![("/v1/chat/completions", vec![MockResponse::sse(OPENAI_TEXT)])]).await;
+    let server = TestServer::start(&upstream, &[("MAX_STREAM_BYTES", "20")]).await;
+
+    let response = server.chat(chat_request("gpt-4o")).await;
+    assert_eq!(response.status(), 200);
+    let frames = parse_frames(&response.text().await.unwrap());
+
+    // `0:"Hello"` fits in 20 bytes, `0:", world"` on top of it does not
+    assert_eq!(streamed_text(&frames), "Hello");
+    assert_eq!(
+        frames.last().unwrap(),
+        &(
+            "d".to_string(),
+            json!({ "finishReason": "length", "usage": { "promptTokens": 0, "completionTokens": 0 } })
+        )
+    );
+}
+
+#[actix_web::test]
+async fn openai_temperature_is_clamped_and_zero_is_kept() {
+    let upstream = MockUpstream::start(vec![("/v1/chat/completions", vec![MockResponse::sse(OPENAI_TEXT)])]).await;
+    let server = TestServer::start(&upstream, &[]).await;
+
+    for temperature in [3.5, 0.0] {
+        let mut request = chat_request("gpt-4o");
+        request["temperature"] = json!(temperature);
+        assert_eq!(server.chat(request).await.status(), 200);
+    }
+    assert_eq!(server.chat(chat_request("gpt-4o")).await.status(), 200);
+
+    let temperatures: Vec<_> = upstream.requests().iter().map(|r| r.body["temperature"].clone()).collect();
+    assert_eq!(temperatures, vec![json!(2.0), json!(0.0), json!(0.2f32)]);
+}
+
+#[actix_web::test]
+async fn add_transformation_calls_build_the_session_pipeline() {
+    let second_step = OPENAI_ADD_TRANSFORMATION.replace("high_value_orders", "top_orders");
+    let upstream = MockUpstream::start(vec![(
+        "/v1/chat/completions",
+        vec![MockResponse::sse(OPENAI_ADD_TRANSFORMATION), MockResponse::sse(&second_step)],
+    )])
+    .await;
+    let server = TestServer::start(&upstream, &[]).await;
+
+    let mut request = chat_request("gpt-4o");
+    request["sessionId"] = json!("session-1");
+    let response = server.chat(request).await;
+    assert_eq!(response.status(), 200);
+    let frames = parse_frames(&response.text().await.unwrap());
+
+    let step = json!({
+        "outputAlias": "high_value_orders",
+        "sql": "SELECT * FROM previous_step WHERE amount > 100",
+        "resolvedSql": "SELECT * FROM previous_step WHERE amount > 100"
+    });
+    assert_eq!(frames[1].0, "9");
+    assert_eq!(
+        frames[2],
+        ("a".to_string(), json!({ "toolCallId": "call_t1", "result": { "pipeline": [step] } }))
+    );
+
+    // The second step's previous_step resolves to the first step
+    let mut request = chat_request("gpt-4o");
+    request["sessionId"] = json!("session-1");
+    assert_eq!(server.chat(request).await.status(), 200);
+
+    let pipeline: serde_json::Value = server.get("/pipeline/session-1").await.json().await.unwrap();
+    assert_eq!(pipeline["steps"][0], step);
+    assert_eq!(
+        pipeline["steps"][1]["resolvedSql"],
+        "WITH high_value_orders AS (SELECT * FROM previous_step WHERE amount > 100), \
+         previous_step AS (SELECT * FROM high_value_orders) SELECT * FROM previous_step WHERE amount > 100"
+    );
+    assert_eq!(server.get("/pipeline/unknown").await.status(), 404);
+}
+
+#[actix_web::test]
+async fn token_budget_uses_the_field_each_openai_model_expects() {
+    let upstream = MockUpstream::start(vec![("/v1/chat/completions", vec![MockResponse::sse(OPENAI_TEXT)])]).await;
+    let server = TestServer::start(&upstream, &[]).await;
+
+    for model in ["gpt-4", "gpt-4o", "o3-mini", "gpt-5"] {
+        let mut request = chat_request(model);
+        request["maxTokens"] = json!(256);
+        assert_eq!(server.chat(request).await.status(), 200);
+    }
+
+    let requests = upstream.requests();
+    for request in &requests[..2] {
+        assert_eq!(request.body["max_tokens"], 256);
+        assert!(request.body.get("max_completion_tokens").is_none());
+    }
+    for request in &requests[2..] {
+        assert_eq!(request.body["max_completion_tokens"], 256);
+        assert!(request.body.get("max_tokens").is_none());
+    }
+}
+
+#[actix_web::test]
+async fn edge_case_text_deltas_produce_well_formed_frames() {
+    let chunk = |content: &str| {
+        format!(
+            "data: {}\n\n",
+            json!({ "choices": [{ "index": 0, "delta": { "content": content }, "finish_reason": null }] })
+        )
+    };
+    let stream = [chunk(""), chunk("\n"), chunk("   "), chunk("\u{1}\t"), chunk("")].concat() + "data: [DONE]\n\n";
+    let upstream = MockUpstream::start(vec![("/v1/chat/completions", vec![MockResponse::sse(&stream)])]).await;
+    let server = TestServer::start(&upstream, &[]).await;
+
+    let response = server.chat(chat_request("gpt-4o")).await;
+    assert_eq!(response.status(), 200);
+    let body = response.text().await.unwrap();
+
+    // Every line is a complete frame, and empty deltas are dropped
+    let text_lines: Vec<&str> = body.lines().filter(|line| line.starts_with("0:")).collect();
+    assert_eq!(text_lines, vec!["0:\"\\n\"", "0:\"   \"", "0:\"\\u0001\\t\""]);
+    assert_eq!(
+        without_step_frames(parse_frames(&body)),
+        vec![
+            ("0".to_string(), json!("\n")),
+            ("0".to_string(), json!("   ")),
+            ("0".to_string(), json!("\u{1}\t")),
+            (
+                "d".to_string(),
+                json!({ "finishReason": "stop", "usage": { "promptTokens": 0, "completionTokens": 0 } })
+            ),
+        ]
+    );
+}
+
+#[actix_web::test]
+async fn multi_byte_characters_split_across_chunks_stay_intact() {
+    let stream = OPENAI_TEXT.replace(", world", ", world \u{1F44B}");
+    // Cut the stream two bytes into the 4-byte emoji
+    let split = stream.find('\u{1F44B}').unwrap() + 2;
+    let (first, rest) = stream.as_bytes().split_at(split);
+    let upstream = MockUpstream::start(vec![(
+        "/v1/chat/completions",
+        vec![MockResponse::chunked_bytes(&[first, rest], std::time::Duration::from_millis(10))],
+    )])
+    .await;
+    let server = TestServer::start(&upstream, &[]).await;
+
+    let response = server.chat(chat_request("gpt-4o")).await;
+    assert_eq!(response.status(), 200);
+    let frames = without_step_frames(parse_frames(&response.text().await.unwrap()));
+
+    assert_eq!(frames[1], ("0".to_string(), json!(", world \u{1F44B}")));
+    assert_eq!(streamed_text(&frames), "Hello, world \u{1F44B}");
+    assert!(!streamed_text(&frames).contains('\u{FFFD}'));
+}
+
+#[actix_web::test]
+async fn streams_of_only_unhandled_events_get_a_diagnostic_in_strict_mode() {
+    let stream = "event: ping\ndata: {\"type\":\"ping\"}\n\n\
+                  event: content_block_start\ndata: {\"type\":\"content_block_start\",\"index\":0}\n\n\
+                  event: content_block_stop\ndata: {\"type\":\"content_block_stop\",\"index\":0}\n\n";
+    let upstream = MockUpstream::start(vec![("/v1/messages", vec![MockResponse::sse(stream)])]).await;
+
+    let lenient = TestServer::start(&upstream, &[]).await;
+    let response = lenient.chat(chat_request("claude-3-5-sonnet-20241022")).await;
+    assert_eq!(response.status(), 200);
+    let frames = parse_frames(&response.text().await.unwrap());
+    assert_eq!(frames.len(), 1);
+    assert_eq!(frames[0].0, "f");
+
+    let strict = TestServer::start(&upstream, &[("STRICT_CONVERSION", "1")]).await;
+    let response = strict.chat(chat_request("claude-3-5-sonnet-20241022")).await;
+    let frames = without_step_frames(parse_frames(&response.text().await.unwrap()));
+    assert_eq!(frames.len(), 1);
+    assert_eq!(frames[0].0, "3");
+    let message = frames[0].1.as_str().unwrap();
+    assert!(
+        message.ends_with("Unhandled event types: [content_block_start, content_block_stop]"),
+        "{}",
+        message
+    );
+}
+
+#[actix_web::test]
+async fn session_header_keys_state_and_idle_sessions_expire() {
+    let upstream =
+        MockUpstream::start(vec![("/v1/chat/completions", vec![MockResponse::sse(OPENAI_ADD_TRANSFORMATION)])]).await;
+    let server = TestServer::start(&upstream, &[("SESSION_TTL_SECS", "1")]).await;
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/sdk-chat", server.base_url))
+        .header("X-Session-Id", "from-header")
+        .json(&chat_request("gpt-4o"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    response.text().await.unwrap();
+    assert_eq!(server.get("/pipeline/from-header").await.status(), 200);
+
+    actix_web::rt::time::sleep(std::time::Duration::from_millis(1500)).await;
+    assert_eq!(server.get("/pipeline/from-header").await.status(), 404);
+}
+
+#[actix_web::test]
+async fn a_session_that_spent_its_token_budget_is_rejected() {
+    // Each response uses 12 prompt and 6 completion tokens
+    let upstream = MockUpstream::start(vec![("/v1/messages", vec![MockResponse::sse(ANTHROPIC_TEXT)])]).await;
+    let server = TestServer::start(&upstream, &[("SESSION_TOKEN_BUDGET", "30")]).await;
+    let session_request = |session_id: &str, max_session_tokens: Option<u64>| {
+        let mut request = chat_request("claude-3-5-sonnet-20241022");
+        request["sessionId"] = json!(session_id);
+        if let Some(max_session_tokens) = max_session_tokens {
+            request["maxSessionTokens"] = json!(max_session_tokens);
+        }
+        request
+    };
+
+    for remaining in ["30", "12"] {
+        let response = server.chat(session_request("budgeted", None)).await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers()["x-session-tokens-remaining"], remaining);
+        response.text().await.unwrap();
+    }
+    let response = server.chat(session_request("budgeted", None)).await;
+    assert_eq!(response.status(), 429);
+    assert_eq!(response.headers()["x-session-tokens-remaining"], "0");
+    let frames = without_step_frames(parse_frames(&response.text().await.unwrap()));
+    assert_eq!(frames[0], ("3".to_string(), json!("budget_exceeded: This session has used its budget of 30 tokens")));
+    assert_eq!(frames[1].1["finishReason"], "error");
+
+    // A client budget below the server's applies; other sessions have their own
+    let response = server.chat(session_request("frugal", Some(10))).await;
+    assert_eq!(response.headers()["x-session-tokens-remaining"], "10");
+    response.text().await.unwrap();
+    assert_eq!(server.chat(session_request("frugal", Some(10))).await.status(), 429);
+    assert_eq!(upstream.requests().len(), 3);
+}
+
+#[actix_web::test]
+async fn requests_without_a_session_leave_tool_calls_to_the_client() {
+    let upstream =
+        MockUpstream::start(vec![("/v1/chat/completions", vec![MockResponse::sse(OPENAI_ADD_TRANSFORMATION)])]).await;
+    let server = TestServer::start(&upstream, &[]).await;
+
+    let response = server.chat(chat_request("gpt-4o")).await;
+    let frames = without_step_frames(parse_frames(&response.text().await.unwrap()));
+    assert_eq!(frames.len(), 2);
+    assert_eq!(frames[0].0, "9");
+}
+
+#[actix_web::test]
+async fn mistral_stream_with_unindexed_tool_calls_becomes_frames() {
+    let upstream =
+        MockUpstream::start(vec![("/mistral/v1/chat/completions", vec![MockResponse::sse(MISTRAL_TOOL_CALLS)])]).await;
+    let mistral_base_url = format!("{}/mistral/v1", upstream.base_url);
+    let server = TestServer::start(
+        &upstream,
+        &[("MISTRAL_API_KEY", "test-mistral-key"), ("MISTRAL_BASE_URL", &mistral_base_url)],
+    )
+    .await;
+
+    let response = server.chat(chat_request("mistral-large-latest")).await;
+    assert_eq!(response.status(), 200);
+    let frames = without_step_frames(parse_frames(&response.text().await.unwrap()));
+
+    assert_eq!(
+        frames,
+        vec![
+            ("0".to_string(), json!("Let me check.")),
+            (
+                "9".to_string(),
+                json!({ "toolCallId": "D681PevKs", "toolName": "executeSQL", "args": { "sql": "SELECT COUNT(*) FROM orders" } })
+            ),
+            (
+                "9".to_string(),
+                json!({ "toolCallId": "k9Qx2LmTe", "toolName": "executeSQL", "args": { "sql": "SELECT 1" } })
+            ),
+            (
+                "d".to_string(),
+                json!({ "finishReason": "tool-calls", "usage": { "promptTokens": 812, "completionTokens": 49 } })
+            ),
+        ]
+    );
+
+    let requests = upstream.requests();
+    assert_eq!(requests[0].headers.get("authorization").map(String::as_str), Some("Bearer test-mistral-key"));
+    assert_eq!(requests[0].body["model"], "mistral-large-latest");
+    assert!(requests[0].body.get("stream_options").is_none());
+}
+
+#[actix_web::test]
+async fn openai_scope_headers_go_to_openai_only() {
+    let upstream = MockUpstream::start(vec![
+        ("/v1/chat/completions", vec![MockResponse::sse(OPENAI_TEXT)]),
+        ("/v1/messages", vec![MockResponse::sse(ANTHROPIC_TEXT)]),
+    ])
+    .await;
+    let server = TestServer::start(
+        &upstream,
+        &[("OPENAI_ORGANIZATION", "org-test"), ("OPENAI_PROJECT", "proj_test")],
+    )
+    .await;
+
+    assert_eq!(server.chat(chat_request("gpt-4o")).await.status(), 200);
+    assert_eq!(server.chat(chat_request("claude-3-5-sonnet-20241022")).await.status(), 200);
+
+    let requests = upstream.requests();
+    let openai = requests.iter().find(|r| r.path == "/v1/chat/completions").unwrap();
+    assert_eq!(openai.headers.get("openai-organization").map(String::as_str), Some("org-test"));
+    assert_eq!(openai.headers.get("openai-project").map(String::as_str), Some("proj_test"));
+
+    let anthropic = requests.iter().find(|r| r.path == "/v1/messages").unwrap();
+    assert!(!anthropic.headers.contains_key("openai-organization"));
+    assert!(!anthropic.headers.contains_key("openai-project"));
+}
+
+#[actix_web::test]
+async fn openai_logprobs_are_forwarded_and_streamed_as_data_frames() {
+    let logprobs = json!([{
+        "token": "Hi",
+        "logprob": -0.01,
+        "bytes": [72, 105],
+        "top_logprobs": [
+            { "token": "Hi", "logprob": -0.01, "bytes": [72, 105] },
+            { "token": "Hello", "logprob": -4.6, "bytes": [72, 101, 108, 108, 111] }
+        ]
+    }]);
+    let stream = format!(
+        "data: {}\n\ndata: {}\n\ndata: [DONE]\n\n",
+        json!({ "choices": [{ "index": 0, "delta": { "content": "Hi" }, "logprobs": { "content": logprobs }, "finish_reason": null }] }),
+        json!({ "choices": [{ "index": 0, "delta": {}, "logprobs": null, "finish_reason": "stop" }] })
+    );
+    let upstream = MockUpstream::start(vec![(
+        "/v1/chat/completions",
+        vec![MockResponse::sse(&stream), MockResponse::sse(OPENAI_TEXT)],
+    )])
+    .await;
+    let server = TestServer::start(&upstream, &[]).await;
+
+    let mut request = chat_request("gpt-4o");
+    request["logprobs"] = json!(true);
+    request["topLogprobs"] = json!(2);
+    let frames = without_step_frames(parse_frames(&server.chat(request).await.text().await.unwrap()));
+    assert_eq!(streamed_text(&frames), "Hi");
+    assert_eq!(frames[1], ("2".to_string(), json!([{ "type": "logprobs", "content": logprobs }])));
+
+    // Reasoning models don't support logprobs, so they aren't sent rather than failing the request
+    let mut request = chat_request("o3-mini");
+    request["logprobs"] = json!(true);
+    assert_eq!(server.chat(request).await.status(), 200);
+
+    let requests = upstream.requests();
+    assert_eq!(requests[0].body["logprobs"], true);
+    assert_eq!(requests[0].body["top_logprobs"], 2);
+    assert!(requests[1].body.get("logprobs").is_none());
+    assert!(requests[1].body.get("top_logprobs").is_none());
+}
+
+#[actix_web::test]
+async fn seed_is_forwarded_and_the_system_fingerprint_is_in_the_finish_frame() {
+    let chunk = |delta: serde_json::Value, finish_reason: serde_json::Value| {
+        json!({
+            "system_fingerprint": "fp_44709d6fcb",
+            "choices": [{ "index": 0, "delta": delta, "finish_reason": finish_reason }]
+        })
+    };
+    let stream = format!(
+        "data: {}\n\ndata: {}\n\ndata: [DONE]\n\n",
+        chunk(json!({ "content": "Same every time" }), json!(null)),
+        chunk(json!({}), json!("stop"))
+    );
+    let upstream = MockUpstream::start(vec![
+        ("/v1/chat/completions", vec![MockResponse::sse(&stream)]),
+        ("/v1/messages", vec![MockResponse::sse(ANTHROPIC_TEXT)]),
+    ])
+    .await;
+    let server = TestServer::start(&upstream, &[]).await;
+
+    let mut request = chat_request("gpt-4o");
+    request["seed"] = json!(42);
+    let frames = parse_frames(&server.chat(request).await.text().await.unwrap());
+    assert_eq!(frames.last().unwrap().1["systemFingerprint"], "fp_44709d6fcb");
+
+    // Anthropic has no seed, so it's left out rather than failing the request
+    let mut request = chat_request("claude-3-5-sonnet-20241022");
+    request["seed"] = json!(42);
+    assert_eq!(server.chat(request).await.status(), 200);
+
+    let requests = upstream.requests();
+    assert_eq!(requests[0].body["seed"], 42);
+    assert!(requests[1].body.get("seed").is_none());
+}
+
+#[actix_web::test]
+async fn system_prompt_bypass_needs_the_server_to_allow_it() {
+    let request = json!({
+        "model": "gpt-4o",
+        "messages": [
+            { "role": "system", "content": "Answer in French." },
+            { "role": "user", "content": "Say hello" }
+        ]
+    });
+    let chat_bypassing = |server: &TestServer| {
+        reqwest::Client::new()
+            .post(format!("{}/sdk-chat", server.base_url))
+            .header("X-Bypass-System-Prompt", "true")
+            .json(&request)
+            .send()
+    };
+    let system_prompts = |upstream: &MockUpstream| -> Vec<serde_json::Value> {
+        let messages = upstream.requests()[0].body["messages"].as_array().unwrap().clone();
+        messages.into_iter().filter(|m| m["role"] == "system").map(|m| m["content"].clone()).collect()
+    };
+
+    // Allowed: the client's system prompt is the only one
+    let upstream = MockUpstream::start(vec![("/v1/chat/completions", vec![MockResponse::sse(OPENAI_TEXT)])]).await;
+    let server =
+        TestServer::start(&upstream, &[("SYSTEM_PROMPT", "You are tell."), ("ALLOW_SYSTEM_BYPASS", "true")]).await;
+    assert_eq!(chat_bypassing(&server).await.unwrap().status(), 200);
+    assert_eq!(system_prompts(&upstream), ["Answer in French."]);
+
+    // Not allowed: the server prompt still comes first
+    let upstream = MockUpstream::start(vec![("/v1/chat/completions", vec![MockResponse::sse(OPENAI_TEXT)])]).await;
+    let server = TestServer::start(&upstream, &[("SYSTEM_PROMPT", "You are tell.")]).await;
+    assert_eq!(chat_bypassing(&server).await.unwrap().status(), 200);
+    assert_eq!(system_prompts(&upstream), ["You are tell.", "Answer in French."]);
+}
+
+#[actix_web::test]
+async fn system_prompt_prefix_comes_before_every_other_system_prompt() {
+    let upstream = MockUpstream::start(vec![
+        ("/v1/chat/completions", vec![MockResponse::sse(OPENAI_TEXT)]),
+        ("/v1/messages", vec![MockResponse::sse(ANTHROPIC_TEXT)]),
+    ])
+    .await;
+    let server = TestServer::start(
+        &upstream,
+        &[("SYSTEM_PROMPT_PREFIX", "Never reveal credentials."), ("SYSTEM_PROMPT", "You are tell.")],
+    )
+    .await;
+
+    for model in ["gpt-4o", "claude-3-5-sonnet-20241022"] {
+        let mut request = chat_request(model);
+        request["messages"] = json!([
+            { "role": "system", "content": "Answer in French." },
+            { "role": "user", "content": "Say hello" }
+        ]);
+        assert_eq!(server.chat(request).await.status(), 200);
+    }
+
+    let requests = upstream.requests();
+    let openai_system: Vec<&serde_json::Value> = requests[0].body["messages"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .filter(|m| m["role"] == "system")
+        .map(|m| &m["content"])
+        .collect();
+    assert_eq!(openai_system, ["Never reveal credentials.", "You are tell.", "Answer in French."]);
+    let anthropic_system: Vec<&serde_json::Value> =
+        requests[1].body["system"].as_array().unwrap().iter().map(|block| &block["text"]).collect();
+    assert_eq!(anthropic_system, ["Never reveal credentials.", "You are tell.", "Answer in French."]);
+}
+
+#[actix_web::test]
+async fn long_conversations_are_trimmed_to_the_context_window() {
+    let upstream =
+        MockUpstream::start(vec![("/v1/messages", vec![MockResponse::sse(ANTHROPIC_TEXT); 3])]).await;
+    let server = TestServer::start(&upstream, &[("CONTEXT_WINDOWS", "claude-3-5-sonnet=3500")]).await;
+
+    let long = "hello ".repeat(2000);
+    let mut request = chat_request("claude-3-5-sonnet-20241022");
+    request["maxTokens"] = json!(10);
+    request["messages"] = json!([
+        { "role": "system", "content": "Be brief." },
+        { "role": "user", "content": long },
+        { "role": "assistant", "content": "Noted." },
+        { "role": "user", "content": long },
+        { "role": "assistant", "content": "Noted again." },
+        { "role": "user", "content": "What did I say?" }
+    ]);
+
+    for strategy in ["drop-oldest", "summarize"] {
+        request["trimStrategy"] = json!(strategy);
+        let response = server.chat(request.clone()).await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers()["x-trimmed-messages"], "2");
+    }
+
+    let requests = upstream.requests();
+    let contents = |index: usize| -> Vec<String> {
+        requests[index].body["messages"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|m| m["content"].as_str().map_or(m["content"][0]["text"].to_string(), str::to_string))
+            .collect()
+    };
+    assert_eq!(contents(0), [long.clone(), "Noted again.".to_string(), "What did I say?".to_string()]);
+    assert_eq!(requests[0].body["system"][0]["text"], "Be brief.");
+
+    // The summary call gets the dropped turn, and its summary follows the system prompt
+    assert_eq!(requests[1].body["model"], "claude-3-5-haiku-20241022");
+    assert!(contents(1)[0].ends_with("assistant: Noted."));
+    assert_eq!(contents(2), contents(0));
+    let system: Vec<&serde_json::Value> =
+        requests[2].body["system"].as_array().unwrap().iter().map(|block| &block["text"]).collect();
+    assert_eq!(system, ["Be brief.", "Summary of the earlier conversation: Hello, world"]);
+}
+
+#[actix_web::test]
+async fn dry_runs_report_the_trim_without_calling_the_provider() {
+    let upstream = MockUpstream::start(vec![("/v1/messages", vec![MockResponse::sse(ANTHROPIC_TEXT)])]).await;
+    let server = TestServer::start(&upstream, &[("CONTEXT_WINDOWS", "claude-3-5-sonnet=3500")]).await;
+
+    let long = "hello ".repeat(2000);
+    let mut request = chat_request("claude-3-5-sonnet-20241022");
+    request["maxTokens"] = json!(10);
+    request["trimStrategy"] = json!("summarize");
+    request["messages"] = json!([
+        { "role": "user", "content": long },
+        { "role": "assistant", "content": "Noted." },
+        { "role": "user", "content": long },
+        { "role": "assistant", "content": "Noted again." },
+        { "role": "user", "content": "What did I say?" }
+    ]);
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/sdk-chat?dryRun=true", server.base_url))
+        .json(&request)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()["x-trimmed-messages"], "2");
+    let dry_run: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(dry_run["body"]["messages"].as_array().unwrap().len(), 3);
+    assert!(dry_run["body"].get("system").is_none());
+    assert!(upstream.requests().is_empty());
+}
+
+fn image_request(model: &str, url: &str) -> serde_json::Value {
+    json!({
+        "model": model,
+        "messages": [{
+            "role": "user",
+            "content": "What is in this chart?",
+            "experimental_attachments": [{ "name": "chart.png", "contentType": "image/png", "url": url }]
+        }]
+    })
+}
+
+#[actix_web::test]
+async fn image_attachments_are_forwarded_in_each_providers_format() {
+    let upstream = MockUpstream::start(vec![
+        ("/v1/chat/completions", vec![MockResponse::sse(OPENAI_TEXT)]),
+        ("/v1/messages", vec![MockResponse::sse(ANTHROPIC_TEXT)]),
+    ])
+    .await;
+    let server = TestServer::start(&upstream, &[]).await;
+    let data_url = "data:image/png;base64,iVBORw0KGgo=";
+
+    assert_eq!(server.chat(image_request("gpt-4o", data_url)).await.status(), 200);
+    assert_eq!(server.chat(image_request("claude-3-5-sonnet-20241022", data_url)).await.status(), 200);
+
+    let requests = upstream.requests();
+    assert_eq!(
+        requests[0].body["messages"][0]["content"],
+        json!([
+            { "type": "text", "text": "What is in this chart?" },
+            { "type": "image_url", "image_url": { "url": data_url } }
+        ])
+    );
+    assert_eq!(
+        requests[1].body["messages"][0]["content"],
+        json!([
+            { "type": "image", "source": { "type": "base64", "media_type": "image/png", "data": "iVBORw0KGgo=" } },
+            { "type": "text", "text": "What is in this chart?" }
+        ])
+    );
+}
+
+#[actix_web::test]
+async fn only_allowed_models_can_be_requested() {
+    let upstream = MockUpstream::start(vec![
+        ("/v1/messages", vec![MockResponse::sse(ANTHROPIC_TEXT)]),
+        ("/v1/chat/completions", vec![MockResponse::sse(OPENAI_TEXT)]),
+    ])
+    .await;
+    let server = TestServer::start(&upstream, &[("ALLOWED_MODELS", "gpt-4o, claude-3-5-*")]).await;
+
+    // Exact match, then a prefix wildcard
+    assert_eq!(server.chat(chat_request("gpt-4o")).await.status(), 200);
+    assert_eq!(server.chat(chat_request("claude-3-5-sonnet-20241022")).await.status(), 200);
+
+    let response = server.chat(chat_request("gpt-4o-mini")).await;
+    assert_eq!(response.status(), 403);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["error"]["type"], "permission_error");
+    assert_eq!(
+        body["error"]["message"],
+        "Model 'gpt-4o-mini' is not permitted on this server. Permitted models: gpt-4o, claude-3-5-*"
+    );
+
+    let mut request = chat_request("gpt-4o");
+    request["fallbackModels"] = json!(["o1"]);
+    assert_eq!(server.chat(request).await.status(), 403);
+    assert_eq!(upstream.requests().len(), 2);
+}
+
+#[actix_web::test]
+async fn over_long_messages_are_rejected_or_truncated() {
+    let request = json!({
+        "model": "gpt-4o",
+        "messages": [{ "role": "user", "content": "Say hello to everyone" }]
+    });
+    let upstream = MockUpstream::start(vec![]).await;
+    let server = TestServer::start(&upstream, &[("MAX_MESSAGE_CHARS_OPENAI", "9")]).await;
+
+    let response = server.chat(request.clone()).await;
+    assert_eq!(response.status(), 400);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["error"]["type"], "invalid_request_error");
+    assert_eq!(
+        body["error"]["message"],
+        "Message at index 0 is 21 characters long, which exceeds the openai limit of 9 characters"
+    );
+    assert!(upstream.requests().is_empty());
+
+    let upstream = MockUpstream::start(vec![("/v1/chat/completions", vec![MockResponse::sse(OPENAI_TEXT)])]).await;
+    let server =
+        TestServer::start(&upstream, &[("MAX_MESSAGE_CHARS_OPENAI", "9"), ("MESSAGE_LENGTH_POLICY", "truncate")])
+            .await;
+
+    let response = server.chat(request).await;
+    assert_eq!(response.status(), 200);
+    response.text().await.unwrap();
+    assert_eq!(upstream.requests()[0].body["messages"][0]["content"], "Say hello");
+}
+
+#[actix_web::test]
+async fn images_for_text_only_models_are_rejected() {
+    let upstream = MockUpstream::start(vec![]).await;
+    let server = TestServer::start(&upstream, &[]).await;
+
+    let response = server.chat(image_request("gpt-3.5-turbo", "https://example.com/chart.png")).await;
+    assert_eq!(response.status(), 400);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(
+        body["error"]["message"],
+        "Model 'gpt-3.5-turbo' does not accept image inputs (message 0 has an image attachment)"
+    );
+    assert!(upstream.requests().is_empty());
+}
+
+#[actix_web::test]
+async fn model_capabilities_shape_the_openai_request() {
+    let upstream = MockUpstream::start(vec![("/v1/chat/completions", vec![MockResponse::sse(OPENAI_TEXT)])]).await;
+    let server = TestServer::start(&upstream, &[("NO_SYSTEM_ROLE_MODELS", "o1-mini,gpt-4o-mini")]).await;
+
+    for model in ["gpt-4o", "gpt-4o-mini", "o1-mini"] {
+        let mut request = chat_request(model);
+        request["messages"] = json!([
+            { "role": "system", "content": "Answer in SQL" },
+            { "role": "user", "content": "Say hello" }
+        ]);
+        assert_eq!(server.chat(request).await.status(), 200);
+    }
+    let response = server.chat(chat_request("o1-pro")).await;
+    assert_eq!(response.status(), 400);
+
+    let requests = upstream.requests();
+    assert_eq!(requests.len(), 3);
+    assert_eq!(requests[0].body["messages"][0]["role"], "system");
+    assert!(requests[0].body.get("temperature").is_some());
+    assert!(requests[0].body.get("tools").is_some());
+
+    // No system role: folded into the first user message
+    for request in &requests[1..] {
+        assert_eq!(
+            request.body["messages"],
+            json!([{ "role": "user", "content": "Answer in SQL\n\nSay hello" }])
+        );
+    }
+    assert!(requests[1].body.get("temperature").is_some());
+    assert!(requests[2].body.get("temperature").is_none());
+    assert!(requests[2].body.get("tools").is_none());
+}
+
+#[actix_web::test]
+async fn dry_run_returns_the_upstream_request_without_sending_it() {
+    let upstream = MockUpstream::start(vec![("/v1/chat/completions", vec![MockResponse::sse(OPENAI_TEXT)])]).await;
+    let server = TestServer::start(&upstream, &[]).await;
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/sdk-chat?dryRun=true", server.base_url))
+        .json(&chat_request("gpt-4o"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let dry_run: serde_json::Value = response.json().await.unwrap();
+
+    assert_eq!(dry_run["provider"], "openai");
+    assert_eq!(dry_run["url"], format!("{}/v1/chat/completions", upstream.base_url));
+    assert_eq!(dry_run["headers"]["Authorization"], "[redacted]");
+    assert_eq!(dry_run["body"]["model"], "gpt-4o");
+    assert_eq!(dry_run["body"]["messages"], json!([{ "role": "user", "content": "Say hello" }]));
+    assert_eq!(dry_run["body"]["temperature"], json!(0.2f32));
+    assert!(dry_run["body"]["tools"].as_array().is_some_and(|tools| !tools.is_empty()));
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/sdk-chat", server.base_url))
+        .header("X-Dry-Run", "true")
+        .json(&chat_request("claude-3-5-sonnet-20241022"))
+        .send()
+        .await
+        .unwrap();
+    let dry_run: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(dry_run["provider"], "anthropic");
+    assert_eq!(dry_run["headers"]["x-api-key"], "[redacted]");
+    assert_eq!(dry_run["headers"]["anthropic-version"], "2023-06-01");
+
+    assert!(upstream.requests().is_empty());
+}
+
+#[actix_web::test]
+async fn tools_for_models_without_tool_support_can_be_rejected_or_described() {
+    let upstream = MockUpstream::start(vec![("/v1/chat/completions", vec![MockResponse::sse(OPENAI_TEXT); 2])]).await;
+
+    let server = TestServer::start(&upstream, &[("UNSUPPORTED_TOOLS", "reject")]).await;
+    let response = server.chat(chat_request("o3-mini")).await;
+    assert_eq!(response.status(), 400);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(
+        body["error"]["message"],
+        "Model o3-mini can't use tools. Send toolChoice \"none\" to call it without them."
+    );
+    let mut request = chat_request("o3-mini");
+    request["toolChoice"] = json!("none");
+    assert_eq!(server.chat(request).await.status(), 200);
+    drop(server);
+
+    let server = TestServer::start(&upstream, &[("UNSUPPORTED_TOOLS", "prompt")]).await;
+    assert_eq!(server.chat(chat_request("o3-mini")).await.status(), 200);
+
+    let requests = upstream.requests();
+    assert_eq!(requests.len(), 2);
+    assert!(requests[0].body["messages"].as_array().unwrap().iter().all(|m| m["role"] != "system"));
+    let described = &requests[1].body["messages"][0];
+    assert_eq!(described["role"], "system");
+    let description = described["content"].as_str().unwrap();
+    assert!(description.contains("- executeSQL: "), "{}", description);
+    assert!(description.contains(r#"{"tool": "<name>", "args": {...}}"#), "{}", description);
+    assert!(requests[1].body.get("tools").is_none());
+}
+
+#[actix_web::test]
+async fn tool_choice_is_mapped_per_provider() {
+    let upstream = MockUpstream::start(vec![]).await;
+    let server = TestServer::start(&upstream, &[("MISTRAL_API_KEY", "test-mistral-key")]).await;
+    let dry_run = |model: &str, tool_choice: serde_json::Value| {
+        let mut request = chat_request(model);
+        request["toolChoice"] = tool_choice;
+        reqwest::Client::new()
+            .post(format!("{}/sdk-chat", server.base_url))
+            .header("X-Dry-Run", "true")
+            .json(&request)
+            .send()
+    };
+
+    let cases = [
+        ("gpt-4o", json!("required"), json!("required")),
+        ("gpt-4o", json!({ "name": "executeSQL" }), json!({ "type": "function", "function": { "name": "executeSQL" } })),
+        ("mistral-large-latest", json!("none"), json!("none")),
+        ("claude-3-5-sonnet-20241022", json!("auto"), json!({ "type": "auto" })),
+        ("claude-3-5-sonnet-20241022", json!("required"), json!({ "type": "any" })),
+        ("claude-3-5-sonnet-20241022", json!({ "name": "executeSQL" }), json!({ "type": "tool", "name": "executeSQL" })),
+    ];
+    for (model, tool_choice, expected) in cases {
+        let response = dry_run(model, tool_choice).await.unwrap();
+        assert_eq!(response.status(), 200);
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(body["body"]["tool_choice"], expected, "{}", model);
+    }
+
+    let response = dry_run("gpt-4o", json!({ "name": "dropTables" })).await.unwrap();
+    assert_eq!(response.status(), 400);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(
+        body["error"]["message"],
+        "toolChoice names unknown tool 'dropTables'. Available tools: executeSQL, addTransformation, createVisualization"
+    );
+    // o1 models aren't sent tools, so they can't be made to call one
+    assert_eq!(dry_run("o1-mini", json!("required")).await.unwrap().status(), 400);
+    assert_eq!(dry_run("o1-mini", json!("none")).await.unwrap().status(), 200);
+    assert!(upstream.requests().is_empty());
+}
+
+#[actix_web::test]
+async fn response_format_is_forwarded_to_openai_and_emulated_for_anthropic() {
+    let upstream = MockUpstream::start(vec![]).await;
+    let server = TestServer::start(&upstream, &[]).await;
+    let dry_run = |model: &str, response_format: serde_json::Value, content: &str| {
+        let mut request = chat_request(model);
+        request["responseFormat"] = response_format;
+        request["messages"][0]["content"] = json!(content);
+        reqwest::Client::new()
+            .post(format!("{}/sdk-chat?dryRun=true", server.base_url))
+            .json(&request)
+            .send()
+    };
+    let schema_format = json!({
+        "type": "json_schema",
+        "json_schema": { "name": "greeting", "schema": { "type": "object", "properties": { "text": { "type": "string" } } } }
+    });
+
+    let response = dry_run("gpt-4o", schema_format.clone(), "Say hello").await.unwrap();
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["body"]["response_format"], schema_format);
+
+    let response = dry_run("gpt-4o", json!({ "type": "json_object" }), "Say hello as JSON").await.unwrap();
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["body"]["response_format"], json!({ "type": "json_object" }));
+
+    // OpenAI rejects json_object when no message asks for JSON
+    let response = dry_run("gpt-4o", json!({ "type": "json_object" }), "Say hello").await.unwrap();
+    assert_eq!(response.status(), 400);
+
+    let response = dry_run("claude-3-5-sonnet-20241022", schema_format, "Say hello").await.unwrap();
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert!(body["body"].get("response_format").is_none());
+    let instruction = body["body"]["system"][0]["text"].as_str().unwrap();
+    assert!(instruction.starts_with("Respond only with a single valid JSON object"), "{}", instruction);
+    assert!(instruction.contains(r#""text":{"type":"string"}"#), "{}", instruction);
+}
+
+#[actix_web::test]
+async fn upstream_connection_failures_mid_stream_end_with_an_error_frame() {
+    let events: Vec<&str> = ANTHROPIC_TEXT.split_inclusive("\n\n").take(4).collect();
+    let chunks: Vec<&str> = OPENAI_TEXT.split_inclusive("\n\n").take(2).collect();
+    let upstream = MockUpstream::start(vec![
+        ("/v1/messages", vec![MockResponse::aborted(&events)]),
+        ("/v1/chat/completions", vec![MockResponse::aborted(&chunks)]),
+    ])
+    .await;
+    let server = TestServer::start(&upstream, &[]).await;
+
+    for model in ["claude-3-5-sonnet-20241022", "gpt-4o"] {
+        let response = server.chat(chat_request(model)).await;
+        assert_eq!(response.status(), 200);
+        let frames = without_step_frames(parse_frames(&response.text().await.unwrap()));
+
+        let prefixes: Vec<&str> = frames.iter().map(|(prefix, _)| prefix.as_str()).collect();
+        assert_eq!(prefixes[prefixes.len() - 2..], ["3", "d"], "{}: {:?}", model, frames);
+        let (_, error) = &frames[frames.len() - 2];
+        assert!(error.as_str().unwrap().starts_with("stream_error: "), "{}", error);
+        assert_eq!(frames.last().unwrap().1["finishReason"], "error");
+    }
+}
+
+#[actix_web::test]
+async fn stalled_upstream_streams_end_with_a_timeout_error_frame() {
+    // The text arrives, then the stream goes silent far longer than READ_TIMEOUT_SECS
+    let events: Vec<&str> = ANTHROPIC_TEXT.split_inclusive("\n\n").collect();
+    let stalled = [events[..4].concat(), events[4..].concat()];
+    let stalled: Vec<&str> = stalled.iter().map(String::as_str).collect();
+    let silent = MockResponse::chunked(&stalled, std::time::Duration::from_secs(30));
+    let upstream = MockUpstream::start(vec![("/v1/messages", vec![silent])]).await;
+    let server = TestServer::start(&upstream, &[("READ_TIMEOUT_SECS", "1")]).await;
+
+    let started = std::time::Instant::now();
+    let response = server.chat(chat_request("claude-3-5-sonnet-20241022")).await;
+    assert_eq!(response.status(), 200);
+    let frames = without_step_frames(parse_frames(&response.text().await.unwrap()));
+    assert!(started.elapsed() < std::time::Duration::from_secs(10), "{:?}", started.elapsed());
+
+    assert_eq!(streamed_text(&frames), "Hello");
+    let prefixes: Vec<&str> = frames.iter().map(|(prefix, _)| prefix.as_str()).collect();
+    assert_eq!(prefixes[prefixes.len() - 2..], ["3", "d"], "{:?}", frames);
+    let (_, error) = &frames[frames.len() - 2];
+    assert!(error.as_str().unwrap().starts_with("timeout_error: "), "{}", error);
+    assert_eq!(frames.last().unwrap().1["finishReason"], "error");
+}
+
+#[actix_web::test]
+async fn provider_header_overrides_model_routing() {
+    let upstream = MockUpstream::start(vec![("/v1/chat/completions", vec![MockResponse::sse(OPENAI_TEXT)])]).await;
+    let server = TestServer::start(&upstream, &[]).await;
+    let chat_with_provider = |provider: &str| {
+        reqwest::Client::new()
+            .post(format!("{}/sdk-chat", server.base_url))
+            .header("X-Provider", provider)
+            .header("X-AI-SDK-Version", "4")
+            .json(&chat_request("claude-3-5-sonnet-20241022"))
+            .send()
+    };
+
+    let response = chat_with_provider("openai").await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(streamed_text(&parse_frames(&response.text().await.unwrap())), "Hello, world");
+    let requests = upstream.requests();
+    assert_eq!(requests.len(), 1);
+    assert_eq!(requests[0].body["model"], "claude-3-5-sonnet-20241022");
+
+    let response = chat_with_provider("gemini").await.unwrap();
+    assert_eq!(response.status(), 400);
+    let body: serde_json::Value = response.json().await.unwrap();
+    let message = body["error"]["message"].as_str().unwrap();
+    assert!(message.starts_with("Unknown provider 'gemini'. Known providers: anthropic, bedrock, openai"), "{}", message);
+}
+
+#[actix_web::test]
+async fn requests_over_the_concurrency_limit_get_503_with_retry_after() {
+    let chunks: Vec<&str> = OPENAI_TEXT.split_inclusive("\n\n").collect();
+    let upstream = MockUpstream::start(vec![(
+        "/v1/chat/completions",
+        vec![MockResponse::chunked(&chunks, std::time::Duration::from_millis(200))],
+    )])
+    .await;
+    let server = TestServer::start(&upstream, &[("MAX_CONCURRENT_UPSTREAM", "1")]).await;
+
+    // The first stream holds the only slot until it has been read to the end
+    let streaming = server.chat(chat_request("gpt-4o")).await;
+    assert_eq!(streaming.status(), 200);
+
+    let rejected = server.chat(chat_request("gpt-4o")).await;
+    assert_eq!(rejected.status(), 503);
+    assert_eq!(rejected.headers()["retry-after"], "1");
+    let body: serde_json::Value = rejected.json().await.unwrap();
+    assert_eq!(body["error"]["type"], "overloaded_error");
+
+    assert_eq!(streamed_text(&parse_frames(&streaming.text().await.unwrap())), "Hello, world");
+    let response = server.chat(chat_request("gpt-4o")).await;
+    assert_eq!(response.status(), 200);
+    response.text().await.unwrap();
+}
+
+#[actix_web::test]
+async fn parallel_tool_calls_are_flushed_separately_and_can_be_disabled() {
+    let upstream = MockUpstream::start(vec![(
+        "/v1/chat/completions",
+        vec![MockResponse::sse(OPENAI_PARALLEL_TOOL_CALLS), MockResponse::sse(OPENAI_TOOL_CALL)],
+    )])
+    .await;
+    let server = TestServer::start(&upstream, &[]).await;
+    let tool_call_ids = |body: &str| -> Vec<serde_json::Value> {
+        parse_frames(body)
+            .into_iter()
+            .filter(|(prefix, _)| prefix == "9")
+            .map(|(_, call)| call["toolCallId"].clone())
+            .collect()
+    };
+
+    let response = server.chat(chat_request("gpt-4o")).await;
+    assert_eq!(tool_call_ids(&response.text().await.unwrap()), vec![json!("call_a"), json!("call_b")]);
+
+    let mut request = chat_request("gpt-4o");
+    request["parallelToolCalls"] = json!(false);
+    let response = server.chat(request).await;
+    assert_eq!(tool_call_ids(&response.text().await.unwrap()), vec![json!("call_1")]);
+
+    let requests = upstream.requests();
+    assert!(requests[0].body.get("parallel_tool_calls").is_none());
+    assert_eq!(requests[1].body["parallel_tool_calls"], false);
+}
+
+#[actix_web::test]
+async fn cohere_stream_becomes_text_and_finish_frames() {
+    // Events split mid-line across network chunks
+    let (first, rest) = COHERE_TEXT.split_at(COHERE_TEXT.find("Hello").unwrap() + 2);
+    let upstream = MockUpstream::start(vec![(
+        "/cohere/v1/chat",
+        vec![MockResponse::chunked(&[first, rest], std::time::Duration::from_millis(10))],
+    )])
+    .await;
+    let cohere_base_url = format!("{}/cohere/v1", upstream.base_url);
+    let server = TestServer::start(
+        &upstream,
+        &[("COHERE_API_KEY", "test-cohere-key"), ("COHERE_BASE_URL", &cohere_base_url)],
+    )
+    .await;
+
+    let mut request = chat_request("command-r-plus");
+    request["messages"] = json!([
+        { "role": "system", "content": "Be brief" },
+        { "role": "user", "content": "Hi" },
+        { "role": "assistant", "content": "Hi there" },
+        { "role": "user", "content": "Say hello" }
+    ]);
+    let response = server.chat(request).await;
+    assert_eq!(response.status(), 200);
+    let frames = without_step_frames(parse_frames(&response.text().await.unwrap()));
+
+    assert_eq!(
+        frames,
+        vec![
+            ("0".to_string(), json!("Hello")),
+            ("0".to_string(), json!(", world")),
+            (
+                "d".to_string(),
+                json!({ "finishReason": "stop", "usage": { "promptTokens": 21, "completionTokens": 4 } })
+            ),
+        ]
+    );
+
+    let requests = upstream.requests();
+    assert_eq!(requests[0].headers.get("authorization").map(String::as_str), Some("Bearer test-cohere-key"));
+    assert_eq!(requests[0].body["model"], "command-r-plus");
+    assert_eq!(requests[0].body["preamble"], "Be brief");
+    assert_eq!(requests[0].body["message"], "Say hello");
+    assert_eq!(
+        requests[0].body["chat_history"],
+        json!([{ "role": "USER", "message": "Hi" }, { "role": "CHATBOT", "message": "Hi there" }])
+    );
+}
+
+#[actix_web::test]
+async fn persisted_conversations_record_each_turn() {
+    let upstream = MockUpstream::start(vec![("/v1/chat/completions", vec![MockResponse::sse(OPENAI_TEXT)])]).await;
+    let db_path = std::env::temp_dir().join(format!("tell-test-{}.db", std::process::id()));
+    let _ = std::fs::remove_file(&db_path);
+    let server = TestServer::start(&upstream, &[("PERSIST", "true"), ("PERSIST_PATH", db_path.to_str().unwrap())]).await;
+
+    let mut request = chat_request("gpt-4o");
+    request["sessionId"] = json!("conversation-1");
+    let response = server.chat(request).await;
+    assert_eq!(response.status(), 200);
+    response.text().await.unwrap();
+
+    // The turn is written off the response path, so give it a moment
+    let mut conversation = server.get("/conversations/conversation-1").await;
+    for _ in 0..20 {
+        if conversation.status() == 200 {
+            break;
+        }
+        actix_web::rt::time::sleep(std::time::Duration::from_millis(50)).await;
+        conversation = server.get("/conversations/conversation-1").await;
+    }
+    assert_eq!(conversation.status(), 200);
+    let conversation: serde_json::Value = conversation.json().await.unwrap();
+    let turns = conversation["turns"].as_array().unwrap();
+    assert_eq!(turns.len(), 1);
+    assert_eq!(turns[0]["model"], "gpt-4o");
+    assert_eq!(turns[0]["messages"][0]["content"], "Say hello");
+    assert_eq!(turns[0]["response"]["text"], "Hello, world");
+    assert_eq!(turns[0]["response"]["finishReason"], "stop");
+
+    assert_eq!(server.get("/conversations/unknown").await.status(), 404);
+    drop(server);
+    let _ = std::fs::remove_file(&db_path);
+}
+
+#[actix_web::test]
+async fn requests_that_never_stream_record_no_turn() {
+    let chunks: Vec<&str> = OPENAI_TEXT.split_inclusive("\n\n").collect();
+    let upstream = MockUpstream::start(vec![(
+        "/v1/chat/completions",
+        vec![MockResponse::chunked(&chunks, std::time::Duration::from_millis(100))],
+    )])
+    .await;
+    let db_path = std::env::temp_dir().join(format!("tell-test-unrecorded-{}.db", std::process::id()));
+    let _ = std::fs::remove_file(&db_path);
+    let server = TestServer::start(
+        &upstream,
+        &[("PERSIST", "true"), ("PERSIST_PATH", db_path.to_str().unwrap()), ("MAX_CONCURRENT_UPSTREAM", "1")],
+    )
+    .await;
+
+    // The first stream holds the only upstream slot, so the second request gets a 503
+    let mut request = chat_request("gpt-4o");
+    request["sessionId"] = json!("streamed");
+    let streaming = server.chat(request).await;
+    assert_eq!(streaming.status(), 200);
+
+    let mut request = chat_request("gpt-4o");
+    request["sessionId"] = json!("never-streamed");
+    let dry_run = reqwest::Client::new()
+        .post(format!("{}/sdk-chat?dryRun=true", server.base_url))
+        .json(&request)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(dry_run.status(), 200);
+    assert_eq!(server.chat(request).await.status(), 503);
+    streaming.text().await.unwrap();
+
+    // Once the streamed turn is written, the other two would have been too
+    let mut conversation = server.get("/conversations/streamed").await;
+    for _ in 0..20 {
+        if conversation.status() == 200 {
+            break;
+        }
+        actix_web::rt::time::sleep(std::time::Duration::from_millis(50)).await;
+        conversation = server.get("/conversations/streamed").await;
+    }
+    assert_eq!(conversation.status(), 200);
+    assert_eq!(server.get("/conversations/never-streamed").await.status(), 404);
+    drop(server);
+    let _ = std::fs::remove_file(&db_path);
+}
+
+#[actix_web::test]
+async fn an_unavailable_conversation_store_degrades_to_no_persistence() {
+    let upstream = MockUpstream::start(vec![("/v1/chat/completions", vec![MockResponse::sse(OPENAI_TEXT)])]).await;
+    let server = TestServer::start(
+        &upstream,
+        &[("PERSIST", "true"), ("PERSIST_PATH", "/nonexistent-dir/tell.db")],
+    )
+    .await;
+
+    let mut request = chat_request("gpt-4o");
+    request["sessionId"] = json!("conversation-2");
+    let response = server.chat(request).await;
+    assert_eq!(response.status(), 200);
+    assert_eq!(streamed_text(&parse_frames(&response.text().await.unwrap())), "Hello, world");
+    assert_eq!(server.get("/conversations/conversation-2").await.status(), 404);
+}
+
+#[actix_web::test]
+async fn content_filter_stops_keep_the_partial_text_and_say_why() {
+    let openai_filtered = OPENAI_TEXT.replace(r#""finish_reason":"stop""#, r#""finish_reason":"content_filter""#);
+    let anthropic_refusal = ANTHROPIC_TEXT.replace(r#""stop_reason":"end_turn""#, r#""stop_reason":"refusal""#);
+    let upstream = MockUpstream::start(vec![
+        ("/v1/chat/completions", vec![MockResponse::sse(&openai_filtered)]),
+        ("/v1/messages", vec![MockResponse::sse(&anthropic_refusal)]),
+    ])
+    .await;
+    let server = TestServer::start(&upstream, &[]).await;
+
+    for model in ["gpt-4o", "claude-3-5-sonnet-20241022"] {
+        let response = server.chat(chat_request(model)).await;
+        assert_eq!(response.status(), 200);
+        let frames = without_step_frames(parse_frames(&response.text().await.unwrap()));
+
+        assert_eq!(streamed_text(&frames), "Hello, world", "{}", model);
+        let tail: Vec<_> = frames[frames.len() - 2..].iter().map(|(prefix, _)| prefix.as_str()).collect();
+        assert_eq!(tail, ["3", "d"], "{}", model);
+        assert!(frames[frames.len() - 2].1.as_str().unwrap().starts_with("content_filter:"));
+        assert_eq!(frames[frames.len() - 1].1["finishReason"], "content-filter", "{}", model);
+    }
+}
+
+#[actix_web::test]
+async fn warmup_lists_models_of_each_configured_provider() {
+    let upstream = MockUpstream::start(vec![("/v1/models", vec![MockResponse::json(200, r#"{"data":[]}"#)])]).await;
+    let server = TestServer::start(&upstream, &[("WARMUP", "true")]).await;
+
+    // Warmup runs in the background; give it a moment
+    let mut warmups = Vec::new();
+    for _ in 0..50 {
+        warmups = upstream.requests().into_iter().filter(|r| r.path == "/v1/models").collect();
+        if warmups.len() == 2 {
+            break;
+        }
+        actix_web::rt::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    assert_eq!(warmups.len(), 2);
+    assert!(warmups.iter().any(|r| r.headers.get("x-api-key").map(String::as_str) == Some("test-anthropic-key")));
+    assert!(warmups
+        .iter()
+        .any(|r| r.headers.get("authorization").map(String::as_str) == Some("Bearer test-openai-key")));
+    drop(server);
+}
+
+#[actix_web::test]
+async fn v4_clients_get_the_data_stream() {
+    let upstream = MockUpstream::start(vec![("/v1/chat/completions", vec![MockResponse::sse(OPENAI_TEXT)])]).await;
+    let server = TestServer::start(&upstream, &[]).await;
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/sdk-chat", server.base_url))
+        .header("X-AI-SDK-Version", "4.3.16")
+        .json(&chat_request("gpt-4o"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()["content-type"], "text/event-stream");
+    let message_id = response.headers()["x-message-id"].to_str().unwrap().to_string();
+    let usage = r#"{"completionTokens":0,"promptTokens":0}"#;
+    assert_eq!(
+        response.text().await.unwrap(),
+        [
+            format!(r#"f:{{"messageId":"{}"}}"#, message_id),
+            r#"0:"Hello""#.to_string(),
+            r#"0:", world""#.to_string(),
+            format!(r#"e:{{"finishReason":"stop","isContinued":false,"usage":{}}}"#, usage),
+            format!(r#"d:{{"finishReason":"stop","usage":{}}}"#, usage),
+            String::new(),
+        ]
+        .join("\n")
+    );
+}
+
+#[actix_web::test]
+async fn v5_clients_get_the_ui_message_stream() {
+    let upstream =
+        MockUpstream::start(vec![("/v1/messages", vec![MockResponse::sse(ANTHROPIC_TEXT_AND_TOOL_USE); 2])]).await;
+    let server = TestServer::start(&upstream, &[]).await;
+    let client = reqwest::Client::new();
+
+    // The body field wins over the header
+    let mut request = chat_request("claude-3-5-sonnet-20241022");
+    request["aiSdkVersion"] = json!(5);
+    let response = client
+        .post(format!("{}/sdk-chat", server.base_url))
+        .header("X-AI-SDK-Version", "4")
+        .json(&request)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()["content-type"], "text/event-stream");
+    assert_eq!(response.headers()["x-vercel-ai-ui-message-stream"], "v1");
+    let message_id = response.headers()["x-message-id"].to_str().unwrap().to_string();
+    let events: Vec<String> = response
+        .text()
+        .await
+        .unwrap()
+        .split_terminator("\n\n")
+        .map(|event| event.strip_prefix("data: ").expect("an SSE data event").to_string())
+        .collect();
+    let (done, chunks) = events.split_last().unwrap();
+    assert_eq!(done, "[DONE]");
+    let chunks: Vec<serde_json::Value> = chunks.iter().map(|chunk| serde_json::from_str(chunk).unwrap()).collect();
+    let args = json!({ "sql": "SELECT 1" });
+    let usage = json!({ "promptTokens": 20, "completionTokens": 15 });
+    assert_eq!(
+        chunks,
+        [
+            json!({ "type": "start", "messageId": message_id }),
+            json!({ "type": "start-step" }),
+            json!({ "type": "text-start", "id": "text-1" }),
+            json!({ "type": "text-delta", "id": "text-1", "delta": "Let me " }),
+            json!({ "type": "text-delta", "id": "text-1", "delta": "check." }),
+            json!({ "type": "text-end", "id": "text-1" }),
+            json!({ "type": "tool-input-available", "toolCallId": "toolu_1", "toolName": "executeSQL", "input": args }),
+            json!({ "type": "finish-step" }),
+            json!({ "type": "finish", "messageMetadata": { "finishReason": "tool-calls", "usage": usage } }),
+        ]
+    );
+
+    // Clients that don't say get v5
+    let response = client
+        .post(format!("{}/sdk-chat", server.base_url))
+        .json(&chat_request("claude-3-5-sonnet-20241022"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.headers()["x-vercel-ai-ui-message-stream"], "v1");
+    assert!(response.text().await.unwrap().starts_with(r#"data: {"messageId""#));
+
+    let response = client
+        .post(format!("{}/sdk-chat", server.base_url))
+        .header("X-AI-SDK-Version", "3")
+        .json(&chat_request("gpt-4o"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 400);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["error"]["message"], "Unsupported AI SDK version '3'. Supported versions: 4, 5");
+}
+
+#[actix_web::test]
+async fn config_file_overrides_merge_under_client_fields() {
+    let config_path = std::env::temp_dir().join(format!("tell-config-{}.toml", std::process::id()));
+    std::fs::write(
+        &config_path,
+        r#"
+[providers.openai]
+body = { temperature = 0.2, user = "tell" }
+headers = { "X-Deployment" = "staging" }
+
+[models."gpt-4o"]
+body = { temperature = 0.1 }
+"#,
+    )
+    .unwrap();
+    let upstream = MockUpstream::start(vec![("/v1/chat/completions", vec![MockResponse::sse(OPENAI_TEXT)])]).await;
+    let server = TestServer::start(&upstream, &[("TELL_CONFIG", config_path.to_str().unwrap())]).await;
+
+    assert_eq!(server.chat(chat_request("gpt-4o")).await.status(), 200);
+    let mut request = chat_request("gpt-4o-mini");
+    request["temperature"] = json!(0.9);
+    assert_eq!(server.chat(request).await.status(), 200);
+
+    let requests = upstream.requests();
+    // The model's overrides win over the provider's
+    assert_eq!(requests[0].body["temperature"], json!(0.1));
+    assert_eq!(requests[0].body["user"], "tell");
+    assert_eq!(requests[0].headers.get("x-deployment").map(String::as_str), Some("staging"));
+    // A temperature the client sent is kept
+    assert_eq!(requests[1].body["temperature"].as_f64().map(|t| (t * 10.0).round()), Some(9.0));
+    assert_eq!(requests[1].body["user"], "tell");
+    let _ = std::fs::remove_file(&config_path);
+}
+
+#[actix_web::test]
+async fn admin_reload_swaps_in_a_valid_config_and_rejects_an_invalid_one() {
+    let config_path = std::env::temp_dir().join(format!("tell-reload-config-{}.json", std::process::id()));
+    std::fs::write(&config_path, r#"{ "models": { "gpt-4o": { "body": { "user": "v1" } } } }"#).unwrap();
+    let upstream = MockUpstream::start(vec![("/v1/chat/completions", vec![MockResponse::sse(OPENAI_TEXT)])]).await;
+    let server = TestServer::start(
+        &upstream,
+        &[("TELL_CONFIG", config_path.to_str().unwrap()), ("ADMIN_TOKEN", "admin-secret")],
+    )
+    .await;
+    let reload = |token: &'static str| {
+        reqwest::Client::new()
+            .post(format!("{}/admin/reload", server.base_url))
+            .bearer_auth(token)
+            .send()
+    };
+
+    assert_eq!(reload("wrong-token").await.unwrap().status(), 401);
+
+    std::fs::write(
+        &config_path,
+        r#"{ "models": { "gpt-4o": { "body": { "user": "v2" } } }, "providers": { "openai": { "headers": { "x-deployment": "b" } } } }"#,
+    )
+    .unwrap();
+    let response = reload("admin-secret").await.unwrap();
+    assert_eq!(response.status(), 200);
+    let changes = response.json::<serde_json::Value>().await.unwrap()["changes"].clone();
+    assert_eq!(changes["config"]["models"], json!({ "added": [], "removed": [], "changed": ["gpt-4o"] }));
+    assert_eq!(changes["config"]["providers"], json!({ "added": ["openai"], "removed": [], "changed": [] }));
+    assert_eq!(changes["tools"], json!({ "added": [], "removed": [], "changed": [] }));
+    assert_eq!(server.chat(chat_request("gpt-4o")).await.status(), 200);
+
+    // An invalid file is rejected and the last good config stays in place
+    std::fs::write(&config_path, r#"{ "providers": { "opnai": {} } }"#).unwrap();
+    assert_eq!(reload("admin-secret").await.unwrap().status(), 400);
+    assert_eq!(server.chat(chat_request("gpt-4o")).await.status(), 200);
+
+    let requests = upstream.requests();
+    assert_eq!(requests[0].body["user"], "v2");
+    assert_eq!(requests[0].headers.get("x-deployment").map(String::as_str), Some("b"));
+    assert_eq!(requests[1].body["user"], "v2");
+    let _ = std::fs::remove_file(&config_path);
+}
+
+#[actix_web::test]
+async fn prompt_templates_are_filled_in_ahead_of_the_messages_and_reloadable() {
+    let templates_dir = std::env::temp_dir().join(format!("tell-templates-{}", std::process::id()));
+    std::fs::create_dir_all(&templates_dir).unwrap();
+    std::fs::write(
+        templates_dir.join("support_agent.toml"),
+        r#"
+system = "You support {{ product }} customers. Answer in {{language}}."
+defaults = { language = "English" }
+
+[[messages]]
+role = "assistant"
+content = "Hi! How can I help with {{product}}?"
+"#,
+    )
+    .unwrap();
+    let upstream = MockUpstream::start(vec![("/v1/chat/completions", vec![MockResponse::sse(OPENAI_TEXT)])]).await;
+    let server = TestServer::start(
+        &upstream,
+        &[("TEMPLATES_DIR", templates_dir.to_str().unwrap()), ("ADMIN_TOKEN", "admin-secret")],
+    )
+    .await;
+    let chat_with_template = |template: &str, variables: serde_json::Value| {
+        let mut request = chat_request("gpt-4o");
+        request["template"] = json!(template);
+        request["variables"] = variables;
+        server.chat(request)
+    };
+
+    let response = chat_with_template("support_agent", json!({ "product": "Tell" })).await;
+    assert_eq!(response.status(), 200);
+    let messages = upstream.requests()[0].body["messages"].clone();
+    assert_eq!(
+        messages,
+        json!([
+            { "role": "system", "content": "You support Tell customers. Answer in English." },
+            { "role": "assistant", "content": "Hi! How can I help with Tell?" },
+            { "role": "user", "content": "Say hello" },
+        ])
+    );
+
+    let response = chat_with_template("support_agent", json!({ "language": "French" })).await;
+    assert_eq!(response.status(), 400);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(
+        body["errors"],
+        json!([{ "field": "variables.product", "message": "is required by template 'support_agent'" }])
+    );
+    let response = chat_with_template("sales_agent", json!({})).await;
+    assert_eq!(response.status(), 400);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["errors"][0]["field"], "template");
+
+    // New templates are picked up by POST /admin/reload
+    std::fs::write(templates_dir.join("sales_agent.json"), r#"{ "system": "Sell {{product}}." }"#).unwrap();
+    let response = reqwest::Client::new()
+        .post(format!("{}/admin/reload", server.base_url))
+        .bearer_auth("admin-secret")
+        .send()
+        .await
+        .unwrap();
+    let changes = response.json::<serde_json::Value>().await.unwrap()["changes"].clone();
+    assert_eq!(changes["templates"], json!({ "added": ["sales_agent"], "removed": [], "changed": [] }));
+    assert_eq!(chat_with_template("sales_agent", json!({ "product": "Tell" })).await.status(), 200);
+    assert_eq!(upstream.requests()[1].body["messages"][0]["content"], "Sell Tell.");
+    let _ = std::fs::remove_dir_all(&templates_dir);
+}
+
+#[actix_web::test]
+async fn token_counts_include_the_template() {
+    let templates_dir = std::env::temp_dir().join(format!("tell-count-templates-{}", std::process::id()));
+    std::fs::create_dir_all(&templates_dir).unwrap();
+    std::fs::write(
+        templates_dir.join("support_agent.json"),
+        r#"{ "system": "You support {{product}} customers. Be brief, friendly and accurate." }"#,
+    )
+    .unwrap();
+    let upstream = MockUpstream::start(vec![]).await;
+    let server = TestServer::start(&upstream, &[("TEMPLATES_DIR", templates_dir.to_str().unwrap())]).await;
+    let url = &format!("{}/count-tokens", server.base_url);
+    let count_tokens = |request: serde_json::Value| async move {
+        let response = reqwest::Client::new()
+            .post(url)
+            .json(&request)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        response.json::<serde_json::Value>().await.unwrap()["promptTokens"].as_u64().unwrap()
+    };
+
+    let mut request = chat_request("gpt-4o");
+    let without_template = count_tokens(request.clone()).await;
+    request["template"] = json!("support_agent");
+    request["variables"] = json!({ "product": "Tell" });
+    let with_template = count_tokens(request).await;
+    assert!(with_template > without_template, "{} <= {}", with_template, without_template);
+    assert!(upstream.requests().is_empty());
+    let _ = std::fs::remove_dir_all(&templates_dir);
+}
+
+#[actix_web::test]
+async fn mock_models_stream_scripted_scenarios_without_calling_a_provider() {
+    let script_path = std::env::temp_dir().join(format!("tell-mock-script-{}.json", std::process::id()));
+    std::fs::write(
+        &script_path,
+        r#"{ "scenarios": {
+            "text": { "delayMs": 0, "events": [{ "text": "Hello" }, { "text": " mock" }] },
+            "chart": { "delayMs": 0, "events": [
+                { "text": "Plotting" },
+                { "toolCall": { "name": "executeSQL", "args": { "sql": "SELECT 1" } } }
+            ] }
+        } }"#,
+    )
+    .unwrap();
+    let upstream = MockUpstream::start(vec![]).await;
+    let server = TestServer::start(
+        &upstream,
+        &[("MOCK_SCRIPT", script_path.to_str().unwrap()), ("MOCK_PROVIDER", "true")],
+    )
+    .await;
+
+    let frames = parse_frames(&server.chat(chat_request("mock/chart")).await.text().await.unwrap());
+    assert_eq!(streamed_text(&frames), "Plotting");
+    let tool_call = frames.iter().find(|(prefix, _)| prefix == "9").expect("tool call frame");
+    assert_eq!(tool_call.1["toolName"], "executeSQL");
+    assert_eq!(tool_call.1["args"], json!({ "sql": "SELECT 1" }));
+    assert_eq!(frames.last().unwrap().1["finishReason"], "tool-calls");
+
+    // MOCK_PROVIDER answers real model names with the default scenario
+    let frames = parse_frames(&server.chat(chat_request("gpt-4o")).await.text().await.unwrap());
+    assert_eq!(streamed_text(&frames), "Hello mock");
+
+    let response = server.chat(chat_request("mock/nope")).await;
+    assert_eq!(response.status(), 400);
+    assert!(response.text().await.unwrap().contains("chart, text"));
+    assert!(upstream.requests().is_empty());
+    let _ = std::fs::remove_file(&script_path);
+}
+
+#[actix_web::test]
+async fn parameter_combinations_the_provider_rejects_are_a_400() {
+    let config_path = std::env::temp_dir().join(format!("tell-constraints-{}.toml", std::process::id()));
+    std::fs::write(
+        &config_path,
+        r#"
+[models."o3-mini"]
+body = { temperature = 0.5, max_tokens = 100 }
+
+[models."claude-sonnet-4-5-20250929"]
+body = { temperature = 0.5, top_p = 0.9 }
+
+[models."claude-3-5-haiku-20241022"]
+body = { temperature = 1.5 }
+
+[models."gpt-4o-mini"]
+body = { top_logprobs = 3 }
+"#,
+    )
+    .unwrap();
+    let upstream = MockUpstream::start(vec![]).await;
+    let server = TestServer::start(&upstream, &[("TELL_CONFIG", config_path.to_str().unwrap())]).await;
+    let dry_run = |model: &str| {
+        let url = format!("{}/sdk-chat?dryRun=true", server.base_url);
+        reqwest::Client::new().post(url).json(&chat_request(model)).send()
+    };
+    let error = |response: reqwest::Response| async move {
+        assert_eq!(response.status(), 400);
+        let body: serde_json::Value = response.json().await.unwrap();
+        body["error"]["message"].as_str().unwrap().to_string()
+    };
+
+    let message = error(dry_run("o3-mini").await.unwrap()).await;
+    assert!(message.contains("temperature isn't accepted"), "{}", message);
+    assert!(message.contains("max_tokens isn't accepted; use max_completion_tokens instead"), "{}", message);
+    let message = error(dry_run("claude-sonnet-4-5-20250929").await.unwrap()).await;
+    assert!(message.contains("temperature and top_p can't be set together"), "{}", message);
+    let message = error(dry_run("claude-3-5-haiku-20241022").await.unwrap()).await;
+    assert!(message.contains("temperature must be between 0 and 1, not 1.5"), "{}", message);
+    let message = error(dry_run("gpt-4o-mini").await.unwrap()).await;
+    assert!(message.contains("top_logprobs is only accepted with logprobs"), "{}", message);
+
+    // The same parameters without the conflicting ones go through
+    assert_eq!(dry_run("gpt-4o").await.unwrap().status(), 200);
+    assert_eq!(dry_run("claude-3-5-sonnet-20241022").await.unwrap().status(), 200);
+    assert!(upstream.requests().is_empty());
+    let _ = std::fs::remove_file(&config_path);
+}
+
+#[test]
+fn an_invalid_config_file_stops_startup() {
+    let config_path = std::env::temp_dir().join(format!("tell-bad-config-{}.json", std::process::id()));
+    std::fs::write(&config_path, r#"{ "providers": { "opnai": { "body": { "user": "tell" } } } }"#).unwrap();
+
+    let status = std::process::Command::new(env!("CARGO_BIN_EXE_backend"))
+        .current_dir(std::env::temp_dir())
+        .env_clear()
+        .env("BIND_ADDR", "127.0.0.1:0")
+        .env("TELL_CONFIG", &config_path)
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .status()
+        .expect("run server binary");
+    assert!(!status.success());
+    let _ = std::fs::remove_file(&config_path);
+}
+
+#[actix_web::test]
+async fn a_tools_file_replaces_the_built_in_tool_definitions() {
+    let tools_path = std::env::temp_dir().join(format!("tell-tools-{}.toml", std::process::id()));
+    std::fs::write(
+        &tools_path,
+        r#"
+[[tools]]
+name = "executeSQL"
+description = "Führt eine SQL-Abfrage aus."
+
+[tools.input_schema]
+type = "object"
+required = ["sql"]
+properties = { sql = { type = "string", description = "Die Abfrage" } }
+"#,
+    )
+    .unwrap();
+    let upstream = MockUpstream::start(vec![("/v1/chat/completions", vec![MockResponse::sse(OPENAI_TEXT)])]).await;
+    let server = TestServer::start(&upstream, &[("TOOLS_FILE", tools_path.to_str().unwrap())]).await;
+
+    assert_eq!(server.chat(chat_request("gpt-4o")).await.status(), 200);
+    let tools = upstream.requests()[0].body["tools"].clone();
+    assert_eq!(tools.as_array().map(Vec::len), Some(1));
+    assert_eq!(tools[0]["function"]["name"], "executeSQL");
+    assert_eq!(tools[0]["function"]["description"], "Führt eine SQL-Abfrage aus.");
+    assert_eq!(tools[0]["function"]["parameters"]["required"], json!(["sql"]));
+    let _ = std::fs::remove_file(&tools_path);
+}
+
+#[actix_web::test]
+async fn anthropic_version_comes_from_the_environment() {
+    let upstream = MockUpstream::start(vec![("/v1/messages", vec![MockResponse::sse(ANTHROPIC_TEXT)])]).await;
+    let server = TestServer::start(&upstream, &[("ANTHROPIC_VERSION", "2024-10-22")]).await;
+
+    server.chat(chat_request("claude-3-5-sonnet-20241022")).await.text().await.unwrap();
+    assert_eq!(upstream.requests()[0].headers["anthropic-version"], "2024-10-22");
+
+    let status = std::process::Command::new(env!("CARGO_BIN_EXE_backend"))
+        .current_dir(std::env::temp_dir())
+        .env_clear()
+        .env("BIND_ADDR", "127.0.0.1:0")
+        .env("ANTHROPIC_VERSION", "latest")
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .status()
+        .expect("run server binary");
+    assert!(!status.success());
+}
+
+#[test]
+fn an_invalid_tools_file_stops_startup() {
+    let tools_path = std::env::temp_dir().join(format!("tell-bad-tools-{}.json", std::process::id()));
+    // `sql` is required but isn't one of the properties
+    std::fs::write(
+        &tools_path,
+        r#"{ "tools": [{ "name": "executeSQL", "description": "Run SQL", "input_schema": { "type": "object", "properties": {}, "required": ["sql"] } }] }"#,
+    )
+    .unwrap();
+
+    let status = std::process::Command::new(env!("CARGO_BIN_EXE_backend"))
+        .current_dir(std::env::temp_dir())
+        .env_clear()
+        .env("BIND_ADDR", "127.0.0.1:0")
+        .env("TOOLS_FILE", &tools_path)
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .status()
+        .expect("run server binary");
+    assert!(!status.success());
+    let _ = std::fs::remove_file(&tools_path);
+}
+
+#[actix_web::test]
+async fn anthropic_prefill_is_streamed_ahead_of_the_continuation() {
+    let upstream = MockUpstream::start(vec![("/v1/messages", vec![MockResponse::sse(ANTHROPIC_TEXT)])]).await;
+    let server = TestServer::start(&upstream, &[]).await;
+
+    let request = json!({
+        "model": "claude-3-5-sonnet-20241022",
+        "messages": [
+            { "role": "user", "content": "Say hello" },
+            { "role": "assistant", "content": "Sure: " }
+        ]
+    });
+    let response = server.chat(request).await;
+    assert_eq!(response.status(), 200);
+    let frames = parse_frames(&response.text().await.unwrap());
+
+    // The prefill is forwarded as the last message and comes back as the first text frame
+    let messages = upstream.requests()[0].body["messages"].clone();
+    assert_eq!(messages.as_array().and_then(|m| m.last()).map(|m| m["role"].clone()), Some(json!("assistant")));
+    let first_text = frames.iter().find(|(prefix, _)| prefix == "0").map(|(_, text)| text.clone());
+    assert_eq!(first_text, Some(json!("Sure: ")));
+    assert!(streamed_text(&frames).starts_with("Sure: ") && streamed_text(&frames).len() > "Sure: ".len());
+}
+
+const ANTHROPIC_OVERLOADED: &str = r#"{"type":"error","error":{"type":"overloaded_error","message":"Overloaded"}}"#;
+
+#[actix_web::test]
+async fn cited_urls_are_streamed_once_as_source_frames() {
+    let citation = r#"{"type":"web_search_result_location","url":"https://example.com/a","title":"Example A","cited_text":"Hello"}"#;
+    let citation_delta = format!(
+        "event: content_block_delta\ndata: {{\"type\":\"content_block_delta\",\"index\":0,\"delta\":{{\"type\":\"citations_delta\",\"citation\":{}}}}}\n\n",
+        citation
+    );
+    let anthropic = ANTHROPIC_TEXT.replacen(
+        "event: content_block_delta",
+        &format!("{}{}event: content_block_delta", citation_delta, citation_delta),
+        1,
+    );
+    let openai = OPENAI_TEXT.replace(
+        r#"{"content":", world"}"#,
+        r#"{"content":", world","annotations":[{"type":"url_citation","url_citation":{"url":"https://example.com/b","title":"Example B","start_index":0,"end_index":7}}]}"#,
+    );
+    let upstream = MockUpstream::start(vec![
+        ("/v1/messages", vec![MockResponse::sse(&anthropic)]),
+        ("/v1/chat/completions", vec![MockResponse::sse(&openai)]),
+    ])
+    .await;
+    let server = TestServer::start(&upstream, &[]).await;
+
+    for (model, url, title) in [
+        ("claude-3-5-sonnet-20241022", "https://example.com/a", "Example A"),
+        ("gpt-4o", "https://example.com/b", "Example B"),
+    ] {
+        let response = server.chat(chat_request(model)).await;
+        let frames = without_step_frames(parse_frames(&response.text().await.unwrap()));
+        let sources: Vec<_> = frames.iter().filter(|(prefix, _)| prefix == "h").map(|(_, source)| source).collect();
+        assert_eq!(sources.len(), 1, "{}", model);
+        assert_eq!(sources[0]["sourceType"], "url");
+        assert_eq!(sources[0]["url"], url);
+        assert_eq!(sources[0]["title"], title);
+        assert!(sources[0]["id"].as_str().is_some_and(|id| id.starts_with("src-")));
+        assert_eq!(streamed_text(&frames), "Hello, world", "{}", model);
+    }
+}
+
+#[actix_web::test]
+async fn overloaded_anthropic_is_retried_until_it_answers() {
+    let upstream = MockUpstream::start(vec![(
+        "/v1/messages",
+        vec![
+            MockResponse::json(529, ANTHROPIC_OVERLOADED),
+            MockResponse::json(529, ANTHROPIC_OVERLOADED),
+            MockResponse::sse(ANTHROPIC_TEXT),
+        ],
+    )])
+    .await;
+    let server = TestServer::start(&upstream, &[("UPSTREAM_RETRY_BASE_MS", "10")]).await;
+
+    let response = server.chat(chat_request("claude-3-5-sonnet-20241022")).await;
+    assert_eq!(response.status(), 200);
+    assert_eq!(streamed_text(&parse_frames(&response.text().await.unwrap())), "Hello, world");
+    assert_eq!(upstream.requests().len(), 3);
+}
+
+#[actix_web::test]
+async fn still_overloaded_after_retries_ends_the_stream_with_an_overloaded_error() {
+    let upstream =
+        MockUpstream::start(vec![("/v1/messages", vec![MockResponse::json(529, ANTHROPIC_OVERLOADED)])]).await;
+    let server = TestServer::start(&upstream, &[("UPSTREAM_RETRIES", "1"), ("UPSTREAM_RETRY_BASE_MS", "10")]).await;
+
+    let response = server.chat(chat_request("claude-3-5-sonnet-20241022")).await;
+    assert_eq!(response.status(), 200);
+    let frames = without_step_frames(parse_frames(&response.text().await.unwrap()));
+    assert_eq!(
+        frames,
+        vec![
+            (
+                "3".to_string(),
+                json!("overloaded_error: The Anthropic API is overloaded right now. Try again shortly.")
+            ),
+            (
+                "d".to_string(),
+                json!({ "finishReason": "error", "usage": { "promptTokens": 0, "completionTokens": 0 } })
+            ),
+        ]
+    );
+    assert_eq!(upstream.requests().len(), 2);
+}
+
+#[actix_web::test]
+async fn cancel_ends_an_in_flight_stream_with_a_stop_finish() {
+    // The rest of the response would only arrive after the test gives up on it
+    let split = OPENAI_TEXT[..OPENAI_TEXT.find(", world").unwrap()].rfind("data: ").unwrap();
+    let (first, rest) = OPENAI_TEXT.split_at(split);
+    let upstream = MockUpstream::start(vec![(
+        "/v1/chat/completions",
+        vec![MockResponse::chunked(&[first, rest], std::time::Duration::from_secs(30))],
+    )])
+    .await;
+    let server = TestServer::start(&upstream, &[]).await;
+    let client = reqwest::Client::new();
+
+    let response = client
+        .post(format!("{}/sdk-chat", server.base_url))
+        .header("X-Request-Id", "req-cancel-1")
+        .header("X-AI-SDK-Version", "4")
+        .json(&chat_request("gpt-4o"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()["x-request-id"], "req-cancel-1");
+
+    let cancel = client.post(format!("{}/cancel/req-cancel-1", server.base_url)).send().await.unwrap();
+    assert_eq!(cancel.status(), 200);
+
+    let body = actix_web::rt::time::timeout(std::time::Duration::from_secs(5), response.text())
+        .await
+        .expect("stream ends after cancel")
+        .unwrap();
+    let frames = without_step_frames(parse_frames(&body));
+    assert_eq!(streamed_text(&frames), "Hello");
+    let (prefix, finish) = frames.last().unwrap();
+    assert_eq!(prefix, "d");
+    assert_eq!(finish["finishReason"], "stop");
+
+    // Once the stream is over there's nothing left to cancel
+    let cancel = client.post(format!("{}/cancel/req-cancel-1", server.base_url)).send().await.unwrap();
+    assert_eq!(cancel.status(), 404);
+}
+
+#[actix_web::test]
+async fn a_client_deadline_ends_a_slow_stream_with_a_timeout_error() {
+    let upstream = MockUpstream::start(vec![(
+        "/v1/chat/completions",
+        vec![MockResponse::sse(OPENAI_TEXT).delayed(std::time::Duration::from_secs(30))],
+    )])
+    .await;
+    let server = TestServer::start(&upstream, &[("MAX_CLIENT_TIMEOUT_MS", "300")]).await;
+    let client = reqwest::Client::new();
+
+    // Over the maximum, so clamped to 300ms
+    let started = std::time::Instant::now();
+    let response = client
+        .post(format!("{}/sdk-chat", server.base_url))
+        .header("X-Timeout-Ms", "60000")
+        .header("X-AI-SDK-Version", "4")
+        .json(&chat_request("gpt-4o"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let frames = without_step_frames(parse_frames(&response.text().await.unwrap()));
+    assert!(started.elapsed() < std::time::Duration::from_secs(5));
+    assert_eq!(frames.len(), 2);
+    assert_eq!(frames[0].0, "3");
+    assert!(frames[0].1.as_str().is_some_and(|error| error.starts_with("timeout_error:")));
+    assert_eq!(frames[1].1["finishReason"], "error");
+
+    let mut request = chat_request("gpt-4o");
+    request["timeoutMs"] = json!(0);
+    assert_eq!(server.chat(request).await.status(), 400);
+}
+
+#[actix_web::test]
+async fn a_failed_primary_falls_back_before_streaming() {
+    let upstream = MockUpstream::start(vec![
+        ("/v1/messages", vec![MockResponse::json(529, ANTHROPIC_OVERLOADED)]),
+        ("/v1/chat/completions", vec![MockResponse::sse(OPENAI_TEXT)]),
+    ])
+    .await;
+    let server = TestServer::start(&upstream, &[("UPSTREAM_RETRIES", "0")]).await;
+
+    let mut request = chat_request("claude-3-5-sonnet-20241022");
+    request["fallbackModels"] = json!(["not-a-model", "gpt-4o"]);
+    let response = server.chat(request).await;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()["x-served-model"], "gpt-4o");
+    let frames = without_step_frames(parse_frames(&response.text().await.unwrap()));
+    assert_eq!(streamed_text(&frames), "Hello, world");
+    assert_eq!(frames.last().unwrap().1["finishReason"], "stop");
+
+    let paths: Vec<String> = upstream.requests().into_iter().map(|r| r.path).collect();
+    assert_eq!(paths, ["/v1/messages", "/v1/chat/completions"]);
+    assert_eq!(upstream.requests()[1].body["model"], "gpt-4o");
+
+    // A primary that answers is used as is
+    let mut request = chat_request("gpt-4o");
+    request["fallbackModels"] = json!(["claude-3-5-sonnet-20241022"]);
+    let response = server.chat(request).await;
+    assert_eq!(response.headers()["x-served-model"], "gpt-4o");
+    assert_eq!(upstream.requests().len(), 3);
+}
+
+#[actix_web::test]
+async fn compare_streams_every_model_tagged_and_isolates_failures() {
+    let upstream = MockUpstream::start(vec![
+        ("/v1/messages", vec![MockResponse::sse(ANTHROPIC_TEXT)]),
+        ("/v1/chat/completions", vec![MockResponse::sse(OPENAI_TEXT)]),
+    ])
+    .await;
+    let server = TestServer::start(&upstream, &[]).await;
+
+    let mut request = chat_request("gpt-4o");
+    request["models"] = json!(["claude-3-5-sonnet-20241022", "gpt-4o", "unknown-model"]);
+    let response = reqwest::Client::new()
+        .post(format!("{}/compare", server.base_url))
+        .json(&request)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()["content-type"], "text/event-stream");
+
+    // Regroup the events per model; each model's frames are a complete stream of their own
+    let mut by_model: std::collections::HashMap<String, String> = std::collections::HashMap::new();
+    for event in response.text().await.unwrap().split("\n\n").filter(|e| !e.is_empty()) {
+        let (name, data) = event.split_once('\n').unwrap();
+        let model = name.strip_prefix("event: ").unwrap();
+        let frame = data.strip_prefix("data: ").unwrap();
+        by_model.entry(model.to_string()).or_default().push_str(&format!("{}\n", frame));
+    }
+    assert_eq!(by_model.len(), 3);
+
+    let claude = parse_frames(&by_model["claude-3-5-sonnet-20241022"]);
+    assert_eq!(streamed_text(&claude), "Hello, world");
+    assert_eq!(claude.last().unwrap().1["finishReason"], "stop");
+    let gpt = parse_frames(&by_model["gpt-4o"]);
+    assert_eq!(gpt.last().unwrap().1["finishReason"], "stop");
+    assert!(!streamed_text(&gpt).is_empty());
+
+    let failed = parse_frames(&by_model["unknown-model"]);
+    assert_eq!(failed[0].0, "3");
+    assert_eq!(failed.last().unwrap().1["finishReason"], "error");
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/compare", server.base_url))
+        .json(&json!({ "messages": [], "models": ["gpt-4o"] }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 400);
+}
+
+#[actix_web::test]
+async fn usage_totals_tokens_per_model_and_session() {
+    let upstream = MockUpstream::start(vec![
+        ("/v1/messages", vec![MockResponse::sse(ANTHROPIC_TEXT)]),
+        ("/v1/chat/completions", vec![MockResponse::sse(OPENAI_TEXT)]),
+    ])
+    .await;
+    let server = TestServer::start(&upstream, &[]).await;
+
+    for session in ["s1", "s2"] {
+        let mut request = chat_request("claude-3-5-sonnet-20241022");
+        request["sessionId"] = json!(session);
+        server.chat(request).await.text().await.unwrap();
+    }
+    server.chat(chat_request("gpt-4o")).await.text().await.unwrap();
+
+    let usage: serde_json::Value = server.get("/usage").await.json().await.unwrap();
+    assert_eq!(usage["requests"], 3);
+    assert_eq!(usage["promptTokens"], 24);
+    assert_eq!(usage["completionTokens"], 12);
+    assert_eq!(usage["windowMinutes"], 1440);
+    assert_eq!(
+        usage["models"][0],
+        json!({
+            "provider": "anthropic",
+            "model": "claude-3-5-sonnet-20241022",
+            "requests": 2,
+            "promptTokens": 24,
+            "completionTokens": 12,
+            "totalTokens": 36
+        })
+    );
+    assert_eq!(usage["models"][1]["model"], "gpt-4o");
+
+    let usage: serde_json::Value = server.get("/usage?session=s1&minutes=5").await.json().await.unwrap();
+    assert_eq!(usage["sessionId"], "s1");
+    assert_eq!(usage["requests"], 1);
+    assert_eq!(usage["totalTokens"], 18);
+    let usage: serde_json::Value = server.get("/usage?provider=openai").await.json().await.unwrap();
+    assert_eq!(usage["requests"], 1);
+    assert_eq!(usage["models"].as_array().unwrap().len(), 1);
+
+    assert_eq!(server.get("/usage?minutes=0").await.status(), 400);
+}
+
+#[actix_web::test]
+async fn malformed_requests_get_an_error_per_field() {
+    let upstream = MockUpstream::start(vec![]).await;
+    let server = TestServer::start(&upstream, &[]).await;
+    let errors = |body: serde_json::Value| async {
+        let response = server.chat(body).await;
+        assert_eq!(response.status(), 400);
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(body["error"]["type"], "invalid_request_error");
+        body["errors"].as_array().unwrap().clone()
+    };
+
+    assert_eq!(
+        errors(json!({ "message": [{ "role": "user", "content": "Hi" }], "temperature": "0.5" })).await,
+        [
+            json!({ "field": "message", "message": "is not a known field; did you mean 'messages'?" }),
+            json!({ "field": "temperature", "message": "must be a number, not a string" }),
+        ]
+    );
+    assert_eq!(
+        errors(json!({
+            "messages": [{ "content": "Hi" }, { "role": "user", "content": ["Hi"] }, "Hi"],
+            "max_tokens": 100,
+            "maxSteps": 0,
+            "toolCallFormat": "anthropic"
+        }))
+        .await,
+        [
+            json!({ "field": "max_tokens", "message": "is not a known field; did you mean 'maxTokens'?" }),
+            json!({ "field": "messages[0].role", "message": "is required" }),
+            json!({ "field": "messages[1].content", "message": "must be a string, not an array" }),
+            json!({ "field": "messages[2]", "message": "must be an object, not a string" }),
+            json!({ "field": "maxSteps", "message": "must be between 1 and 4294967295" }),
+            json!({ "field": "toolCallFormat", "message": "must be one of ai-sdk, openai" }),
+        ]
+    );
+    assert_eq!(errors(json!([])).await, [json!({ "field": "", "message": "must be a JSON object, not an array" })]);
+    assert!(upstream.requests().is_empty());
+}
+
+#[actix_web::test]
+async fn accept_header_picks_between_stream_and_json() {
+    let upstream = MockUpstream::start(vec![("/v1/messages", vec![MockResponse::sse(ANTHROPIC_TEXT); 2])]).await;
+    let server = TestServer::start(&upstream, &[]).await;
+    let client = reqwest::Client::new();
+    let url = format!("{}/sdk-chat", server.base_url);
+
+    let response = client
+        .post(&url)
+        .header("Accept", "application/json, text/event-stream")
+        .json(&chat_request("claude-3-5-sonnet-20241022"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()["content-type"], "application/json");
+    assert_eq!(response.headers()["x-served-model"], "claude-3-5-sonnet-20241022");
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["text"], "Hello, world");
+    assert_eq!(body["finishReason"], "stop");
+
+    // An explicit stream field wins over the Accept header
+    let mut request = chat_request("claude-3-5-sonnet-20241022");
+    request["stream"] = json!(true);
+    request["aiSdkVersion"] = json!(4);
+    let response = client.post(&url).header("Accept", "application/json").json(&request).send().await.unwrap();
+    assert_eq!(response.headers()["content-type"], "text/event-stream");
+    assert_eq!(streamed_text(&parse_frames(&response.text().await.unwrap())), "Hello, world");
+
+    let response = client
+        .post(&url)
+        .header("Content-Type", "text/plain")
+        .body(chat_request("claude-3-5-sonnet-20241022").to_string())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 415);
+    assert_eq!(upstream.requests().len(), 2);
+}
+
+#[actix_web::test]
+async fn stream_transforms_redact_text_and_tool_call_arguments() {
+    let upstream =
+        MockUpstream::start(vec![("/v1/messages", vec![MockResponse::sse(ANTHROPIC_TEXT_AND_TOOL_USE)])]).await;
+    let server =
+        TestServer::start(&upstream, &[("STREAM_TRANSFORMS", "redact"), ("REDACT_PATTERNS", "check SELECT")]).await;
+
+    let frames = parse_frames(&server.chat(chat_request("claude-3-5-sonnet-20241022")).await.text().await.unwrap());
+    let prefixes: Vec<&str> = frames.iter().map(|(prefix, _)| prefix.as_str()).collect();
+    // "check." is held back until the tool call frame, since no whitespace follows it
+    assert_eq!(prefixes, ["f", "0", "0", "9", "e", "d"]);
+    assert_eq!(streamed_text(&frames), "Let me [redacted].");
+    let (_, tool_call) = frames.iter().find(|(prefix, _)| prefix == "9").unwrap();
+    assert_eq!(tool_call["args"], json!({ "sql": "[redacted] 1" }));
+}
+
+#[actix_web::test]
+async fn stream_transforms_redact_streamed_tool_call_argument_deltas() {
+    // The address is split across two argument deltas
+    let stream = OPENAI_TOOL_CALL
+        .replace(r#"{\"sql\":"#, r#"{\"sql\":\"SELECT * FROM users WHERE email = 'ada@"#)
+        .replace(r#"\"SELECT 1\"}"#, r#"example.com'\"}"#);
+    let upstream = MockUpstream::start(vec![("/v1/chat/completions", vec![MockResponse::sse(&stream)])]).await;
+    let server = TestServer::start(&upstream, &[("STREAM_TRANSFORMS", "redact")]).await;
+
+    let mut request = chat_request("gpt-4o");
+    request["toolCallStreaming"] = json!(true);
+    let body = server.chat(request).await.text().await.unwrap();
+    assert!(!body.contains("ada@") && !body.contains("example.com"), "{}", body);
+
+    let frames = without_step_frames(parse_frames(&body));
+    let args = json!({ "sql": "SELECT * FROM users WHERE email = '[redacted]'" });
+    assert_eq!(
+        frames[..3],
+        [
+            ("b".to_string(), json!({ "toolCallId": "call_1", "toolName": "executeSQL" })),
+            ("c".to_string(), json!({ "toolCallId": "call_1", "argsTextDelta": args.to_string() })),
+            ("9".to_string(), json!({ "toolCallId": "call_1", "toolName": "executeSQL", "args": args })),
+        ]
+    );
+}
+
+#[actix_web::test]
+async fn short_responses_are_sent_as_json_and_longer_ones_stream() {
+    let events: Vec<&str> = ANTHROPIC_TEXT.split_inclusive("\n\n").collect();
+    let upstream = MockUpstream::start(vec![(
+        "/v1/messages",
+        vec![
+            MockResponse::sse(ANTHROPIC_TEXT),
+            MockResponse::sse(ANTHROPIC_TEXT_AND_TOOL_USE),
+            MockResponse::chunked(&events, std::time::Duration::from_millis(100)),
+            MockResponse::sse(ANTHROPIC_TEXT),
+            MockResponse::sse(ANTHROPIC_TEXT),
+        ],
+    )])
+    .await;
+    let server = TestServer::start(&upstream, &[("SHORT_RESPONSE_WINDOW_MS", "250")]).await;
+    let request = chat_request("claude-3-5-sonnet-20241022");
+
+    let response = server.chat(request.clone()).await;
+    assert_eq!(response.headers()["content-type"], "application/json");
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["text"], "Hello, world");
+    assert_eq!(body["finishReason"], "stop");
+
+    // Tool calls always stream
+    let response = server.chat(request.clone()).await;
+    assert_eq!(response.headers()["content-type"], "text/event-stream");
+    let frames = parse_frames(&response.text().await.unwrap());
+    assert_eq!(streamed_text(&frames), "Let me check.");
+
+    // Slower than the window: the frames held back so far start the stream
+    let response = server.chat(request.clone()).await;
+    assert_eq!(response.headers()["content-type"], "text/event-stream");
+    let frames = parse_frames(&response.text().await.unwrap());
+    assert_eq!(frames.first().unwrap().0, "f");
+    assert_eq!(streamed_text(&frames), "Hello, world");
+    assert_eq!(frames.last().unwrap().1["finishReason"], "stop");
+
+    // Other output formats always get the format they asked for
+    let url = format!("{}/sdk-chat?format=text", server.base_url);
+    let response = reqwest::Client::new().post(&url).json(&request).send().await.unwrap();
+    assert_eq!(response.headers()["content-type"], "text/plain; charset=utf-8");
+    assert_eq!(response.text().await.unwrap(), "Hello, world");
+
+    let mut request = request;
+    request["stream"] = json!(true);
+    let response = server.chat(request).await;
+    assert_eq!(response.headers()["content-type"], "text/event-stream");
+}
+
+#[actix_web::test]
+async fn output_format_reencodes_the_stream_as_plain_text_or_json_lines() {
+    let upstream =
+        MockUpstream::start(vec![("/v1/messages", vec![MockResponse::sse(ANTHROPIC_TEXT_AND_TOOL_USE); 3])]).await;
+    let server = TestServer::start(&upstream, &[]).await;
+    let client = reqwest::Client::new();
+    let request = chat_request("claude-3-5-sonnet-20241022");
+
+    let response = client
+        .post(format!("{}/sdk-chat", server.base_url))
+        .header("X-Output-Format", "text")
+        .json(&request)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.headers()["content-type"], "text/plain; charset=utf-8");
+    assert_eq!(response.text().await.unwrap(), "Let me check.");
+
+    let url = format!("{}/sdk-chat?format=jsonl", server.base_url);
+    let response = client.post(&url).json(&request).send().await.unwrap();
+    assert_eq!(response.headers()["content-type"], "application/x-ndjson");
+    let events: Vec<serde_json::Value> =
+        response.text().await.unwrap().lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+    let types: Vec<&str> = events.iter().map(|event| event["type"].as_str().unwrap()).collect();
+    assert_eq!(types, ["step-start", "text", "text", "tool-call", "step-finish", "finish"]);
+    assert_eq!(events[1], json!({ "type": "text", "text": "Let me " }));
+    assert_eq!(events[3]["toolName"], "executeSQL");
+    assert_eq!(events[3]["args"], json!({ "sql": "SELECT 1" }));
+    assert_eq!(events[5]["finishReason"], "tool-calls");
+
+    // The default is the AI SDK stream
+    let frames = parse_frames(&server.chat(request.clone()).await.text().await.unwrap());
+    assert_eq!(streamed_text(&frames), "Let me check.");
+
+    let response = client
+        .post(format!("{}/sdk-chat", server.base_url))
+        .header("X-Output-Format", "xml")
+        .json(&request)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 400);
+    assert_eq!(upstream.requests().len(), 3);
+}
+
+#[actix_web::test]
+async fn tool_loop_guard_counts_only_the_current_loop() {
+    let upstream = MockUpstream::start(vec![("/v1/messages", vec![MockResponse::sse(ANTHROPIC_TEXT)])]).await;
+    let server = TestServer::start(&upstream, &[("MAX_TOOL_REPEATS", "2")]).await;
+    let tool_step = |id: &str| {
+        json!({ "role": "assistant", "content": "", "toolInvocations": [{
+            "state": "result", "step": 0, "toolCallId": id, "toolName": "executeSQL",
+            "args": { "sql": "SELECT 1" }, "result": [{ "count": 1 }],
+        }] })
+    };
+    let request = |messages: &[serde_json::Value]| json!({ "model": "claude-3-5-sonnet-20241022", "messages": messages });
+    let mut messages =
+        vec![json!({ "role": "user", "content": "Count the rows" }), tool_step("call_1"), tool_step("call_2")];
+
+    let frames = parse_frames(&server.chat(request(&messages)).await.text().await.unwrap());
+    assert_eq!(frames[1].1[0]["type"], "tool-loop-guard");
+    assert_eq!(frames[1].1[0]["repeats"], 2);
+    assert!(upstream.requests().is_empty());
+
+    // A new user message starts a new loop, so the same call after it is the first of its run
+    messages.push(json!({ "role": "user", "content": "Try again" }));
+    messages.push(tool_step("call_3"));
+    let frames = parse_frames(&server.chat(request(&messages)).await.text().await.unwrap());
+    assert_eq!(streamed_text(&frames), "Hello, world");
+    assert_eq!(upstream.requests().len(), 1);
+}
+
+#[actix_web::test]
+async fn tool_loop_guard_ends_repeated_tool_calls_with_a_finished_stream() {
+    let upstream = MockUpstream::start(vec![]).await;
+    let server = TestServer::start(&upstream, &[]).await;
+    let tool_step = |id: &str| {
+        json!({ "role": "assistant", "content": "", "toolInvocations": [{
+            "state": "result", "step": 0, "toolCallId": id, "toolName": "executeSQL",
+            "args": { "sql": "SELECT 1" }, "result": [{ "count": 1 }],
+        }] })
+    };
+    let request = json!({
+        "model": "claude-3-5-sonnet-20241022",
+        "messages": [
+            { "role": "user", "content": "Count the rows" },
+            tool_step("call_1"),
+            tool_step("call_2"),
+            tool_step("call_3"),
+        ],
+    });
+
+    let frames = parse_frames(&server.chat(request).await.text().await.unwrap());
+    let prefixes: Vec<&str> = frames.iter().map(|(prefix, _)| prefix.as_str()).collect();
+    assert_eq!(prefixes, ["f", "8", "0", "e", "d"]);
+    assert_eq!(frames[1].1[0]["type"], "tool-loop-guard");
+    assert_eq!(frames[1].1[0]["repeats"], 3);
+    assert_eq!(frames.last().unwrap().1["finishReason"], "stop");
+    assert!(upstream.requests().is_empty());
+}
+
+#[actix_web::test]
+async fn max_steps_stops_a_loop_whose_model_keeps_calling_tools() {
+    let upstream =
+        MockUpstream::start(vec![("/v1/messages", vec![MockResponse::sse(ANTHROPIC_TEXT_AND_TOOL_USE); 2])]).await;
+    let server = TestServer::start(&upstream, &[]).await;
+    let tool_step = |id: &str| {
+        json!({ "role": "assistant", "content": "", "toolInvocations": [{
+            "state": "result", "step": 0, "toolCallId": id, "toolName": "executeSQL",
+            "args": { "sql": "SELECT 1" }, "result": [{ "count": 1 }],
+        }] })
+    };
+    let mut messages = vec![json!({ "role": "user", "content": "Count the rows" })];
+    messages.push(tool_step("call_1"));
+    let mut request = json!({ "model": "claude-3-5-sonnet-20241022", "messages": messages, "maxSteps": 2 });
+
+    // Under the limit the model may call another tool
+    let frames = parse_frames(&server.chat(request.clone()).await.text().await.unwrap());
+    assert!(frames.iter().any(|(prefix, _)| prefix == "9"));
+    assert_eq!(upstream.requests()[0].body.get("tool_choice"), None);
+
+    // At the limit it is asked for an answer without tools; the tool call it makes anyway ends the stream
+    messages.push(tool_step("call_2"));
+    request["messages"] = json!(messages);
+    let frames = parse_frames(&server.chat(request.clone()).await.text().await.unwrap());
+    assert_eq!(upstream.requests()[1].body["tool_choice"], json!({ "type": "none" }));
+    let prefixes: Vec<&str> = frames.iter().map(|(prefix, _)| prefix.as_str()).collect();
+    assert_eq!(prefixes, ["f", "0", "0", "e", "d"]);
+    assert_eq!(frames.last().unwrap().1["finishReason"], "max-steps");
+
+    // A forced tool call can't be answered at all
+    request["toolChoice"] = json!("required");
+    let frames = parse_frames(&server.chat(request).await.text().await.unwrap());
+    assert_eq!(frames[1].1[0]["type"], "max-steps");
+    assert_eq!(frames.last().unwrap().1["finishReason"], "max-steps");
+    assert_eq!(upstream.requests().len(), 2);
+
+    let metrics = server.get("/metrics").await.text().await.unwrap();
+    assert!(metrics.contains(r#"api_step_limit_stops_total{outcome="final_completion"} 1"#), "{}", metrics);
+    assert!(metrics.contains(r#"api_step_limit_stops_total{outcome="terminal_message"} 1"#), "{}", metrics);
+}
+
+#[actix_web::test]
+async fn batch_items_in_one_session_keep_their_own_tool_calls() {
+    let first: Vec<&str> = OPENAI_TOOL_CALL.split_inclusive("\n\n").collect();
+    let second_stream = OPENAI_TOOL_CALL.replace("call_1", "call_2").replace("SELECT 1", "SELECT 2");
+    let second: Vec<&str> = second_stream.split_inclusive("\n\n").collect();
+    let delay = std::time::Duration::from_millis(30);
+    let upstream = MockUpstream::start(vec![(
+        "/v1/chat/completions",
+        vec![MockResponse::chunked(&first, delay), MockResponse::chunked(&second, delay)],
+    )])
+    .await;
+    let server = TestServer::start(&upstream, &[]).await;
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/batch", server.base_url))
+        .header("X-Session-Id", "shared-session")
+        .json(&json!([chat_request("gpt-4o"), chat_request("gpt-4o")]))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let results: serde_json::Value = response.json().await.unwrap();
+    let mut tool_calls: Vec<(String, serde_json::Value)> = results
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|result| {
+            assert_eq!(result["toolCalls"].as_array().unwrap().len(), 1, "{}", result);
+            let tool_call = &result["toolCalls"][0];
+            (tool_call["toolCallId"].as_str().unwrap().to_string(), tool_call["args"].clone())
+        })
+        .collect();
+    tool_calls.sort_by(|a, b| a.0.cmp(&b.0));
+    assert_eq!(
+        tool_calls,
+        [
+            ("call_1".to_string(), json!({ "sql": "SELECT 1" })),
+            ("call_2".to_string(), json!({ "sql": "SELECT 2" })),
+        ]
+    );
+}
+
+#[actix_web::test]
+async fn batch_items_are_cancelled_by_their_own_request_ids() {
+    // The second item's response would only finish after the test gives up on it
+    let split = OPENAI_TEXT[..OPENAI_TEXT.find(", world").unwrap()].rfind("data: ").unwrap();
+    let (first, rest) = OPENAI_TEXT.split_at(split);
+    let upstream = MockUpstream::start(vec![
+        ("/v1/messages", vec![MockResponse::sse(ANTHROPIC_TEXT)]),
+        (
+            "/v1/chat/completions",
+            vec![MockResponse::chunked(&[first, rest], std::time::Duration::from_secs(30))],
+        ),
+    ])
+    .await;
+    let server = TestServer::start(&upstream, &[]).await;
+    let client = reqwest::Client::new();
+
+    let batch = client
+        .post(format!("{}/batch", server.base_url))
+        .header("X-Request-Id", "batch-1")
+        .json(&json!([chat_request("claude-3-5-sonnet-20241022"), chat_request("gpt-4o")]))
+        .send();
+    let cancel = async {
+        while upstream.requests().len() < 2 {
+            actix_web::rt::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+        client.post(format!("{}/cancel/batch-1-1", server.base_url)).send().await.unwrap()
+    };
+    let (response, cancel) = futures::join!(
+        actix_web::rt::time::timeout(std::time::Duration::from_secs(5), batch),
+        cancel
+    );
+    assert_eq!(cancel.status(), 200);
+    let response = response.expect("batch ends after cancel").unwrap();
+    assert_eq!(response.headers()["x-request-id"], "batch-1");
+    let results: serde_json::Value = response.json().await.unwrap();
+
+    assert_eq!(results[0]["requestId"], "batch-1-0");
+    assert_eq!(results[0]["text"], "Hello, world");
+    assert_eq!(results[1]["requestId"], "batch-1-1");
+    assert_eq!(results[1]["text"], "Hello");
+    assert_eq!(results[1]["finishReason"], "stop");
+}
+
+#[actix_web::test]
+async fn batch_returns_buffered_results_in_order_with_isolated_failures() {
+    let upstream = MockUpstream::start(vec![
+        ("/v1/messages", vec![MockResponse::sse(ANTHROPIC_TEXT)]),
+        ("/v1/chat/completions", vec![MockResponse::sse(OPENAI_TOOL_CALL)]),
+    ])
+    .await;
+    let server = TestServer::start(&upstream, &[]).await;
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/batch", server.base_url))
+        .json(&json!([
+            chat_request("claude-3-5-sonnet-20241022"),
+            { "model": "gpt-4o" },
+            chat_request("gpt-4o"),
+        ]))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let results: serde_json::Value = response.json().await.unwrap();
+    let results = results.as_array().unwrap();
+    assert_eq!(results.len(), 3);
+
+    assert_eq!(results[0]["text"], "Hello, world");
+    assert_eq!(results[0]["finishReason"], "stop");
+    assert_eq!(results[0]["usage"], json!({ "promptTokens": 12, "completionTokens": 6 }));
+    assert_eq!(results[0]["error"], serde_json::Value::Null);
+
+    assert_eq!(results[1]["finishReason"], "error");
+    assert_eq!(results[1]["error"]["code"], 400);
+    assert_eq!(results[1]["error"]["type"], "invalid_request_error");
+
+    assert_eq!(results[2]["finishReason"], "tool-calls");
+    assert_eq!(results[2]["toolCalls"][0]["toolName"], "executeSQL");
+    assert_eq!(results[2]["toolCalls"][0]["args"], json!({ "sql": "SELECT 1" }));
+}